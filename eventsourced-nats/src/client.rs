@@ -0,0 +1,333 @@
+//! Connecting to [NATS](https://nats.io/), shared by the [NatsEvtLog](crate::NatsEvtLog) and
+//! the [NatsSnapshotStore](crate::NatsSnapshotStore).
+
+use crate::Error;
+use async_nats::{Client, ConnectOptions, ServerAddr};
+use serde::{Deserialize, Serialize};
+use std::{path::PathBuf, time::Duration};
+use tracing::debug;
+
+/// Configuration for connecting to NATS.
+///
+/// To share one connection between the [NatsEvtLog](crate::NatsEvtLog) and the
+/// [NatsSnapshotStore](crate::NatsSnapshotStore), [connect] once and create both with their
+/// respective `with_client` function.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct ClientConfig {
+    /// One or more comma separated server addresses, e.g. "nats://a:4222,nats://b:4222".
+    #[serde(default = "server_addr_default")]
+    pub server_addr: String,
+
+    /// Name of the connection, e.g. shown in the monitoring of the server.
+    pub connection_name: Option<String>,
+
+    /// Path to a credentials file, i.e. JWT and nkey seed.
+    pub credentials: Option<PathBuf>,
+
+    pub nkey_seed: Option<String>,
+
+    pub user: Option<String>,
+
+    pub password: Option<String>,
+
+    pub token: Option<String>,
+
+    #[serde(default)]
+    pub tls: TlsConfig,
+
+    #[serde(default = "connection_timeout_default", with = "humantime_serde")]
+    pub connection_timeout: Duration,
+
+    /// Whether to retry connecting initially, else creating the client fails if no server is
+    /// available.
+    #[serde(default)]
+    pub retry_on_initial_connect: bool,
+
+    /// Delay before the first reconnect attempt, doubled for every further attempt.
+    #[serde(default = "reconnect_delay_min_default", with = "humantime_serde")]
+    pub reconnect_delay_min: Duration,
+
+    /// Maximum delay between reconnect attempts.
+    #[serde(default = "reconnect_delay_max_default", with = "humantime_serde")]
+    pub reconnect_delay_max: Duration,
+}
+
+impl ClientConfig {
+    /// The parsed server addresses.
+    pub fn server_addrs(&self) -> Result<Vec<ServerAddr>, Error> {
+        self.server_addr
+            .split(',')
+            .map(|server_addr| {
+                server_addr.trim().parse::<ServerAddr>().map_err(|error| {
+                    Error::Nats(
+                        format!("invalid NATS server address '{server_addr}'"),
+                        error.into(),
+                    )
+                })
+            })
+            .collect()
+    }
+}
+
+impl Default for ClientConfig {
+    /// Connect to "localhost:4222" without authentication and TLS.
+    fn default() -> Self {
+        Self {
+            server_addr: server_addr_default(),
+            connection_name: None,
+            credentials: None,
+            nkey_seed: None,
+            user: None,
+            password: None,
+            token: None,
+            tls: TlsConfig::default(),
+            connection_timeout: connection_timeout_default(),
+            retry_on_initial_connect: false,
+            reconnect_delay_min: reconnect_delay_min_default(),
+            reconnect_delay_max: reconnect_delay_max_default(),
+        }
+    }
+}
+
+/// TLS configuration for connecting to NATS.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct TlsConfig {
+    /// Whether TLS is required, else it is used if the server supports it.
+    #[serde(default)]
+    pub require: bool,
+
+    /// Paths to PEM files with additional root certificates.
+    #[serde(default)]
+    pub ca_certs: Vec<PathBuf>,
+
+    /// Path to a PEM file with the client certificate, requires `client_key`.
+    pub client_cert: Option<PathBuf>,
+
+    /// Path to a PEM file with the client key, requires `client_cert`.
+    pub client_key: Option<PathBuf>,
+}
+
+/// Connect to NATS with the given configuration.
+pub async fn connect(config: &ClientConfig) -> Result<Client, Error> {
+    debug!(server_addr = config.server_addr, "connecting to NATS");
+
+    let mut options = ConnectOptions::new()
+        .connection_timeout(config.connection_timeout)
+        .require_tls(config.tls.require);
+
+    if let Some(name) = &config.connection_name {
+        options = options.name(name);
+    }
+
+    // Authentication.
+    if let Some(credentials) = &config.credentials {
+        options = options
+            .credentials_file(credentials)
+            .await
+            .map_err(|error| {
+                Error::Nats(
+                    format!(
+                        "cannot read NATS credentials file at {}",
+                        credentials.display()
+                    ),
+                    error.into(),
+                )
+            })?;
+    };
+    if let Some(nkey_seed) = &config.nkey_seed {
+        options = options.nkey(nkey_seed.clone());
+    }
+    match (&config.user, &config.password) {
+        (Some(user), Some(password)) => {
+            options = options.user_and_password(user.clone(), password.clone());
+        }
+        (None, None) => {}
+        _ => return Err(Error::InvalidClientConfig("user and password")),
+    }
+    if let Some(token) = &config.token {
+        options = options.token(token.clone());
+    }
+
+    // TLS.
+    for ca_cert in &config.tls.ca_certs {
+        options = options.add_root_certificates(ca_cert.clone());
+    }
+    match (&config.tls.client_cert, &config.tls.client_key) {
+        (Some(cert), Some(key)) => {
+            options = options.add_client_certificate(cert.clone(), key.clone());
+        }
+        (None, None) => {}
+        _ => return Err(Error::InvalidClientConfig("client certificate and key")),
+    }
+
+    // Reconnecting.
+    if config.retry_on_initial_connect {
+        options = options.retry_on_initial_connect();
+    }
+    let reconnect_delay_min = config.reconnect_delay_min;
+    let reconnect_delay_max = config.reconnect_delay_max;
+    options = options.reconnect_delay_callback(move |attempts| {
+        reconnect_delay(attempts, reconnect_delay_min, reconnect_delay_max)
+    });
+
+    options
+        .connect(config.server_addrs()?)
+        .await
+        .map_err(|error| {
+            Error::Nats(
+                format!("cannot connect to NATS server at {}", config.server_addr),
+                error.into(),
+            )
+        })
+}
+
+/// Exponential backoff, no delay for the first attempt.
+fn reconnect_delay(attempts: usize, min: Duration, max: Duration) -> Duration {
+    if attempts <= 1 {
+        Duration::ZERO
+    } else {
+        let factor = 2_u32.saturating_pow((attempts - 2).min(31) as u32);
+        min.saturating_mul(factor).min(max)
+    }
+}
+
+fn server_addr_default() -> String {
+    "localhost:4222".to_string()
+}
+
+const fn connection_timeout_default() -> Duration {
+    Duration::from_secs(5)
+}
+
+const fn reconnect_delay_min_default() -> Duration {
+    Duration::from_millis(100)
+}
+
+const fn reconnect_delay_max_default() -> Duration {
+    Duration::from_secs(8)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        tests::NATS_VERSION, NatsEvtLog, NatsEvtLogConfig, NatsSnapshotStore,
+        NatsSnapshotStoreConfig,
+    };
+    use error_ext::BoxError;
+    use eventsourced::{EventSourced, EvtLog, SnapshotStore};
+    use serde_json::json;
+    use std::convert::Infallible;
+    use testcontainers::{clients::Cli, core::WaitFor};
+    use testcontainers_modules::testcontainers::GenericImage;
+    use uuid::Uuid;
+
+    #[derive(Debug)]
+    struct Dummy;
+
+    impl EventSourced for Dummy {
+        type Id = Uuid;
+        type Cmd = ();
+        type Evt = u32;
+        type State = u64;
+        type Error = Infallible;
+
+        const TYPE_NAME: &'static str = "dummy";
+
+        fn handle_cmd(
+            _id: &Self::Id,
+            _state: &Self::State,
+            _cmd: Self::Cmd,
+        ) -> Result<Self::Evt, Self::Error> {
+            todo!()
+        }
+
+        fn handle_evt(_state: Self::State, _evt: Self::Evt) -> Self::State {
+            todo!()
+        }
+    }
+
+    #[test]
+    fn test_config() -> Result<(), BoxError> {
+        let config = serde_json::from_value::<ClientConfig>(json!({
+            "server-addr": "nats://a:4222, nats://b:4222",
+            "user": "user",
+            "password": "password",
+            "tls": { "require": true },
+            "reconnect-delay-max": "1s"
+        }))?;
+        assert_eq!(config.server_addrs()?.len(), 2);
+        assert!(config.tls.require);
+        assert_eq!(config.connection_timeout, Duration::from_secs(5));
+
+        let min = config.reconnect_delay_min;
+        let max = config.reconnect_delay_max;
+        assert_eq!(reconnect_delay(1, min, max), Duration::ZERO);
+        assert_eq!(reconnect_delay(2, min, max), Duration::from_millis(100));
+        assert_eq!(reconnect_delay(4, min, max), Duration::from_millis(400));
+        assert_eq!(reconnect_delay(100, min, max), Duration::from_secs(1));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_shared_client() -> Result<(), BoxError> {
+        let client = Cli::default();
+        let nats_image = GenericImage::new("nats", NATS_VERSION)
+            .with_wait_for(WaitFor::message_on_stderr("Server is ready"));
+        let container = client.run((nats_image, vec!["-js".to_string()]));
+        let server_addr = format!("localhost:{}", container.get_host_port_ipv4(4222));
+
+        let client = connect(&ClientConfig {
+            server_addr,
+            connection_name: Some("test".to_string()),
+            ..Default::default()
+        })
+        .await?;
+
+        let mut evt_log = NatsEvtLog::<Uuid>::with_client(
+            client.clone(),
+            NatsEvtLogConfig {
+                setup: true,
+                ..Default::default()
+            },
+        )
+        .await?;
+        let mut snapshot_store = NatsSnapshotStore::<Uuid>::with_client(
+            client,
+            NatsSnapshotStoreConfig {
+                setup: true,
+                ..Default::default()
+            },
+        )
+        .await?;
+
+        let id = Uuid::now_v7();
+        let seq_no = evt_log
+            .persist::<Dummy, _, _>(
+                &1,
+                &id,
+                None,
+                None,
+                &eventsourced::binarize::serde_json::to_bytes,
+            )
+            .await?
+            .seq_no;
+        snapshot_store
+            .save(
+                &id,
+                seq_no,
+                &42,
+                &eventsourced::binarize::serde_json::to_bytes,
+            )
+            .await?;
+        let snapshot = snapshot_store
+            .load::<i32, _, _>(&id, eventsourced::binarize::serde_json::from_bytes)
+            .await?;
+        assert_eq!(snapshot.map(|snapshot| snapshot.seq_no), Some(seq_no));
+
+        Ok(())
+    }
+}
@@ -1,116 +1,399 @@
 //! An [EvtLog] implementation based on [NATS](https://nats.io/).
 
-use crate::Error;
+use crate::{
+    client::connect,
+    setup::{create_or_update, stream_config, Drift},
+    ClientConfig, Error, IdEncoding, StreamConfig,
+};
 use async_nats::{
     jetstream::{
         self,
         consumer::{pull, AckPolicy, DeliverPolicy},
-        context::Publish,
-        stream::{LastRawMessageErrorKind, Stream as JetstreamStream},
+        context::{Publish, PublishErrorKind},
+        response::Response,
+        stream::{LastRawMessageErrorKind, RawMessage, Stream as JetstreamStream},
         Context as Jetstream, Message,
     },
-    ConnectOptions,
+    Client, HeaderMap,
 };
 use bytes::Bytes;
-use eventsourced::{EventSourced, EvtLog};
-use futures::{future::ready, Stream, StreamExt, TryStreamExt};
-use serde::{Deserialize, Serialize};
+use eventsourced::{slot, EventSourced, EvtLog, EvtsQuery, Persisted};
+use futures::{future::ready, stream, Stream, StreamExt, TryStreamExt};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::json;
 use std::{
+    collections::{HashMap, VecDeque},
     error::Error as StdError,
     fmt::{self, Debug, Display, Formatter},
     marker::PhantomData,
     num::NonZeroU64,
-    path::PathBuf,
+    str::FromStr,
     time::Duration,
 };
-use tracing::{debug, instrument};
+use tracing::{debug, instrument, warn};
+
+/// The maximum number of messages fetched at once for current events.
+const FETCH_BATCH_SIZE: usize = 256;
+
+/// Header for the sequence number of an event for its entity.
+pub const SEQ_NO_HEADER: &str = "EventSourced-Seq-No";
+
+/// Header for the idempotency key of the command which has resulted in an event, if any.
+pub const IDEMPOTENCY_KEY_HEADER: &str = "EventSourced-Idempotency-Key";
+
+/// Header for the tags of an event, one value per tag.
+pub const TAG_HEADER: &str = "EventSourced-Tag";
+
+/// Header for the position of an event in the copies published for its tags.
+pub const POSITION_HEADER: &str = "EventSourced-Position";
 
 /// An [EvtLog] implementation based on [NATS](https://nats.io/).
+///
+/// The events of an entity are published to their own subject with dense sequence numbers, i.e.
+/// starting at one and without gaps, in the [SEQ_NO_HEADER] header; optimistic locking is enforced
+/// by expecting the stream sequence of the last message on the subject, which is looked up before
+/// publishing unless the last event of the entity has been persisted by this event log. Events
+/// published by earlier versions, i.e. without that header, are numbered by their position on their
+/// subject, hence existing streams can be used as is, as long as no messages have been removed from
+/// them; snapshots taken before contain stream sequences and are ignored by the
+/// [NatsSnapshotStore](crate::NatsSnapshotStore).
+///
+/// Like the events by type, the events by ID are tailed with a consumer, i.e. without end, unless
+/// only the current ones are queried, which are fetched in batches up to the last one at query
+/// time. The consumer starts at the stream sequence of the requested event, which is found with a
+/// binary search over the stream sequences, i.e. with a logarithmic number of requests. Current
+/// events in reverse order are buffered up to the limit, as messages can only be read in ascending
+/// order.
+///
+/// As the events of a type are spread over many subjects, the sequence numbers of the events by
+/// type and the last sequence number by type are stream sequences, which are increasing but not
+/// dense; these are the positions of the persisted events.
+///
+/// Tags of events are published in the [TAG_HEADER] header and, as a subject has room for the ID
+/// only, a copy of an event with its position in the [POSITION_HEADER] header is published for
+/// each of its tags to `{evt_stream_name}._tags.{type_name}.{tag}`, with the tag escaped like by
+/// [IdEncoding::Escape]. Hence the events by tag are read from their own subject and are numbered
+/// by the positions of the events. Publishing the copies is not atomic with publishing the event:
+/// if it fails, the event is persisted nevertheless, yet an error is returned.
+///
+/// Entity IDs are encoded with the configured [IdEncoding] as the last token(s) of the subjects,
+/// which are `{evt_stream_name}.{type_name}.{id}`; IDs with dots span multiple tokens.
 #[derive(Clone)]
 pub struct NatsEvtLog<I> {
     evt_stream_name: String,
+    evt_stream: StreamConfig,
+    id_encoding: IdEncoding,
     jetstream: Jetstream,
+    last_persisted: Option<LastPersisted>,
     _id: PhantomData<I>,
 }
 
+/// The subject, the sequence number and the stream sequence of the last persisted event.
+type LastPersisted = (String, NonZeroU64, u64);
+
 impl<I> NatsEvtLog<I> {
     #[allow(missing_docs)]
     pub async fn new(config: Config) -> Result<Self, Error> {
+        let client = connect(&config.client).await?;
+        Self::with_client(client, config).await
+    }
+
+    /// Create a [NatsEvtLog] with the given client, e.g. shared with a
+    /// [NatsSnapshotStore](crate::NatsSnapshotStore), ignoring the client configuration.
+    pub async fn with_client(client: Client, config: Config) -> Result<Self, Error> {
         debug!(?config, "creating NatsEvtLog");
 
-        let mut options = ConnectOptions::new();
-        if let Some(credentials) = config.credentials {
-            options = options
-                .credentials_file(&credentials)
-                .await
-                .map_err(|error| {
-                    Error::Nats(
-                        format!(
-                            "cannot read NATS credentials file at {})",
-                            credentials.display()
-                        ),
-                        error.into(),
-                    )
-                })?;
-        };
-        let client = options
-            .connect(&config.server_addr)
-            .await
-            .map_err(|error| {
-                Error::Nats(
-                    format!("cannot connect to NATS server at {})", config.server_addr),
-                    error.into(),
-                )
-            })?;
         let jetstream = jetstream::new(client);
 
         // Setup stream.
+        let evt_stream = config.evt_stream();
         if config.setup {
-            jetstream
-                .create_stream(jetstream::stream::Config {
-                    name: config.evt_stream_name.clone(),
-                    subjects: vec![format!("{}.>", config.evt_stream_name)],
-                    max_bytes: config.evt_stream_max_bytes,
-                    ..Default::default()
-                })
-                .await
-                .map_err(|error| {
-                    Error::Nats(
-                        format!("cannot create evt stream '{}'", config.evt_stream_name),
-                        error.into(),
-                    )
-                })?;
+            let name = &config.evt_stream_name;
+            let desired = &evt_stream;
+            create_or_update(
+                &jetstream,
+                name,
+                || async {
+                    let mut stream_config = jetstream::stream::Config {
+                        name: name.clone(),
+                        subjects: vec![format!("{name}.>")],
+                        ..Default::default()
+                    };
+                    desired.apply(&mut stream_config);
+                    jetstream
+                        .create_stream(stream_config)
+                        .await
+                        .map_err(|error| {
+                            Error::Nats(format!("cannot create evt stream '{name}'"), error.into())
+                        })?;
+                    Ok(())
+                },
+                |stream_config| desired.drift(stream_config),
+                |stream_config| desired.apply(stream_config),
+            )
+            .await?;
         }
 
         Ok(Self {
             evt_stream_name: config.evt_stream_name,
+            evt_stream,
+            id_encoding: config.id_encoding,
             jetstream,
+            last_persisted: None,
             _id: PhantomData,
         })
     }
 
-    async fn evts<E, F, FromBytes, FromBytesError>(
-        &self,
-        subject: String,
-        seq_no: NonZeroU64,
-        filter: F,
-        from_bytes: FromBytes,
-    ) -> Result<impl Stream<Item = Result<(NonZeroU64, E), Error>> + Send, Error>
+    /// The differences between the actual configuration of the evt stream and the desired one.
+    pub async fn drift(&self) -> Result<Vec<Drift>, Error> {
+        stream_config(&self.jetstream, &self.evt_stream_name)
+            .await?
+            .map(|stream_config| self.evt_stream.drift(&stream_config))
+            .ok_or_else(|| Error::StreamNotFound(self.evt_stream_name.clone()))
+    }
+
+    /// Decode the entity ID from the given subject of an event, e.g. of a message received from
+    /// the evt stream.
+    pub fn id_of(&self, subject: &str) -> Result<I, Error>
     where
-        E: Send,
-        F: Fn(&Message) -> bool + Send,
-        FromBytes: Fn(Bytes) -> Result<E, FromBytesError> + Copy + Send + Sync + 'static,
-        FromBytesError: StdError + Send + Sync + 'static,
+        I: FromStr,
+        I::Err: StdError + Send + Sync + 'static,
     {
-        let msgs = msgs(
+        let encoded = encoded_id(subject)
+            .ok_or_else(|| Error::InvalidEncodedId(subject.to_string(), None))?;
+        self.id_encoding.decode(encoded)
+    }
+
+    /// The subject of the events of the entity with the given type name and ID.
+    fn subject(&self, type_name: &str, id: &impl Display) -> Result<String, Error> {
+        let id = self.id_encoding.encode(id)?;
+        Ok(format!("{}.{type_name}.{id}", self.evt_stream_name))
+    }
+
+    /// The subject of the copies of the events with the given type name and tag.
+    fn tag_subject(&self, type_name: &str, tag: &str) -> Result<String, Error> {
+        let tag = IdEncoding::Escape.encode(&tag)?;
+        Ok(format!("{}._tags.{type_name}.{tag}", self.evt_stream_name))
+    }
+
+    async fn msgs(
+        &self,
+        subject: String,
+        deliver_policy: DeliverPolicy,
+    ) -> Result<impl Stream<Item = Result<Message, Error>> + Send, Error> {
+        msgs(
             &self.jetstream,
             &self.evt_stream_name,
             subject,
-            start_at(seq_no),
+            deliver_policy,
+        )
+        .await
+    }
+
+    /// The sequence number and the stream sequence of the last message on the given subject of an
+    /// entity.
+    async fn last_msg_by_id(&self, subject: &str) -> Result<Option<(NonZeroU64, u64)>, Error> {
+        let Some(msg) = self.last_raw_msg(subject).await? else {
+            return Ok(None);
+        };
+        let stream_seq_no = msg.sequence;
+        let msg = async_nats::Message::try_from(msg)
+            .map_err(|error| Error::Nats("cannot decode last message".into(), error))?;
+
+        let seq_no = match header_seq_no(msg.headers.as_ref()).transpose()? {
+            Some(seq_no) => seq_no,
+
+            // Published by an earlier version, i.e. numbered by its position on the subject.
+            None => self.subject_msg_count(subject).await?,
+        };
+
+        Ok(Some((seq_no, stream_seq_no)))
+    }
+
+    async fn last_raw_msg(&self, subject: &str) -> Result<Option<RawMessage>, Error> {
+        stream(&self.jetstream, &self.evt_stream_name)
+            .await?
+            .get_last_raw_message_by_subject(subject)
+            .await
+            .map_or_else(
+                |error| {
+                    if error.kind() == LastRawMessageErrorKind::NoMessageFound {
+                        debug!(subject, "no last message found");
+                        Ok(None)
+                    } else {
+                        Err(Error::Nats(
+                            format!(
+                                "cannot get last message for NATS stream '{}'",
+                                self.evt_stream_name
+                            ),
+                            error.into(),
+                        ))
+                    }
+                },
+                |msg| Ok(Some(msg)),
+            )
+    }
+
+    /// The number of messages on the given subject, using the JetStream API directly, as the
+    /// client does not support filtering the stream info by subject.
+    async fn subject_msg_count(&self, subject: &str) -> Result<NonZeroU64, Error> {
+        #[derive(Deserialize)]
+        struct StreamInfo {
+            state: StreamState,
+        }
+
+        #[derive(Deserialize)]
+        struct StreamState {
+            #[serde(default)]
+            subjects: HashMap<String, u64>,
+        }
+
+        let info = api_request::<StreamInfo>(
+            &self.jetstream,
+            format!("STREAM.INFO.{}", self.evt_stream_name),
+            json!({ "subjects_filter": subject }),
+            || format!("cannot get info for NATS stream '{}'", self.evt_stream_name),
+        )
+        .await?;
+
+        info.state
+            .subjects
+            .get(subject)
+            .copied()
+            .unwrap_or_default()
+            .try_into()
+            .map_err(|_| Error::InvalidNonZeroU64)
+    }
+
+    /// Replay the messages on the given subject starting at the given sequence number with their
+    /// sequence numbers and positions, given the sequence number and the stream sequence of the
+    /// last message on the subject, if any: if `current` up to that last message, else without end,
+    /// tailing newly published messages. Messages before the given sequence number might be
+    /// included, if published by an earlier version, see [replay_start](NatsEvtLog::replay_start).
+    async fn replay(
+        &self,
+        subject: String,
+        seq_no: NonZeroU64,
+        last: Option<(NonZeroU64, u64)>,
+        current: bool,
+    ) -> Result<impl Stream<Item = Result<(Persisted, Message), Error>> + Send, Error> {
+        let (start, position) = match last {
+            Some((last_seq_no, last_stream_seq_no)) if seq_no > last_seq_no => {
+                (last_stream_seq_no + 1, last_seq_no.get())
+            }
+            Some(last) => self.replay_start(&subject, seq_no, last).await?,
+            None => (1, 0),
+        };
+        let deliver_policy = DeliverPolicy::ByStartSequence {
+            start_sequence: start,
+        };
+
+        let msgs = match last {
+            _ if !current => self.msgs(subject, deliver_policy).await?.left_stream(),
+
+            Some((last_seq_no, last_stream_seq_no)) if seq_no <= last_seq_no => current_msgs(
+                &self.jetstream,
+                &self.evt_stream_name,
+                subject,
+                deliver_policy,
+                last_stream_seq_no,
+            )
+            .await?
+            .left_stream()
+            .right_stream(),
+
+            _ => stream::empty().right_stream().right_stream(),
+        };
+
+        // Messages published by earlier versions are numbered by their position.
+        let msgs = msgs.scan(position, |position, msg| {
+            let msg = msg.and_then(|msg| {
+                let stream_seq_no = stream_seq_no(&msg)?;
+                let seq_no = header_seq_no(msg.headers.as_ref()).unwrap_or_else(|| {
+                    (*position + 1)
+                        .try_into()
+                        .map_err(|_| Error::InvalidNonZeroU64)
+                })?;
+                *position = seq_no.get();
+                let persisted = Persisted {
+                    seq_no,
+                    position: stream_seq_no,
+                };
+                Ok((persisted, msg))
+            });
+            ready(Some(msg))
+        });
+
+        Ok(msgs)
+    }
+
+    /// The stream sequence at which to start replaying the messages on the given subject from the
+    /// given sequence number along with the sequence number of the message before, given the
+    /// sequence number and the stream sequence of the last message on the subject.
+    ///
+    /// The message with the given sequence number is found by a binary search over the stream
+    /// sequences, getting the next message on the subject at a stream sequence via the JetStream
+    /// API, i.e. with a logarithmic number of requests and without creating a consumer: it has at
+    /// least the given sequence number as stream sequence and is followed by the later messages
+    /// on the subject. If it has been published by an earlier version, i.e. is numbered by its
+    /// position on the subject, or cannot be found, e.g. because messages have been removed, all
+    /// messages are replayed.
+    async fn replay_start(
+        &self,
+        subject: &str,
+        seq_no: NonZeroU64,
+        (last_seq_no, last_stream_seq_no): (NonZeroU64, u64),
+    ) -> Result<(u64, u64), Error> {
+        let mut lo = seq_no.get();
+        let mut hi = last_stream_seq_no.saturating_sub(last_seq_no.get() - seq_no.get());
+
+        // The first message with at least the given sequence number found so far.
+        let mut found = None;
+        while lo > 1 && lo <= hi {
+            let mid = lo + (hi - lo) / 2;
+            let (stream_seq_no, n) = self.next_msg_seq_no(subject, mid).await?;
+            match n {
+                Some(n) if n >= seq_no => {
+                    found = Some((stream_seq_no, n));
+                    hi = mid - 1;
+                }
+
+                // Messages without sequence number are published before the ones with one.
+                _ => lo = stream_seq_no + 1,
+            }
+        }
+
+        match found {
+            Some((stream_seq_no, n)) if n == seq_no => Ok((stream_seq_no, seq_no.get() - 1)),
+            _ => Ok((1, 0)),
+        }
+    }
+
+    /// The stream sequence and the sequence number, if any, of the next message on the given
+    /// subject at or after the given stream sequence.
+    async fn next_msg_seq_no(
+        &self,
+        subject: &str,
+        stream_seq_no: u64,
+    ) -> Result<(u64, Option<NonZeroU64>), Error> {
+        #[derive(Deserialize)]
+        struct MsgGet {
+            message: RawMessage,
+        }
+
+        let MsgGet { message } = api_request::<MsgGet>(
+            &self.jetstream,
+            format!("STREAM.MSG.GET.{}", self.evt_stream_name),
+            json!({ "seq": stream_seq_no, "next_by_subj": subject }),
+            || format!("cannot get next message on subject '{subject}'"),
         )
         .await?;
+        let stream_seq_no = message.sequence;
+        let msg = async_nats::Message::try_from(message)
+            .map_err(|error| Error::Nats("cannot decode message".into(), error))?;
+        let seq_no = header_seq_no(msg.headers.as_ref()).transpose()?;
 
-        Ok(evts(msgs, filter, from_bytes).await)
+        Ok((stream_seq_no, seq_no))
     }
 }
 
@@ -136,67 +419,177 @@ where
         evt: &E::Evt,
         id: &Self::Id,
         last_seq_no: Option<NonZeroU64>,
+        idempotency_key: Option<&str>,
         to_bytes: &ToBytes,
-    ) -> Result<NonZeroU64, Self::Error>
+    ) -> Result<Persisted, Self::Error>
     where
         E: EventSourced,
         ToBytes: Fn(&E::Evt) -> Result<Bytes, ToBytesError> + Sync,
         ToBytesError: StdError + Send + Sync + 'static,
     {
-        let bytes = to_bytes(evt).map_err(|error| Error::IntoBytes(error.into()))?;
-        let publish = Publish::build().payload(bytes);
-        let publish = last_seq_no.into_iter().fold(publish, |p, last_seq_no| {
-            p.expected_last_subject_sequence(last_seq_no.get())
+        let subject = self.subject(E::TYPE_NAME, id)?;
+
+        // Get the stream sequence of the last message to expect when publishing, which fails if
+        // another event has been published meanwhile, without looking it up if known from the last
+        // persisted event, else checking the given last sequence number.
+        let last_stream_seq_no = match self.last_persisted.take() {
+            Some((last_subject, seq_no, stream_seq_no))
+                if last_subject == subject && Some(seq_no) == last_seq_no =>
+            {
+                stream_seq_no
+            }
+
+            _ => {
+                let last_msg = self.last_msg_by_id(&subject).await?;
+                let (current_last_seq_no, last_stream_seq_no) = last_msg.unzip();
+                if current_last_seq_no != last_seq_no {
+                    return Err(Error::InvalidLastSeqNo(last_seq_no, current_last_seq_no));
+                }
+                last_stream_seq_no.unwrap_or_default()
+            }
+        };
+        let seq_no = last_seq_no.map_or(NonZeroU64::MIN, |n| {
+            n.checked_add(1).expect("sequence number does not overflow")
         });
 
-        let subject = format!("{}.{}.{id}", self.evt_stream_name, E::TYPE_NAME);
-        self.jetstream
-            .send_publish(subject, publish)
+        let bytes = to_bytes(evt).map_err(|error| Error::IntoBytes(error.into()))?;
+        let tags = E::tags(evt);
+        let tag_subjects = tags
+            .iter()
+            .map(|tag| self.tag_subject(E::TYPE_NAME, tag))
+            .collect::<Result<Vec<_>, _>>()?;
+        let mut headers = HeaderMap::new();
+        headers.insert(SEQ_NO_HEADER, seq_no.to_string().as_str());
+        if let Some(idempotency_key) = idempotency_key {
+            headers.insert(IDEMPOTENCY_KEY_HEADER, idempotency_key);
+        }
+        for tag in tags {
+            headers.append(TAG_HEADER, tag.as_str());
+        }
+        let publish = Publish::build()
+            .payload(bytes.clone())
+            .headers(headers)
+            .expected_last_subject_sequence(last_stream_seq_no);
+
+        let ack = self
+            .jetstream
+            .send_publish(subject.clone(), publish)
             .await
             .map_err(|error| Error::Nats("cannot publish event".into(), error.into()))?
+            .await;
+        let ack = match ack {
+            Ok(ack) => ack,
+
+            Err(error) if error.kind() == PublishErrorKind::WrongLastSequence => {
+                let current_last_seq_no = self
+                    .last_msg_by_id(&subject)
+                    .await?
+                    .map(|(seq_no, _)| seq_no);
+                return Err(Error::InvalidLastSeqNo(last_seq_no, current_last_seq_no));
+            }
+
+            Err(error) => {
+                return Err(Error::Nats(
+                    "cannot get ACK for published event".into(),
+                    error.into(),
+                ))
+            }
+        };
+        let position: NonZeroU64 = ack
+            .sequence
+            .try_into()
+            .map_err(|_| Error::InvalidNonZeroU64)?;
+        self.last_persisted = Some((subject, seq_no, ack.sequence));
+
+        // Publish the copies for the tags, deduplicated in case of retries.
+        for tag_subject in tag_subjects {
+            let mut headers = HeaderMap::new();
+            headers.insert(POSITION_HEADER, position.to_string().as_str());
+            let publish = Publish::build()
+                .payload(bytes.clone())
+                .headers(headers)
+                .message_id(format!("{tag_subject}:{position}"));
+            self.jetstream
+                .send_publish(tag_subject, publish)
+                .await
+                .map_err(|error| Error::Nats("cannot publish event for tag".into(), error.into()))?
+                .await
+                .map_err(|error| {
+                    Error::Nats(
+                        "cannot get ACK for published event for tag".into(),
+                        error.into(),
+                    )
+                })?;
+        }
+
+        Ok(Persisted { seq_no, position })
+    }
+
+    #[instrument(skip(self))]
+    async fn last_seq_no<E>(&self, id: &Self::Id) -> Result<Option<NonZeroU64>, Self::Error>
+    where
+        E: EventSourced,
+    {
+        let subject = self.subject(E::TYPE_NAME, id)?;
+        self.last_msg_by_id(&subject)
             .await
-            .map_err(|error| Error::Nats("cannot get ACK for published event".into(), error.into()))
-            .and_then(|ack| {
-                ack.sequence
+            .map(|last_msg| last_msg.map(|(seq_no, _)| seq_no))
+    }
+
+    #[instrument(skip(self))]
+    async fn last_seq_no_by_type<E>(&self) -> Result<Option<NonZeroU64>, Self::Error>
+    where
+        E: EventSourced,
+    {
+        let subject = format!("{}.{}.>", self.evt_stream_name, E::TYPE_NAME);
+        self.last_raw_msg(&subject)
+            .await?
+            .map(|msg| {
+                msg.sequence
                     .try_into()
                     .map_err(|_| Error::InvalidNonZeroU64)
             })
+            .transpose()
     }
 
     #[instrument(skip(self))]
-    async fn last_seq_no<E>(&self, id: &Self::Id) -> Result<Option<NonZeroU64>, Self::Error>
+    async fn last_seq_no_by_tag<E>(&self, tag: &str) -> Result<Option<NonZeroU64>, Self::Error>
     where
         E: EventSourced,
     {
-        let subject = format!("{}.{}.{id}", self.evt_stream_name, E::TYPE_NAME);
-        stream(&self.jetstream, &self.evt_stream_name)
+        let subject = self.tag_subject(E::TYPE_NAME, tag)?;
+        let Some(msg) = self.last_raw_msg(&subject).await? else {
+            return Ok(None);
+        };
+        let msg = async_nats::Message::try_from(msg)
+            .map_err(|error| Error::Nats("cannot decode last message".into(), error))?;
+        header_position(msg.headers.as_ref()).map(Some)
+    }
+
+    #[instrument(skip(self))]
+    async fn idempotency_keys<E>(
+        &self,
+        id: &Self::Id,
+        seq_no: NonZeroU64,
+    ) -> Result<impl Stream<Item = Result<(Persisted, String), Self::Error>> + Send, Self::Error>
+    where
+        E: EventSourced,
+    {
+        let subject = self.subject(E::TYPE_NAME, id)?;
+        let last_msg = self.last_msg_by_id(&subject).await?;
+        let keys = self
+            .replay(subject, seq_no, last_msg, true)
             .await?
-            .get_last_raw_message_by_subject(&subject)
-            .await
-            .map_or_else(
-                |error| {
-                    if error.kind() == LastRawMessageErrorKind::NoMessageFound {
-                        debug!(%id, "no last message found");
-                        Ok(None)
-                    } else {
-                        Err(Error::Nats(
-                            format!(
-                                "cannot get last message for NATS stream '{}'",
-                                self.evt_stream_name
-                            ),
-                            error.into(),
-                        ))
-                    }
-                },
-                |msg| {
-                    Some(
-                        msg.sequence
-                            .try_into()
-                            .map_err(|_| Error::InvalidNonZeroU64),
-                    )
-                    .transpose()
-                },
-            )
+            .try_filter_map(move |(persisted, msg)| {
+                let key = msg
+                    .headers
+                    .as_ref()
+                    .and_then(|headers| headers.get(IDEMPOTENCY_KEY_HEADER))
+                    .filter(|_| persisted.seq_no >= seq_no)
+                    .map(|key| (persisted, key.to_string()));
+                ready(Ok(key))
+            });
+        Ok(keys)
     }
 
     #[instrument(skip(self, from_bytes))]
@@ -217,8 +610,13 @@ where
             seq_no,
             "building events by ID stream"
         );
-        let subject = format!("{}.{}.{id}", self.evt_stream_name, E::TYPE_NAME);
-        self.evts(subject, seq_no, |_| true, from_bytes).await
+        let subject = self.subject(E::TYPE_NAME, id)?;
+        let last_msg = self.last_msg_by_id(&subject).await?;
+        let msgs = self
+            .replay(subject, seq_no, last_msg, false)
+            .await?
+            .map_ok(|(persisted, msg)| (persisted.seq_no, msg.message.payload));
+        Ok(evts(msgs, seq_no, from_bytes))
     }
 
     #[instrument(skip(self, from_bytes))]
@@ -236,8 +634,153 @@ where
             type_name = E::TYPE_NAME,
             seq_no, "building events by type stream"
         );
-        let subject = format!("{}.{}.*", self.evt_stream_name, E::TYPE_NAME);
-        self.evts(subject, seq_no, |_| true, from_bytes).await
+        let subject = format!("{}.{}.>", self.evt_stream_name, E::TYPE_NAME);
+        let msgs = self
+            .msgs(subject, start_at(seq_no))
+            .await?
+            .and_then(|msg| ready(stream_seq_no(&msg).map(|seq_no| (seq_no, msg.message.payload))));
+        Ok(evts(msgs, seq_no, from_bytes))
+    }
+
+    /// Events are distributed to slots by the hash of the encoded entity ID, see
+    /// [slot](eventsourced::slot). As NATS cannot filter by it, the events of other slots are
+    /// filtered out by the client, only passing their sequence numbers.
+    #[instrument(skip(self, from_bytes))]
+    async fn evts_by_type_in_slots<E, FromBytes, FromBytesError>(
+        &self,
+        slots: &[usize],
+        seq_no: NonZeroU64,
+        from_bytes: FromBytes,
+    ) -> Result<
+        Option<
+            impl Stream<Item = Result<(NonZeroU64, Option<(usize, E::Evt)>), Self::Error>> + Send,
+        >,
+        Self::Error,
+    >
+    where
+        E: EventSourced,
+        FromBytes: Fn(Bytes) -> Result<E::Evt, FromBytesError> + Copy + Send + Sync + 'static,
+        FromBytesError: StdError + Send + Sync + 'static,
+    {
+        debug!(
+            type_name = E::TYPE_NAME,
+            ?slots,
+            seq_no,
+            "building events by type in slots stream"
+        );
+        let slots = slots.to_vec();
+        let subject = format!("{}.{}.>", self.evt_stream_name, E::TYPE_NAME);
+        let evts = self
+            .msgs(subject, start_at(seq_no))
+            .await?
+            .try_filter_map(move |msg| {
+                let evt = stream_seq_no(&msg).and_then(|n| {
+                    if n < seq_no {
+                        return Ok(None);
+                    }
+                    let evt_slot = encoded_id(&msg.subject)
+                        .map(|id| slot(id.as_bytes()))
+                        .unwrap_or_default();
+                    if !slots.contains(&evt_slot) {
+                        return Ok(Some((n, None)));
+                    }
+                    from_bytes(msg.message.payload)
+                        .map_err(|error| Error::FromBytes(error.into()))
+                        .map(|evt| Some((n, Some((evt_slot, evt)))))
+                });
+                ready(evt)
+            });
+        Ok(Some(evts))
+    }
+
+    #[instrument(skip(self, from_bytes))]
+    async fn evts_by_tag<E, FromBytes, FromBytesError>(
+        &self,
+        tag: &str,
+        seq_no: NonZeroU64,
+        from_bytes: FromBytes,
+    ) -> Result<impl Stream<Item = Result<(NonZeroU64, E::Evt), Self::Error>> + Send, Self::Error>
+    where
+        E: EventSourced,
+        FromBytes: Fn(Bytes) -> Result<E::Evt, FromBytesError> + Copy + Send + Sync + 'static,
+        FromBytesError: StdError + Send + Sync + 'static,
+    {
+        debug!(
+            type_name = E::TYPE_NAME,
+            tag, seq_no, "building events by tag stream"
+        );
+        // The copies are published after the events, i.e. at greater stream sequences than the
+        // positions of the events.
+        let subject = self.tag_subject(E::TYPE_NAME, tag)?;
+        let msgs = self.msgs(subject, start_at(seq_no)).await?.and_then(|msg| {
+            let msg = header_position(msg.headers.as_ref())
+                .map(|position| (position, msg.message.payload));
+            ready(msg)
+        });
+        Ok(evts(msgs, seq_no, from_bytes))
+    }
+
+    #[instrument(skip(self, from_bytes))]
+    async fn current_evts_by_id<E, FromBytes, FromBytesError>(
+        &self,
+        id: &Self::Id,
+        query: EvtsQuery,
+        from_bytes: FromBytes,
+    ) -> Result<impl Stream<Item = Result<(NonZeroU64, E::Evt), Self::Error>> + Send, Self::Error>
+    where
+        E: EventSourced,
+        FromBytes: Fn(Bytes) -> Result<E::Evt, FromBytesError> + Copy + Send + Sync + 'static,
+        FromBytesError: StdError + Send + Sync + 'static,
+    {
+        debug!(
+            type_name = E::TYPE_NAME,
+            %id,
+            ?query,
+            "building current events by ID stream"
+        );
+        let subject = self.subject(E::TYPE_NAME, id)?;
+        let last_msg = self.last_msg_by_id(&subject).await?;
+        let msgs = self
+            .replay(subject, query.from, last_msg, true)
+            .await?
+            .map_ok(|(persisted, msg)| (persisted.seq_no, msg.message.payload))
+            .try_take_while(move |(seq_no, _)| ready(Ok(query.to.is_none_or(|to| *seq_no <= to))))
+            .try_filter(move |(seq_no, _)| ready(query.contains(*seq_no)));
+        current_evts(msgs, query, from_bytes).await
+    }
+
+    #[instrument(skip(self, from_bytes))]
+    async fn current_evts_by_type<E, FromBytes, FromBytesError>(
+        &self,
+        query: EvtsQuery,
+        from_bytes: FromBytes,
+    ) -> Result<impl Stream<Item = Result<(NonZeroU64, E::Evt), Self::Error>> + Send, Self::Error>
+    where
+        E: EventSourced,
+        FromBytes: Fn(Bytes) -> Result<E::Evt, FromBytesError> + Copy + Send + Sync + 'static,
+        FromBytesError: StdError + Send + Sync + 'static,
+    {
+        debug!(
+            type_name = E::TYPE_NAME,
+            ?query,
+            "building current events by type stream"
+        );
+        // The messages are fetched up to the last one at query time or the upper bound, ending
+        // early if no more messages are pending, e.g. because the last one has been removed.
+        let Some(end) = query.end(self.last_seq_no_by_type::<E>().await?) else {
+            return current_evts(stream::empty().left_stream(), query, from_bytes).await;
+        };
+        let subject = format!("{}.{}.>", self.evt_stream_name, E::TYPE_NAME);
+        let msgs = current_msgs(
+            &self.jetstream,
+            &self.evt_stream_name,
+            subject,
+            start_at(query.from),
+            end.get(),
+        )
+        .await?
+        .and_then(|msg| ready(stream_seq_no(&msg).map(|seq_no| (seq_no, msg.message.payload))));
+        current_evts(msgs.right_stream(), query, from_bytes).await
     }
 }
 
@@ -245,15 +788,22 @@ where
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(rename_all = "kebab-case")]
 pub struct Config {
-    pub server_addr: String,
-
-    pub credentials: Option<PathBuf>,
+    #[serde(flatten)]
+    pub client: ClientConfig,
 
     #[serde(default = "evt_stream_name_default")]
     pub evt_stream_name: String,
 
-    #[serde(default = "evt_stream_max_bytes_default")]
-    pub evt_stream_max_bytes: i64,
+    #[serde(default)]
+    pub evt_stream: StreamConfig,
+
+    /// Deprecated, use `evt-stream.max-bytes` instead; overrides it if given.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub evt_stream_max_bytes: Option<i64>,
+
+    /// Encoding of the entity IDs in the subjects, must match the one of the snapshot store.
+    #[serde(default)]
+    pub id_encoding: IdEncoding,
 
     #[serde(default)]
     pub setup: bool,
@@ -263,43 +813,93 @@ impl Default for Config {
     /// The default values used are:
     fn default() -> Self {
         Self {
-            server_addr: "localhost:4222".into(),
-            credentials: None,
+            client: ClientConfig::default(),
             evt_stream_name: evt_stream_name_default(),
-            evt_stream_max_bytes: evt_stream_max_bytes_default(),
+            evt_stream: StreamConfig::default(),
+            evt_stream_max_bytes: None,
+            id_encoding: IdEncoding::default(),
             setup: false,
         }
     }
 }
 
-async fn evts<E, F, FromBytes, FromBytesError>(
-    msgs: impl Stream<Item = Result<Message, Error>> + Send,
-    filter: F,
+impl Config {
+    /// The desired configuration of the evt stream, taking the deprecated `evt-stream-max-bytes`
+    /// into account.
+    fn evt_stream(&self) -> StreamConfig {
+        let mut evt_stream = self.evt_stream.clone();
+        if let Some(max_bytes) = self.evt_stream_max_bytes {
+            warn!("evt-stream-max-bytes is deprecated, use evt-stream.max-bytes instead");
+            evt_stream.max_bytes = max_bytes;
+        }
+        evt_stream
+    }
+}
+
+/// Events from the given payloads with their sequence numbers, skipping those before the given
+/// sequence number.
+fn evts<E, FromBytes, FromBytesError>(
+    msgs: impl Stream<Item = Result<(NonZeroU64, Bytes), Error>> + Send,
+    seq_no: NonZeroU64,
     from_bytes: FromBytes,
 ) -> impl Stream<Item = Result<(NonZeroU64, E), Error>> + Send
 where
     E: Send,
-    F: Fn(&Message) -> bool + Send,
     FromBytes: Fn(Bytes) -> Result<E, FromBytesError> + Copy + Send + Sync + 'static,
     FromBytesError: StdError + Send + Sync + 'static,
 {
-    msgs.filter_map(move |msg| {
-        let evt = match msg {
-            Ok(msg) if filter(&msg) => {
-                let evt = seq_no(&msg).and_then(|seq_no| {
-                    from_bytes(msg.message.payload)
-                        .map_err(|error| Error::FromBytes(error.into()))
-                        .map(|evt| (seq_no, evt))
-                });
-                Some(evt)
-            }
+    msgs.try_filter_map(move |(n, payload)| {
+        let evt = (n >= seq_no)
+            .then(|| {
+                from_bytes(payload)
+                    .map_err(|error| Error::FromBytes(error.into()))
+                    .map(|evt| (n, evt))
+            })
+            .transpose();
+        ready(evt)
+    })
+}
 
-            Ok(_) => None,
+/// Current events from the given payloads with their sequence numbers, which must all match the
+/// given query, applying its order and limit.
+async fn current_evts<E, FromBytes, FromBytesError>(
+    msgs: impl Stream<Item = Result<(NonZeroU64, Bytes), Error>> + Send,
+    query: EvtsQuery,
+    from_bytes: FromBytes,
+) -> Result<impl Stream<Item = Result<(NonZeroU64, E), Error>> + Send, Error>
+where
+    E: Send,
+    FromBytes: Fn(Bytes) -> Result<E, FromBytesError> + Copy + Send + Sync + 'static,
+    FromBytesError: StdError + Send + Sync + 'static,
+{
+    let limit = query.limit.map_or(usize::MAX, |limit| {
+        usize::try_from(limit).unwrap_or(usize::MAX)
+    });
+    let msgs = if query.reverse {
+        // Only keep the last messages up to the limit, decoded not before being returned.
+        let msgs = msgs
+            .try_fold(VecDeque::new(), move |mut msgs, msg| {
+                if limit > 0 {
+                    if msgs.len() == limit {
+                        msgs.pop_front();
+                    }
+                    msgs.push_back(msg);
+                }
+                ready(Ok(msgs))
+            })
+            .await?;
+        stream::iter(msgs.into_iter().rev().map(Ok)).left_stream()
+    } else {
+        msgs.take(limit).right_stream()
+    };
 
-            Err(err) => Some(Err(err)),
-        };
+    let evts = msgs.and_then(move |(seq_no, payload)| {
+        let evt = from_bytes(payload)
+            .map_err(|error| Error::FromBytes(error.into()))
+            .map(|evt| (seq_no, evt));
         ready(evt)
-    })
+    });
+    Ok(evts)
 }
 
 async fn msgs(
@@ -338,6 +938,87 @@ async fn msgs(
         })
 }
 
+/// The messages on the given subject up to the given stream sequence, fetched in batches without
+/// waiting; they end when no more messages are pending, e.g. if the message at the given stream
+/// sequence has been removed.
+async fn current_msgs(
+    jetstream: &Jetstream,
+    stream_name: &str,
+    subject: String,
+    deliver_policy: DeliverPolicy,
+    end: u64,
+) -> Result<impl Stream<Item = Result<Message, Error>> + Send, Error> {
+    let consumer = stream(jetstream, stream_name)
+        .await?
+        .create_consumer(pull::Config {
+            filter_subject: subject,
+            ack_policy: AckPolicy::None, // Important!
+            deliver_policy,
+            ..Default::default()
+        })
+        .await
+        .map_err(|error| Error::Nats("cannot create NATS consumer".into(), error.into()))?;
+
+    let msgs = stream::try_unfold(Some(consumer), move |consumer| async move {
+        let Some(consumer) = consumer else {
+            return Ok::<_, Error>(None);
+        };
+
+        let msgs = consumer
+            .fetch()
+            .max_messages(FETCH_BATCH_SIZE)
+            .messages()
+            .await
+            .map_err(|error| {
+                Error::Nats(
+                    "cannot fetch messages from NATS consumer".into(),
+                    error.into(),
+                )
+            })?
+            .map_err(|error| {
+                Error::Nats("cannot get message from NATS message batch".into(), error)
+            })
+            .try_collect::<Vec<_>>()
+            .await?;
+
+        let last = msgs
+            .last()
+            .map(|msg| msg.info().map(|info| (info.stream_sequence, info.pending)))
+            .transpose()
+            .map_err(|error| Error::Nats("cannot get message info".into(), error))?;
+        let done = last.is_none_or(|(stream_seq_no, pending)| stream_seq_no >= end || pending == 0);
+
+        let msgs = msgs
+            .into_iter()
+            .filter(move |msg| msg.info().is_ok_and(|info| info.stream_sequence <= end))
+            .map(Ok);
+        Ok(Some((stream::iter(msgs), (!done).then_some(consumer))))
+    })
+    .try_flatten();
+
+    Ok(msgs)
+}
+
+/// Send the given request to the JetStream API, e.g. for features not supported by the client.
+async fn api_request<T>(
+    jetstream: &Jetstream,
+    subject: String,
+    payload: serde_json::Value,
+    context: impl FnOnce() -> String,
+) -> Result<T, Error>
+where
+    T: DeserializeOwned,
+{
+    let response = jetstream
+        .request::<_, _, Response<T>>(subject, &payload)
+        .await;
+    match response {
+        Ok(Response::Ok(response)) => Ok(response),
+        Ok(Response::Err { error }) => Err(Error::Nats(context(), error.into())),
+        Err(error) => Err(Error::Nats(context(), error.into())),
+    }
+}
+
 async fn stream(jetstream: &Jetstream, stream_name: &str) -> Result<JetstreamStream, Error> {
     jetstream.get_stream(stream_name).await.map_err(|error| {
         Error::Nats(
@@ -353,7 +1034,35 @@ fn start_at(seq_no: NonZeroU64) -> DeliverPolicy {
     }
 }
 
-fn seq_no(msg: &Message) -> Result<NonZeroU64, Error> {
+/// The encoded entity ID of the given subject of an event, i.e. all tokens after the stream name
+/// and the type name, as IDs with dots span multiple tokens.
+fn encoded_id(subject: &str) -> Option<&str> {
+    subject.splitn(3, '.').nth(2)
+}
+
+fn header_seq_no(headers: Option<&HeaderMap>) -> Option<Result<NonZeroU64, Error>> {
+    header_non_zero_u64(headers, SEQ_NO_HEADER)
+}
+
+fn header_position(headers: Option<&HeaderMap>) -> Result<NonZeroU64, Error> {
+    header_non_zero_u64(headers, POSITION_HEADER).unwrap_or(Err(Error::InvalidNonZeroU64))
+}
+
+fn header_non_zero_u64(
+    headers: Option<&HeaderMap>,
+    name: &str,
+) -> Option<Result<NonZeroU64, Error>> {
+    headers.and_then(|headers| headers.get(name)).map(|seq_no| {
+        seq_no
+            .as_str()
+            .parse::<u64>()
+            .ok()
+            .and_then(|n| n.try_into().ok())
+            .ok_or(Error::InvalidNonZeroU64)
+    })
+}
+
+fn stream_seq_no(msg: &Message) -> Result<NonZeroU64, Error> {
     msg.info()
         .map_err(|error| Error::Nats("cannot get message info".into(), error))
         .and_then(|info| {
@@ -367,17 +1076,13 @@ fn evt_stream_name_default() -> String {
     "evts".to_string()
 }
 
-fn evt_stream_max_bytes_default() -> i64 {
-    -1
-}
-
 #[cfg(test)]
 mod tests {
     use super::*;
     use crate::tests::NATS_VERSION;
     use error_ext::BoxError;
     use eventsourced::binarize;
-    use futures::TryStreamExt;
+    use futures::{StreamExt, TryStreamExt};
     use std::{convert::Infallible, future};
     use testcontainers::{clients::Cli, core::WaitFor};
     use testcontainers_modules::testcontainers::GenericImage;
@@ -406,6 +1111,25 @@ mod tests {
         fn handle_evt(_state: Self::State, _evt: Self::Evt) -> Self::State {
             todo!()
         }
+
+        fn tags(evt: &Self::Evt) -> Vec<String> {
+            if evt % 2 == 0 {
+                vec!["even".to_string()]
+            } else {
+                vec![]
+            }
+        }
+    }
+
+    #[test]
+    fn test_deprecated_max_bytes() -> Result<(), BoxError> {
+        let config = serde_json::from_str::<Config>(r#"{ "evt-stream-max-bytes": 42 }"#)?;
+        assert_eq!(config.evt_stream().max_bytes, 42);
+
+        let config = serde_json::from_str::<Config>(r#"{ "evt-stream": { "max-bytes": 42 } }"#)?;
+        assert_eq!(config.evt_stream().max_bytes, 42);
+
+        Ok(())
     }
 
     #[tokio::test]
@@ -417,11 +1141,32 @@ mod tests {
         let server_addr = format!("localhost:{}", container.get_host_port_ipv4(4222));
 
         let config = Config {
-            server_addr,
+            client: ClientConfig {
+                server_addr,
+                ..Default::default()
+            },
             setup: true,
             ..Default::default()
         };
-        let mut evt_log = NatsEvtLog::<Uuid>::new(config).await?;
+        let mut evt_log = NatsEvtLog::<Uuid>::new(config.clone()).await?;
+        assert!(evt_log.drift().await?.is_empty());
+
+        // Setup is idempotent and updates a drifted stream.
+        let max_age = Duration::from_secs(3_600);
+        let other_evt_log = NatsEvtLog::<Uuid>::new(Config {
+            evt_stream: StreamConfig {
+                max_age,
+                ..Default::default()
+            },
+            ..config.clone()
+        })
+        .await?;
+        assert!(other_evt_log.drift().await?.is_empty());
+        let drift = evt_log.drift().await?;
+        assert_eq!(drift.len(), 1);
+        assert_eq!(drift[0].field, "max-age");
+        NatsEvtLog::<Uuid>::new(config).await?;
+        assert!(evt_log.drift().await?.is_empty());
 
         let id = Uuid::now_v7();
 
@@ -429,32 +1174,54 @@ mod tests {
 
         let last_seq_no = evt_log.last_seq_no::<Dummy>(&id).await?;
         assert_eq!(last_seq_no, None);
+        let last_seq_no = evt_log.last_seq_no_by_type::<Dummy>().await?;
+        assert_eq!(last_seq_no, None);
 
         let last_seq_no = evt_log
-            .persist::<Dummy, _, _>(&1, &id, None, &binarize::serde_json::to_bytes)
-            .await?;
+            .persist::<Dummy, _, _>(&1, &id, None, None, &binarize::serde_json::to_bytes)
+            .await?
+            .seq_no;
         assert!(last_seq_no.get() == 1);
 
         evt_log
-            .persist::<Dummy, _, _>(&2, &id, Some(last_seq_no), &binarize::serde_json::to_bytes)
+            .persist::<Dummy, _, _>(
+                &2,
+                &id,
+                Some(last_seq_no),
+                None,
+                &binarize::serde_json::to_bytes,
+            )
             .await?;
 
         let result = evt_log
-            .persist::<Dummy, _, _>(&3, &id, Some(last_seq_no), &binarize::serde_json::to_bytes)
+            .persist::<Dummy, _, _>(
+                &3,
+                &id,
+                Some(last_seq_no),
+                None,
+                &binarize::serde_json::to_bytes,
+            )
             .await;
-        assert!(result.is_err());
+        assert!(matches!(
+            result,
+            Err(Error::InvalidLastSeqNo(Some(n), Some(m))) if n.get() == 1 && m.get() == 2
+        ));
 
         evt_log
             .persist::<Dummy, _, _>(
                 &3,
                 &id,
                 Some(last_seq_no.checked_add(1).expect("overflow")),
+                None,
                 &binarize::serde_json::to_bytes,
             )
             .await?;
 
+        // Event 2 is tagged, hence followed by a copy for its tag.
         let last_seq_no = evt_log.last_seq_no::<Dummy>(&id).await?;
         assert_eq!(last_seq_no, Some(3.try_into()?));
+        let last_seq_no = evt_log.last_seq_no_by_type::<Dummy>().await?;
+        assert_eq!(last_seq_no, Some(4.try_into()?));
 
         let evts = evt_log
             .evts_by_id::<Dummy, _, _>(&id, 2.try_into()?, binarize::serde_json::from_bytes)
@@ -471,21 +1238,212 @@ mod tests {
 
         let last_seq_no = evt_log
             .clone()
-            .persist::<Dummy, _, _>(&4, &id, last_seq_no, &binarize::serde_json::to_bytes)
-            .await?;
+            .persist::<Dummy, _, _>(&4, &id, last_seq_no, None, &binarize::serde_json::to_bytes)
+            .await?
+            .seq_no;
         evt_log
             .clone()
-            .persist::<Dummy, _, _>(&5, &id, Some(last_seq_no), &binarize::serde_json::to_bytes)
+            .persist::<Dummy, _, _>(
+                &5,
+                &id,
+                Some(last_seq_no),
+                None,
+                &binarize::serde_json::to_bytes,
+            )
             .await?;
         let last_seq_no = evt_log.last_seq_no::<Dummy>(&id).await?;
         assert_eq!(last_seq_no, Some(5.try_into()?));
 
+        // Replaying events by ID starts at the given sequence number.
+        let replayed = evt_log
+            .evts_by_id::<Dummy, _, _>(&id, 4.try_into()?, binarize::serde_json::from_bytes)
+            .await?
+            .take(2)
+            .try_collect::<Vec<_>>()
+            .await?;
+        assert_eq!(replayed, vec![(4.try_into()?, 4), (5.try_into()?, 5)]);
+
         let sum = evts
             .take(5)
             .try_fold(0u32, |acc, (_, n)| future::ready(Ok(acc + n)))
             .await?;
         assert_eq!(sum, 15);
 
+        // The last persisted event is outdated by the ones persisted via the clones.
+        let result = evt_log
+            .persist::<Dummy, _, _>(
+                &4,
+                &id,
+                Some(3.try_into()?),
+                None,
+                &binarize::serde_json::to_bytes,
+            )
+            .await;
+        assert!(matches!(
+            result,
+            Err(Error::InvalidLastSeqNo(Some(n), Some(m))) if n.get() == 3 && m.get() == 5
+        ));
+
+        // Sequence numbers are dense per entity, whereas those by type, i.e. the positions, are
+        // stream sequences.
+        let other_id = Uuid::now_v7();
+        let persisted = evt_log
+            .persist::<Dummy, _, _>(&6, &other_id, None, None, &binarize::serde_json::to_bytes)
+            .await?;
+        assert_eq!(persisted.seq_no, NonZeroU64::MIN);
+        assert_eq!(persisted.position, 8.try_into()?);
+        let last_seq_no = evt_log.last_seq_no_by_type::<Dummy>().await?;
+        assert_eq!(last_seq_no, Some(8.try_into()?));
+
+        // Events by type in slots are the ones of the entities in these slots, for the others only
+        // the sequence numbers are passed.
+        let id_slot = slot(evt_log.id_encoding.encode(&id)?.as_bytes());
+        let other_slot = slot(evt_log.id_encoding.encode(&other_id)?.as_bytes());
+        let evts = evt_log
+            .evts_by_type_in_slots::<Dummy, _, _>(
+                &[id_slot],
+                NonZeroU64::MIN,
+                binarize::serde_json::from_bytes,
+            )
+            .await?
+            .expect("events by type in slots")
+            .take(6)
+            .map_ok(|(_, evt)| evt.map(|(_, n)| n))
+            .try_collect::<Vec<_>>()
+            .await?;
+        let other_evt = (other_slot == id_slot).then_some(6);
+        assert_eq!(
+            evts,
+            vec![Some(1), Some(2), Some(3), Some(4), Some(5), other_evt]
+        );
+
+        // Messages without sequence number header are numbered by their position on the subject.
+        let legacy_id = Uuid::now_v7();
+        let subject = format!("evts.{}.{legacy_id}", Dummy::TYPE_NAME);
+        for n in [7, 8] {
+            evt_log
+                .jetstream
+                .publish(subject.clone(), binarize::serde_json::to_bytes(&n)?)
+                .await?
+                .await?;
+        }
+        let last_seq_no = evt_log.last_seq_no::<Dummy>(&legacy_id).await?;
+        assert_eq!(last_seq_no, Some(2.try_into()?));
+        let last_seq_no = evt_log
+            .persist::<Dummy, _, _>(
+                &9,
+                &legacy_id,
+                last_seq_no,
+                None,
+                &binarize::serde_json::to_bytes,
+            )
+            .await?
+            .seq_no;
+        assert_eq!(last_seq_no, 3.try_into()?);
+        let evts = evt_log
+            .evts_by_id::<Dummy, _, _>(&legacy_id, 2.try_into()?, binarize::serde_json::from_bytes)
+            .await?
+            .take(2)
+            .try_collect::<Vec<_>>()
+            .await?;
+        assert_eq!(evts, vec![(2.try_into()?, 8), (3.try_into()?, 9)]);
+
+        // Idempotency keys are stored alongside the events.
+        let last_seq_no = evt_log
+            .persist::<Dummy, _, _>(
+                &7,
+                &other_id,
+                Some(NonZeroU64::MIN),
+                Some("key-2"),
+                &binarize::serde_json::to_bytes,
+            )
+            .await?
+            .seq_no;
+        let persisted = evt_log
+            .persist::<Dummy, _, _>(
+                &8,
+                &other_id,
+                Some(last_seq_no),
+                Some("key-3"),
+                &binarize::serde_json::to_bytes,
+            )
+            .await?;
+        let keys = evt_log
+            .idempotency_keys::<Dummy>(&other_id, 3.try_into()?)
+            .await?
+            .try_collect::<Vec<_>>()
+            .await?;
+        assert_eq!(keys, vec![(persisted, "key-3".to_string())]);
+
+        // Events by tag are the tagged events by type, with their positions; the ones published
+        // without copies are not tagged.
+        let last_seq_no = evt_log.last_seq_no_by_tag::<Dummy>("even").await?;
+        assert_eq!(last_seq_no, Some(14.try_into()?));
+        let last_seq_no = evt_log.last_seq_no_by_tag::<Dummy>("odd").await?;
+        assert_eq!(last_seq_no, None);
+        let evts = evt_log
+            .evts_by_tag::<Dummy, _, _>("even", 3.try_into()?, binarize::serde_json::from_bytes)
+            .await?
+            .take(3)
+            .try_collect::<Vec<_>>()
+            .await?;
+        assert_eq!(
+            evts,
+            vec![(5.try_into()?, 4), (8.try_into()?, 6), (14.try_into()?, 8)]
+        );
+
+        // Current events end with the last event at query time or the upper bound.
+        let evts = evt_log
+            .current_evts_by_id::<Dummy, _, _>(
+                &id,
+                EvtsQuery::range(2.try_into()?..=4.try_into()?),
+                binarize::serde_json::from_bytes,
+            )
+            .await?
+            .try_collect::<Vec<_>>()
+            .await?;
+        assert_eq!(
+            evts,
+            vec![(2.try_into()?, 2), (3.try_into()?, 3), (4.try_into()?, 4)]
+        );
+        let evts = evt_log
+            .current_evts_by_id::<Dummy, _, _>(
+                &id,
+                EvtsQuery::default().with_limit(2).reversed(),
+                binarize::serde_json::from_bytes,
+            )
+            .await?
+            .try_collect::<Vec<_>>()
+            .await?;
+        assert_eq!(evts, vec![(5.try_into()?, 5), (4.try_into()?, 4)]);
+        let evts = evt_log
+            .current_evts_by_type::<Dummy, _, _>(
+                EvtsQuery::from(13.try_into()?),
+                binarize::serde_json::from_bytes,
+            )
+            .await?
+            .try_collect::<Vec<_>>()
+            .await?;
+        assert_eq!(evts, vec![(13.try_into()?, 7), (14.try_into()?, 8)]);
+        let evts = evt_log
+            .current_evts_by_type::<Dummy, _, _>(
+                EvtsQuery::range(2.try_into()?..=4.try_into()?).reversed(),
+                binarize::serde_json::from_bytes,
+            )
+            .await?
+            .try_collect::<Vec<_>>()
+            .await?;
+        assert_eq!(evts, vec![(4.try_into()?, 3), (2.try_into()?, 2)]);
+        let evts = evt_log
+            .current_evts_by_type::<Dummy, _, _>(
+                EvtsQuery::from(15.try_into()?),
+                binarize::serde_json::from_bytes,
+            )
+            .await?
+            .try_collect::<Vec<_>>()
+            .await?;
+        assert!(evts.is_empty());
+
         Ok(())
     }
 }
@@ -0,0 +1,176 @@
+//! Encoding of entity IDs as tokens of NATS subjects and as keys of NATS KV buckets.
+
+use crate::Error;
+use data_encoding::{BASE32_NOPAD, BASE64URL_NOPAD};
+use serde::{Deserialize, Serialize};
+use std::{error::Error as StdError, fmt::Display, str::FromStr};
+
+/// Encoding of entity IDs as single tokens of NATS subjects and as keys of NATS KV buckets, applied
+/// consistently by the [NatsEvtLog](crate::NatsEvtLog) and the
+/// [NatsSnapshotStore](crate::NatsSnapshotStore); hence both must use the same encoding.
+///
+/// Changing the encoding of existing streams and buckets makes existing events and snapshots
+/// inaccessible, unless the encoded IDs are the same, e.g. for UUIDs with `display` and `escape`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum IdEncoding {
+    /// The [Display] representation as is, refusing IDs which are no valid keys of NATS KV
+    /// buckets, i.e. empty ones, ones with characters other than ASCII letters, digits, '-',
+    /// '/', '_', '=' and '.' or ones with empty tokens, i.e. starting or ending with '.' or
+    /// containing "..". IDs with dots span multiple tokens of the subjects.
+    #[default]
+    Display,
+
+    /// The [Display] representation with all characters other than ASCII letters, digits, '-' and
+    /// '_' escaped byte by byte as '=' followed by two hex digits, e.g. "a.b" as "a=2Eb".
+    Escape,
+
+    /// The [Display] representation encoded as Base32 without padding.
+    Base32,
+
+    /// The [Display] representation encoded as URL safe Base64 without padding.
+    Base64Url,
+}
+
+impl IdEncoding {
+    /// Encode the given ID.
+    pub fn encode(&self, id: &impl Display) -> Result<String, Error> {
+        let id = id.to_string();
+
+        match self {
+            IdEncoding::Display => {
+                let invalid = id.is_empty()
+                    || id.starts_with('.')
+                    || id.ends_with('.')
+                    || id.contains("..")
+                    || !id.bytes().all(|b| {
+                        b.is_ascii_alphanumeric() || matches!(b, b'-' | b'/' | b'_' | b'=' | b'.')
+                    });
+                if invalid {
+                    Err(Error::InvalidId(id))
+                } else {
+                    Ok(id)
+                }
+            }
+
+            IdEncoding::Escape => {
+                let mut encoded = String::with_capacity(id.len());
+                for b in id.bytes() {
+                    if b.is_ascii_alphanumeric() || b == b'-' || b == b'_' {
+                        encoded.push(b as char);
+                    } else {
+                        encoded.push_str(&format!("={b:02X}"));
+                    }
+                }
+                if encoded.is_empty() {
+                    Err(Error::InvalidId(id))
+                } else {
+                    Ok(encoded)
+                }
+            }
+
+            IdEncoding::Base32 if !id.is_empty() => Ok(BASE32_NOPAD.encode(id.as_bytes())),
+
+            IdEncoding::Base64Url if !id.is_empty() => Ok(BASE64URL_NOPAD.encode(id.as_bytes())),
+
+            _ => Err(Error::InvalidId(id)),
+        }
+    }
+
+    /// Decode the given encoded ID, e.g. the last token of a subject.
+    pub fn decode<I>(&self, encoded: &str) -> Result<I, Error>
+    where
+        I: FromStr,
+        I::Err: StdError + Send + Sync + 'static,
+    {
+        let id = match self {
+            IdEncoding::Display => encoded.to_string(),
+
+            IdEncoding::Escape => {
+                let mut bytes = Vec::with_capacity(encoded.len());
+                let mut chars = encoded.bytes();
+                while let Some(b) = chars.next() {
+                    if b == b'=' {
+                        let hex = [chars.next(), chars.next()]
+                            .into_iter()
+                            .collect::<Option<Vec<_>>>()
+                            .and_then(|hex| String::from_utf8(hex).ok())
+                            .and_then(|hex| u8::from_str_radix(&hex, 16).ok())
+                            .ok_or_else(|| Error::InvalidEncodedId(encoded.to_string(), None))?;
+                        bytes.push(hex);
+                    } else {
+                        bytes.push(b);
+                    }
+                }
+                String::from_utf8(bytes).map_err(|error| {
+                    Error::InvalidEncodedId(encoded.to_string(), Some(error.into()))
+                })?
+            }
+
+            IdEncoding::Base32 => decode_utf8(encoded, BASE32_NOPAD.decode(encoded.as_bytes()))?,
+
+            IdEncoding::Base64Url => {
+                decode_utf8(encoded, BASE64URL_NOPAD.decode(encoded.as_bytes()))?
+            }
+        };
+
+        id.parse().map_err(|error: I::Err| {
+            Error::InvalidEncodedId(encoded.to_string(), Some(error.into()))
+        })
+    }
+}
+
+fn decode_utf8(
+    encoded: &str,
+    bytes: Result<Vec<u8>, data_encoding::DecodeError>,
+) -> Result<String, Error> {
+    bytes
+        .map_err(|error| Error::InvalidEncodedId(encoded.to_string(), Some(error.into())))
+        .and_then(|bytes| {
+            String::from_utf8(bytes)
+                .map_err(|error| Error::InvalidEncodedId(encoded.to_string(), Some(error.into())))
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    #[test]
+    fn test_display() {
+        assert_eq!(
+            IdEncoding::Display.encode(&"a-b_c/d=e.f").ok(),
+            Some("a-b_c/d=e.f".to_string())
+        );
+        for id in ["", ".a", "a.", "a..b", "a b", "a*", "a>", "a:b", "ä"] {
+            assert!(matches!(
+                IdEncoding::Display.encode(&id),
+                Err(Error::InvalidId(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn test_escape() {
+        assert_eq!(
+            IdEncoding::Escape.encode(&"a.b ä").ok(),
+            Some("a=2Eb=20=C3=A4".to_string())
+        );
+        assert!(IdEncoding::Escape.decode::<String>("a=2").is_err());
+    }
+
+    proptest! {
+        #[test]
+        fn test_roundtrip(id in ".+") {
+            for encoding in [IdEncoding::Escape, IdEncoding::Base32, IdEncoding::Base64Url] {
+                let encoded = encoding.encode(&id).unwrap();
+                prop_assert!(encoded
+                    .bytes()
+                    .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_' || b == b'='));
+                let decoded = encoding.decode::<String>(&encoded).unwrap();
+                prop_assert_eq!(&decoded, &id);
+            }
+        }
+    }
+}
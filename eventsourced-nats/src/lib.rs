@@ -1,14 +1,24 @@
 //! [EvtLog](eventsourced::EvtLog) and [SnapshotStore](eventsourced::SnapshotStore) implementations
 //! based upon [NATS](https://nats.io/).
 
+mod client;
 pub mod evt_log;
+mod id_encoding;
+mod setup;
 mod snapshot_store;
 
-pub use evt_log::{Config as NatsEvtLogConfig, NatsEvtLog};
+pub use client::{connect, ClientConfig, TlsConfig};
+pub use evt_log::{
+    Config as NatsEvtLogConfig, NatsEvtLog, IDEMPOTENCY_KEY_HEADER, POSITION_HEADER, SEQ_NO_HEADER,
+    TAG_HEADER,
+};
+pub use id_encoding::IdEncoding;
+pub use setup::{BucketConfig, Drift, StreamConfig};
 pub use snapshot_store::{Config as NatsSnapshotStoreConfig, NatsSnapshotStore};
 
 use error_ext::BoxError;
 use prost::{DecodeError, EncodeError};
+use std::num::NonZeroU64;
 use thiserror::Error;
 
 /// Errors from the [NatsEvtLog] or [NatsSnapshotStore].
@@ -33,9 +43,41 @@ pub enum Error {
     #[error("cannot decode snapshot from Protocol Buffers")]
     DecodeSnapshot(#[from] DecodeError),
 
+    /// JetStream stream, e.g. backing a KV bucket, does not exist.
+    #[error("NATS stream '{0}' does not exist")]
+    StreamNotFound(String),
+
+    /// Invalid client configuration, i.e. only one of the given settings is set.
+    #[error("invalid NATS client configuration: {0} must be given together")]
+    InvalidClientConfig(&'static str),
+
+    /// The digest of the object of a snapshot does not match the one of its pointer.
+    #[error("invalid digest of snapshot object {0}")]
+    InvalidSnapshotDigest(String),
+
+    /// Snapshot has a sequence number less than the one of the current snapshot.
+    #[error("cannot save snapshot with sequence number {0}, current one is {1}")]
+    StaleSnapshot(NonZeroU64, u64),
+
+    /// Snapshot cannot be saved because of concurrent updates.
+    #[error("cannot save snapshot with sequence number {0} because of concurrent updates")]
+    ConcurrentSnapshot(NonZeroU64),
+
+    /// Entity ID cannot be encoded with the configured [IdEncoding].
+    #[error("invalid entity ID '{0}' for the configured encoding")]
+    InvalidId(String),
+
+    /// Encoded entity ID cannot be decoded with the configured [IdEncoding].
+    #[error("cannot decode entity ID '{0}'")]
+    InvalidEncodedId(String, #[source] Option<BoxError>),
+
     /// Invalid sequence number.
     #[error("invalid sequence number")]
     InvalidNonZeroU64,
+
+    /// The given last sequence number does not match the current one.
+    #[error("invalid last sequence number: {0:?} {1:?}")]
+    InvalidLastSeqNo(Option<NonZeroU64>, Option<NonZeroU64>),
 }
 
 #[cfg(test)]
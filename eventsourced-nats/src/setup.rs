@@ -0,0 +1,316 @@
+//! Declarative configuration of the JetStream stream of the [NatsEvtLog](crate::NatsEvtLog) and
+//! the KV bucket of the [NatsSnapshotStore](crate::NatsSnapshotStore).
+
+use crate::Error;
+use async_nats::jetstream::{
+    context::GetStreamErrorKind,
+    stream::{self, Compression, DiscardPolicy, RetentionPolicy, StorageType},
+    Context as Jetstream, ErrorCode,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    fmt::{self, Debug, Display, Formatter},
+    future::Future,
+    time::Duration,
+};
+use tracing::{debug, warn};
+
+/// Desired configuration of the JetStream stream of the [NatsEvtLog](crate::NatsEvtLog).
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct StreamConfig {
+    #[serde(default = "replicas_default")]
+    pub replicas: usize,
+
+    #[serde(default)]
+    pub storage: StorageType,
+
+    #[serde(default)]
+    pub retention: RetentionPolicy,
+
+    #[serde(default)]
+    pub discard: DiscardPolicy,
+
+    /// Maximum age of events, zero means unlimited.
+    #[serde(default, with = "humantime_serde")]
+    pub max_age: Duration,
+
+    /// Maximum size of the stream in bytes, -1 means unlimited.
+    #[serde(default = "max_bytes_default")]
+    pub max_bytes: i64,
+
+    #[serde(default = "duplicate_window_default", with = "humantime_serde")]
+    pub duplicate_window: Duration,
+
+    /// Whether to compress the stream with S2.
+    #[serde(default)]
+    pub compression: bool,
+}
+
+impl StreamConfig {
+    /// Apply this configuration to the given stream configuration.
+    pub(crate) fn apply(&self, config: &mut stream::Config) {
+        config.num_replicas = self.replicas;
+        config.storage = self.storage;
+        config.retention = self.retention;
+        config.discard = self.discard;
+        config.max_age = self.max_age;
+        config.max_bytes = self.max_bytes;
+        config.duplicate_window = self.duplicate_window;
+        config.compression = Some(compression(self.compression));
+    }
+
+    /// The differences between the given actual stream configuration and this one.
+    pub(crate) fn drift(&self, config: &stream::Config) -> Vec<Drift> {
+        [
+            Drift::of("replicas", config.num_replicas, self.replicas),
+            Drift::of("storage", config.storage, self.storage),
+            Drift::of("retention", config.retention, self.retention),
+            Drift::of("discard", config.discard, self.discard),
+            Drift::of("max-age", config.max_age, self.max_age),
+            Drift::of("max-bytes", config.max_bytes, self.max_bytes),
+            Drift::of(
+                "duplicate-window",
+                config.duplicate_window,
+                self.duplicate_window,
+            ),
+            Drift::of("compression", is_compressed(config), self.compression),
+        ]
+        .into_iter()
+        .flatten()
+        .collect()
+    }
+}
+
+impl Default for StreamConfig {
+    /// The defaults of JetStream, i.e. a single replica on file storage, limits retention,
+    /// discarding old events, unlimited age and size, two minutes duplicate window and no
+    /// compression.
+    fn default() -> Self {
+        Self {
+            replicas: replicas_default(),
+            storage: StorageType::default(),
+            retention: RetentionPolicy::default(),
+            discard: DiscardPolicy::default(),
+            max_age: Duration::ZERO,
+            max_bytes: max_bytes_default(),
+            duplicate_window: duplicate_window_default(),
+            compression: false,
+        }
+    }
+}
+
+/// Desired configuration of the KV bucket of the [NatsSnapshotStore](crate::NatsSnapshotStore);
+/// retention and discard policy are given by the KV semantics.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct BucketConfig {
+    #[serde(default = "replicas_default")]
+    pub replicas: usize,
+
+    #[serde(default)]
+    pub storage: StorageType,
+
+    /// Maximum age of snapshots, zero means unlimited.
+    #[serde(default, with = "humantime_serde")]
+    pub max_age: Duration,
+
+    /// Maximum size of the bucket in bytes, -1 means unlimited.
+    #[serde(default = "max_bytes_default")]
+    pub max_bytes: i64,
+
+    /// Whether to compress the bucket with S2.
+    #[serde(default)]
+    pub compression: bool,
+}
+
+impl BucketConfig {
+    /// Apply this configuration to the given configuration of the stream backing the bucket.
+    pub(crate) fn apply(&self, config: &mut stream::Config) {
+        config.num_replicas = self.replicas;
+        config.storage = self.storage;
+        config.max_age = self.max_age;
+        config.max_bytes = self.max_bytes;
+        config.compression = Some(compression(self.compression));
+    }
+
+    /// The differences between the given actual configuration of the stream backing the bucket
+    /// and this one.
+    pub(crate) fn drift(&self, config: &stream::Config) -> Vec<Drift> {
+        [
+            Drift::of("replicas", config.num_replicas, self.replicas),
+            Drift::of("storage", config.storage, self.storage),
+            Drift::of("max-age", config.max_age, self.max_age),
+            Drift::of("max-bytes", config.max_bytes, self.max_bytes),
+            Drift::of("compression", is_compressed(config), self.compression),
+        ]
+        .into_iter()
+        .flatten()
+        .collect()
+    }
+}
+
+impl Default for BucketConfig {
+    /// The defaults of JetStream, i.e. a single replica on file storage, unlimited age and size
+    /// and no compression.
+    fn default() -> Self {
+        Self {
+            replicas: replicas_default(),
+            storage: StorageType::default(),
+            max_age: Duration::ZERO,
+            max_bytes: max_bytes_default(),
+            compression: false,
+        }
+    }
+}
+
+/// A difference between the actual and the desired configuration of a stream or bucket.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Drift {
+    pub field: &'static str,
+    pub actual: String,
+    pub desired: String,
+}
+
+impl Drift {
+    pub(crate) fn of<T>(field: &'static str, actual: T, desired: T) -> Option<Self>
+    where
+        T: Debug + PartialEq,
+    {
+        (actual != desired).then(|| Self {
+            field,
+            actual: format!("{actual:?}"),
+            desired: format!("{desired:?}"),
+        })
+    }
+}
+
+impl Display for Drift {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} is {} instead of {}",
+            self.field, self.actual, self.desired
+        )
+    }
+}
+
+/// Get the configuration of the stream with the given name, if it exists.
+pub(crate) async fn stream_config(
+    jetstream: &Jetstream,
+    name: &str,
+) -> Result<Option<stream::Config>, Error> {
+    match jetstream.get_stream(name).await {
+        Ok(stream) => Ok(Some(stream.cached_info().config.clone())),
+
+        Err(error)
+            if matches!(
+                error.kind(),
+                GetStreamErrorKind::JetStream(ref error)
+                    if error.error_code() == ErrorCode::STREAM_NOT_FOUND
+            ) =>
+        {
+            Ok(None)
+        }
+
+        Err(error) => Err(Error::Nats(
+            format!("cannot get NATS stream '{name}'"),
+            error.into(),
+        )),
+    }
+}
+
+/// Create the stream with the given name with the given creation function if it does not exist,
+/// else update it if it has drifted from the desired configuration, which is idempotent. Drift is
+/// reported as warning; settings which cannot be changed, e.g. the storage type, make the update
+/// fail.
+pub(crate) async fn create_or_update<C, F, D, A>(
+    jetstream: &Jetstream,
+    name: &str,
+    create: C,
+    drift: D,
+    apply: A,
+) -> Result<(), Error>
+where
+    C: FnOnce() -> F,
+    F: Future<Output = Result<(), Error>>,
+    D: FnOnce(&stream::Config) -> Vec<Drift>,
+    A: FnOnce(&mut stream::Config),
+{
+    let Some(mut config) = stream_config(jetstream, name).await? else {
+        debug!(name, "creating NATS stream");
+        return create().await;
+    };
+
+    let drift = drift(&config);
+    if drift.is_empty() {
+        debug!(name, "NATS stream has desired configuration");
+        return Ok(());
+    }
+
+    for drift in &drift {
+        warn!(name, %drift, "NATS stream has drifted from desired configuration");
+    }
+    apply(&mut config);
+    jetstream.update_stream(&config).await.map_err(|error| {
+        Error::Nats(
+            format!("cannot update NATS stream '{name}' to desired configuration"),
+            error.into(),
+        )
+    })?;
+
+    Ok(())
+}
+
+fn compression(compression: bool) -> Compression {
+    if compression {
+        Compression::S2
+    } else {
+        Compression::None
+    }
+}
+
+fn is_compressed(config: &stream::Config) -> bool {
+    config.compression == Some(Compression::S2)
+}
+
+const fn replicas_default() -> usize {
+    1
+}
+
+const fn max_bytes_default() -> i64 {
+    -1
+}
+
+const fn duplicate_window_default() -> Duration {
+    Duration::from_secs(120)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_drift() {
+        let desired = StreamConfig {
+            max_age: Duration::from_secs(60),
+            compression: true,
+            ..Default::default()
+        };
+
+        let mut config = stream::Config {
+            num_replicas: 1,
+            duplicate_window: Duration::from_secs(120),
+            max_bytes: -1,
+            ..Default::default()
+        };
+        let drift = desired.drift(&config);
+        assert_eq!(
+            drift.iter().map(|drift| drift.field).collect::<Vec<_>>(),
+            vec!["max-age", "compression"]
+        );
+
+        desired.apply(&mut config);
+        assert!(desired.drift(&config).is_empty());
+    }
+}
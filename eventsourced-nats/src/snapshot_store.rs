@@ -1,12 +1,23 @@
 //! A [SnapshotStore] implementation based on [NATS](https://nats.io/).
 
-use crate::Error;
+use crate::{
+    client::connect,
+    setup::{create_or_update, stream_config, Drift},
+    BucketConfig, ClientConfig, Error, IdEncoding,
+};
 use async_nats::{
-    jetstream::{self, kv::Store, Context as Jetstream},
-    ConnectOptions,
+    jetstream::{
+        self,
+        kv::{Entry, Operation, Store},
+        object_store::ObjectStore,
+        stream::Config as StreamConfig,
+        Context as Jetstream,
+    },
+    Client,
 };
 use bytes::{Bytes, BytesMut};
 use eventsourced::{Snapshot, SnapshotStore};
+use futures::TryStreamExt;
 use prost::Message;
 use serde::{Deserialize, Serialize};
 use std::{
@@ -14,82 +25,311 @@ use std::{
     fmt::{self, Debug, Display, Formatter},
     marker::PhantomData,
     num::NonZeroU64,
-    path::PathBuf,
 };
-use tracing::debug;
+use tokio::io::AsyncReadExt;
+use tracing::{debug, warn};
+
+const MAX_SAVE_ATTEMPTS: usize = 3;
 
 /// A [SnapshotStore] implementation based on [NATS](https://nats.io/).
+///
+/// Snapshots are stored in a KV bucket, yet states larger than the configured object threshold
+/// are stored in an object store bucket, i.e. in chunks, and the KV entry only holds the
+/// sequence number and a pointer to the object with its digest, which is verified when loading.
+///
+/// Snapshots are saved with compare-and-set semantics using the revisions of the KV entries and a
+/// snapshot with a sequence number less than the one of the current snapshot is refused. If more
+/// than one snapshot per entity is kept as history, loading can optionally fall back to earlier
+/// snapshots if the latest one cannot be decoded, e.g. after an incompatible change of the state.
+///
+/// Snapshots saved by earlier versions, which have stream sequences instead of the dense sequence
+/// numbers per entity of the [NatsEvtLog](crate::NatsEvtLog), are ignored when loading, such that
+/// all events are replayed, and are replaced when saving.
+///
+/// Entity IDs are encoded with the configured [IdEncoding] as keys.
 #[derive(Clone)]
 pub struct NatsSnapshotStore<I> {
     jetstream: Jetstream,
-    bucket: String,
+    bucket_name: String,
+    bucket: BucketConfig,
+    object_bucket_name: String,
+    object_threshold: usize,
+    history: i64,
+    load_fallback: bool,
+    id_encoding: IdEncoding,
     _id: PhantomData<I>,
 }
 
 impl<I> NatsSnapshotStore<I> {
     #[allow(missing_docs)]
     pub async fn new(config: Config) -> Result<Self, Error> {
+        let client = connect(&config.client).await?;
+        Self::with_client(client, config).await
+    }
+
+    /// Create a [NatsSnapshotStore] with the given client, e.g. shared with a
+    /// [NatsEvtLog](crate::NatsEvtLog), ignoring the client configuration.
+    pub async fn with_client(client: Client, config: Config) -> Result<Self, Error> {
         debug!(?config, "creating NatsSnapshotStore");
 
-        let mut options = ConnectOptions::new();
-        if let Some(credentials) = config.credentials {
-            options = options
-                .credentials_file(&credentials)
-                .await
-                .map_err(|error| {
-                    Error::Nats(
-                        format!(
-                            "cannot read NATS credentials file at {})",
-                            credentials.display()
-                        ),
-                        error.into(),
-                    )
-                })?;
-        };
-        let client = options
-            .connect(&config.server_addr)
-            .await
-            .map_err(|error| {
-                Error::Nats(
-                    format!("cannot connect to NATS server at {})", config.server_addr),
-                    error.into(),
-                )
-            })?;
         let jetstream = jetstream::new(client);
 
         // Setup bucket.
+        let bucket = config.bucket();
         if config.setup {
-            let _ = jetstream
-                .create_key_value(jetstream::kv::Config {
-                    bucket: config.bucket_name.clone(),
-                    max_bytes: config.bucket_max_bytes,
-                    ..Default::default()
-                })
-                .await
-                .map_err(|error| {
-                    Error::Nats("cannot create NATS KV bucket".into(), error.into())
-                })?;
+            let name = &config.bucket_name;
+            let desired = &bucket;
+            let history = config.history;
+            create_or_update(
+                &jetstream,
+                &format!("KV_{name}"),
+                || async {
+                    jetstream
+                        .create_key_value(jetstream::kv::Config {
+                            bucket: name.clone(),
+                            max_age: desired.max_age,
+                            max_bytes: desired.max_bytes,
+                            storage: desired.storage,
+                            num_replicas: desired.replicas,
+                            compression: desired.compression,
+                            history,
+                            ..Default::default()
+                        })
+                        .await
+                        .map_err(|error| {
+                            Error::Nats("cannot create NATS KV bucket".into(), error.into())
+                        })?;
+                    Ok(())
+                },
+                |stream_config| kv_drift(desired, history, stream_config),
+                |stream_config| {
+                    desired.apply(stream_config);
+                    stream_config.max_messages_per_subject = history;
+                },
+            )
+            .await?;
+
+            let name = &config.object_bucket_name;
+            create_or_update(
+                &jetstream,
+                &format!("OBJ_{name}"),
+                || async {
+                    jetstream
+                        .create_object_store(jetstream::object_store::Config {
+                            bucket: name.clone(),
+                            max_age: desired.max_age,
+                            storage: desired.storage,
+                            num_replicas: desired.replicas,
+                            ..Default::default()
+                        })
+                        .await
+                        .map_err(|error| {
+                            Error::Nats(
+                                "cannot create NATS object store bucket".into(),
+                                error.into(),
+                            )
+                        })?;
+
+                    // Not all settings are supported when creating an object store.
+                    let mut stream_config = stream_config(&jetstream, &format!("OBJ_{name}"))
+                        .await?
+                        .ok_or_else(|| Error::StreamNotFound(format!("OBJ_{name}")))?;
+                    desired.apply(&mut stream_config);
+                    jetstream
+                        .update_stream(&stream_config)
+                        .await
+                        .map_err(|error| {
+                            Error::Nats(
+                                "cannot update NATS object store bucket".into(),
+                                error.into(),
+                            )
+                        })?;
+                    Ok(())
+                },
+                |stream_config| desired.drift(stream_config),
+                |stream_config| desired.apply(stream_config),
+            )
+            .await?;
         }
 
         Ok(Self {
             jetstream,
-            bucket: config.bucket_name,
+            bucket_name: config.bucket_name,
+            bucket,
+            object_bucket_name: config.object_bucket_name,
+            object_threshold: config.object_threshold,
+            history: config.history,
+            load_fallback: config.load_fallback,
+            id_encoding: config.id_encoding,
             _id: PhantomData,
         })
     }
 
+    /// The differences between the actual configuration of the KV and object store buckets and
+    /// the desired one.
+    pub async fn drift(&self) -> Result<Vec<Drift>, Error> {
+        let name = format!("KV_{}", self.bucket_name);
+        let config = stream_config(&self.jetstream, &name)
+            .await?
+            .ok_or(Error::StreamNotFound(name))?;
+        let mut drift = kv_drift(&self.bucket, self.history, &config);
+
+        let name = format!("OBJ_{}", self.object_bucket_name);
+        let config = stream_config(&self.jetstream, &name)
+            .await?
+            .ok_or(Error::StreamNotFound(name))?;
+        drift.extend(self.bucket.drift(&config));
+
+        Ok(drift)
+    }
+
     async fn get_bucket(&self, name: &str) -> Result<Store, Error> {
         self.jetstream
             .get_key_value(name)
             .await
             .map_err(|error| Error::Nats("cannot get NATS KV bucket".into(), error.into()))
     }
+
+    async fn get_object_store(&self) -> Result<ObjectStore, Error> {
+        self.jetstream
+            .get_object_store(&self.object_bucket_name)
+            .await
+            .map_err(|error| {
+                Error::Nats("cannot get NATS object store bucket".into(), error.into())
+            })
+    }
+
+    /// Store the given state as object named after the given key and sequence number, returning
+    /// the name and the digest of the object.
+    async fn put_object(
+        &self,
+        key: &str,
+        seq_no: NonZeroU64,
+        state: Bytes,
+    ) -> Result<(String, String), Error> {
+        let name = format!("{key}.{seq_no}");
+        let info = self
+            .get_object_store()
+            .await?
+            .put(name.as_str(), &mut state.as_ref())
+            .await
+            .map_err(|error| {
+                Error::Nats(
+                    "cannot store snapshot in NATS object store bucket".into(),
+                    error.into(),
+                )
+            })?;
+        let digest = info.digest.unwrap_or_default();
+        Ok((name, digest))
+    }
+
+    /// Get the state from the object with the given name, verifying its digest.
+    async fn get_object(&self, name: &str, digest: &str) -> Result<Bytes, Error> {
+        let object_store = self.get_object_store().await?;
+        let mut object = object_store.get(name).await.map_err(|error| {
+            Error::Nats(
+                "cannot load snapshot from NATS object store bucket".into(),
+                error.into(),
+            )
+        })?;
+
+        // The object might have been replaced, else reading verifies the digest of its content.
+        if object.info().digest.as_deref() != Some(digest) {
+            return Err(Error::InvalidSnapshotDigest(name.to_string()));
+        }
+        let mut state = Vec::with_capacity(object.info().size);
+        object.read_to_end(&mut state).await.map_err(|error| {
+            Error::Nats(
+                "cannot read snapshot from NATS object store bucket".into(),
+                error.into(),
+            )
+        })?;
+
+        Ok(state.into())
+    }
+
+    /// The entries for the given key, oldest first: all kept ones if more than one snapshot is
+    /// kept as history, else only the latest one.
+    async fn entries(&self, bucket: &Store, key: &str) -> Result<Vec<Entry>, Error> {
+        let latest = bucket.entry(key).await.map_err(|error| {
+            Error::Nats(
+                "cannot load snapshot from NATS KV bucket".into(),
+                error.into(),
+            )
+        })?;
+
+        // Getting the history of a key without entries would not terminate.
+        match latest {
+            Some(_) if self.history > 1 => bucket
+                .history(key)
+                .await
+                .map_err(|error| {
+                    Error::Nats(
+                        "cannot get snapshot history from NATS KV bucket".into(),
+                        error.into(),
+                    )
+                })?
+                .try_collect()
+                .await
+                .map_err(|error| {
+                    Error::Nats(
+                        "cannot get snapshot history from NATS KV bucket".into(),
+                        error.into(),
+                    )
+                }),
+
+            latest => Ok(latest.into_iter().collect()),
+        }
+    }
+
+    /// Decode the given snapshot into its sequence number and state, getting the latter from the
+    /// object store if stored there; `None` for a legacy snapshot with a stream sequence.
+    async fn decode(&self, bytes: Bytes) -> Result<Option<(NonZeroU64, Bytes)>, Error> {
+        let proto::Snapshot {
+            seq_no,
+            state,
+            object_name,
+            object_digest,
+            dense_seq_no,
+        } = proto::Snapshot::decode(bytes)?;
+        if !dense_seq_no {
+            return Ok(None);
+        }
+
+        let seq_no = seq_no.try_into().map_err(|_| Error::InvalidNonZeroU64)?;
+        let state = if object_name.is_empty() {
+            state
+        } else {
+            self.get_object(&object_name, &object_digest).await?
+        };
+
+        Ok(Some((seq_no, state)))
+    }
+
+    /// Delete the object with the given name, if any; failure is only logged, as the object is
+    /// not referenced anymore.
+    async fn delete_object(&self, name: &str) {
+        if name.is_empty() {
+            return;
+        }
+
+        let result = match self.get_object_store().await {
+            Ok(object_store) => object_store
+                .delete(name)
+                .await
+                .map_err(|error| Error::Nats("cannot delete object".into(), error.into())),
+            Err(error) => Err(error),
+        };
+        if let Err(error) = result {
+            warn!(%error, name, "cannot delete object of snapshot");
+        }
+    }
 }
 
 impl<I> Debug for NatsSnapshotStore<I> {
     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
         f.debug_struct("NatsSnapshotStore")
-            .field("bucket", &self.bucket)
+            .field("bucket", &self.bucket_name)
             .finish()
     }
 }
@@ -114,27 +354,88 @@ where
         ToBytes: Fn(&S) -> Result<Bytes, ToBytesError> + Sync,
         ToBytesError: StdError + Send + Sync + 'static,
     {
-        let mut bytes = BytesMut::new();
         let state = to_bytes(state).map_err(|error| Error::IntoBytes(Box::new(error)))?;
-        let snapshot = proto::Snapshot {
-            seq_no: seq_no.get(),
-            state,
-        };
-        snapshot.encode(&mut bytes)?;
-
-        self.get_bucket(&self.bucket)
-            .await?
-            .put(id.to_string(), bytes.into())
-            .await
-            .map_err(|error| {
-                Error::Nats(
-                    "cannot store snapshot in NATS KV bucket".into(),
-                    error.into(),
-                )
-            })?;
-        debug!(%id, %seq_no, "saved snapshot");
+        let key = self.id_encoding.encode(id)?;
+        let bucket = self.get_bucket(&self.bucket_name).await?;
+
+        for _ in 0..MAX_SAVE_ATTEMPTS {
+            let entries = self.entries(&bucket, &key).await?;
+            let kept = entries
+                .iter()
+                .filter(|entry| entry.operation == Operation::Put)
+                .map(|entry| proto::Snapshot::decode(entry.value.clone()).unwrap_or_default())
+                .collect::<Vec<_>>();
+
+            // Refuse to regress the sequence number, ignoring saving the current one again; legacy
+            // snapshots have stream sequences, hence are replaced.
+            let current_seq_no = kept
+                .last()
+                .filter(|snapshot| snapshot.dense_seq_no)
+                .map(|snapshot| snapshot.seq_no)
+                .unwrap_or_default();
+            if seq_no.get() < current_seq_no {
+                return Err(Error::StaleSnapshot(seq_no, current_seq_no));
+            }
+            if seq_no.get() == current_seq_no {
+                debug!(%id, %seq_no, "snapshot already saved");
+                return Ok(());
+            }
+
+            let snapshot = if state.len() > self.object_threshold {
+                let (object_name, object_digest) =
+                    self.put_object(&key, seq_no, state.clone()).await?;
+                proto::Snapshot {
+                    seq_no: seq_no.get(),
+                    state: Bytes::new(),
+                    object_name,
+                    object_digest,
+                    dense_seq_no: true,
+                }
+            } else {
+                proto::Snapshot {
+                    seq_no: seq_no.get(),
+                    state: state.clone(),
+                    dense_seq_no: true,
+                    ..Default::default()
+                }
+            };
+            let mut bytes = BytesMut::new();
+            snapshot.encode(&mut bytes)?;
+
+            // Expect the revision of the latest entry, if any, else no entry.
+            let revision = entries
+                .last()
+                .map(|entry| entry.revision)
+                .unwrap_or_default();
+            match bucket.update(&key, bytes.into(), revision).await {
+                Ok(_) => {
+                    debug!(%id, %seq_no, "saved snapshot");
+
+                    // Delete the object of the snapshot dropped from the history, if any; a legacy
+                    // one might have the same name like the saved one.
+                    if entries.len() as i64 >= self.history {
+                        if let Some((dropped, kept)) = kept.split_first() {
+                            if dropped.object_name != snapshot.object_name
+                                && kept
+                                    .iter()
+                                    .all(|kept| kept.object_name != dropped.object_name)
+                            {
+                                self.delete_object(&dropped.object_name).await;
+                            }
+                        }
+                    }
+
+                    return Ok(());
+                }
+
+                Err(error) => {
+                    debug!(%id, %seq_no, %error, "cannot update snapshot, retrying");
+                    self.delete_object(&snapshot.object_name).await;
+                }
+            }
+        }
 
-        Ok(())
+        Err(Error::ConcurrentSnapshot(seq_no))
     }
 
     async fn load<S, FromBytes, FromBytesError>(
@@ -146,40 +447,62 @@ where
         FromBytes: Fn(Bytes) -> Result<S, FromBytesError> + Send,
         FromBytesError: StdError + Send + Sync + 'static,
     {
-        let snapshot = self
-            .get_bucket(&self.bucket)
-            .await?
-            .get(id.to_string())
-            .await
-            .map_err(|error| {
-                Error::Nats(
-                    "cannot load snapshot from NATS KV bucket".into(),
-                    error.into(),
-                )
-            })?
-            .map(|bytes| {
-                proto::Snapshot::decode(bytes)
-                    .map_err(Error::DecodeSnapshot)
-                    .and_then(|proto::Snapshot { seq_no, state }| {
-                        from_bytes(state)
-                            .map_err(|error| Error::FromBytes(Box::new(error)))
-                            .and_then(|state| {
-                                seq_no
-                                    .try_into()
-                                    .map_err(|_| Error::InvalidNonZeroU64)
-                                    .map(|seq_no| Snapshot::new(seq_no, state))
-                            })
-                    })
-            })
-            .transpose()?;
+        let key = self.id_encoding.encode(id)?;
+        let bucket = self.get_bucket(&self.bucket_name).await?;
 
-        if snapshot.is_some() {
-            debug!(%id, "loaded snapshot");
+        let entries = if self.load_fallback {
+            self.entries(&bucket, &key).await?
         } else {
-            debug!(%id, "no snapshot to load");
+            bucket
+                .entry(key)
+                .await
+                .map_err(|error| {
+                    Error::Nats(
+                        "cannot load snapshot from NATS KV bucket".into(),
+                        error.into(),
+                    )
+                })?
+                .into_iter()
+                .collect()
+        };
+        let mut entries = entries
+            .into_iter()
+            .rev()
+            .take_while(|entry| entry.operation == Operation::Put)
+            .peekable();
+
+        // Fall back to earlier snapshots, if any, if a snapshot cannot be decoded.
+        while let Some(entry) = entries.next() {
+            let snapshot = match self.decode(entry.value).await {
+                Ok(Some((seq_no, state))) => from_bytes(state)
+                    .map(|state| Snapshot::new(seq_no, state))
+                    .map_err(|error| Error::FromBytes(Box::new(error))),
+
+                // Earlier snapshots, if any, are legacy ones too.
+                Ok(None) => {
+                    warn!(%id, "ignoring legacy snapshot with stream sequence");
+                    break;
+                }
+
+                Err(error) => Err(error),
+            };
+
+            match snapshot {
+                Ok(snapshot) => {
+                    debug!(%id, "loaded snapshot");
+                    return Ok(Some(snapshot));
+                }
+
+                Err(error) if entries.peek().is_some() => {
+                    warn!(%id, %error, "cannot decode snapshot, falling back to earlier one");
+                }
+
+                Err(error) => return Err(error),
+            }
         }
 
-        Ok(snapshot)
+        debug!(%id, "no snapshot to load");
+        Ok(None)
     }
 }
 
@@ -187,41 +510,102 @@ where
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(rename_all = "kebab-case")]
 pub struct Config {
-    pub server_addr: String,
-
-    pub credentials: Option<PathBuf>,
+    #[serde(flatten)]
+    pub client: ClientConfig,
 
     #[serde(default = "bucket_name_default")]
     pub bucket_name: String,
 
-    #[serde(default = "bucket_max_bytes_default")]
-    pub bucket_max_bytes: i64,
+    #[serde(default)]
+    pub bucket: BucketConfig,
+
+    /// Deprecated, use `bucket.max-bytes` instead; overrides it if given.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub bucket_max_bytes: Option<i64>,
+
+    /// Name of the object store bucket for large snapshots, which has the same desired
+    /// configuration like the KV bucket.
+    #[serde(default = "bucket_name_default")]
+    pub object_bucket_name: String,
+
+    /// Snapshots with states larger than this number of bytes are stored in the object store
+    /// bucket, which should be less than the maximum payload of the NATS server.
+    #[serde(default = "object_threshold_default")]
+    pub object_threshold: usize,
+
+    /// Number of snapshots kept per entity, at most 64.
+    #[serde(default = "history_default")]
+    pub history: i64,
+
+    /// Whether to fall back to earlier snapshots if the latest one cannot be decoded, which
+    /// requires to keep more than one snapshot per entity.
+    #[serde(default)]
+    pub load_fallback: bool,
+
+    /// Encoding of the entity IDs in the keys, must match the one of the evt log.
+    #[serde(default)]
+    pub id_encoding: IdEncoding,
 
     #[serde(default)]
     pub setup: bool,
 }
 
 impl Default for Config {
-    /// Use "localhost:4222" for `server_addr` and "snapshots" for `bucket`.
+    /// Use the default [ClientConfig], "snapshots" for the bucket names, 256KiB for the object
+    /// threshold and keep only the latest snapshot per entity.
     fn default() -> Self {
         Self {
-            server_addr: "localhost:4222".to_string(),
-            credentials: None,
+            client: ClientConfig::default(),
             bucket_name: bucket_name_default(),
-            bucket_max_bytes: bucket_max_bytes_default(),
+            bucket: BucketConfig::default(),
+            bucket_max_bytes: None,
+            object_bucket_name: bucket_name_default(),
+            object_threshold: object_threshold_default(),
+            history: history_default(),
+            load_fallback: false,
+            id_encoding: IdEncoding::default(),
             setup: false,
         }
     }
 }
 
-fn bucket_max_bytes_default() -> i64 {
-    -1
+impl Config {
+    /// The desired configuration of the buckets, taking the deprecated `bucket-max-bytes` into
+    /// account.
+    fn bucket(&self) -> BucketConfig {
+        let mut bucket = self.bucket.clone();
+        if let Some(max_bytes) = self.bucket_max_bytes {
+            warn!("bucket-max-bytes is deprecated, use bucket.max-bytes instead");
+            bucket.max_bytes = max_bytes;
+        }
+        bucket
+    }
 }
 
 fn bucket_name_default() -> String {
     "snapshots".to_string()
 }
 
+/// The differences between the given actual configuration of the stream backing the KV bucket and
+/// the desired one including the history.
+fn kv_drift(desired: &BucketConfig, history: i64, stream_config: &StreamConfig) -> Vec<Drift> {
+    let mut drift = desired.drift(stream_config);
+    drift.extend(Drift::of(
+        "history",
+        stream_config.max_messages_per_subject,
+        history,
+    ));
+    drift
+}
+
+const fn history_default() -> i64 {
+    1
+}
+
+const fn object_threshold_default() -> usize {
+    256 * 1_024
+}
+
 mod proto {
     include!(concat!(env!("OUT_DIR"), "/snapshot_store.rs"));
 }
@@ -236,6 +620,17 @@ mod tests {
     use testcontainers_modules::testcontainers::GenericImage;
     use uuid::Uuid;
 
+    #[test]
+    fn test_deprecated_max_bytes() -> Result<(), BoxError> {
+        let config = serde_json::from_str::<Config>(r#"{ "bucket-max-bytes": 42 }"#)?;
+        assert_eq!(config.bucket().max_bytes, 42);
+
+        let config = serde_json::from_str::<Config>(r#"{ "bucket": { "max-bytes": 42 } }"#)?;
+        assert_eq!(config.bucket().max_bytes, 42);
+
+        Ok(())
+    }
+
     #[tokio::test]
     async fn test_snapshot_store() -> Result<(), BoxError> {
         let client = Cli::default();
@@ -245,11 +640,18 @@ mod tests {
         let server_addr = format!("localhost:{}", container.get_host_port_ipv4(4222));
 
         let config = Config {
-            server_addr,
+            client: ClientConfig {
+                server_addr,
+                ..Default::default()
+            },
             setup: true,
             ..Default::default()
         };
-        let mut snapshot_store = NatsSnapshotStore::new(config).await?;
+        let mut snapshot_store = NatsSnapshotStore::new(config.clone()).await?;
+        assert!(snapshot_store.drift().await?.is_empty());
+
+        // Setup is idempotent.
+        NatsSnapshotStore::<Uuid>::new(config).await?;
 
         let id = Uuid::now_v7();
 
@@ -274,6 +676,113 @@ mod tests {
         assert_eq!(snapshot.seq_no, seq_no);
         assert_eq!(snapshot.state, state);
 
+        // Large snapshots are stored in the object store and replaced objects deleted.
+        let state = vec![42; 1_024 * 1_024];
+        for seq_no in [43, 44] {
+            snapshot_store
+                .save(
+                    &id,
+                    seq_no.try_into()?,
+                    &state,
+                    &binarize::serde_json::to_bytes,
+                )
+                .await?;
+        }
+        let snapshot = snapshot_store
+            .load::<Vec<u8>, _, _>(&id, &binarize::serde_json::from_bytes)
+            .await?
+            .expect("snapshot");
+        assert_eq!(snapshot.seq_no, 44.try_into()?);
+        assert_eq!(snapshot.state, state);
+
+        let object_store = snapshot_store.get_object_store().await?;
+        assert!(object_store.info(format!("{id}.43")).await.is_err());
+        assert!(object_store.info(format!("{id}.44")).await.is_ok());
+
+        // Stale snapshots are refused.
+        let result = snapshot_store
+            .save(
+                &id,
+                seq_no,
+                &Vec::<u8>::new(),
+                &binarize::serde_json::to_bytes,
+            )
+            .await;
+        assert!(matches!(result, Err(Error::StaleSnapshot(s, 44)) if s == seq_no));
+        let snapshot = snapshot_store
+            .load::<Vec<u8>, _, _>(&id, &binarize::serde_json::from_bytes)
+            .await?
+            .expect("snapshot");
+        assert_eq!(snapshot.seq_no, 44.try_into()?);
+
+        // Legacy snapshots with stream sequences are ignored and replaced.
+        let id = Uuid::now_v7();
+        let legacy = proto::Snapshot {
+            seq_no: 42,
+            state: binarize::serde_json::to_bytes(&666)?,
+            ..Default::default()
+        };
+        let mut bytes = BytesMut::new();
+        legacy.encode(&mut bytes)?;
+        snapshot_store
+            .get_bucket(&snapshot_store.bucket_name)
+            .await?
+            .put(snapshot_store.id_encoding.encode(&id)?, bytes.into())
+            .await?;
+        let snapshot = snapshot_store
+            .load::<i32, _, _>(&id, &binarize::serde_json::from_bytes)
+            .await?;
+        assert!(snapshot.is_none());
+        snapshot_store
+            .save(&id, 7.try_into()?, &777, &binarize::serde_json::to_bytes)
+            .await?;
+        let snapshot = snapshot_store
+            .load::<i32, _, _>(&id, &binarize::serde_json::from_bytes)
+            .await?
+            .expect("snapshot");
+        assert_eq!(snapshot.seq_no, 7.try_into()?);
+        assert_eq!(snapshot.state, 777);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_load_fallback() -> Result<(), BoxError> {
+        let client = Cli::default();
+        let nats_image = GenericImage::new("nats", NATS_VERSION)
+            .with_wait_for(WaitFor::message_on_stderr("Server is ready"));
+        let container = client.run((nats_image, vec!["-js".to_string()]));
+        let server_addr = format!("localhost:{}", container.get_host_port_ipv4(4222));
+
+        let config = Config {
+            client: ClientConfig {
+                server_addr,
+                ..Default::default()
+            },
+            history: 2,
+            load_fallback: true,
+            setup: true,
+            ..Default::default()
+        };
+        let mut snapshot_store = NatsSnapshotStore::<Uuid>::new(config).await?;
+        assert!(snapshot_store.drift().await?.is_empty());
+
+        let id = Uuid::now_v7();
+        snapshot_store
+            .save(&id, 42.try_into()?, &666, &binarize::serde_json::to_bytes)
+            .await?;
+        snapshot_store
+            .save(&id, 43.try_into()?, &"666", &binarize::serde_json::to_bytes)
+            .await?;
+
+        // The latest snapshot cannot be decoded as i32, hence the earlier one is loaded.
+        let snapshot = snapshot_store
+            .load::<i32, _, _>(&id, &binarize::serde_json::from_bytes)
+            .await?
+            .expect("snapshot");
+        assert_eq!(snapshot.seq_no, 42.try_into()?);
+        assert_eq!(snapshot.state, 666);
+
         Ok(())
     }
 }
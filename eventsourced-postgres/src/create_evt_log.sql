@@ -5,4 +5,22 @@ CREATE TABLE
     id uuid,
     evt bytea,
     PRIMARY KEY (seq_no, id)
-  );
\ No newline at end of file
+  );
+
+-- Global position and inserting transaction, used by the outbox relay to tail events in commit
+-- order.
+ALTER TABLE evts
+ADD COLUMN IF NOT EXISTS position bigserial,
+ADD COLUMN IF NOT EXISTS tx_id bigint DEFAULT txid_current();
+
+CREATE INDEX IF NOT EXISTS evts_tx_id_position ON evts (tx_id, position);
+
+-- Idempotency key of the command which has resulted in an event, if any.
+ALTER TABLE evts
+ADD COLUMN IF NOT EXISTS idempotency_key text;
+
+-- Tags of an event, used to query events of cross-cutting categories.
+ALTER TABLE evts
+ADD COLUMN IF NOT EXISTS tags text[] NOT NULL DEFAULT '{}';
+
+CREATE INDEX IF NOT EXISTS evts_tags ON evts USING GIN (tags);
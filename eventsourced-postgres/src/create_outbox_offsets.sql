@@ -0,0 +1,6 @@
+CREATE TABLE
+  IF NOT EXISTS outbox_offsets (
+    name text PRIMARY KEY,
+    tx_id bigint NOT NULL,
+    position bigint NOT NULL
+  );
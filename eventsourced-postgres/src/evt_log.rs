@@ -4,8 +4,8 @@ use crate::{Cnn, CnnPool, Error};
 use async_stream::stream;
 use bb8_postgres::{bb8::Pool, PostgresConnectionManager};
 use bytes::Bytes;
-use eventsourced::{EventSourced, EvtLog};
-use futures::{Stream, StreamExt, TryStreamExt};
+use eventsourced::{EventSourced, EvtLog, EvtsQuery, Persisted, SLOTS};
+use futures::{future, stream, Stream, StreamExt, TryStreamExt};
 use serde::{Deserialize, Serialize};
 use std::{
     error::Error as StdError,
@@ -15,10 +15,18 @@ use std::{
     time::Duration,
 };
 use tokio::time::sleep;
-use tokio_postgres::{types::ToSql, NoTls};
+use tokio_postgres::{types::ToSql, NoTls, Row};
 use tracing::{debug, instrument};
 
 /// An [EvtLog] implementation based on [PostgreSQL](https://www.postgresql.org/).
+///
+/// The sequence numbers of the events by type are their global positions, which are increasing but
+/// not dense. Only events of transactions older than all running ones are yielded, such that no
+/// event with a lower position can be committed later. Projections which have tracked the
+/// per-entity sequence numbers of earlier versions must be rebuilt.
+///
+/// Tags of events are stored in an array column; like the events by type, the events by tag are
+/// numbered by their positions.
 #[derive(Clone)]
 pub struct PostgresEvtLog<I> {
     poll_interval: Duration,
@@ -65,7 +73,7 @@ where
         })
     }
 
-    async fn cnn(&self) -> Result<Cnn<NoTls>, Error> {
+    async fn cnn(&self) -> Result<Cnn<'_, NoTls>, Error> {
         self.cnn_pool.get().await.map_err(Error::GetConnection)
     }
 
@@ -111,7 +119,7 @@ where
     async fn next_evts_by_type<E, FromBytes, FromBytesError>(
         &self,
         type_name: &str,
-        seq_no: i64,
+        position: i64,
         from_bytes: FromBytes,
     ) -> Result<impl Stream<Item = Result<(NonZeroU64, E), Error>> + Send, Error>
     where
@@ -119,14 +127,16 @@ where
         FromBytes: Fn(Bytes) -> Result<E, FromBytesError> + Send,
         FromBytesError: StdError + Send + Sync + 'static,
     {
-        debug!(%type_name, seq_no, "querying events");
+        debug!(%type_name, position, "querying events");
 
-        let params: [&(dyn ToSql + Sync); 2] = [&type_name, &seq_no];
+        // Only events of transactions older than all running ones are visible, such that no event
+        // with a lower position can be committed after an event has been yielded.
+        let params: [&(dyn ToSql + Sync); 2] = [&type_name, &position];
         let evts = self
             .cnn()
             .await?
             .query_raw(
-                "SELECT seq_no, evt FROM evts WHERE type = $1 AND seq_no >= $2",
+                "SELECT position, evt FROM evts WHERE type = $1 AND position >= $2 AND tx_id < txid_snapshot_xmin(txid_current_snapshot()) ORDER BY position",
                 params,
             )
             .await
@@ -134,40 +144,171 @@ where
             .map_err(|error| Error::Postgres("cannot get next row".to_string(), error))
             .map(move |row| {
                 row.and_then(|row| {
-                    let seq_no = (row.get::<_, i64>(0) as u64)
+                    let position = (row.get::<_, i64>(0) as u64)
                         .try_into()
                         .map_err(|_| Error::ZeroNonZeroU64)?;
                     let bytes = row.get::<_, &[u8]>(1);
                     let bytes = Bytes::copy_from_slice(bytes);
                     from_bytes(bytes)
                         .map_err(|source| Error::FromBytes(Box::new(source)))
-                        .map(|evt| (seq_no, evt))
+                        .map(|evt| (position, evt))
                 })
             });
 
         Ok(evts)
     }
 
-    async fn last_seq_no_by_type(&self, type_name: &str) -> Result<Option<NonZeroU64>, Error> {
-        self.cnn()
+    async fn next_evts_by_type_in_slots<E, FromBytes, FromBytesError>(
+        &self,
+        type_name: &str,
+        position: i64,
+        slots: &[i32],
+        from_bytes: FromBytes,
+    ) -> Result<impl Stream<Item = Result<(NonZeroU64, Option<(usize, E)>), Error>> + Send, Error>
+    where
+        E: Send,
+        FromBytes: Fn(Bytes) -> Result<E, FromBytesError> + Send,
+        FromBytesError: StdError + Send + Sync + 'static,
+    {
+        debug!(%type_name, position, ?slots, "querying events in slots");
+
+        // Like for `next_evts_by_type`, yet the events of other slots are filtered out, only the
+        // last position, which comes last with a NULL slot, is passed to track progress.
+        let query = format!(
+            "SELECT position, slot, evt FROM (SELECT position, (hashtextextended(id::text, 0) & {mask})::int AS slot, evt FROM evts WHERE type = $1 AND position >= $2 AND tx_id < txid_snapshot_xmin(txid_current_snapshot())) AS e WHERE slot = ANY($3) UNION ALL SELECT MAX(position), NULL, NULL FROM evts WHERE type = $1 AND position >= $2 AND tx_id < txid_snapshot_xmin(txid_current_snapshot()) ORDER BY position, slot",
+            mask = SLOTS - 1
+        );
+        let params: [&(dyn ToSql + Sync); 3] = [&type_name, &position, &slots];
+        let evts = self
+            .cnn()
             .await?
-            .query_one(
-                "SELECT MAX(seq_no) FROM evts WHERE type = $1",
-                &[&type_name],
+            .query_raw(&query, params)
+            .await
+            .map_err(|error| Error::Postgres("cannot execute query".to_string(), error))?
+            .map_err(|error| Error::Postgres("cannot get next row".to_string(), error))
+            .try_filter_map(move |row| {
+                let evt = || {
+                    // Without events, the last position is NULL, hence use `try_get`.
+                    let Ok(position) = row.try_get::<_, i64>(0) else {
+                        return Ok(None);
+                    };
+                    let position = (position as u64)
+                        .try_into()
+                        .map_err(|_| Error::ZeroNonZeroU64)?;
+                    let Ok(slot) = row.try_get::<_, i32>(1) else {
+                        return Ok(Some((position, None)));
+                    };
+                    let bytes = row.get::<_, &[u8]>(2);
+                    let bytes = Bytes::copy_from_slice(bytes);
+                    from_bytes(bytes)
+                        .map_err(|source| Error::FromBytes(Box::new(source)))
+                        .map(|evt| Some((position, Some((slot as usize, evt)))))
+                };
+                future::ready(evt())
+            });
+
+        Ok(evts)
+    }
+
+    async fn next_evts_by_tag<E, FromBytes, FromBytesError>(
+        &self,
+        type_name: &str,
+        tag: &str,
+        position: i64,
+        from_bytes: FromBytes,
+    ) -> Result<impl Stream<Item = Result<(NonZeroU64, E), Error>> + Send, Error>
+    where
+        E: Send,
+        FromBytes: Fn(Bytes) -> Result<E, FromBytesError> + Send,
+        FromBytesError: StdError + Send + Sync + 'static,
+    {
+        debug!(%type_name, %tag, position, "querying events");
+
+        // Only events of transactions older than all running ones are visible, such that no event
+        // with a lower position can be committed after an event has been yielded.
+        let params: [&(dyn ToSql + Sync); 3] = [&type_name, &tag, &position];
+        let evts = self
+            .cnn()
+            .await?
+            .query_raw(
+                "SELECT position, evt FROM evts WHERE type = $1 AND $2 = ANY(tags) AND position >= $3 AND tx_id < txid_snapshot_xmin(txid_current_snapshot()) ORDER BY position",
+                params,
             )
             .await
-            .map_err(|error| Error::Postgres("cannot execute query".to_string(), error))
-            .and_then(|row| {
-                // If there is no seq_no there is one row with a NULL column, hence use `try_get`.
-                row.try_get::<_, i64>(0)
-                    .ok()
-                    .map(|seq_no| {
-                        (seq_no as u64)
-                            .try_into()
-                            .map_err(|_| Error::ZeroNonZeroU64)
-                    })
-                    .transpose()
-            })
+            .map_err(|error| Error::Postgres("cannot execute query".to_string(), error))?
+            .map_err(|error| Error::Postgres("cannot get next row".to_string(), error))
+            .map(move |row| {
+                row.and_then(|row| {
+                    let position = (row.get::<_, i64>(0) as u64)
+                        .try_into()
+                        .map_err(|_| Error::ZeroNonZeroU64)?;
+                    let bytes = row.get::<_, &[u8]>(1);
+                    let bytes = Bytes::copy_from_slice(bytes);
+                    from_bytes(bytes)
+                        .map_err(|source| Error::FromBytes(Box::new(source)))
+                        .map(|evt| (position, evt))
+                })
+            });
+
+        Ok(evts)
+    }
+
+    /// Query the current events with the given value of the given column, numbered by the given
+    /// column, i.e. `seq_no` or `position`.
+    async fn query_current_evts<E, FromBytes, FromBytesError>(
+        &self,
+        column: &str,
+        value: &(dyn ToSql + Sync),
+        number: &str,
+        query: EvtsQuery,
+        from_bytes: FromBytes,
+    ) -> Result<impl Stream<Item = Result<(NonZeroU64, E), Error>> + Send, Error>
+    where
+        E: Send,
+        FromBytes: Fn(Bytes) -> Result<E, FromBytesError> + Send,
+        FromBytesError: StdError + Send + Sync + 'static,
+    {
+        debug!(column, number, ?query, "querying current events");
+
+        // A single query only sees the events committed at query time, hence ends with the last.
+        // Like for the events by type, positions are only returned below the ones of transactions
+        // still in progress, as these would leave gaps, which would never be returned, if resuming
+        // after the last position; sequence numbers by ID do not need that, as they are assigned
+        // in order by optimistic locking.
+        let order = if query.reverse { "DESC" } else { "ASC" };
+        let visible = if number == "position" {
+            "AND tx_id < txid_snapshot_xmin(txid_current_snapshot())"
+        } else {
+            ""
+        };
+        let statement = format!(
+            "SELECT {number}, evt FROM evts WHERE {column} = $1 AND {number} >= $2 AND {number} <= $3 {visible} ORDER BY {number} {order} LIMIT $4"
+        );
+        let from = query.from.get() as i64;
+        let to = query.to.map_or(i64::MAX, |n| n.get() as i64);
+        let limit = query.limit.map(|n| n.min(i64::MAX as u64) as i64);
+        let params: [&(dyn ToSql + Sync); 4] = [value, &from, &to, &limit];
+        let evts = self
+            .cnn()
+            .await?
+            .query_raw(&statement, params)
+            .await
+            .map_err(|error| Error::Postgres("cannot execute query".to_string(), error))?
+            .map_err(|error| Error::Postgres("cannot get next row".to_string(), error))
+            .map(move |row| {
+                row.and_then(|row| {
+                    let seq_no = (row.get::<_, i64>(0) as u64)
+                        .try_into()
+                        .map_err(|_| Error::ZeroNonZeroU64)?;
+                    let bytes = row.get::<_, &[u8]>(1);
+                    let bytes = Bytes::copy_from_slice(bytes);
+                    from_bytes(bytes)
+                        .map_err(|source| Error::FromBytes(Box::new(source)))
+                        .map(|evt| (seq_no, evt))
+                })
+            });
+
+        Ok(evts)
     }
 }
 
@@ -187,7 +328,7 @@ where
 
     /// The maximum value for sequence numbers. As PostgreSQL does not support unsigned integers,
     /// this is `i64::MAX` or `9_223_372_036_854_775_807`.
-    const MAX_SEQ_NO: NonZeroU64 = unsafe { NonZeroU64::new_unchecked(i64::MAX as u64) };
+    const MAX_SEQ_NO: NonZeroU64 = NonZeroU64::new(i64::MAX as u64).unwrap();
 
     #[instrument(skip(self, evt, to_bytes))]
     async fn persist<E, ToBytes, ToBytesError>(
@@ -195,8 +336,9 @@ where
         evt: &E::Evt,
         id: &Self::Id,
         last_seq_no: Option<NonZeroU64>,
+        idempotency_key: Option<&str>,
         to_bytes: &ToBytes,
-    ) -> Result<NonZeroU64, Self::Error>
+    ) -> Result<Persisted, Self::Error>
     where
         E: EventSourced,
         ToBytes: Fn(&E::Evt) -> Result<Bytes, ToBytesError> + Sync,
@@ -205,20 +347,17 @@ where
         let seq_no = last_seq_no.map(|n| n.get() as i64).unwrap_or_default() + 1;
 
         let bytes = to_bytes(evt).map_err(|error| Error::ToBytes(Box::new(error)))?;
+        let tags = E::tags(evt);
 
         self.cnn()
             .await?
             .query_one(
-                "INSERT INTO evts (seq_no, type, id, evt) VALUES ($1, $2, $3, $4) RETURNING seq_no",
-                &[&seq_no, &E::TYPE_NAME, &id, &bytes.as_ref()],
+                "INSERT INTO evts (seq_no, type, id, evt, idempotency_key, tags) VALUES ($1, $2, $3, $4, $5, $6) RETURNING seq_no, position",
+                &[&seq_no, &E::TYPE_NAME, &id, &bytes.as_ref(), &idempotency_key, &tags],
             )
             .await
             .map_err(|error| Error::Postgres("cannot execute query".to_string(), error))
-            .and_then(|row| {
-                (row.get::<_, i64>(0) as u64)
-                    .try_into()
-                    .map_err(|_| Error::ZeroNonZeroU64)
-            })
+            .and_then(|row| persisted(&row, 0))
     }
 
     #[instrument(skip(self))]
@@ -244,6 +383,83 @@ where
             })
     }
 
+    #[instrument(skip(self))]
+    async fn last_seq_no_by_type<E>(&self) -> Result<Option<NonZeroU64>, Self::Error>
+    where
+        E: EventSourced,
+    {
+        self.cnn()
+            .await?
+            .query_one(
+                "SELECT MAX(position) FROM evts WHERE type = $1",
+                &[&E::TYPE_NAME],
+            )
+            .await
+            .map_err(|error| Error::Postgres("cannot execute query".to_string(), error))
+            .and_then(|row| {
+                // If there is no position there is one row with a NULL column, hence use `try_get`.
+                row.try_get::<_, i64>(0)
+                    .ok()
+                    .map(|position| {
+                        (position as u64)
+                            .try_into()
+                            .map_err(|_| Error::ZeroNonZeroU64)
+                    })
+                    .transpose()
+            })
+    }
+
+    #[instrument(skip(self))]
+    async fn last_seq_no_by_tag<E>(&self, tag: &str) -> Result<Option<NonZeroU64>, Self::Error>
+    where
+        E: EventSourced,
+    {
+        self.cnn()
+            .await?
+            .query_one(
+                "SELECT MAX(position) FROM evts WHERE type = $1 AND $2 = ANY(tags)",
+                &[&E::TYPE_NAME, &tag],
+            )
+            .await
+            .map_err(|error| Error::Postgres("cannot execute query".to_string(), error))
+            .and_then(|row| {
+                // If there is no position there is one row with a NULL column, hence use `try_get`.
+                row.try_get::<_, i64>(0)
+                    .ok()
+                    .map(|position| {
+                        (position as u64)
+                            .try_into()
+                            .map_err(|_| Error::ZeroNonZeroU64)
+                    })
+                    .transpose()
+            })
+    }
+
+    #[instrument(skip(self))]
+    async fn idempotency_keys<E>(
+        &self,
+        id: &Self::Id,
+        seq_no: NonZeroU64,
+    ) -> Result<impl Stream<Item = Result<(Persisted, String), Self::Error>> + Send, Self::Error>
+    where
+        E: EventSourced,
+    {
+        let seq_no = seq_no.get() as i64;
+        let keys = self
+            .cnn()
+            .await?
+            .query(
+                "SELECT seq_no, position, idempotency_key FROM evts WHERE id = $1 AND seq_no >= $2 AND idempotency_key IS NOT NULL ORDER BY seq_no",
+                &[&id, &seq_no],
+            )
+            .await
+            .map_err(|error| Error::Postgres("cannot execute query".to_string(), error))?
+            .into_iter()
+            .map(|row| Ok((persisted(&row, 0)?, row.get::<_, String>(2))));
+
+        Ok(stream::iter(keys))
+    }
+
     #[instrument(skip(self, from_bytes))]
     async fn evts_by_id<E, FromBytes, FromBytesError>(
         &self,
@@ -309,23 +525,23 @@ where
             seq_no, "building events by type stream"
         );
 
-        let last_seq_no = self
-            .last_seq_no_by_type(E::TYPE_NAME)
+        let last_position = self
+            .last_seq_no_by_type::<E>()
             .await?
             .map(|n| n.get() as i64)
             .unwrap_or_default();
 
-        let mut current_seq_no = seq_no.get() as i64;
+        let mut current_position = seq_no.get() as i64;
         let evts = stream! {
             'outer: loop {
                 let evts = self
-                    .next_evts_by_type(E::TYPE_NAME, current_seq_no, from_bytes)
+                    .next_evts_by_type(E::TYPE_NAME, current_position, from_bytes)
                     .await?;
 
                 for await evt in evts {
                     match evt {
-                        Ok(evt @ (seq_no, _)) => {
-                            current_seq_no = seq_no.get() as i64 + 1;
+                        Ok(evt @ (position, _)) => {
+                            current_position = position.get() as i64 + 1;
                             yield Ok(evt);
                         }
 
@@ -337,7 +553,128 @@ where
                 }
 
                 // Only sleep if requesting future events.
-                if current_seq_no >= last_seq_no {
+                if current_position >= last_position {
+                    sleep(self.poll_interval).await;
+                }
+            }
+        };
+
+        Ok(evts)
+    }
+
+    /// Events are distributed to slots by a hash of the entity ID calculated by the database, the
+    /// events of other slots are filtered out by the database.
+    #[instrument(skip(self, from_bytes))]
+    async fn evts_by_type_in_slots<E, FromBytes, FromBytesError>(
+        &self,
+        slots: &[usize],
+        seq_no: NonZeroU64,
+        from_bytes: FromBytes,
+    ) -> Result<
+        Option<
+            impl Stream<Item = Result<(NonZeroU64, Option<(usize, E::Evt)>), Self::Error>> + Send,
+        >,
+        Self::Error,
+    >
+    where
+        E: EventSourced,
+        FromBytes: Fn(Bytes) -> Result<E::Evt, FromBytesError> + Copy + Send + Sync + 'static,
+        FromBytesError: StdError + Send + Sync + 'static,
+    {
+        debug!(
+            type_name = E::TYPE_NAME,
+            ?slots,
+            seq_no,
+            "building events by type in slots stream"
+        );
+
+        let last_position = self
+            .last_seq_no_by_type::<E>()
+            .await?
+            .map(|n| n.get() as i64)
+            .unwrap_or_default();
+
+        let slots = slots.iter().map(|slot| *slot as i32).collect::<Vec<_>>();
+        let mut current_position = seq_no.get() as i64;
+        let evts = stream! {
+            'outer: loop {
+                let evts = self
+                    .next_evts_by_type_in_slots(E::TYPE_NAME, current_position, &slots, from_bytes)
+                    .await?;
+
+                for await evt in evts {
+                    match evt {
+                        // The last position is redundant if it is the one of the last event.
+                        Ok((position, None)) if (position.get() as i64) < current_position => {}
+
+                        Ok(evt @ (position, _)) => {
+                            current_position = position.get() as i64 + 1;
+                            yield Ok(evt);
+                        }
+
+                        Err(error) => {
+                            yield Err(error);
+                            break 'outer;
+                        }
+                    }
+                }
+
+                // Only sleep if requesting future events.
+                if current_position >= last_position {
+                    sleep(self.poll_interval).await;
+                }
+            }
+        };
+
+        Ok(Some(evts))
+    }
+
+    #[instrument(skip(self, from_bytes))]
+    async fn evts_by_tag<E, FromBytes, FromBytesError>(
+        &self,
+        tag: &str,
+        seq_no: NonZeroU64,
+        from_bytes: FromBytes,
+    ) -> Result<impl Stream<Item = Result<(NonZeroU64, E::Evt), Self::Error>> + Send, Self::Error>
+    where
+        E: EventSourced,
+        FromBytes: Fn(Bytes) -> Result<E::Evt, FromBytesError> + Copy + Send + Sync + 'static,
+        FromBytesError: StdError + Send + Sync + 'static,
+    {
+        debug!(
+            type_name = E::TYPE_NAME,
+            tag, seq_no, "building events by tag stream"
+        );
+
+        let last_position = self
+            .last_seq_no_by_tag::<E>(tag)
+            .await?
+            .map(|n| n.get() as i64)
+            .unwrap_or_default();
+
+        let mut current_position = seq_no.get() as i64;
+        let evts = stream! {
+            'outer: loop {
+                let evts = self
+                    .next_evts_by_tag(E::TYPE_NAME, tag, current_position, from_bytes)
+                    .await?;
+
+                for await evt in evts {
+                    match evt {
+                        Ok(evt @ (position, _)) => {
+                            current_position = position.get() as i64 + 1;
+                            yield Ok(evt);
+                        }
+
+                        Err(error) => {
+                            yield Err(error);
+                            break 'outer;
+                        }
+                    }
+                }
+
+                // Only sleep if requesting future events.
+                if current_position >= last_position {
                     sleep(self.poll_interval).await;
                 }
             }
@@ -345,6 +682,49 @@ where
 
         Ok(evts)
     }
+
+    #[instrument(skip(self, from_bytes))]
+    async fn current_evts_by_id<E, FromBytes, FromBytesError>(
+        &self,
+        id: &Self::Id,
+        query: EvtsQuery,
+        from_bytes: FromBytes,
+    ) -> Result<impl Stream<Item = Result<(NonZeroU64, E::Evt), Self::Error>> + Send, Self::Error>
+    where
+        E: EventSourced,
+        FromBytes: Fn(Bytes) -> Result<E::Evt, FromBytesError> + Copy + Send + Sync + 'static,
+        FromBytesError: StdError + Send + Sync + 'static,
+    {
+        self.query_current_evts("id", id, "seq_no", query, from_bytes)
+            .await
+    }
+
+    #[instrument(skip(self, from_bytes))]
+    async fn current_evts_by_type<E, FromBytes, FromBytesError>(
+        &self,
+        query: EvtsQuery,
+        from_bytes: FromBytes,
+    ) -> Result<impl Stream<Item = Result<(NonZeroU64, E::Evt), Self::Error>> + Send, Self::Error>
+    where
+        E: EventSourced,
+        FromBytes: Fn(Bytes) -> Result<E::Evt, FromBytesError> + Copy + Send + Sync + 'static,
+        FromBytesError: StdError + Send + Sync + 'static,
+    {
+        self.query_current_evts("type", &E::TYPE_NAME, "position", query, from_bytes)
+            .await
+    }
+}
+
+/// The sequence number and the position of an event from the given row, starting at the given
+/// column.
+fn persisted(row: &Row, column: usize) -> Result<Persisted, Error> {
+    let seq_no = (row.get::<_, i64>(column) as u64)
+        .try_into()
+        .map_err(|_| Error::ZeroNonZeroU64)?;
+    let position = (row.get::<_, i64>(column + 1) as u64)
+        .try_into()
+        .map_err(|_| Error::ZeroNonZeroU64)?;
+    Ok(Persisted { seq_no, position })
 }
 
 /// Configuration for the [PostgresEvtLog].
@@ -377,7 +757,7 @@ pub struct Config {
 }
 
 impl Config {
-    fn cnn_config(&self) -> String {
+    pub(crate) fn cnn_config(&self) -> String {
         format!(
             "host={} port={} user={} password={} dbname={} sslmode={}",
             self.host, self.port, self.user, self.password, self.dbname, self.sslmode
@@ -447,6 +827,14 @@ mod tests {
         fn handle_evt(_state: Self::State, _evt: Self::Evt) -> Self::State {
             todo!()
         }
+
+        fn tags(evt: &Self::Evt) -> Vec<String> {
+            if evt % 2 == 0 {
+                vec!["even".to_string()]
+            } else {
+                vec![]
+            }
+        }
     }
 
     #[tokio::test]
@@ -468,32 +856,52 @@ mod tests {
 
         let last_seq_no = evt_log.last_seq_no::<Dummy>(&id).await?;
         assert_eq!(last_seq_no, None);
+        let last_seq_no = evt_log.last_seq_no_by_type::<Dummy>().await?;
+        assert_eq!(last_seq_no, None);
 
         let last_seq_no = evt_log
-            .persist::<Dummy, _, _>(&1, &id, None, &binarize::serde_json::to_bytes)
-            .await?;
+            .persist::<Dummy, _, _>(&1, &id, None, None, &binarize::serde_json::to_bytes)
+            .await?
+            .seq_no;
         assert!(last_seq_no.get() == 1);
 
         evt_log
-            .persist::<Dummy, _, _>(&2, &id, Some(last_seq_no), &binarize::serde_json::to_bytes)
+            .persist::<Dummy, _, _>(
+                &2,
+                &id,
+                Some(last_seq_no),
+                None,
+                &binarize::serde_json::to_bytes,
+            )
             .await?;
 
         let result = evt_log
-            .persist::<Dummy, _, _>(&3, &id, Some(last_seq_no), &binarize::serde_json::to_bytes)
+            .persist::<Dummy, _, _>(
+                &3,
+                &id,
+                Some(last_seq_no),
+                None,
+                &binarize::serde_json::to_bytes,
+            )
             .await;
         assert!(result.is_err());
 
-        evt_log
+        let persisted = evt_log
             .persist::<Dummy, _, _>(
                 &3,
                 &id,
                 Some(last_seq_no.checked_add(1).expect("overflow")),
+                None,
                 &binarize::serde_json::to_bytes,
             )
             .await?;
 
+        // The events by type are numbered by their positions, which are not dense.
         let last_seq_no = evt_log.last_seq_no::<Dummy>(&id).await?;
         assert_eq!(last_seq_no, Some(3.try_into()?));
+        assert_eq!(persisted.seq_no, 3.try_into()?);
+        let last_position = evt_log.last_seq_no_by_type::<Dummy>().await?;
+        assert_eq!(last_position, Some(persisted.position));
 
         let evts = evt_log
             .evts_by_id::<Dummy, _, _>(&id, 2.try_into()?, binarize::serde_json::from_bytes)
@@ -510,20 +918,158 @@ mod tests {
 
         let last_seq_no = evt_log
             .clone()
-            .persist::<Dummy, _, _>(&4, &id, last_seq_no, &binarize::serde_json::to_bytes)
-            .await?;
-        evt_log
+            .persist::<Dummy, _, _>(&4, &id, last_seq_no, None, &binarize::serde_json::to_bytes)
+            .await?
+            .seq_no;
+        let persisted = evt_log
             .clone()
-            .persist::<Dummy, _, _>(&5, &id, Some(last_seq_no), &binarize::serde_json::to_bytes)
+            .persist::<Dummy, _, _>(
+                &5,
+                &id,
+                Some(last_seq_no),
+                Some("key-5"),
+                &binarize::serde_json::to_bytes,
+            )
             .await?;
         let last_seq_no = evt_log.last_seq_no::<Dummy>(&id).await?;
         assert_eq!(last_seq_no, Some(5.try_into()?));
 
-        let sum = evts
+        let evts = evts.take(5).try_collect::<Vec<_>>().await?;
+        assert_eq!(
+            evts.iter().map(|(_, n)| *n).collect::<Vec<_>>(),
+            vec![1, 2, 3, 4, 5]
+        );
+        assert_eq!(evts[4].0, persisted.position);
+
+        // Events by type in slots are the ones of the entities in these slots, for the others only
+        // the last position is passed.
+        let evts = evt_log
+            .evts_by_type_in_slots::<Dummy, _, _>(
+                &(0..SLOTS).collect::<Vec<_>>(),
+                NonZeroU64::MIN,
+                binarize::serde_json::from_bytes,
+            )
+            .await?
+            .expect("events by type in slots")
             .take(5)
-            .try_fold(0u32, |acc, (_, n)| future::ready(Ok(acc + n)))
+            .try_collect::<Vec<_>>()
+            .await?;
+        let slot = evts[0].1.map(|(slot, _)| slot).expect("event");
+        assert!(evts
+            .iter()
+            .all(|(_, evt)| evt.is_some_and(|(n, _)| n == slot)));
+        assert_eq!(evts[4].0, persisted.position);
+        let evts = evt_log
+            .evts_by_type_in_slots::<Dummy, _, _>(
+                &(0..SLOTS).filter(|n| *n != slot).collect::<Vec<_>>(),
+                NonZeroU64::MIN,
+                binarize::serde_json::from_bytes,
+            )
+            .await?
+            .expect("events by type in slots")
+            .take(1)
+            .try_collect::<Vec<_>>()
+            .await?;
+        assert_eq!(evts, vec![(persisted.position, None)]);
+
+        // Idempotency keys are stored alongside the events.
+        let keys = evt_log
+            .idempotency_keys::<Dummy>(&id, 4.try_into()?)
+            .await?
+            .try_collect::<Vec<_>>()
+            .await?;
+        assert_eq!(keys, vec![(persisted, "key-5".to_string())]);
+
+        // Events by tag are numbered by their positions.
+        let evts = evt_log
+            .evts_by_tag::<Dummy, _, _>("even", NonZeroU64::MIN, binarize::serde_json::from_bytes)
+            .await?
+            .take(2)
+            .try_collect::<Vec<_>>()
+            .await?;
+        assert_eq!(evts.iter().map(|(_, n)| *n).collect::<Vec<_>>(), vec![2, 4]);
+        assert!(evts[0].0 < evts[1].0);
+        let last_seq_no = evt_log.last_seq_no_by_tag::<Dummy>("even").await?;
+        assert_eq!(last_seq_no, Some(evts[1].0));
+        let last_seq_no = evt_log.last_seq_no_by_tag::<Dummy>("odd").await?;
+        assert_eq!(last_seq_no, None);
+
+        // Current events end with the last event at query time or the upper bound.
+        let evts = evt_log
+            .current_evts_by_id::<Dummy, _, _>(
+                &id,
+                EvtsQuery::range(2.try_into()?..=4.try_into()?),
+                binarize::serde_json::from_bytes,
+            )
+            .await?
+            .try_collect::<Vec<_>>()
+            .await?;
+        assert_eq!(
+            evts,
+            vec![(2.try_into()?, 2), (3.try_into()?, 3), (4.try_into()?, 4)]
+        );
+        let evts = evt_log
+            .current_evts_by_id::<Dummy, _, _>(
+                &id,
+                EvtsQuery::default().with_limit(2).reversed(),
+                binarize::serde_json::from_bytes,
+            )
+            .await?
+            .try_collect::<Vec<_>>()
+            .await?;
+        assert_eq!(evts, vec![(5.try_into()?, 5), (4.try_into()?, 4)]);
+        let evts = evt_log
+            .current_evts_by_type::<Dummy, _, _>(
+                EvtsQuery::from(persisted.position),
+                binarize::serde_json::from_bytes,
+            )
+            .await?
+            .try_collect::<Vec<_>>()
+            .await?;
+        assert_eq!(evts, vec![(persisted.position, 5)]);
+
+        // Current events by type end before the events of transactions still in progress.
+        let mut cnn = evt_log.cnn().await?;
+        let tx = cnn.transaction().await?;
+        tx.execute(
+            "INSERT INTO evts (seq_no, type, id, evt) VALUES (1, $1, $2, $3)",
+            &[
+                &Dummy::TYPE_NAME,
+                &Uuid::now_v7(),
+                &binarize::serde_json::to_bytes(&6)?.as_ref(),
+            ],
+        )
+        .await?;
+        evt_log
+            .clone()
+            .persist::<Dummy, _, _>(
+                &7,
+                &Uuid::now_v7(),
+                None,
+                None,
+                &binarize::serde_json::to_bytes,
+            )
+            .await?;
+        let evts = evt_log
+            .current_evts_by_type::<Dummy, _, _>(
+                EvtsQuery::from(persisted.position),
+                binarize::serde_json::from_bytes,
+            )
+            .await?
+            .try_collect::<Vec<_>>()
+            .await?;
+        assert_eq!(evts, vec![(persisted.position, 5)]);
+        tx.commit().await?;
+        let evts = evt_log
+            .current_evts_by_type::<Dummy, _, _>(
+                EvtsQuery::from(persisted.position),
+                binarize::serde_json::from_bytes,
+            )
+            .await?
+            .map_ok(|(_, n)| n)
+            .try_collect::<Vec<_>>()
             .await?;
-        assert_eq!(sum, 15);
+        assert_eq!(evts, vec![5, 6, 7]);
 
         Ok(())
     }
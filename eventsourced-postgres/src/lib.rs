@@ -1,10 +1,22 @@
+#![cfg_attr(docsrs, feature(doc_cfg))]
+
 //! [EvtLog](eventsourced::EvtLog) and [SnapshotStore](eventsourced::SnapshotStore) implementations
-//! based upon [PostgreSQL](https://www.postgresql.org/).
+//! based upon [PostgreSQL](https://www.postgresql.org/) and an optional transactional outbox
+//! relaying the events to [NATS](https://nats.io/).
 
 mod evt_log;
+#[cfg_attr(docsrs, doc(cfg(feature = "async-nats")))]
+#[cfg(feature = "async-nats")]
+mod outbox;
 mod snapshot_store;
 
 pub use evt_log::{Config as PostgresEvtLogConfig, PostgresEvtLog};
+#[cfg_attr(docsrs, doc(cfg(feature = "async-nats")))]
+#[cfg(feature = "async-nats")]
+pub use outbox::{
+    Config as OutboxRelayConfig, OutboxRelay, ID_HEADER, POSITION_HEADER, SEQ_NO_HEADER,
+    TYPE_HEADER,
+};
 pub use snapshot_store::{Config as PostgresSnapshotStoreConfig, PostgresSnapshotStore};
 
 use bb8_postgres::{
@@ -18,7 +30,7 @@ type CnnPool<T> = Pool<PostgresConnectionManager<T>>;
 
 type Cnn<'a, T> = PooledConnection<'a, PostgresConnectionManager<T>>;
 
-/// Errors from the [PostgresEvtLog] or [PostgresSnapshotStore].
+/// Errors from the [PostgresEvtLog], [PostgresSnapshotStore] or outbox relay.
 #[derive(Debug, Error)]
 pub enum Error {
     /// Postgres error.
@@ -44,4 +56,12 @@ pub enum Error {
     /// Sequence number must not be zero.
     #[error("invalid last sequence number: {0:?} {1:?}")]
     InvalidLastNonZeroU64(Option<NonZeroU64>, Option<NonZeroU64>),
+
+    /// NATS error.
+    #[cfg(feature = "async-nats")]
+    #[error("NATS error: {0}")]
+    Nats(
+        String,
+        #[source] Box<dyn std::error::Error + Send + Sync + 'static>,
+    ),
 }
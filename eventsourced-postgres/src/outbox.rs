@@ -0,0 +1,408 @@
+//! A relay of the events of a [PostgresEvtLog](crate::PostgresEvtLog) to
+//! [NATS](https://nats.io/) JetStream, i.e. a transactional outbox.
+
+use crate::{evt_log::Config as EvtLogConfig, Cnn, CnnPool, Error};
+use async_nats::{
+    jetstream::{self, context::Publish, Context as Jetstream},
+    Client,
+};
+use bb8_postgres::{bb8::Pool, PostgresConnectionManager};
+use bytes::Bytes;
+use eventsourced_nats::{connect, ClientConfig, IdEncoding};
+use serde::{Deserialize, Serialize};
+use std::{
+    fmt::{self, Debug, Formatter},
+    num::NonZeroUsize,
+    time::Duration,
+};
+use tokio::time::sleep;
+use tokio_postgres::NoTls;
+use tracing::{debug, instrument};
+
+/// Header for the entity type of a relayed event.
+pub const TYPE_HEADER: &str = "EventSourced-Type";
+
+/// Header for the entity ID of a relayed event.
+pub const ID_HEADER: &str = "EventSourced-Id";
+
+/// Header for the sequence number of a relayed event.
+pub const SEQ_NO_HEADER: &str = "EventSourced-Seq-No";
+
+/// Header for the global position of a relayed event in the events table.
+pub const POSITION_HEADER: &str = "EventSourced-Position";
+
+/// A relay of the events of a [PostgresEvtLog](crate::PostgresEvtLog) to NATS JetStream.
+///
+/// The events table is tailed in commit order: events are ordered by their inserting transaction
+/// and then by their global position and only events of transactions older than any running one
+/// are relayed, such that events committed late are not skipped. Each event is published to the
+/// configured subject with its type, ID, sequence number and position as headers and with a
+/// `Nats-Msg-Id` made of its type, ID and sequence number. The ID in the subject is encoded with
+/// the configured [IdEncoding], which must match the one of a
+/// [NatsEvtLog](eventsourced_nats::NatsEvtLog) reading the relayed events; IDs which cannot be
+/// encoded fail relaying.
+///
+/// The offset of the last relayed event is saved after each batch, hence every event is published
+/// at least once; duplicates are filtered by JetStream within the duplicate window of the stream.
+#[derive(Clone)]
+pub struct OutboxRelay {
+    cnn_pool: CnnPool<NoTls>,
+    jetstream: Jetstream,
+    config: Config,
+}
+
+impl OutboxRelay {
+    #[allow(missing_docs)]
+    pub async fn new(config: Config) -> Result<Self, Error> {
+        let client = connect(&config.client).await.map_err(|error| {
+            Error::Nats("cannot connect to NATS server".to_string(), error.into())
+        })?;
+        Self::with_client(client, config).await
+    }
+
+    /// Create an [OutboxRelay] with the given client, e.g. shared with a
+    /// [NatsEvtLog](eventsourced_nats::NatsEvtLog), ignoring the client configuration.
+    pub async fn with_client(client: Client, config: Config) -> Result<Self, Error> {
+        debug!(?config, "creating OutboxRelay");
+
+        // Create connection pool.
+        let tls = NoTls;
+        let cnn_manager =
+            PostgresConnectionManager::new_from_stringlike(config.evt_log.cnn_config(), tls)
+                .map_err(|error| {
+                    Error::Postgres("cannot create connection manager".to_string(), error)
+                })?;
+        let cnn_pool = Pool::builder()
+            .build(cnn_manager)
+            .await
+            .map_err(|error| Error::Postgres("cannot create connection pool".to_string(), error))?;
+
+        let jetstream = jetstream::new(client);
+
+        // Setup tables.
+        if config.setup {
+            cnn_pool
+                .get()
+                .await
+                .map_err(Error::GetConnection)?
+                .batch_execute(
+                    &include_str!("create_outbox_offsets.sql")
+                        .replace("outbox_offsets", &config.offsets_table),
+                )
+                .await
+                .map_err(|error| Error::Postgres("cannot execute query".to_string(), error))?;
+        }
+
+        Ok(Self {
+            cnn_pool,
+            jetstream,
+            config,
+        })
+    }
+
+    /// Relay events until an error occurs, waiting for the poll interval whenever less than a
+    /// batch of events is available.
+    pub async fn run(&self) -> Result<(), Error> {
+        loop {
+            let count = self.relay().await?;
+            if count < self.config.batch_size.get() {
+                sleep(self.config.poll_interval).await;
+            }
+        }
+    }
+
+    /// Relay the next batch of events and save the offset of the last one, returning the number
+    /// of relayed events.
+    #[instrument(skip(self))]
+    pub async fn relay(&self) -> Result<usize, Error> {
+        let Config {
+            evt_log: EvtLogConfig { evts_table, .. },
+            name,
+            offsets_table,
+            subject,
+            id_encoding,
+            batch_size,
+            ..
+        } = &self.config;
+
+        let cnn = self.cnn().await?;
+
+        let (tx_id, position) = cnn
+            .query_opt(
+                &format!("SELECT tx_id, position FROM {offsets_table} WHERE name = $1"),
+                &[name],
+            )
+            .await
+            .map_err(|error| Error::Postgres("cannot execute query".to_string(), error))?
+            .map(|row| (row.get::<_, i64>(0), row.get::<_, i64>(1)))
+            .unwrap_or_default();
+
+        let rows = cnn
+            .query(
+                &format!(
+                    "SELECT tx_id, position, type, id::text, seq_no, evt FROM {evts_table}
+                     WHERE (tx_id, position) > ($1, $2)
+                     AND tx_id < txid_snapshot_xmin(txid_current_snapshot())
+                     ORDER BY tx_id, position
+                     LIMIT $3"
+                ),
+                &[&tx_id, &position, &(batch_size.get() as i64)],
+            )
+            .await
+            .map_err(|error| Error::Postgres("cannot execute query".to_string(), error))?;
+        let Some(last) = rows.last() else {
+            return Ok(0);
+        };
+
+        // Publish all events of the batch before awaiting the ACKs.
+        let mut acks = Vec::with_capacity(rows.len());
+        for row in &rows {
+            let position = row.get::<_, i64>(1).to_string();
+            let r#type = row.get::<_, &str>(2);
+            let id = row.get::<_, &str>(3);
+            let seq_no = row.get::<_, i64>(4).to_string();
+            let evt = Bytes::copy_from_slice(row.get::<_, &[u8]>(5));
+
+            let publish = Publish::build()
+                .payload(evt)
+                .message_id(format!("{type}.{id}.{seq_no}"))
+                .header(TYPE_HEADER, r#type)
+                .header(ID_HEADER, id)
+                .header(SEQ_NO_HEADER, seq_no.as_str())
+                .header(POSITION_HEADER, position.as_str());
+            let encoded_id = id_encoding.encode(&id).map_err(|error| {
+                Error::Nats(format!("cannot encode ID {id} for subject"), error.into())
+            })?;
+            let subject = subject
+                .replace("{type}", r#type)
+                .replace("{id}", &encoded_id);
+            let ack = self
+                .jetstream
+                .send_publish(subject, publish)
+                .await
+                .map_err(|error| Error::Nats("cannot publish event".to_string(), error.into()))?;
+            acks.push(ack);
+        }
+        for ack in acks {
+            ack.await.map_err(|error| {
+                Error::Nats(
+                    "cannot get ACK for published event".to_string(),
+                    error.into(),
+                )
+            })?;
+        }
+
+        let tx_id = last.get::<_, i64>(0);
+        let position = last.get::<_, i64>(1);
+        cnn.execute(
+            &format!(
+                "INSERT INTO {offsets_table} (name, tx_id, position) VALUES ($1, $2, $3)
+                 ON CONFLICT (name) DO UPDATE
+                 SET tx_id = EXCLUDED.tx_id, position = EXCLUDED.position"
+            ),
+            &[name, &tx_id, &position],
+        )
+        .await
+        .map_err(|error| Error::Postgres("cannot execute query".to_string(), error))?;
+        debug!(name, count = rows.len(), tx_id, position, "relayed events");
+
+        Ok(rows.len())
+    }
+
+    async fn cnn(&self) -> Result<Cnn<'_, NoTls>, Error> {
+        self.cnn_pool.get().await.map_err(Error::GetConnection)
+    }
+}
+
+impl Debug for OutboxRelay {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.debug_struct("OutboxRelay")
+            .field("name", &self.config.name)
+            .field("subject", &self.config.subject)
+            .finish()
+    }
+}
+
+/// Configuration for the [OutboxRelay].
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct Config {
+    /// Connection and events table of the [PostgresEvtLog](crate::PostgresEvtLog), which must
+    /// have set up the events table.
+    pub evt_log: EvtLogConfig,
+
+    /// Name of the relay, under which its offset is saved.
+    #[serde(default = "name_default")]
+    pub name: String,
+
+    #[serde(default = "offsets_table_default")]
+    pub offsets_table: String,
+
+    #[serde(flatten)]
+    pub client: ClientConfig,
+
+    /// Subject to publish events to, where `{type}` and `{id}` are replaced by the entity type
+    /// and the encoded entity ID of the respective event.
+    #[serde(default = "subject_default")]
+    pub subject: String,
+
+    /// Encoding of the entity IDs in the subjects, must match the one of the
+    /// [NatsEvtLog](eventsourced_nats::NatsEvtLog).
+    #[serde(default)]
+    pub id_encoding: IdEncoding,
+
+    #[serde(default = "batch_size_default")]
+    pub batch_size: NonZeroUsize,
+
+    #[serde(default = "poll_interval_default", with = "humantime_serde")]
+    pub poll_interval: Duration,
+
+    #[serde(default)]
+    pub setup: bool,
+}
+
+impl Default for Config {
+    /// Default values suitable for local testing only.
+    fn default() -> Self {
+        Self {
+            evt_log: EvtLogConfig::default(),
+            name: name_default(),
+            offsets_table: offsets_table_default(),
+            client: ClientConfig::default(),
+            subject: subject_default(),
+            id_encoding: IdEncoding::default(),
+            batch_size: batch_size_default(),
+            poll_interval: poll_interval_default(),
+            setup: false,
+        }
+    }
+}
+
+fn name_default() -> String {
+    "outbox".to_string()
+}
+
+fn offsets_table_default() -> String {
+    "outbox_offsets".to_string()
+}
+
+fn subject_default() -> String {
+    "evts.{type}.{id}".to_string()
+}
+
+const fn batch_size_default() -> NonZeroUsize {
+    NonZeroUsize::new(100).expect("100 is not zero")
+}
+
+const fn poll_interval_default() -> Duration {
+    Duration::from_secs(1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{PostgresEvtLog, PostgresEvtLogConfig};
+    use async_nats::jetstream::stream;
+    use eventsourced::{binarize, EventSourced, EvtLog};
+    use std::{convert::Infallible, error::Error as StdError};
+    use testcontainers::{clients::Cli, core::WaitFor, GenericImage};
+    use testcontainers_modules::postgres::Postgres;
+    use uuid::Uuid;
+
+    #[derive(Debug)]
+    struct Dummy;
+
+    impl EventSourced for Dummy {
+        type Id = Uuid;
+        type Cmd = ();
+        type Evt = u32;
+        type State = u64;
+        type Error = Infallible;
+
+        const TYPE_NAME: &'static str = "dummy";
+
+        fn handle_cmd(
+            _id: &Self::Id,
+            _state: &Self::State,
+            _cmd: Self::Cmd,
+        ) -> Result<Self::Evt, Self::Error> {
+            todo!()
+        }
+
+        fn handle_evt(_state: Self::State, _evt: Self::Evt) -> Self::State {
+            todo!()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_outbox_relay() -> Result<(), Box<dyn StdError + Send + Sync>> {
+        let client = Cli::default();
+        let postgres = client.run(Postgres::default().with_host_auth());
+        let nats_image = GenericImage::new("nats", "2.10.9")
+            .with_wait_for(WaitFor::message_on_stderr("Server is ready"));
+        let nats = client.run((nats_image, vec!["-js".to_string()]));
+
+        let evt_log_config = PostgresEvtLogConfig {
+            port: postgres.get_host_port_ipv4(5432),
+            setup: true,
+            ..Default::default()
+        };
+        let mut evt_log = PostgresEvtLog::<Uuid>::new(evt_log_config.clone()).await?;
+        let id = Uuid::now_v7();
+        let mut last_seq_no = None;
+        for evt in 1..=3 {
+            let seq_no = evt_log
+                .persist::<Dummy, _, _>(
+                    &evt,
+                    &id,
+                    last_seq_no,
+                    None,
+                    &binarize::serde_json::to_bytes,
+                )
+                .await?
+                .seq_no;
+            last_seq_no = Some(seq_no);
+        }
+
+        let server_addr = format!("localhost:{}", nats.get_host_port_ipv4(4222));
+        let client = async_nats::connect(&server_addr).await?;
+        let jetstream = jetstream::new(client.clone());
+        let mut stream = jetstream
+            .create_stream(stream::Config {
+                name: "evts".to_string(),
+                subjects: vec!["evts.>".to_string()],
+                ..Default::default()
+            })
+            .await?;
+
+        let config = Config {
+            evt_log: evt_log_config,
+            id_encoding: IdEncoding::Base32,
+            setup: true,
+            ..Default::default()
+        };
+        let relay = OutboxRelay::with_client(client, config).await?;
+
+        assert_eq!(relay.relay().await?, 3);
+        assert_eq!(relay.relay().await?, 0);
+        assert_eq!(stream.info().await?.state.messages, 3);
+
+        let msg = async_nats::Message::try_from(stream.get_raw_message(1).await?)?;
+        let encoded_id = IdEncoding::Base32.encode(&id)?;
+        assert_eq!(msg.subject.as_str(), format!("evts.dummy.{encoded_id}"));
+        let headers = msg.headers.expect("headers");
+        assert_eq!(headers.get(TYPE_HEADER).map(|h| h.as_str()), Some("dummy"));
+        assert_eq!(headers.get(SEQ_NO_HEADER).map(|h| h.as_str()), Some("1"));
+
+        // Relaying again from the start publishes duplicates, which are filtered.
+        relay
+            .cnn()
+            .await?
+            .execute("DELETE FROM outbox_offsets", &[])
+            .await?;
+        assert_eq!(relay.relay().await?, 3);
+        assert_eq!(stream.info().await?.state.messages, 3);
+
+        Ok(())
+    }
+}
@@ -58,7 +58,7 @@ impl<I> PostgresSnapshotStore<I> {
         })
     }
 
-    async fn cnn(&self) -> Result<Cnn<NoTls>, Error> {
+    async fn cnn(&self) -> Result<Cnn<'_, NoTls>, Error> {
         self.cnn_pool.get().await.map_err(Error::GetConnection)
     }
 }
@@ -1,2 +1,7 @@
 CREATE TABLE
-  IF NOT EXISTS projection (name text PRIMARY KEY, seq_no bigint);
\ No newline at end of file
+  IF NOT EXISTS projection (
+    name text,
+    source text,
+    seq_no bigint,
+    PRIMARY KEY (name, source)
+  );
\ No newline at end of file
@@ -0,0 +1,8 @@
+CREATE TABLE
+  IF NOT EXISTS projection_dead_letter (
+    name text,
+    source text,
+    seq_no bigint,
+    error text NOT NULL,
+    PRIMARY KEY (name, source, seq_no)
+  );
@@ -0,0 +1,7 @@
+CREATE TABLE
+  IF NOT EXISTS projection_lease (
+    name text,
+    holder text NOT NULL,
+    expires_at timestamptz NOT NULL,
+    PRIMARY KEY (name)
+  );
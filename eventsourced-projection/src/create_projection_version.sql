@@ -0,0 +1,6 @@
+CREATE TABLE
+  IF NOT EXISTS projection_version (
+    name text,
+    version bigint NOT NULL,
+    PRIMARY KEY (name)
+  );
\ No newline at end of file
@@ -0,0 +1,81 @@
+use std::{convert::Infallible, error::Error as StdError, future::Future, time::Duration};
+
+/// Leader election for a [Projection](crate::Projection) created by more than one replica of a
+/// service, e.g. on behalf of horizontal scaling: only the replica holding the [Lease] of the
+/// projection runs it, the others keep trying to acquire it.
+///
+/// The lease is renewed every `heartbeat` and expires after `ttl`, hence if the replica holding it
+/// dies, another one takes over within `ttl` plus `heartbeat`; a stopped replica releases its lease
+/// right away. A replica which cannot renew its lease stops running the projection once it has
+/// expired, which requires `heartbeat` to be well below `ttl`.
+#[derive(Debug, Clone)]
+pub struct LeaderElection<L> {
+    pub lease: L,
+    pub holder: String,
+    pub ttl: Duration,
+    pub heartbeat: Duration,
+}
+
+impl<L> LeaderElection<L> {
+    /// Create a [LeaderElection] using the given [Lease] on behalf of the given holder, which must
+    /// be unique per replica, with a `ttl` of ten seconds and a `heartbeat` of three seconds.
+    pub fn new(lease: L, holder: String) -> Self {
+        Self {
+            lease,
+            holder,
+            ttl: Duration::from_secs(10),
+            heartbeat: Duration::from_secs(3),
+        }
+    }
+}
+
+/// A lease per projection name held by at most one holder at a time, e.g. a row in a database
+/// table or an entry in a NATS KV bucket.
+pub trait Lease: Clone + Send + Sync + 'static {
+    type Error: StdError + Send + Sync + 'static;
+
+    /// Initialize this lease, e.g. create the table for the leases.
+    fn init(&self) -> impl Future<Output = Result<(), Self::Error>> + Send;
+
+    /// Acquire the lease of the projection with the given name for the given holder or renew it
+    /// if already held by that holder, in both cases until the given time to live has elapsed.
+    /// Returns whether the lease is held by the given holder.
+    fn acquire(
+        &self,
+        name: &str,
+        holder: &str,
+        ttl: Duration,
+    ) -> impl Future<Output = Result<bool, Self::Error>> + Send;
+
+    /// Release the lease of the projection with the given name if held by the given holder.
+    fn release(
+        &self,
+        name: &str,
+        holder: &str,
+    ) -> impl Future<Output = Result<(), Self::Error>> + Send;
+}
+
+/// [Lease] for projections without [LeaderElection], which cannot be instantiated.
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum NoLease {}
+
+impl Lease for NoLease {
+    type Error = Infallible;
+
+    async fn init(&self) -> Result<(), Self::Error> {
+        match *self {}
+    }
+
+    async fn acquire(
+        &self,
+        _name: &str,
+        _holder: &str,
+        _ttl: Duration,
+    ) -> Result<bool, Self::Error> {
+        match *self {}
+    }
+
+    async fn release(&self, _name: &str, _holder: &str) -> Result<(), Self::Error> {
+        match *self {}
+    }
+}
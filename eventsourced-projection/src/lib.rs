@@ -1 +1,285 @@
+#![cfg_attr(docsrs, feature(doc_cfg))]
+
+//! Projections for the CQRS read side: events of one or more [Source]s are handled by an
+//! [EvtHandler] within transactions of some [Target], e.g. a Postgres database.
+
+pub mod memory;
+#[cfg_attr(docsrs, doc(cfg(feature = "async-nats")))]
+#[cfg(feature = "async-nats")]
+pub mod nats;
+#[cfg_attr(docsrs, doc(cfg(feature = "async-nats")))]
+#[cfg(feature = "async-nats")]
+pub mod nats_consumer;
 pub mod postgres;
+#[cfg_attr(docsrs, doc(cfg(feature = "sqlite")))]
+#[cfg(feature = "sqlite")]
+pub mod sqlite;
+
+mod leader_election;
+mod partitioning;
+mod process_manager;
+mod projection;
+mod source;
+
+pub use leader_election::*;
+pub use partitioning::{Partitioning, PARTITION_SLOTS};
+pub use process_manager::*;
+pub use projection::*;
+pub use source::*;
+
+#[cfg(test)]
+pub mod tests {
+    use bytes::Bytes;
+    use error_ext::BoxError;
+    use eventsourced::{slot, EventSourced, EvtLog, EvtsQuery, Persisted};
+    use futures::{future, stream, Stream, StreamExt};
+    use std::{convert::Infallible, error::Error as StdError, num::NonZeroU64};
+    use thiserror::Error;
+    use uuid::Uuid;
+
+    #[derive(Debug)]
+    pub struct Dummy;
+
+    impl EventSourced for Dummy {
+        type Id = Uuid;
+        type Cmd = ();
+        type Evt = i32;
+        type State = u64;
+        type Error = Infallible;
+
+        const TYPE_NAME: &'static str = "simple";
+
+        fn handle_cmd(
+            _id: &Self::Id,
+            _state: &Self::State,
+            _cmd: Self::Cmd,
+        ) -> Result<Self::Evt, Self::Error> {
+            todo!()
+        }
+
+        fn handle_evt(_state: Self::State, _evt: Self::Evt) -> Self::State {
+            todo!()
+        }
+    }
+
+    #[derive(Debug)]
+    pub struct Other;
+
+    impl EventSourced for Other {
+        type Id = Uuid;
+        type Cmd = ();
+        type Evt = i32;
+        type State = u64;
+        type Error = Infallible;
+
+        const TYPE_NAME: &'static str = "other";
+
+        fn handle_cmd(
+            _id: &Self::Id,
+            _state: &Self::State,
+            _cmd: Self::Cmd,
+        ) -> Result<Self::Evt, Self::Error> {
+            todo!()
+        }
+
+        fn handle_evt(_state: Self::State, _evt: Self::Evt) -> Self::State {
+            todo!()
+        }
+    }
+
+    #[derive(Debug, Clone)]
+    pub struct TestEvtLog;
+
+    impl EvtLog for TestEvtLog {
+        type Id = Uuid;
+        type Error = TestEvtLogError;
+
+        async fn persist<E, ToBytes, ToBytesError>(
+            &mut self,
+            _evt: &E::Evt,
+            _id: &Self::Id,
+            last_seq_no: Option<NonZeroU64>,
+            _idempotency_key: Option<&str>,
+            _to_bytes: &ToBytes,
+        ) -> Result<Persisted, Self::Error>
+        where
+            E: EventSourced,
+            ToBytes: Fn(&E::Evt) -> Result<Bytes, ToBytesError> + Sync,
+            ToBytesError: StdError + Send + Sync + 'static,
+        {
+            let seq_no = last_seq_no.unwrap_or(NonZeroU64::MIN);
+            Ok(Persisted {
+                seq_no,
+                position: seq_no,
+            })
+        }
+
+        async fn last_seq_no<E>(
+            &self,
+            _entity_id: &Self::Id,
+        ) -> Result<Option<NonZeroU64>, Self::Error>
+        where
+            E: EventSourced,
+        {
+            Ok(Some(42.try_into().unwrap()))
+        }
+
+        async fn last_seq_no_by_type<E>(&self) -> Result<Option<NonZeroU64>, Self::Error>
+        where
+            E: EventSourced,
+        {
+            Ok(Some(100.try_into().unwrap()))
+        }
+
+        async fn last_seq_no_by_tag<E>(&self, _tag: &str) -> Result<Option<NonZeroU64>, Self::Error>
+        where
+            E: EventSourced,
+        {
+            Ok(Some(100.try_into().unwrap()))
+        }
+
+        async fn idempotency_keys<E>(
+            &self,
+            _id: &Self::Id,
+            _seq_no: NonZeroU64,
+        ) -> Result<impl Stream<Item = Result<(Persisted, String), Self::Error>> + Send, Self::Error>
+        where
+            E: EventSourced,
+        {
+            Ok(stream::empty())
+        }
+
+        async fn evts_by_id<E, FromBytes, FromBytesError>(
+            &self,
+            _id: &Self::Id,
+            _seq_no: NonZeroU64,
+            _evt_from_bytes: FromBytes,
+        ) -> Result<impl Stream<Item = Result<(NonZeroU64, E::Evt), Self::Error>> + Send, Self::Error>
+        where
+            E: EventSourced,
+            FromBytes: Fn(Bytes) -> Result<E::Evt, FromBytesError> + Copy + Send + Sync,
+            FromBytesError: StdError + Send + Sync + 'static,
+        {
+            Ok(stream::empty())
+        }
+
+        async fn evts_by_type<E, FromBytes, FromBytesError>(
+            &self,
+            seq_no: NonZeroU64,
+            evt_from_bytes: FromBytes,
+        ) -> Result<impl Stream<Item = Result<(NonZeroU64, E::Evt), Self::Error>> + Send, Self::Error>
+        where
+            E: EventSourced,
+            FromBytes: Fn(Bytes) -> Result<E::Evt, FromBytesError> + Copy + Send + Sync,
+            FromBytesError: StdError + Send + Sync + 'static,
+        {
+            // Like real event logs, tail newly persisted events, i.e. never end.
+            let evts = stream::iter(seq_no.get()..=100)
+                .map(move |n| {
+                    let evt = n as i64;
+                    let n = NonZeroU64::new(n).unwrap();
+                    let evt = evt_from_bytes(serde_json::to_vec(&evt).unwrap().into()).unwrap();
+                    Ok((n, evt))
+                })
+                .chain(stream::pending());
+
+            Ok(evts)
+        }
+
+        async fn evts_by_type_in_slots<E, FromBytes, FromBytesError>(
+            &self,
+            slots: &[usize],
+            seq_no: NonZeroU64,
+            evt_from_bytes: FromBytes,
+        ) -> Result<
+            Option<
+                impl Stream<Item = Result<(NonZeroU64, Option<(usize, E::Evt)>), Self::Error>> + Send,
+            >,
+            Self::Error,
+        >
+        where
+            E: EventSourced,
+            FromBytes: Fn(Bytes) -> Result<E::Evt, FromBytesError> + Copy + Send + Sync,
+            FromBytesError: StdError + Send + Sync + 'static,
+        {
+            // Event n is emitted by entity n modulo seven, with only the last sequence number
+            // passed for the events of other slots.
+            let slots = slots.to_vec();
+            let evts = stream::iter(seq_no.get()..=100)
+                .filter_map(move |n| {
+                    let slot = slot(&(n as i32 % 7).to_be_bytes());
+                    let evt = slots.contains(&slot).then(|| {
+                        let evt = n as i64;
+                        let evt = evt_from_bytes(serde_json::to_vec(&evt).unwrap().into()).unwrap();
+                        (slot, evt)
+                    });
+                    let n = NonZeroU64::new(n).unwrap();
+                    future::ready((evt.is_some() || n.get() == 100).then_some(Ok((n, evt))))
+                })
+                .chain(stream::pending());
+
+            Ok(Some(evts))
+        }
+
+        async fn evts_by_tag<E, FromBytes, FromBytesError>(
+            &self,
+            tag: &str,
+            seq_no: NonZeroU64,
+            evt_from_bytes: FromBytes,
+        ) -> Result<impl Stream<Item = Result<(NonZeroU64, E::Evt), Self::Error>> + Send, Self::Error>
+        where
+            E: EventSourced,
+            FromBytes: Fn(Bytes) -> Result<E::Evt, FromBytesError> + Copy + Send + Sync,
+            FromBytesError: StdError + Send + Sync + 'static,
+        {
+            let even = tag == "even";
+            let evts = stream::iter(seq_no.get()..=100)
+                .filter(move |n| future::ready(even && n % 2 == 0))
+                .map(move |n| {
+                    let evt = n as i64;
+                    let n = NonZeroU64::new(n).unwrap();
+                    let evt = evt_from_bytes(serde_json::to_vec(&evt).unwrap().into()).unwrap();
+                    Ok((n, evt))
+                });
+
+            Ok(evts)
+        }
+
+        async fn current_evts_by_id<E, FromBytes, FromBytesError>(
+            &self,
+            _id: &Self::Id,
+            _query: EvtsQuery,
+            _evt_from_bytes: FromBytes,
+        ) -> Result<impl Stream<Item = Result<(NonZeroU64, E::Evt), Self::Error>> + Send, Self::Error>
+        where
+            E: EventSourced,
+            FromBytes: Fn(Bytes) -> Result<E::Evt, FromBytesError> + Copy + Send + Sync,
+            FromBytesError: StdError + Send + Sync + 'static,
+        {
+            Ok(stream::empty())
+        }
+
+        async fn current_evts_by_type<E, FromBytes, FromBytesError>(
+            &self,
+            _query: EvtsQuery,
+            _evt_from_bytes: FromBytes,
+        ) -> Result<impl Stream<Item = Result<(NonZeroU64, E::Evt), Self::Error>> + Send, Self::Error>
+        where
+            E: EventSourced,
+            FromBytes: Fn(Bytes) -> Result<E::Evt, FromBytesError> + Copy + Send + Sync,
+            FromBytesError: StdError + Send + Sync + 'static,
+        {
+            Ok(stream::empty())
+        }
+    }
+
+    #[derive(Debug, Error)]
+    #[error("TestEvtLogError")]
+    pub struct TestEvtLogError(#[source] BoxError);
+
+    #[derive(Debug)]
+    pub enum MultiEvt {
+        Dummy(i32),
+        Other(i32),
+    }
+}
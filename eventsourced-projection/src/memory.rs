@@ -0,0 +1,933 @@
+//! In-memory [Target] of projections, e.g. for tests or for read models which are rebuilt on each
+//! start: tables are ordered maps from string keys to values.
+//!
+//! Transactions operate on a copy of all data which replaces it when committed. As transactions
+//! are serialized, event handlers must not access the [MemoryTarget] itself.
+//!
+//! When rebuilding a projection, the tables returned by [MemoryTx::table] are those of its shadow
+//! copy. The shadow tables are swapped in atomically.
+//!
+//! A [MemoryTarget] also is a [Lease] for [LeaderElection](crate::LeaderElection) among
+//! projections sharing it, e.g. to test leader election.
+
+use crate::{DeadLetter, Lease, Target};
+use std::{collections::BTreeMap, convert::Infallible, num::NonZeroU64, sync::Arc, time::Duration};
+use tokio::{
+    sync::{Mutex, OwnedMutexGuard},
+    time::Instant,
+};
+
+/// A table of a [MemoryTarget].
+pub type Table<V> = BTreeMap<String, V>;
+
+/// In-memory [Target] with tables of values of type `V`.
+#[derive(Debug)]
+pub struct MemoryTarget<V> {
+    data: Arc<Mutex<Data<V>>>,
+}
+
+impl<V> MemoryTarget<V> {
+    pub fn new() -> Self {
+        Self {
+            data: Arc::new(Mutex::new(Data::default())),
+        }
+    }
+
+    /// Get a copy of the table with the given name; empty if it does not exist.
+    pub async fn table(&self, name: &str) -> Table<V>
+    where
+        V: Clone,
+    {
+        self.data
+            .lock()
+            .await
+            .tables
+            .get(name)
+            .cloned()
+            .unwrap_or_default()
+    }
+}
+
+impl<V> Default for MemoryTarget<V> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<V> Clone for MemoryTarget<V> {
+    fn clone(&self) -> Self {
+        Self {
+            data: self.data.clone(),
+        }
+    }
+}
+
+impl<V> Target for MemoryTarget<V>
+where
+    V: Clone + Send + Sync + 'static,
+{
+    type Tx = MemoryTx<V>;
+
+    type Error = Infallible;
+
+    async fn init(&self) -> Result<(), Self::Error> {
+        Ok(())
+    }
+
+    async fn load_seq_nos(
+        &self,
+        name: &str,
+        shadow: bool,
+    ) -> Result<BTreeMap<String, NonZeroU64>, Self::Error> {
+        let data = self.data.lock().await;
+        let seq_nos = if shadow {
+            data.shadows.get(name).map(|shadow| &shadow.seq_nos)
+        } else {
+            data.seq_nos.get(name)
+        };
+        Ok(seq_nos.cloned().unwrap_or_default())
+    }
+
+    async fn load_version(&self, name: &str) -> Result<Option<u32>, Self::Error> {
+        Ok(self.data.lock().await.versions.get(name).copied())
+    }
+
+    async fn begin(&self, name: &str, shadow: bool) -> Result<Self::Tx, Self::Error> {
+        let data = self.data.clone().lock_owned().await;
+        let working = data.clone();
+        let shadow = shadow.then(|| name.to_string());
+        Ok(MemoryTx {
+            data,
+            working,
+            shadow,
+        })
+    }
+
+    async fn save_seq_no(
+        &self,
+        tx: &mut Self::Tx,
+        name: &str,
+        shadow: bool,
+        source: &str,
+        seq_no: NonZeroU64,
+    ) -> Result<(), Self::Error> {
+        let seq_nos = if shadow {
+            &mut tx
+                .working
+                .shadows
+                .entry(name.to_string())
+                .or_default()
+                .seq_nos
+        } else {
+            tx.working.seq_nos.entry(name.to_string()).or_default()
+        };
+        seq_nos.insert(source.to_string(), seq_no);
+        Ok(())
+    }
+
+    async fn save_version(
+        &self,
+        tx: &mut Self::Tx,
+        name: &str,
+        version: u32,
+    ) -> Result<(), Self::Error> {
+        tx.working.versions.insert(name.to_string(), version);
+        Ok(())
+    }
+
+    async fn save_dead_letter(
+        &self,
+        tx: &mut Self::Tx,
+        name: &str,
+        dead_letter: &DeadLetter,
+    ) -> Result<(), Self::Error> {
+        let DeadLetter {
+            source,
+            seq_no,
+            error,
+        } = dead_letter;
+        tx.working
+            .dead_letters
+            .entry(name.to_string())
+            .or_default()
+            .insert((source.clone(), *seq_no), error.clone());
+        Ok(())
+    }
+
+    async fn delete_dead_letter(
+        &self,
+        tx: &mut Self::Tx,
+        name: &str,
+        source: &str,
+        seq_no: NonZeroU64,
+    ) -> Result<(), Self::Error> {
+        if let Some(dead_letters) = tx.working.dead_letters.get_mut(name) {
+            dead_letters.remove(&(source.to_string(), seq_no));
+        }
+        Ok(())
+    }
+
+    async fn load_dead_letters(&self, name: &str) -> Result<Vec<DeadLetter>, Self::Error> {
+        let data = self.data.lock().await;
+        let dead_letters = data
+            .dead_letters
+            .get(name)
+            .into_iter()
+            .flatten()
+            .map(|((source, seq_no), error)| DeadLetter {
+                source: source.clone(),
+                seq_no: *seq_no,
+                error: error.clone(),
+            })
+            .collect();
+        Ok(dead_letters)
+    }
+
+    async fn commit(&self, tx: Self::Tx) -> Result<(), Self::Error> {
+        let MemoryTx {
+            mut data, working, ..
+        } = tx;
+        *data = working;
+        Ok(())
+    }
+
+    async fn create_shadow(&self, name: &str, _tables: &[&str]) -> Result<(), Self::Error> {
+        let mut data = self.data.lock().await;
+        data.shadows.insert(name.to_string(), Shadow::default());
+        Ok(())
+    }
+
+    async fn create_shadow_tables(&self, name: &str, tables: &[&str]) -> Result<(), Self::Error> {
+        let mut data = self.data.lock().await;
+        let shadow = data.shadows.entry(name.to_string()).or_default();
+        for table in tables {
+            shadow.tables.entry(table.to_string()).or_default();
+        }
+        Ok(())
+    }
+
+    async fn swap_shadow(
+        &self,
+        name: &str,
+        tables: &[&str],
+        version: u32,
+    ) -> Result<(), Self::Error> {
+        let mut data = self.data.lock().await;
+        let mut shadow = data.shadows.remove(name).unwrap_or_default();
+        for table in tables {
+            let shadow_table = shadow.tables.remove(*table).unwrap_or_default();
+            data.tables.insert(table.to_string(), shadow_table);
+        }
+        data.seq_nos.insert(name.to_string(), shadow.seq_nos);
+        data.versions.insert(name.to_string(), version);
+        Ok(())
+    }
+}
+
+impl<V> Lease for MemoryTarget<V>
+where
+    V: Send + 'static,
+{
+    type Error = Infallible;
+
+    async fn init(&self) -> Result<(), Self::Error> {
+        Ok(())
+    }
+
+    async fn acquire(&self, name: &str, holder: &str, ttl: Duration) -> Result<bool, Self::Error> {
+        let mut data = self.data.lock().await;
+        let now = Instant::now();
+        match data.leases.get(name) {
+            Some((current, expires_at)) if current != holder && *expires_at > now => Ok(false),
+            _ => {
+                data.leases
+                    .insert(name.to_string(), (holder.to_string(), now + ttl));
+                Ok(true)
+            }
+        }
+    }
+
+    async fn release(&self, name: &str, holder: &str) -> Result<(), Self::Error> {
+        let mut data = self.data.lock().await;
+        if data
+            .leases
+            .get(name)
+            .is_some_and(|(current, _)| current == holder)
+        {
+            data.leases.remove(name);
+        }
+        Ok(())
+    }
+}
+
+/// Transaction of a [MemoryTarget].
+#[derive(Debug)]
+pub struct MemoryTx<V> {
+    data: OwnedMutexGuard<Data<V>>,
+    working: Data<V>,
+    shadow: Option<String>,
+}
+
+impl<V> MemoryTx<V> {
+    /// The table with the given name, created if it does not exist; when rebuilding a projection,
+    /// the one of its shadow copy.
+    pub fn table(&mut self, name: &str) -> &mut Table<V> {
+        let tables = match &self.shadow {
+            Some(projection) => {
+                &mut self
+                    .working
+                    .shadows
+                    .entry(projection.clone())
+                    .or_default()
+                    .tables
+            }
+            None => &mut self.working.tables,
+        };
+        tables.entry(name.to_string()).or_default()
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Data<V> {
+    tables: BTreeMap<String, Table<V>>,
+    seq_nos: BTreeMap<String, BTreeMap<String, NonZeroU64>>,
+    versions: BTreeMap<String, u32>,
+    shadows: BTreeMap<String, Shadow<V>>,
+    leases: BTreeMap<String, (String, Instant)>,
+    dead_letters: BTreeMap<String, BTreeMap<(String, NonZeroU64), String>>,
+}
+
+impl<V> Default for Data<V> {
+    fn default() -> Self {
+        Self {
+            tables: BTreeMap::new(),
+            seq_nos: BTreeMap::new(),
+            versions: BTreeMap::new(),
+            shadows: BTreeMap::new(),
+            leases: BTreeMap::new(),
+            dead_letters: BTreeMap::new(),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Shadow<V> {
+    tables: BTreeMap<String, Table<V>>,
+    seq_nos: BTreeMap<String, NonZeroU64>,
+}
+
+impl<V> Default for Shadow<V> {
+    fn default() -> Self {
+        Self {
+            tables: BTreeMap::new(),
+            seq_nos: BTreeMap::new(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        partitioning::slot_key,
+        tests::{Dummy, TestEvtLog},
+        AwaitPositionError, Backoff, Batching, ErrorStrategy, EvtHandler, LeaderElection,
+        Partitioning, Projection, Source, PARTITION_SLOTS,
+    };
+    use error_ext::BoxError;
+    use eventsourced::{binarize::serde_json::SerdeJsonBinarize, slot, EventSourced};
+    use std::{
+        num::NonZeroUsize,
+        sync::atomic::{AtomicBool, Ordering},
+        time::Duration,
+    };
+    use thiserror::Error;
+    use tokio::time::sleep;
+
+    #[derive(Clone)]
+    struct TestHandler;
+
+    impl EvtHandler for TestHandler {
+        type Evt = i32;
+
+        type Error = Infallible;
+
+        type Target = MemoryTarget<i64>;
+
+        const TABLES: &'static [&'static str] = &["test"];
+
+        async fn handle_evt(
+            &self,
+            evt: Self::Evt,
+            tx: &mut MemoryTx<i64>,
+        ) -> Result<(), Infallible> {
+            tx.table("test").insert(evt.to_string(), evt as i64);
+            Ok(())
+        }
+    }
+
+    #[derive(Clone)]
+    struct TestHandlerV1;
+
+    impl EvtHandler for TestHandlerV1 {
+        type Evt = i32;
+
+        type Error = Infallible;
+
+        type Target = MemoryTarget<i64>;
+
+        const VERSION: u32 = 1;
+
+        const TABLES: &'static [&'static str] = &["test"];
+
+        async fn handle_evt(
+            &self,
+            evt: Self::Evt,
+            tx: &mut MemoryTx<i64>,
+        ) -> Result<(), Infallible> {
+            tx.table("test").insert(evt.to_string(), 2 * evt as i64);
+            Ok(())
+        }
+    }
+
+    /// Fails for multiples of ten unless fixed.
+    #[derive(Clone)]
+    struct FailingTestHandler(Arc<AtomicBool>);
+
+    impl EvtHandler for FailingTestHandler {
+        type Evt = i32;
+
+        type Error = FailingTestHandlerError;
+
+        type Target = MemoryTarget<i64>;
+
+        async fn handle_evt(
+            &self,
+            evt: Self::Evt,
+            tx: &mut MemoryTx<i64>,
+        ) -> Result<(), FailingTestHandlerError> {
+            if evt % 10 == 0 && !self.0.load(Ordering::SeqCst) {
+                return Err(FailingTestHandlerError(evt));
+            }
+            tx.table("test").insert(evt.to_string(), evt as i64);
+            Ok(())
+        }
+    }
+
+    #[derive(Debug, Error)]
+    #[error("cannot handle {0}")]
+    struct FailingTestHandlerError(i32);
+
+    /// Counts how often each event has been handled and fails if the events of an entity, i.e.
+    /// with the same remainder modulo seven, are not handled in order.
+    #[derive(Clone)]
+    struct OrderingTestHandler;
+
+    impl EvtHandler for OrderingTestHandler {
+        type Evt = i32;
+
+        type Error = FailingTestHandlerError;
+
+        type Target = MemoryTarget<i64>;
+
+        async fn handle_evt(
+            &self,
+            evt: Self::Evt,
+            tx: &mut MemoryTx<i64>,
+        ) -> Result<(), FailingTestHandlerError> {
+            let last = tx.table("last").insert((evt % 7).to_string(), evt as i64);
+            if last.is_some_and(|last| last >= evt as i64) {
+                return Err(FailingTestHandlerError(evt));
+            }
+            *tx.table("test").entry(evt.to_string()).or_default() += 1;
+            Ok(())
+        }
+    }
+
+    async fn sum(target: &MemoryTarget<i64>) -> i64 {
+        target.table("test").await.values().sum()
+    }
+
+    #[tokio::test]
+    async fn test() -> Result<(), BoxError> {
+        let target = MemoryTarget::new();
+
+        let projection = Projection::new::<Dummy, _, _, _>(
+            "test-projection".to_string(),
+            TestEvtLog,
+            SerdeJsonBinarize,
+            TestHandler,
+            ErrorStrategy::Stop,
+            Batching::default(),
+            target.clone(),
+        )
+        .await?;
+
+        projection.run().await?;
+
+        let mut state = projection.get_state().await?;
+        let max = Some(NonZeroU64::new(100).unwrap());
+        while state.seq_no(Dummy::TYPE_NAME) < max {
+            sleep(Duration::from_millis(100)).await;
+            state = projection.get_state().await?;
+        }
+        assert_eq!(sum(&target).await, 5_050);
+        assert_eq!(
+            target.load_seq_nos("test-projection", false).await?,
+            state.seq_nos().clone()
+        );
+
+        projection.stop().await?;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_rebuild() -> Result<(), BoxError> {
+        let target = MemoryTarget::new();
+
+        let projection = Projection::new::<Dummy, _, _, _>(
+            "test-projection".to_string(),
+            TestEvtLog,
+            SerdeJsonBinarize,
+            TestHandler,
+            ErrorStrategy::Stop,
+            Batching::default(),
+            target.clone(),
+        )
+        .await?;
+
+        projection.run().await?;
+
+        let mut state = projection.get_state().await?;
+        let max = Some(NonZeroU64::new(100).unwrap());
+        while state.seq_no(Dummy::TYPE_NAME) < max {
+            sleep(Duration::from_millis(100)).await;
+            state = projection.get_state().await?;
+        }
+
+        // Rebuild while running.
+        let state = projection.rebuild().await?;
+        assert!(state.rebuilding());
+        let mut state = projection.get_state().await?;
+        while state.rebuilding() {
+            sleep(Duration::from_millis(100)).await;
+            state = projection.get_state().await?;
+        }
+        assert!(state.running());
+        assert_eq!(state.seq_no(Dummy::TYPE_NAME), max);
+        assert_eq!(sum(&target).await, 5_050);
+
+        projection.stop().await?;
+
+        // A version bump triggers a rebuild.
+        let projection = Projection::new::<Dummy, _, _, _>(
+            "test-projection".to_string(),
+            TestEvtLog,
+            SerdeJsonBinarize,
+            TestHandlerV1,
+            ErrorStrategy::Stop,
+            Batching::default(),
+            target.clone(),
+        )
+        .await?;
+
+        let state = projection.run().await?;
+        assert!(state.rebuilding());
+        let mut state = projection.get_state().await?;
+        while state.rebuilding() {
+            sleep(Duration::from_millis(100)).await;
+            state = projection.get_state().await?;
+        }
+        assert_eq!(state.version(), 1);
+        assert_eq!(state.seq_no(Dummy::TYPE_NAME), max);
+        assert_eq!(sum(&target).await, 10_100);
+        assert_eq!(target.load_version("test-projection").await?, Some(1));
+
+        projection.stop().await?;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_leader_election() -> Result<(), BoxError> {
+        let target = MemoryTarget::new();
+
+        let replica = |holder: &str| {
+            let leader_election = LeaderElection {
+                ttl: Duration::from_secs(1),
+                heartbeat: Duration::from_millis(100),
+                ..LeaderElection::new(target.clone(), holder.to_string())
+            };
+            Projection::with_leader_election(
+                "test-projection".to_string(),
+                vec![Source::new::<Dummy, _, _, _>(
+                    TestEvtLog,
+                    SerdeJsonBinarize,
+                    |evt| evt,
+                )],
+                TestHandler,
+                ErrorStrategy::Stop,
+                Batching::default(),
+                Partitioning::default(),
+                target.clone(),
+                leader_election,
+            )
+        };
+        let projection_a = replica("a").await?;
+        let projection_b = replica("b").await?;
+
+        projection_a.run().await?;
+        let mut state = projection_a.get_state().await?;
+        while !state.leader() {
+            sleep(Duration::from_millis(100)).await;
+            state = projection_a.get_state().await?;
+        }
+        projection_b.run().await?;
+
+        let max = Some(NonZeroU64::new(100).unwrap());
+        while state.seq_no(Dummy::TYPE_NAME) < max {
+            sleep(Duration::from_millis(100)).await;
+            state = projection_a.get_state().await?;
+        }
+        assert_eq!(sum(&target).await, 5_050);
+
+        // Only the leader runs the projection.
+        sleep(Duration::from_millis(300)).await;
+        let state = projection_b.get_state().await?;
+        assert!(state.running());
+        assert!(!state.leader());
+        assert_eq!(state.evt_count(), 0);
+
+        // Another replica takes over when the leader stops, continuing where it has stopped.
+        let state = projection_a.stop().await?;
+        assert!(!state.leader());
+        let mut state = projection_b.get_state().await?;
+        for _ in 0..10 {
+            if state.leader() {
+                break;
+            }
+            sleep(Duration::from_millis(100)).await;
+            state = projection_b.get_state().await?;
+        }
+        assert!(state.leader());
+        assert_eq!(state.seq_no(Dummy::TYPE_NAME), max);
+        assert_eq!(state.evt_count(), 0);
+
+        projection_b.stop().await?;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_dead_letter() -> Result<(), BoxError> {
+        let target = MemoryTarget::new();
+        let fixed = Arc::new(AtomicBool::new(false));
+
+        let backoff = Backoff {
+            max_retries: 1,
+            min_delay: Duration::from_millis(1),
+            max_delay: Duration::from_millis(10),
+        };
+        let batching = Batching {
+            max_size: NonZeroUsize::new(3).unwrap(),
+            max_delay: Duration::from_millis(10),
+        };
+        let projection = Projection::new::<Dummy, _, _, _>(
+            "test-projection".to_string(),
+            TestEvtLog,
+            SerdeJsonBinarize,
+            FailingTestHandler(fixed.clone()),
+            ErrorStrategy::DeadLetter(backoff),
+            batching,
+            target.clone(),
+        )
+        .await?;
+
+        projection.run().await?;
+
+        let mut state = projection.get_state().await?;
+        let max = Some(NonZeroU64::new(100).unwrap());
+        while state.seq_no(Dummy::TYPE_NAME) < max {
+            sleep(Duration::from_millis(100)).await;
+            state = projection.get_state().await?;
+        }
+        assert_eq!(sum(&target).await, 5_050 - 550);
+
+        // Only the failing events are dead-lettered, not the others of their batches.
+        let dead_letters = target.load_dead_letters("test-projection").await?;
+        let seq_nos = dead_letters
+            .iter()
+            .map(|dead_letter| dead_letter.seq_no.get())
+            .collect::<Vec<_>>();
+        assert_eq!(seq_nos, (10..=100).step_by(10).collect::<Vec<_>>());
+        assert_eq!(dead_letters[0].source, Dummy::TYPE_NAME);
+        assert_eq!(dead_letters[0].error, "cannot handle 10");
+
+        // Replaying without a fix keeps the dead letters.
+        projection.replay_dead_letters().await?;
+        assert_eq!(target.load_dead_letters("test-projection").await?.len(), 10);
+
+        fixed.store(true, Ordering::SeqCst);
+        projection.replay_dead_letters().await?;
+        assert!(target
+            .load_dead_letters("test-projection")
+            .await?
+            .is_empty());
+        assert_eq!(sum(&target).await, 5_050);
+
+        projection.stop().await?;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_progress() -> Result<(), BoxError> {
+        let target = MemoryTarget::new();
+        let fixed = Arc::new(AtomicBool::new(false));
+
+        let projection = Projection::new::<Dummy, _, _, _>(
+            "test-projection".to_string(),
+            TestEvtLog,
+            SerdeJsonBinarize,
+            FailingTestHandler(fixed.clone()),
+            ErrorStrategy::Retry(Duration::from_millis(10)),
+            Batching::default(),
+            target.clone(),
+        )
+        .await?;
+
+        let mut state = projection.watch();
+        projection.run().await?;
+
+        // Retrying, hence not healthy.
+        let retrying = state.wait_for(|state| state.retries() > 1).await?.clone();
+        assert_eq!(retrying.seq_no(Dummy::TYPE_NAME), NonZeroU64::new(9));
+        assert!(retrying
+            .error()
+            .is_some_and(|error| error.ends_with("cannot handle 10")));
+        assert!(retrying.error_at().is_some());
+        assert!(!retrying.healthy(Duration::MAX));
+
+        // Caught up with the head after the fix.
+        fixed.store(true, Ordering::SeqCst);
+        let max = NonZeroU64::new(100);
+        let caught_up = state
+            .wait_for(|state| {
+                state.head(Dummy::TYPE_NAME) == max
+                    && state.lag(Dummy::TYPE_NAME) == 0
+                    && state.time_lag() == Duration::ZERO
+            })
+            .await?
+            .clone();
+        assert_eq!(caught_up.seq_no(Dummy::TYPE_NAME), max);
+        assert_eq!(caught_up.retries(), 0);
+        assert!(caught_up.handled_at().is_some());
+        assert!(caught_up.healthy(Duration::ZERO));
+
+        projection.stop().await?;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_await_position() -> Result<(), BoxError> {
+        let projection = Projection::new::<Dummy, _, _, _>(
+            "test-projection".to_string(),
+            TestEvtLog,
+            SerdeJsonBinarize,
+            TestHandler,
+            ErrorStrategy::Stop,
+            Batching::default(),
+            MemoryTarget::new(),
+        )
+        .await?;
+
+        let position = NonZeroU64::new(100).unwrap();
+        let result = projection
+            .await_position(Dummy::TYPE_NAME, position, Duration::from_millis(100))
+            .await;
+        assert!(matches!(result, Err(AwaitPositionError::Timeout(..))));
+
+        projection.run().await?;
+        let state = projection
+            .await_position(Dummy::TYPE_NAME, position, Duration::from_secs(5))
+            .await?;
+        assert_eq!(state.seq_no(Dummy::TYPE_NAME), Some(position));
+
+        projection.stop().await?;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_partitioning() -> Result<(), BoxError> {
+        let target = MemoryTarget::new();
+        let key = |evt: &i32| (evt % 7).to_be_bytes();
+
+        // Sequence numbers saved by two workers, with the second one ahead.
+        let mut tx = target.begin("test-projection", false).await?;
+        for slot in 0..PARTITION_SLOTS {
+            let seq_no = if slot.is_multiple_of(2) { 40 } else { 60 };
+            let seq_no = NonZeroU64::new(seq_no).unwrap();
+            let key = slot_key(Dummy::TYPE_NAME, slot);
+            target
+                .save_seq_no(&mut tx, "test-projection", false, &key, seq_no)
+                .await?;
+        }
+        target.commit(tx).await?;
+
+        let projection = Projection::with_sources(
+            "test-projection".to_string(),
+            vec![
+                Source::new::<Dummy, _, _, _>(TestEvtLog, SerdeJsonBinarize, |evt| evt)
+                    .with_partition_key(key),
+            ],
+            OrderingTestHandler,
+            ErrorStrategy::Stop,
+            Batching::default(),
+            Partitioning {
+                workers: NonZeroUsize::new(3).unwrap(),
+            },
+            target.clone(),
+        )
+        .await?;
+        let state = projection.get_state().await?;
+        assert_eq!(state.seq_no(Dummy::TYPE_NAME), NonZeroU64::new(40));
+        assert_eq!(state.worker_seq_nos().len(), 3);
+
+        // Three workers take over without handling any event twice.
+        projection.run().await?;
+        let max = NonZeroU64::new(100).unwrap();
+        let state = projection
+            .await_position(Dummy::TYPE_NAME, max, Duration::from_secs(5))
+            .await?;
+        assert!(state.error().is_none());
+        assert!(state
+            .worker_seq_nos()
+            .iter()
+            .all(|seq_nos| seq_nos.get(Dummy::TYPE_NAME) == Some(&max)));
+
+        let expected = (1..=100)
+            .filter(|evt| {
+                let seq_no = if slot(&key(evt)).is_multiple_of(2) {
+                    40
+                } else {
+                    60
+                };
+                *evt > seq_no
+            })
+            .count();
+        let handled = target.table("test").await;
+        assert_eq!(handled.len(), expected);
+        assert!(handled.values().all(|count| *count == 1));
+
+        projection.stop().await?;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_partitioning_single_worker() -> Result<(), BoxError> {
+        let target = MemoryTarget::new();
+
+        // Sequence numbers saved by two workers without partition key, with the second one ahead.
+        let mut tx = target.begin("test-projection", false).await?;
+        for slot in 0..PARTITION_SLOTS {
+            let seq_no = if slot.is_multiple_of(2) { 40 } else { 60 };
+            let seq_no = NonZeroU64::new(seq_no).unwrap();
+            let key = slot_key(Dummy::TYPE_NAME, slot);
+            target
+                .save_seq_no(&mut tx, "test-projection", false, &key, seq_no)
+                .await?;
+        }
+        target.commit(tx).await?;
+
+        let projection = Projection::with_sources(
+            "test-projection".to_string(),
+            vec![Source::new::<Dummy, _, _, _>(
+                TestEvtLog,
+                SerdeJsonBinarize,
+                |evt| evt,
+            )],
+            OrderingTestHandler,
+            ErrorStrategy::Stop,
+            Batching::default(),
+            Partitioning::default(),
+            target.clone(),
+        )
+        .await?;
+        let state = projection.get_state().await?;
+        assert_eq!(state.seq_no(Dummy::TYPE_NAME), NonZeroU64::new(40));
+
+        // A single worker takes over without handling any event twice.
+        projection.run().await?;
+        let max = NonZeroU64::new(100).unwrap();
+        let state = projection
+            .await_position(Dummy::TYPE_NAME, max, Duration::from_secs(5))
+            .await?;
+        assert!(state.error().is_none());
+
+        let expected = (1..=100)
+            .filter(|evt| {
+                let seq_no = if slot(&(evt % 7_i32).to_be_bytes()).is_multiple_of(2) {
+                    40
+                } else {
+                    60
+                };
+                *evt > seq_no
+            })
+            .count();
+        assert!(40 < expected && expected < 60);
+        let handled = target.table("test").await;
+        assert_eq!(handled.len(), expected);
+        assert!(handled.values().all(|count| *count == 1));
+
+        projection.stop().await?;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_partitioning_by_entity() -> Result<(), BoxError> {
+        let target = MemoryTarget::new();
+
+        // Without partition key, the event log only passes the events of their slots to the
+        // workers, yet the sequence numbers of all of them.
+        let projection = Projection::with_sources(
+            "test-projection".to_string(),
+            vec![Source::new::<Dummy, _, _, _>(
+                TestEvtLog,
+                SerdeJsonBinarize,
+                |evt| evt,
+            )],
+            OrderingTestHandler,
+            ErrorStrategy::Stop,
+            Batching::default(),
+            Partitioning {
+                workers: NonZeroUsize::new(3).unwrap(),
+            },
+            target.clone(),
+        )
+        .await?;
+
+        projection.run().await?;
+        let max = NonZeroU64::new(100).unwrap();
+        let state = projection
+            .await_position(Dummy::TYPE_NAME, max, Duration::from_secs(5))
+            .await?;
+        assert!(state.error().is_none());
+        assert!(state
+            .worker_seq_nos()
+            .iter()
+            .all(|seq_nos| seq_nos.get(Dummy::TYPE_NAME) == Some(&max)));
+
+        let handled = target.table("test").await;
+        assert_eq!(handled.len(), 100);
+        assert!(handled.values().all(|count| *count == 1));
+
+        projection.stop().await?;
+
+        Ok(())
+    }
+}
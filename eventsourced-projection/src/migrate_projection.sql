@@ -0,0 +1,24 @@
+-- Migrate the table of earlier versions, keyed by name only, as projections had a single source:
+-- the name of that source is unknown here, hence its sequence number is saved under the empty
+-- source name. As it is no position, the projection is rebuilt when run.
+DO $$
+DECLARE
+  pkey text;
+BEGIN
+  IF NOT EXISTS (
+    SELECT
+    FROM information_schema.columns
+    WHERE table_schema = current_schema()
+      AND table_name = 'projection'
+      AND column_name = 'source'
+  ) THEN
+    ALTER TABLE projection ADD COLUMN source text NOT NULL DEFAULT '';
+    ALTER TABLE projection ALTER COLUMN source DROP DEFAULT;
+
+    SELECT conname INTO pkey
+    FROM pg_constraint
+    WHERE conrelid = 'projection'::regclass AND contype = 'p';
+    EXECUTE format('ALTER TABLE projection DROP CONSTRAINT %I', pkey);
+    ALTER TABLE projection ADD PRIMARY KEY (name, source);
+  END IF;
+END $$;
\ No newline at end of file
@@ -0,0 +1,659 @@
+//! NATS KV as [Target] of projections: tables are KV buckets and event handlers get a [NatsKvTx]
+//! collecting puts and deletes, which are applied when committed, followed by the sequence numbers.
+//! As NATS KV has no transactions, events are handled at least once, hence event handlers must be
+//! idempotent.
+//!
+//! Sequence numbers, versions and dead letters of projections are stored in the `projection`,
+//! `projection_version` and `projection_dead_letter` buckets under the keys `<name>.<source>`,
+//! `<name>` and `<name>.<source>.<seq_no>`, hence these names must be valid keys.
+//!
+//! When rebuilding a projection, its shadow copy consists of buckets named like the current ones
+//! with a `_rebuild` suffix, which [NatsKvTx] takes care of. Buckets not created by
+//! [EvtHandler::setup](crate::LocalEvtHandler::setup) are created like the current ones. Swapping
+//! the shadow copy in copies its entries to the current buckets, which is not atomic.
+//!
+//! As [Lease] for [LeaderElection](crate::LeaderElection), leases are stored in the
+//! `projection_lease` bucket under the key `<name>`, updated optimistically based on the revision.
+//! Their expiry is based on the clocks of the replicas, which hence must be reasonably in sync.
+
+use crate::{DeadLetter, Lease, Target};
+use async_nats::{
+    jetstream::{
+        self,
+        kv::{self, Store, UpdateErrorKind},
+        Context as Jetstream,
+    },
+    Client,
+};
+use bytes::Bytes;
+use error_ext::BoxError;
+use futures::TryStreamExt;
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    num::NonZeroU64,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use thiserror::Error;
+
+const SEQ_NOS_BUCKET: &str = "projection";
+const VERSIONS_BUCKET: &str = "projection_version";
+const LEASES_BUCKET: &str = "projection_lease";
+const DEAD_LETTERS_BUCKET: &str = "projection_dead_letter";
+
+/// NATS KV [Target].
+#[derive(Debug, Clone)]
+pub struct NatsKvTarget {
+    jetstream: Jetstream,
+}
+
+impl NatsKvTarget {
+    /// Create a [NatsKvTarget] using the given NATS client.
+    pub fn new(client: Client) -> Self {
+        Self {
+            jetstream: jetstream::new(client),
+        }
+    }
+
+    /// The bucket for the table with the given name, if it exists.
+    pub async fn bucket(&self, table: &str) -> Result<Option<Store>, Error> {
+        bucket(&self.jetstream, table).await
+    }
+}
+
+impl Target for NatsKvTarget {
+    type Tx = NatsKvTx;
+
+    type Error = Error;
+
+    async fn init(&self) -> Result<(), Self::Error> {
+        for bucket in [SEQ_NOS_BUCKET, VERSIONS_BUCKET, DEAD_LETTERS_BUCKET] {
+            bucket_or_create(&self.jetstream, bucket).await?;
+        }
+        Ok(())
+    }
+
+    async fn load_seq_nos(
+        &self,
+        name: &str,
+        shadow: bool,
+    ) -> Result<BTreeMap<String, NonZeroU64>, Self::Error> {
+        let seq_nos = bucket_or_create(&self.jetstream, SEQ_NOS_BUCKET).await?;
+        let prefix = format!("{}.", with_suffix(name, shadow));
+
+        let mut seq_nos_by_source = BTreeMap::new();
+        for key in keys(&seq_nos, &prefix).await? {
+            if let Some(value) = get(&seq_nos, &key).await? {
+                let seq_no = parse::<u64>(&key, &value)?
+                    .try_into()
+                    .map_err(|_| Error::InvalidValue(key.clone()))?;
+                seq_nos_by_source.insert(key[prefix.len()..].to_string(), seq_no);
+            }
+        }
+
+        Ok(seq_nos_by_source)
+    }
+
+    async fn load_version(&self, name: &str) -> Result<Option<u32>, Self::Error> {
+        let versions = bucket_or_create(&self.jetstream, VERSIONS_BUCKET).await?;
+        get(&versions, name)
+            .await?
+            .map(|value| parse(name, &value))
+            .transpose()
+    }
+
+    async fn begin(&self, _name: &str, shadow: bool) -> Result<Self::Tx, Self::Error> {
+        Ok(NatsKvTx {
+            jetstream: self.jetstream.clone(),
+            shadow,
+            ops: vec![],
+            seq_nos: vec![],
+            version: None,
+        })
+    }
+
+    async fn save_seq_no(
+        &self,
+        tx: &mut Self::Tx,
+        name: &str,
+        shadow: bool,
+        source: &str,
+        seq_no: NonZeroU64,
+    ) -> Result<(), Self::Error> {
+        let key = format!("{}.{source}", with_suffix(name, shadow));
+        tx.seq_nos.push((key, seq_no.to_string().into()));
+        Ok(())
+    }
+
+    async fn save_version(
+        &self,
+        tx: &mut Self::Tx,
+        name: &str,
+        version: u32,
+    ) -> Result<(), Self::Error> {
+        tx.version = Some((name.to_string(), version.to_string().into()));
+        Ok(())
+    }
+
+    async fn save_dead_letter(
+        &self,
+        tx: &mut Self::Tx,
+        name: &str,
+        dead_letter: &DeadLetter,
+    ) -> Result<(), Self::Error> {
+        let DeadLetter {
+            source,
+            seq_no,
+            error,
+        } = dead_letter;
+        let key = format!("{name}.{source}.{seq_no}");
+        let value = Bytes::from(error.clone());
+        tx.ops
+            .push((DEAD_LETTERS_BUCKET.to_string(), key, Some(value)));
+        Ok(())
+    }
+
+    async fn delete_dead_letter(
+        &self,
+        tx: &mut Self::Tx,
+        name: &str,
+        source: &str,
+        seq_no: NonZeroU64,
+    ) -> Result<(), Self::Error> {
+        let key = format!("{name}.{source}.{seq_no}");
+        tx.ops.push((DEAD_LETTERS_BUCKET.to_string(), key, None));
+        Ok(())
+    }
+
+    async fn load_dead_letters(&self, name: &str) -> Result<Vec<DeadLetter>, Self::Error> {
+        let dead_letters_bucket = bucket_or_create(&self.jetstream, DEAD_LETTERS_BUCKET).await?;
+        let prefix = format!("{name}.");
+
+        let mut dead_letters = vec![];
+        for key in keys(&dead_letters_bucket, &prefix).await? {
+            if let Some(value) = get(&dead_letters_bucket, &key).await? {
+                let (source, seq_no) = key[prefix.len()..]
+                    .rsplit_once('.')
+                    .and_then(|(source, seq_no)| Some((source, seq_no.parse().ok()?)))
+                    .ok_or_else(|| Error::InvalidValue(key.clone()))?;
+                let error = String::from_utf8_lossy(&value).into_owned();
+                dead_letters.push(DeadLetter {
+                    source: source.to_string(),
+                    seq_no,
+                    error,
+                });
+            }
+        }
+        dead_letters.sort_by(|a, b| (&a.source, a.seq_no).cmp(&(&b.source, b.seq_no)));
+
+        Ok(dead_letters)
+    }
+
+    async fn commit(&self, tx: Self::Tx) -> Result<(), Self::Error> {
+        let mut buckets = HashMap::new();
+        for (bucket, key, value) in tx.ops {
+            if !buckets.contains_key(&bucket) {
+                let store = bucket_or_create(&self.jetstream, &bucket).await?;
+                buckets.insert(bucket.clone(), store);
+            }
+            let store = &buckets[&bucket];
+            match value {
+                Some(value) => put(store, &key, value).await?,
+                None => delete(store, &key).await?,
+            }
+        }
+
+        let seq_nos = bucket_or_create(&self.jetstream, SEQ_NOS_BUCKET).await?;
+        for (key, value) in tx.seq_nos {
+            put(&seq_nos, &key, value).await?;
+        }
+
+        if let Some((key, value)) = tx.version {
+            let versions = bucket_or_create(&self.jetstream, VERSIONS_BUCKET).await?;
+            put(&versions, &key, value).await?;
+        }
+
+        Ok(())
+    }
+
+    async fn create_shadow(&self, name: &str, tables: &[&str]) -> Result<(), Self::Error> {
+        for table in tables {
+            let shadow_bucket = with_suffix(table, true);
+            if bucket(&self.jetstream, &shadow_bucket).await?.is_some() {
+                self.jetstream
+                    .delete_key_value(&shadow_bucket)
+                    .await
+                    .map_err(|error| {
+                        Error::Nats(
+                            format!("cannot delete NATS KV bucket {shadow_bucket}"),
+                            error.into(),
+                        )
+                    })?;
+            }
+        }
+
+        let seq_nos = bucket_or_create(&self.jetstream, SEQ_NOS_BUCKET).await?;
+        let prefix = format!("{}.", with_suffix(name, true));
+        for key in keys(&seq_nos, &prefix).await? {
+            delete(&seq_nos, &key).await?;
+        }
+
+        Ok(())
+    }
+
+    async fn create_shadow_tables(&self, _name: &str, tables: &[&str]) -> Result<(), Self::Error> {
+        for table in tables {
+            let shadow_bucket = with_suffix(table, true);
+            if bucket(&self.jetstream, &shadow_bucket).await?.is_some() {
+                continue;
+            }
+
+            if let Some(current) = bucket(&self.jetstream, table).await? {
+                let info = current
+                    .status()
+                    .await
+                    .map_err(|error| {
+                        Error::Nats(
+                            format!("cannot get status of NATS KV bucket {table}"),
+                            error.into(),
+                        )
+                    })?
+                    .info;
+                let config = kv::Config {
+                    bucket: shadow_bucket.clone(),
+                    history: info.config.max_messages_per_subject,
+                    max_age: info.config.max_age,
+                    max_bytes: info.config.max_bytes,
+                    storage: info.config.storage,
+                    num_replicas: info.config.num_replicas,
+                    ..Default::default()
+                };
+                create_bucket(&self.jetstream, config).await?;
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn swap_shadow(
+        &self,
+        name: &str,
+        tables: &[&str],
+        version: u32,
+    ) -> Result<(), Self::Error> {
+        for table in tables {
+            let shadow_bucket = with_suffix(table, true);
+            let current = bucket_or_create(&self.jetstream, table).await?;
+
+            let mut shadow_keys = HashSet::new();
+            if let Some(shadow) = bucket(&self.jetstream, &shadow_bucket).await? {
+                for key in keys(&shadow, "").await? {
+                    if let Some(value) = get(&shadow, &key).await? {
+                        put(&current, &key, value).await?;
+                    }
+                    shadow_keys.insert(key);
+                }
+            }
+            for key in keys(&current, "").await? {
+                if !shadow_keys.contains(&key) {
+                    delete(&current, &key).await?;
+                }
+            }
+
+            if bucket(&self.jetstream, &shadow_bucket).await?.is_some() {
+                self.jetstream
+                    .delete_key_value(&shadow_bucket)
+                    .await
+                    .map_err(|error| {
+                        Error::Nats(
+                            format!("cannot delete NATS KV bucket {shadow_bucket}"),
+                            error.into(),
+                        )
+                    })?;
+            }
+        }
+
+        let seq_nos = bucket_or_create(&self.jetstream, SEQ_NOS_BUCKET).await?;
+        let prefix = format!("{name}.");
+        let shadow_prefix = format!("{}.", with_suffix(name, true));
+        for key in keys(&seq_nos, &prefix).await? {
+            delete(&seq_nos, &key).await?;
+        }
+        for key in keys(&seq_nos, &shadow_prefix).await? {
+            if let Some(value) = get(&seq_nos, &key).await? {
+                let source = &key[shadow_prefix.len()..];
+                put(&seq_nos, &format!("{prefix}{source}"), value).await?;
+            }
+            delete(&seq_nos, &key).await?;
+        }
+
+        let versions = bucket_or_create(&self.jetstream, VERSIONS_BUCKET).await?;
+        put(&versions, name, version.to_string().into()).await?;
+
+        Ok(())
+    }
+}
+
+/// Transaction of the [NatsKvTarget], collecting puts and deletes which are applied when
+/// committed.
+#[derive(Debug)]
+pub struct NatsKvTx {
+    jetstream: Jetstream,
+    shadow: bool,
+    ops: Vec<(String, String, Option<Bytes>)>,
+    seq_nos: Vec<(String, Bytes)>,
+    version: Option<(String, Bytes)>,
+}
+
+impl NatsKvTx {
+    /// Put the given value for the given key into the bucket for the table with the given name,
+    /// which is created if it does not exist.
+    pub fn put(&mut self, table: &str, key: impl Into<String>, value: Bytes) {
+        self.ops.push((self.bucket(table), key.into(), Some(value)));
+    }
+
+    /// Delete the given key from the bucket for the table with the given name.
+    pub fn delete(&mut self, table: &str, key: impl Into<String>) {
+        self.ops.push((self.bucket(table), key.into(), None));
+    }
+
+    /// The name of the bucket for the table with the given name; when rebuilding a projection, the
+    /// one of the shadow copy. Use this to create buckets with a custom configuration in
+    /// [EvtHandler::setup](crate::LocalEvtHandler::setup).
+    pub fn bucket(&self, table: &str) -> String {
+        with_suffix(table, self.shadow)
+    }
+
+    /// The JetStream context, e.g. to create buckets.
+    pub fn jetstream(&self) -> &Jetstream {
+        &self.jetstream
+    }
+}
+
+impl Lease for NatsKvTarget {
+    type Error = Error;
+
+    async fn init(&self) -> Result<(), Self::Error> {
+        bucket_or_create(&self.jetstream, LEASES_BUCKET).await?;
+        Ok(())
+    }
+
+    async fn acquire(&self, name: &str, holder: &str, ttl: Duration) -> Result<bool, Self::Error> {
+        let leases = bucket_or_create(&self.jetstream, LEASES_BUCKET).await?;
+        let now = now_millis();
+
+        // Revision 0 means the lease has never been acquired.
+        let entry = leases.entry(name).await.map_err(|error| {
+            Error::Nats(
+                format!("cannot get entry for NATS KV key {name}"),
+                error.into(),
+            )
+        })?;
+        let revision = entry
+            .as_ref()
+            .map(|entry| entry.revision)
+            .unwrap_or_default();
+        let lease = entry.as_ref().and_then(|entry| parse_lease(&entry.value));
+        if lease.is_some_and(|(expires_at, current)| current != holder && expires_at > now) {
+            return Ok(false);
+        }
+
+        let expires_at = now + ttl.as_millis();
+        let value = format!("{expires_at}:{holder}").into();
+        match leases.update(name, value, revision).await {
+            Ok(_) => Ok(true),
+
+            // Another replica has updated the lease in the meantime.
+            Err(error) if error.kind() == UpdateErrorKind::Other => Ok(false),
+
+            Err(error) => Err(Error::Nats(
+                format!("cannot update value for NATS KV key {name}"),
+                error.into(),
+            )),
+        }
+    }
+
+    async fn release(&self, name: &str, holder: &str) -> Result<(), Self::Error> {
+        let leases = bucket_or_create(&self.jetstream, LEASES_BUCKET).await?;
+
+        let entry = leases.entry(name).await.map_err(|error| {
+            Error::Nats(
+                format!("cannot get entry for NATS KV key {name}"),
+                error.into(),
+            )
+        })?;
+        let Some(entry) = entry else {
+            return Ok(());
+        };
+        if parse_lease(&entry.value).is_none_or(|(_, current)| current != holder) {
+            return Ok(());
+        }
+
+        // Ignore the lease having been taken over in the meantime.
+        match leases.update(name, "0:".into(), entry.revision).await {
+            Ok(_) => Ok(()),
+            Err(error) if error.kind() == UpdateErrorKind::Other => Ok(()),
+            Err(error) => Err(Error::Nats(
+                format!("cannot update value for NATS KV key {name}"),
+                error.into(),
+            )),
+        }
+    }
+}
+
+/// Errors from the [NatsKvTarget] as [Target] and [Lease].
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("NATS error: {0}")]
+    Nats(String, #[source] BoxError),
+
+    /// Invalid sequence number, version or dead letter key.
+    #[error("invalid sequence number, version or dead letter key {0}")]
+    InvalidValue(String),
+}
+
+/// Name of the projection or table with the given name or of its shadow copy.
+fn with_suffix(name: &str, shadow: bool) -> String {
+    if shadow {
+        format!("{name}_rebuild")
+    } else {
+        name.to_string()
+    }
+}
+
+async fn bucket(jetstream: &Jetstream, name: &str) -> Result<Option<Store>, Error> {
+    let stream_exists = jetstream
+        .stream_names()
+        .try_any(|stream| {
+            let exists = stream == format!("KV_{name}");
+            async move { exists }
+        })
+        .await
+        .map_err(|error| Error::Nats("cannot list NATS streams".to_string(), error.into()))?;
+
+    if !stream_exists {
+        return Ok(None);
+    }
+
+    jetstream
+        .get_key_value(name)
+        .await
+        .map(Some)
+        .map_err(|error| Error::Nats(format!("cannot get NATS KV bucket {name}"), error.into()))
+}
+
+async fn bucket_or_create(jetstream: &Jetstream, name: &str) -> Result<Store, Error> {
+    match bucket(jetstream, name).await? {
+        Some(store) => Ok(store),
+        None => {
+            let config = kv::Config {
+                bucket: name.to_string(),
+                ..Default::default()
+            };
+            create_bucket(jetstream, config).await
+        }
+    }
+}
+
+async fn create_bucket(jetstream: &Jetstream, config: kv::Config) -> Result<Store, Error> {
+    let name = config.bucket.clone();
+    jetstream
+        .create_key_value(config)
+        .await
+        .map_err(|error| Error::Nats(format!("cannot create NATS KV bucket {name}"), error.into()))
+}
+
+/// The keys of the given bucket starting with the given prefix.
+async fn keys(store: &Store, prefix: &str) -> Result<Vec<String>, Error> {
+    let keys = store
+        .keys()
+        .await
+        .map_err(|error| Error::Nats("cannot get NATS KV keys".to_string(), error.into()))?;
+    keys.try_filter(|key| futures::future::ready(key.starts_with(prefix)))
+        .try_collect::<Vec<_>>()
+        .await
+        .map_err(|error| Error::Nats("cannot get NATS KV keys".to_string(), error.into()))
+}
+
+async fn get(store: &Store, key: &str) -> Result<Option<Bytes>, Error> {
+    store.get(key).await.map_err(|error| {
+        Error::Nats(
+            format!("cannot get value for NATS KV key {key}"),
+            error.into(),
+        )
+    })
+}
+
+async fn put(store: &Store, key: &str, value: Bytes) -> Result<(), Error> {
+    store.put(key, value).await.map_err(|error| {
+        Error::Nats(
+            format!("cannot put value for NATS KV key {key}"),
+            error.into(),
+        )
+    })?;
+    Ok(())
+}
+
+async fn delete(store: &Store, key: &str) -> Result<(), Error> {
+    store
+        .delete(key)
+        .await
+        .map_err(|error| Error::Nats(format!("cannot delete NATS KV key {key}"), error.into()))
+}
+
+/// Parse a lease value into its expiry in milliseconds since the epoch and its holder; `None` for
+/// deleted or invalid values.
+fn parse_lease(value: &[u8]) -> Option<(u128, &str)> {
+    let (expires_at, holder) = std::str::from_utf8(value).ok()?.split_once(':')?;
+    Some((expires_at.parse().ok()?, holder))
+}
+
+fn now_millis() -> u128 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis()
+}
+
+fn parse<T>(key: &str, value: &[u8]) -> Result<T, Error>
+where
+    T: std::str::FromStr,
+{
+    std::str::from_utf8(value)
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .ok_or_else(|| Error::InvalidValue(key.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        tests::{Dummy, TestEvtLog},
+        Batching, ErrorStrategy, EvtHandler, Projection,
+    };
+    use eventsourced::{binarize::serde_json::SerdeJsonBinarize, EventSourced};
+    use std::{convert::Infallible, time::Duration};
+    use testcontainers::{clients::Cli, core::WaitFor};
+    use testcontainers_modules::testcontainers::GenericImage;
+    use tokio::time::sleep;
+
+    const NATS_VERSION: &str = "2.10.9";
+
+    #[derive(Clone)]
+    struct TestHandler;
+
+    impl EvtHandler for TestHandler {
+        type Evt = i32;
+
+        type Error = Infallible;
+
+        type Target = NatsKvTarget;
+
+        const TABLES: &'static [&'static str] = &["test"];
+
+        async fn handle_evt(&self, evt: Self::Evt, tx: &mut NatsKvTx) -> Result<(), Self::Error> {
+            tx.put("test", evt.to_string(), evt.to_string().into());
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test() -> Result<(), BoxError> {
+        let client = Cli::default();
+        let nats_image = GenericImage::new("nats", NATS_VERSION)
+            .with_wait_for(WaitFor::message_on_stderr("Server is ready"));
+        let container = client.run((nats_image, vec!["-js".to_string()]));
+        let server_addr = format!("localhost:{}", container.get_host_port_ipv4(4222));
+
+        let target = NatsKvTarget::new(async_nats::connect(server_addr).await?);
+
+        let projection = Projection::new::<Dummy, _, _, _>(
+            "test-projection".to_string(),
+            TestEvtLog,
+            SerdeJsonBinarize,
+            TestHandler,
+            ErrorStrategy::Stop,
+            Batching::default(),
+            target.clone(),
+        )
+        .await?;
+
+        projection.run().await?;
+
+        let mut state = projection.get_state().await?;
+        let max = Some(NonZeroU64::new(100).unwrap());
+        while state.seq_no(Dummy::TYPE_NAME) < max {
+            sleep(Duration::from_millis(100)).await;
+            state = projection.get_state().await?;
+        }
+
+        let sum = |target: NatsKvTarget| async move {
+            let test = target.bucket("test").await?.expect("test bucket");
+            let mut sum = 0;
+            for key in keys(&test, "").await? {
+                let value = get(&test, &key).await?.expect("value");
+                sum += parse::<i64>(&key, &value)?;
+            }
+            Ok::<_, Error>(sum)
+        };
+        assert_eq!(sum(target.clone()).await?, 5_050);
+        assert_eq!(
+            target.load_seq_nos("test-projection", false).await?,
+            state.seq_nos().clone()
+        );
+
+        projection.rebuild().await?;
+        let mut state = projection.get_state().await?;
+        while state.rebuilding() {
+            sleep(Duration::from_millis(100)).await;
+            state = projection.get_state().await?;
+        }
+        assert_eq!(state.seq_no(Dummy::TYPE_NAME), max);
+        assert_eq!(sum(target.clone()).await?, 5_050);
+        assert!(target.bucket("test_rebuild").await?.is_none());
+
+        projection.stop().await?;
+
+        Ok(())
+    }
+}
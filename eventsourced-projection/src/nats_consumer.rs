@@ -0,0 +1,630 @@
+//! Projections driven by a durable JetStream consumer on the stream of a `NatsEvtLog`, which serves
+//! as offset store, hence no sequence numbers are stored in the [Target], which can be any one.
+//!
+//! Events are handled one at a time, each in one transaction of the [Target], and acknowledged
+//! explicitly after committing it. As acknowledging may fail after committing, events are handled
+//! at least once, hence event handlers should be idempotent. At most one event is pending at any
+//! time, hence events are handled in order, also when redelivered.
+//!
+//! The [ErrorStrategy] maps to redeliveries: failed events are negatively acknowledged with the
+//! delay of [ErrorStrategy::Retry] or the [Backoff](crate::Backoff) of [ErrorStrategy::Skip] and
+//! [ErrorStrategy::DeadLetter], which terminate the redelivery of events which still fail after
+//! `max_retries` redeliveries, recording them as [DeadLetter] for the latter. Events are also
+//! redelivered once the `ack_wait` of the [Config] has elapsed, e.g. if the handling process has
+//! crashed, which hence must exceed the time needed to handle an event.
+//!
+//! The version of the [EvtHandler] is saved when the projection is created for the first time,
+//! yet rebuilding is not supported: delete the consumer and the tables instead.
+
+use crate::{DeadLetter, ErrorStrategy, EvtHandler, Target};
+use async_nats::{
+    jetstream::{
+        self,
+        consumer::{pull, AckPolicy, DeliverPolicy},
+        AckKind, Message,
+    },
+    Client,
+};
+use bytes::Bytes;
+use error_ext::{BoxError, StdErrorExt};
+use eventsourced::{binarize::Binarize, EventSourced};
+use futures::StreamExt;
+use std::{
+    num::NonZeroU64,
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+use thiserror::Error;
+use tokio::{
+    sync::watch,
+    task::{self, JoinHandle},
+    time::sleep,
+};
+use tracing::{debug, error, info, warn};
+
+/// Delay before consuming again after an error of the consumer itself.
+const CONSUMER_RETRY_DELAY: Duration = Duration::from_secs(1);
+
+/// A projection of the events of an [EventSourced] entity type from the stream of a `NatsEvtLog`
+/// to some [Target], driven by a durable JetStream consumer named like the projection. It runs
+/// from its creation until it is stopped or dropped, or until an error according to
+/// [ErrorStrategy::Stop].
+#[derive(Debug)]
+pub struct ConsumerProjection {
+    name: String,
+    state: Arc<watch::Sender<State>>,
+    task: JoinHandle<()>,
+}
+
+impl ConsumerProjection {
+    /// Create a [ConsumerProjection] of the events of the [EventSourced] entity type `E` handled
+    /// by the given [EvtHandler], converting them from bytes via the given [Binarize]
+    /// implementation, which must match the one used on the write side.
+    ///
+    /// The durable consumer is created if it does not exist yet, starting with the first event,
+    /// hence the given name must be a valid consumer name, e.g. without dots.
+    pub async fn new<E, B, H>(
+        name: String,
+        client: Client,
+        config: Config,
+        binarize: B,
+        evt_handler: H,
+        error_strategy: ErrorStrategy,
+        target: H::Target,
+    ) -> Result<Self, Error>
+    where
+        E: EventSourced,
+        E::Evt: Send + 'static,
+        B: Binarize<E::Evt, E::State>,
+        H: EvtHandler<Evt = E::Evt> + Send + Sync + 'static,
+    {
+        target.init().await.map_err(target_error)?;
+
+        match target.load_version(&name).await.map_err(target_error)? {
+            Some(version) if version != H::VERSION => {
+                warn!(
+                    name,
+                    version,
+                    handler_version = H::VERSION,
+                    "projection version changed, rebuilding not supported"
+                );
+            }
+
+            Some(_) => {}
+
+            None => {
+                let mut tx = target.begin(&name, false).await.map_err(target_error)?;
+                evt_handler
+                    .setup(&mut tx)
+                    .await
+                    .map_err(|error| Error::Setup(error.into()))?;
+                target
+                    .save_version(&mut tx, &name, H::VERSION)
+                    .await
+                    .map_err(target_error)?;
+                target.commit(tx).await.map_err(target_error)?;
+            }
+        }
+
+        let consumer_config = pull::Config {
+            durable_name: Some(name.clone()),
+            filter_subject: format!("{}.{}.>", config.evt_stream_name, E::TYPE_NAME),
+            deliver_policy: DeliverPolicy::All,
+            ack_policy: AckPolicy::Explicit,
+            ack_wait: config.ack_wait,
+            max_deliver: -1,
+            max_ack_pending: 1,
+            ..Default::default()
+        };
+        let consumer = jetstream::new(client)
+            .get_stream(&config.evt_stream_name)
+            .await
+            .map_err(|error| {
+                Error::Nats(
+                    format!("cannot get NATS stream '{}'", config.evt_stream_name),
+                    error.into(),
+                )
+            })?
+            .get_or_create_consumer(&name, consumer_config)
+            .await
+            .map_err(|error| {
+                Error::Nats(
+                    format!("cannot get or create NATS consumer '{name}'"),
+                    error.into(),
+                )
+            })?;
+
+        let state = Arc::new(watch::Sender::new(State {
+            running: true,
+            ..Default::default()
+        }));
+
+        let runner = Runner {
+            name: name.clone(),
+            state: state.clone(),
+            evt_from_bytes: move |bytes| {
+                binarize
+                    .evt_from_bytes(bytes)
+                    .map_err(|error| error.as_chain())
+            },
+            evt_handler,
+            error_strategy,
+            target,
+        };
+        let task = task::spawn(async move {
+            info!(name = runner.name, "running projection");
+
+            loop {
+                let msgs = consumer.stream().max_messages_per_batch(1).messages().await;
+                let mut msgs = match msgs {
+                    Ok(msgs) => msgs,
+                    Err(error) => {
+                        error!(
+                            error = error.as_chain(),
+                            name = runner.name,
+                            "cannot consume events"
+                        );
+                        sleep(CONSUMER_RETRY_DELAY).await;
+                        continue;
+                    }
+                };
+
+                while let Some(msg) = msgs.next().await {
+                    match msg {
+                        Ok(msg) => {
+                            if !runner.handle_msg(msg).await {
+                                info!(name = runner.name, "projection stopped after error");
+                                runner.state.send_modify(|state| state.running = false);
+                                return;
+                            }
+                        }
+
+                        Err(error) => {
+                            warn!(
+                                error = error.as_chain(),
+                                name = runner.name,
+                                "cannot receive event"
+                            );
+                        }
+                    }
+                }
+            }
+        });
+
+        Ok(Self { name, state, task })
+    }
+
+    /// The name of this projection, which is also the name of its durable consumer.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Get the current [State].
+    pub fn get_state(&self) -> State {
+        self.state.borrow().clone()
+    }
+
+    /// Watch the [State].
+    pub fn watch(&self) -> watch::Receiver<State> {
+        self.state.subscribe()
+    }
+
+    /// Stop this projection; the event being handled, if any, is redelivered once running again.
+    pub fn stop(&self) {
+        if !self.task.is_finished() {
+            info!(name = self.name, "stopping projection");
+            self.task.abort();
+            self.state.send_modify(|state| state.running = false);
+        }
+    }
+}
+
+impl Drop for ConsumerProjection {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+/// Configuration for a [ConsumerProjection].
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// Name of the stream of the `NatsEvtLog`.
+    pub evt_stream_name: String,
+
+    /// Duration after which unacknowledged events are redelivered.
+    pub ack_wait: Duration,
+}
+
+impl Default for Config {
+    /// Use "evts" for the stream name like the `NatsEvtLog` and 30 seconds for the ack wait.
+    fn default() -> Self {
+        Self {
+            evt_stream_name: "evts".to_string(),
+            ack_wait: Duration::from_secs(30),
+        }
+    }
+}
+
+/// State of a [ConsumerProjection].
+#[derive(Debug, Clone, Default)]
+pub struct State {
+    seq_no: Option<NonZeroU64>,
+    running: bool,
+    error: Option<String>,
+    error_at: Option<SystemTime>,
+    retries: u32,
+    evt_count: u64,
+    skipped_count: u64,
+    handled_at: Option<SystemTime>,
+}
+
+impl State {
+    /// The stream sequence of the last handled or skipped event, if any.
+    pub fn seq_no(&self) -> Option<NonZeroU64> {
+        self.seq_no
+    }
+
+    pub fn running(&self) -> bool {
+        self.running
+    }
+
+    /// The last error, if any, cleared once an event has been handled or skipped.
+    pub fn error(&self) -> Option<&str> {
+        self.error.as_deref()
+    }
+
+    pub fn error_at(&self) -> Option<SystemTime> {
+        self.error_at
+    }
+
+    /// The number of redeliveries of the event currently failing, if any.
+    pub fn retries(&self) -> u32 {
+        self.retries
+    }
+
+    /// The number of events handled.
+    pub fn evt_count(&self) -> u64 {
+        self.evt_count
+    }
+
+    /// The number of events skipped according to [ErrorStrategy::Skip] or
+    /// [ErrorStrategy::DeadLetter].
+    pub fn skipped_count(&self) -> u64 {
+        self.skipped_count
+    }
+
+    pub fn handled_at(&self) -> Option<SystemTime> {
+        self.handled_at
+    }
+}
+
+/// Errors from creating a [ConsumerProjection].
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("NATS error: {0}")]
+    Nats(String, #[source] BoxError),
+
+    #[error("cannot create ConsumerProjection, b/c of a target error")]
+    Target(#[source] BoxError),
+
+    #[error("cannot create ConsumerProjection, b/c cannot set up tables")]
+    Setup(#[source] BoxError),
+}
+
+fn target_error<E>(error: E) -> Error
+where
+    E: std::error::Error + Send + Sync + 'static,
+{
+    Error::Target(error.into())
+}
+
+/// Why handling an event has failed.
+enum Failure {
+    /// The event cannot be decoded.
+    Decode(String),
+
+    /// The [EvtHandler] has failed.
+    Handler(String),
+
+    /// The [Target] has failed, which is retried forever.
+    Target(String),
+}
+
+impl Failure {
+    fn error(&self) -> &str {
+        match self {
+            Failure::Decode(error) | Failure::Handler(error) | Failure::Target(error) => error,
+        }
+    }
+}
+
+struct Runner<F, H>
+where
+    H: EvtHandler,
+{
+    name: String,
+    state: Arc<watch::Sender<State>>,
+    evt_from_bytes: F,
+    evt_handler: H,
+    error_strategy: ErrorStrategy,
+    target: H::Target,
+}
+
+impl<F, H> Runner<F, H>
+where
+    F: Fn(Bytes) -> Result<H::Evt, String>,
+    H: EvtHandler + Sync,
+    H::Evt: Send,
+{
+    /// Handle the given message, acknowledging it according to the outcome. Returns whether to
+    /// continue, i.e. `false` if stopped according to [ErrorStrategy::Stop].
+    async fn handle_msg(&self, msg: Message) -> bool {
+        let name = &self.name;
+
+        let (seq_no, delivered) = match msg.info() {
+            Ok(info) => (
+                NonZeroU64::new(info.stream_sequence),
+                info.delivered.max(1) as u32,
+            ),
+            Err(error) => {
+                error!(%error, name, "cannot get info of event message");
+                return true;
+            }
+        };
+        let Some(seq_no) = seq_no else {
+            error!(name, "invalid stream sequence of event message");
+            return true;
+        };
+
+        let result = match (self.evt_from_bytes)(msg.payload.clone()) {
+            Ok(evt) => self.handle_evt(evt).await,
+            Err(error) => Err(Failure::Decode(error)),
+        };
+
+        match result {
+            Ok(()) => {
+                debug!(name, seq_no, "projection handled event");
+                ack(&msg, AckKind::Ack, name).await;
+                self.state.send_modify(|state| {
+                    state.seq_no = Some(seq_no);
+                    state.evt_count += 1;
+                    state.handled_at = Some(SystemTime::now());
+                    state.retries = 0;
+                    state.error = None;
+                    state.error_at = None;
+                });
+                true
+            }
+
+            Err(failure) => self.handle_failure(&msg, seq_no, delivered, failure).await,
+        }
+    }
+
+    async fn handle_evt(&self, evt: H::Evt) -> Result<(), Failure> {
+        let target_failure =
+            |error: <H::Target as Target>::Error| Failure::Target(error.as_chain());
+
+        let mut tx = self
+            .target
+            .begin(&self.name, false)
+            .await
+            .map_err(target_failure)?;
+        self.evt_handler
+            .handle_evt(evt, &mut tx)
+            .await
+            .map_err(|error| Failure::Handler(error.as_chain()))?;
+        self.target.commit(tx).await.map_err(target_failure)
+    }
+
+    /// Handle the given failure of handling the event with the given stream sequence and number
+    /// of deliveries according to the [ErrorStrategy]. Returns whether to continue.
+    async fn handle_failure(
+        &self,
+        msg: &Message,
+        seq_no: NonZeroU64,
+        delivered: u32,
+        failure: Failure,
+    ) -> bool {
+        let Runner {
+            name,
+            state,
+            error_strategy,
+            ..
+        } = self;
+        let error = failure.error();
+
+        error!(error, name, seq_no, delivered, "projection error");
+        state.send_modify(|state| {
+            state.error = Some(error.to_string());
+            state.error_at = Some(SystemTime::now());
+            state.retries = delivered - 1;
+        });
+
+        match error_strategy {
+            ErrorStrategy::Retry(delay) => {
+                info!(name, seq_no, ?delay, "projection retrying after error");
+                ack(msg, AckKind::Nak(Some(*delay)), name).await;
+            }
+
+            ErrorStrategy::Stop => {
+                ack(msg, AckKind::Nak(None), name).await;
+                return false;
+            }
+
+            ErrorStrategy::Skip(backoff) | ErrorStrategy::DeadLetter(backoff) => {
+                let skip = match failure {
+                    Failure::Decode(_) => true,
+                    Failure::Handler(_) => delivered > backoff.max_retries,
+                    Failure::Target(_) => false,
+                };
+
+                if !skip {
+                    let delay = backoff.delay(delivered);
+                    info!(name, seq_no, ?delay, "projection retrying after error");
+                    ack(msg, AckKind::Nak(Some(delay)), name).await;
+                    return true;
+                }
+
+                if matches!(error_strategy, ErrorStrategy::DeadLetter(_)) {
+                    warn!(name, seq_no, error, "projection dead-letters event");
+                    let dead_letter = DeadLetter {
+                        source: msg
+                            .subject
+                            .split('.')
+                            .nth(1)
+                            .unwrap_or_default()
+                            .to_string(),
+                        seq_no,
+                        error: error.to_string(),
+                    };
+                    if let Err(error) = self.save_dead_letter(&dead_letter).await {
+                        error!(
+                            error = error.as_chain(),
+                            name, seq_no, "cannot save dead letter"
+                        );
+                        ack(msg, AckKind::Nak(Some(backoff.delay(delivered))), name).await;
+                        return true;
+                    }
+                } else {
+                    warn!(name, seq_no, error, "projection skips failed event");
+                }
+
+                ack(msg, AckKind::Term, name).await;
+                state.send_modify(|state| {
+                    state.seq_no = Some(seq_no);
+                    state.skipped_count += 1;
+                    state.retries = 0;
+                });
+            }
+        }
+
+        true
+    }
+
+    async fn save_dead_letter(
+        &self,
+        dead_letter: &DeadLetter,
+    ) -> Result<(), <H::Target as Target>::Error> {
+        let mut tx = self.target.begin(&self.name, false).await?;
+        self.target
+            .save_dead_letter(&mut tx, &self.name, dead_letter)
+            .await?;
+        self.target.commit(tx).await
+    }
+}
+
+/// Acknowledge the given message with the given kind; failure is only logged, as unacknowledged
+/// messages are redelivered.
+async fn ack(msg: &Message, kind: AckKind, name: &str) {
+    if let Err(error) = msg.ack_with(kind).await {
+        warn!(%error, name, "cannot acknowledge event message");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        memory::{MemoryTarget, MemoryTx},
+        tests::Dummy,
+        Backoff,
+    };
+    use eventsourced::binarize::serde_json::SerdeJsonBinarize;
+    use testcontainers::{clients::Cli, core::WaitFor};
+    use testcontainers_modules::testcontainers::GenericImage;
+    use thiserror::Error;
+    use uuid::Uuid;
+
+    const NATS_VERSION: &str = "2.10.9";
+
+    #[derive(Debug, Error)]
+    #[error("negative")]
+    struct NegativeError;
+
+    #[derive(Clone)]
+    struct TestHandler;
+
+    impl EvtHandler for TestHandler {
+        type Evt = i32;
+
+        type Error = NegativeError;
+
+        type Target = MemoryTarget<i32>;
+
+        async fn handle_evt(
+            &self,
+            evt: Self::Evt,
+            tx: &mut MemoryTx<i32>,
+        ) -> Result<(), Self::Error> {
+            if evt < 0 {
+                return Err(NegativeError);
+            }
+            tx.table("test").insert(evt.to_string(), evt);
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test() -> Result<(), BoxError> {
+        let client = Cli::default();
+        let nats_image = GenericImage::new("nats", NATS_VERSION)
+            .with_wait_for(WaitFor::message_on_stderr("Server is ready"));
+        let container = client.run((nats_image, vec!["-js".to_string()]));
+        let server_addr = format!("localhost:{}", container.get_host_port_ipv4(4222));
+
+        let client = async_nats::connect(server_addr).await?;
+        let jetstream = jetstream::new(client.clone());
+        jetstream
+            .create_stream(jetstream::stream::Config {
+                name: "evts".to_string(),
+                subjects: vec!["evts.>".to_string()],
+                ..Default::default()
+            })
+            .await?;
+        let id = Uuid::now_v7();
+        for evt in (1..=10).chain([-1]).chain(11..=20) {
+            jetstream
+                .publish(
+                    format!("evts.{}.{id}", Dummy::TYPE_NAME),
+                    serde_json::to_vec(&evt)?.into(),
+                )
+                .await?
+                .await?;
+        }
+
+        let target = MemoryTarget::new();
+        let projection = ConsumerProjection::new::<Dummy, _, _>(
+            "test-projection".to_string(),
+            client,
+            Config::default(),
+            SerdeJsonBinarize,
+            TestHandler,
+            ErrorStrategy::DeadLetter(Backoff {
+                max_retries: 2,
+                min_delay: Duration::from_millis(10),
+                max_delay: Duration::from_millis(10),
+            }),
+            target.clone(),
+        )
+        .await?;
+
+        let mut state = projection.watch();
+        state
+            .wait_for(|state| state.seq_no() == NonZeroU64::new(21))
+            .await?;
+        let state = projection.get_state();
+        assert_eq!(state.evt_count(), 20);
+        assert_eq!(state.skipped_count(), 1);
+
+        let test = target.table("test").await;
+        assert_eq!(test.values().sum::<i32>(), 210);
+        let dead_letters = target.load_dead_letters("test-projection").await?;
+        assert_eq!(dead_letters.len(), 1);
+        assert_eq!(dead_letters[0].seq_no, NonZeroU64::new(11).unwrap());
+
+        projection.stop();
+        assert!(!projection.get_state().running());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,134 @@
+use std::{
+    collections::BTreeMap,
+    num::{NonZeroU64, NonZeroUsize},
+};
+
+/// The number of slots the events of a [Source](crate::Source) are distributed to by their
+/// partition key, which is the maximum number of workers of a partitioned projection.
+pub const PARTITION_SLOTS: usize = eventsourced::SLOTS;
+
+/// Partitioning of a projection into parallel workers: events are distributed to
+/// [PARTITION_SLOTS] slots by a stable hash of the partition key of their [Source](crate::Source),
+/// by default the ID of the entity which has emitted them, and each worker handles the events of
+/// every `workers`-th slot in order. Hence events with the same partition key are handled in order,
+/// yet events with different ones may be handled in parallel, each worker in its own transactions.
+///
+/// The sequence numbers are tracked per slot, hence the number of workers can be changed safely,
+/// i.e. without handling any event twice. The default is one worker, i.e. no partitioning.
+#[derive(Debug, Clone, Copy)]
+pub struct Partitioning {
+    pub workers: NonZeroUsize,
+}
+
+impl Default for Partitioning {
+    fn default() -> Self {
+        Self {
+            workers: NonZeroUsize::MIN,
+        }
+    }
+}
+
+/// The slots handled by the given worker.
+pub(crate) fn worker_slots(worker: usize, workers: usize) -> impl Iterator<Item = usize> + Clone {
+    (worker..PARTITION_SLOTS).step_by(workers)
+}
+
+/// The key under which the sequence number of the given slot of the given source is saved.
+pub(crate) fn slot_key(source: &str, slot: usize) -> String {
+    format!("{source}#{slot}")
+}
+
+/// The sequence numbers per slot of a [Source](crate::Source) up to which its events have been
+/// handled.
+#[derive(Debug, Clone)]
+pub(crate) struct SlotSeqNos([Option<NonZeroU64>; PARTITION_SLOTS]);
+
+impl SlotSeqNos {
+    /// Get the sequence numbers of the source with the given name from the given saved ones.
+    ///
+    /// Both the sequence number saved for all slots, i.e. without partitioning, and those saved
+    /// for single slots, i.e. with partitioning, only ever grow and state that all events up to
+    /// them have been handled, hence the greater one applies, whichever number of workers has
+    /// saved it.
+    pub(crate) fn new(seq_nos: &BTreeMap<String, NonZeroU64>, source: &str) -> Self {
+        let all = seq_nos.get(source).copied();
+        let slots =
+            std::array::from_fn(|slot| all.max(seq_nos.get(&slot_key(source, slot)).copied()));
+        Self(slots)
+    }
+
+    /// The sequence number of the given slot.
+    pub(crate) fn get(&self, slot: usize) -> Option<NonZeroU64> {
+        self.0[slot]
+    }
+
+    /// The lowest sequence number of the given slots, i.e. up to which all of their events have
+    /// been handled.
+    pub(crate) fn min(&self, slots: impl Iterator<Item = usize>) -> Option<NonZeroU64> {
+        slots.map(|slot| self.0[slot]).min().flatten()
+    }
+
+    /// Advance the sequence number of the given slot to the given one, if less.
+    pub(crate) fn advance(&mut self, slot: usize, seq_no: NonZeroU64) {
+        self.0[slot] = self.0[slot].max(Some(seq_no));
+    }
+}
+
+/// The sequence numbers by [Source](crate::Source) name up to which all events have been handled,
+/// overall and per worker, from the given saved ones.
+pub(crate) fn positions<'a>(
+    seq_nos: &BTreeMap<String, NonZeroU64>,
+    sources: impl Iterator<Item = &'a str>,
+    workers: usize,
+) -> (
+    BTreeMap<String, NonZeroU64>,
+    Vec<BTreeMap<String, NonZeroU64>>,
+) {
+    let mut overall = BTreeMap::new();
+    let mut per_worker = vec![BTreeMap::new(); if workers > 1 { workers } else { 0 }];
+
+    for source in sources {
+        let slot_seq_nos = SlotSeqNos::new(seq_nos, source);
+        if let Some(seq_no) = slot_seq_nos.min(0..PARTITION_SLOTS) {
+            overall.insert(source.to_string(), seq_no);
+        }
+        for (worker, seq_nos) in per_worker.iter_mut().enumerate() {
+            if let Some(seq_no) = slot_seq_nos.min(worker_slots(worker, workers)) {
+                seq_nos.insert(source.to_string(), seq_no);
+            }
+        }
+    }
+
+    (overall, per_worker)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_slot_seq_nos() {
+        let seq_nos = BTreeMap::from([
+            ("source".to_string(), NonZeroU64::new(10).unwrap()),
+            (slot_key("source", 1), NonZeroU64::new(20).unwrap()),
+            (slot_key("source", 2), NonZeroU64::new(5).unwrap()),
+        ]);
+        let slot_seq_nos = SlotSeqNos::new(&seq_nos, "source");
+        assert_eq!(slot_seq_nos.get(0), NonZeroU64::new(10));
+        assert_eq!(slot_seq_nos.get(1), NonZeroU64::new(20));
+        assert_eq!(slot_seq_nos.get(2), NonZeroU64::new(10));
+
+        let (overall, per_worker) = positions(&seq_nos, ["source", "other"].into_iter(), 2);
+        assert_eq!(
+            overall,
+            BTreeMap::from([("source".to_string(), NonZeroU64::new(10).unwrap())])
+        );
+        assert_eq!(per_worker.len(), 2);
+        assert_eq!(per_worker[1].get("source"), NonZeroU64::new(10).as_ref());
+
+        let seq_nos = BTreeMap::from([(slot_key("source", 0), NonZeroU64::new(10).unwrap())]);
+        let (overall, per_worker) = positions(&seq_nos, ["source"].into_iter(), 2);
+        assert!(overall.is_empty());
+        assert_eq!(per_worker[0].get("source"), None);
+    }
+}
@@ -0,0 +1,538 @@
+use crate::{
+    source::Sequenced, Batching, CmdError, Error, ErrorStrategy, EvtHandler, Partitioning,
+    Projection, Source, State, Target,
+};
+use error_ext::StdErrorExt;
+use eventsourced::{
+    binarize::Binarize, EntityRef, EventSourced, EventSourcedExt, EvtLog, HandleCmdError,
+    SnapshotStore, SpawnError,
+};
+use std::{
+    collections::{BTreeMap, HashMap},
+    error::Error as StdError,
+    future::Future,
+    hash::Hash,
+    marker::PhantomData,
+    num::NonZeroUsize,
+    sync::Arc,
+};
+use thiserror::Error;
+use tokio::sync::Mutex;
+use tracing::debug;
+
+/// A process manager, a.k.a. saga, coordinating entities: it is itself an [EventSourced] entity
+/// type, yet its commands are the events of one or more [Source]s, each passed to the instance
+/// identified by its correlation ID, and its persisted events are reacted to, e.g. by issuing
+/// commands to other entities via their [EntityRef]s.
+///
+/// Both the events of the sources and the events of the process manager are handled at least once.
+/// Commands are passed with an idempotency key made of the source name and the sequence number of
+/// the event, hence after a crash already handled events are not persisted again, provided that
+/// [IDEMPOTENCY_WINDOW](EventSourced::IDEMPOTENCY_WINDOW) is positive. Reactions, however, may be
+/// repeated, hence the commands issued must tolerate duplicates.
+pub trait ProcessManager: EventSourced + Clone + Send + Sync + 'static {
+    type ReactError: StdError + Send + Sync + 'static;
+
+    /// The correlation ID of the given command, i.e. event of a [Source], identifying the instance
+    /// to handle it; `None` if no instance is interested in it.
+    fn correlation_id(cmd: &Self::Cmd) -> Option<Self::Id>;
+
+    /// React to the given persisted event of some instance of this process manager, e.g. by
+    /// issuing commands to other entities. As events of a type do not carry the ID of the entity,
+    /// the events of a process manager typically contain its correlation ID.
+    fn react(&self, evt: Self::Evt) -> impl Future<Output = Result<(), Self::ReactError>> + Send;
+}
+
+/// A handle for a spawned [ProcessManager], which consists of two [Projection]s: one for passing
+/// the events of the sources to the instances of the process manager and one for reacting to the
+/// events of the process manager, each tracking its progress in the given [Target], such that the
+/// process manager resumes where it has left off after a crash.
+#[derive(Debug, Clone)]
+pub struct ProcessManagerRef {
+    inputs: Projection,
+    reactions: Projection,
+}
+
+impl ProcessManagerRef {
+    /// Spawn the given [ProcessManager] for the given [Source]s, which convert their events into
+    /// commands of the process manager. Its instances are spawned on demand using the given
+    /// [EvtLog], [SnapshotStore] and [Binarize] implementation and kept in memory up to the given
+    /// maximum number, beyond which the least recently used one is dropped.
+    ///
+    /// The [Projection]s are named like the process manager with the suffixes `-inputs` and
+    /// `-reactions`.
+    #[allow(clippy::too_many_arguments)]
+    pub async fn spawn<P, L, S, B, T>(
+        name: String,
+        sources: Vec<Source<P::Cmd>>,
+        process_manager: P,
+        evt_log: L,
+        snapshot_store: S,
+        binarize: B,
+        max_instances: NonZeroUsize,
+        error_strategy: ErrorStrategy,
+        target: T,
+    ) -> Result<Self, Error>
+    where
+        P: ProcessManager,
+        P::Id: Clone + Eq + Hash + Sync,
+        P::Evt: 'static,
+        L: EvtLog<Id = P::Id> + Sync,
+        S: SnapshotStore<Id = P::Id> + Sync,
+        B: Binarize<P::Evt, P::State>,
+        T: Target,
+    {
+        let inputs = Inputs {
+            evt_log: evt_log.clone(),
+            snapshot_store,
+            binarize,
+            instances: Arc::new(Mutex::new(Instances::<P>::new(max_instances))),
+            _target: PhantomData::<T>,
+        };
+        let inputs = Projection::with_sources(
+            format!("{name}-inputs"),
+            sources.into_iter().map(Source::sequenced).collect(),
+            inputs,
+            error_strategy,
+            Batching::default(),
+            Partitioning::default(),
+            target.clone(),
+        )
+        .await?;
+
+        let reactions = Reactions {
+            process_manager,
+            _target: PhantomData::<T>,
+        };
+        let reactions = Projection::new::<P, _, _, _>(
+            format!("{name}-reactions"),
+            evt_log,
+            binarize,
+            reactions,
+            error_strategy,
+            Batching::default(),
+            target,
+        )
+        .await?;
+
+        Ok(Self { inputs, reactions })
+    }
+
+    /// Run both [Projection]s of this process manager.
+    pub async fn run(&self) -> Result<(State, State), CmdError> {
+        let reactions = self.reactions.run().await?;
+        let inputs = self.inputs.run().await?;
+        Ok((inputs, reactions))
+    }
+
+    /// Stop both [Projection]s of this process manager.
+    pub async fn stop(&self) -> Result<(State, State), CmdError> {
+        let inputs = self.inputs.stop().await?;
+        let reactions = self.reactions.stop().await?;
+        Ok((inputs, reactions))
+    }
+
+    /// The [Projection] passing the events of the sources to the instances of the process
+    /// manager, e.g. to watch its state.
+    pub fn inputs(&self) -> &Projection {
+        &self.inputs
+    }
+
+    /// The [Projection] reacting to the events of the process manager, e.g. to watch its state.
+    pub fn reactions(&self) -> &Projection {
+        &self.reactions
+    }
+}
+
+/// Error from passing an event of a [Source] to an instance of a [ProcessManager].
+#[derive(Debug, Error)]
+pub enum ProcessManagerError {
+    #[error("cannot spawn process manager instance {0}")]
+    Spawn(String, #[source] SpawnError),
+
+    #[error("cannot pass command to process manager instance {0}")]
+    HandleCmd(String, #[source] HandleCmdError),
+}
+
+/// [EvtHandler] passing the events of the sources as commands to the instances of the process
+/// manager, ignoring the [Target].
+struct Inputs<P, L, S, B, T>
+where
+    P: ProcessManager,
+{
+    evt_log: L,
+    snapshot_store: S,
+    binarize: B,
+    instances: Arc<Mutex<Instances<P>>>,
+    _target: PhantomData<T>,
+}
+
+impl<P, L, S, B, T> Clone for Inputs<P, L, S, B, T>
+where
+    P: ProcessManager,
+    L: Clone,
+    S: Clone,
+    B: Copy,
+{
+    fn clone(&self) -> Self {
+        Self {
+            evt_log: self.evt_log.clone(),
+            snapshot_store: self.snapshot_store.clone(),
+            binarize: self.binarize,
+            instances: self.instances.clone(),
+            _target: PhantomData,
+        }
+    }
+}
+
+impl<P, L, S, B, T> EvtHandler for Inputs<P, L, S, B, T>
+where
+    P: ProcessManager,
+    P::Id: Clone + Eq + Hash + Sync,
+    L: EvtLog<Id = P::Id> + Sync,
+    S: SnapshotStore<Id = P::Id> + Sync,
+    B: Binarize<P::Evt, P::State>,
+    T: Target,
+{
+    type Evt = Sequenced<P::Cmd>;
+
+    type Error = ProcessManagerError;
+
+    type Target = T;
+
+    async fn handle_evt(&self, evt: Self::Evt, _tx: &mut T::Tx) -> Result<(), Self::Error> {
+        let Sequenced {
+            source,
+            seq_no,
+            evt: cmd,
+        } = evt;
+        let Some(id) = P::correlation_id(&cmd) else {
+            debug!(?cmd, "no process manager instance interested in command");
+            return Ok(());
+        };
+
+        // Commands are passed one at a time anyway, as events are handled in order.
+        let mut instances = self.instances.lock().await;
+        if !instances.contains(&id) {
+            let instance = P::spawn(
+                id.clone(),
+                None,
+                NonZeroUsize::MIN,
+                self.evt_log.clone(),
+                self.snapshot_store.clone(),
+                self.binarize,
+            )
+            .await
+            .map_err(|error| ProcessManagerError::Spawn(format!("{id:?}"), error))?;
+            instances.insert(id.clone(), instance);
+        }
+        let instance = instances.get(&id).expect("instance for ID");
+
+        match instance
+            .handle_cmd_idempotent(cmd, format!("{source}:{seq_no}"))
+            .await
+        {
+            Ok(Ok(_)) => Ok(()),
+
+            // Rejected commands are ignored.
+            Ok(Err(error)) => {
+                debug!(
+                    error = error.as_chain(),
+                    ?id,
+                    "process manager rejected command"
+                );
+                Ok(())
+            }
+
+            // The instance has terminated, hence respawn it when retrying.
+            Err(error) => {
+                instances.remove(&id);
+                Err(ProcessManagerError::HandleCmd(format!("{id:?}"), error))
+            }
+        }
+    }
+}
+
+/// The spawned instances of a process manager, dropping the least recently used one beyond the
+/// maximum number, which terminates it.
+struct Instances<P>
+where
+    P: ProcessManager,
+{
+    max: NonZeroUsize,
+    instances: HashMap<P::Id, (EntityRef<P>, u64)>,
+    used: BTreeMap<u64, P::Id>,
+    tick: u64,
+}
+
+impl<P> Instances<P>
+where
+    P: ProcessManager,
+    P::Id: Clone + Eq + Hash,
+{
+    fn new(max: NonZeroUsize) -> Self {
+        Self {
+            max,
+            instances: HashMap::new(),
+            used: BTreeMap::new(),
+            tick: 0,
+        }
+    }
+
+    fn contains(&self, id: &P::Id) -> bool {
+        self.instances.contains_key(id)
+    }
+
+    /// Get the instance with the given ID, marking it as most recently used.
+    fn get(&mut self, id: &P::Id) -> Option<&EntityRef<P>> {
+        let (instance, used) = self.instances.get_mut(id)?;
+        self.used.remove(used);
+        self.tick += 1;
+        *used = self.tick;
+        self.used.insert(self.tick, id.clone());
+        Some(instance)
+    }
+
+    /// Insert the given instance as most recently used, dropping the least recently used one if
+    /// exceeding the maximum number.
+    fn insert(&mut self, id: P::Id, instance: EntityRef<P>) {
+        self.remove(&id);
+        self.tick += 1;
+        self.used.insert(self.tick, id.clone());
+        self.instances.insert(id, (instance, self.tick));
+
+        if self.instances.len() > self.max.get() {
+            if let Some((_, id)) = self.used.pop_first() {
+                self.instances.remove(&id);
+            }
+        }
+    }
+
+    fn remove(&mut self, id: &P::Id) {
+        if let Some((_, used)) = self.instances.remove(id) {
+            self.used.remove(&used);
+        }
+    }
+}
+
+/// [EvtHandler] reacting to the events of the process manager, ignoring the [Target].
+struct Reactions<P, T> {
+    process_manager: P,
+    _target: PhantomData<T>,
+}
+
+impl<P, T> Clone for Reactions<P, T>
+where
+    P: Clone,
+{
+    fn clone(&self) -> Self {
+        Self {
+            process_manager: self.process_manager.clone(),
+            _target: PhantomData,
+        }
+    }
+}
+
+impl<P, T> EvtHandler for Reactions<P, T>
+where
+    P: ProcessManager,
+    T: Target,
+{
+    type Evt = P::Evt;
+
+    type Error = P::ReactError;
+
+    type Target = T;
+
+    async fn handle_evt(&self, evt: Self::Evt, _tx: &mut T::Tx) -> Result<(), Self::Error> {
+        self.process_manager.react(evt).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        memory::MemoryTarget,
+        tests::{Dummy, TestEvtLog},
+    };
+    use error_ext::BoxError;
+    use eventsourced::{binarize::serde_json::SerdeJsonBinarize, NoopSnapshotStore};
+    use std::{
+        convert::Infallible,
+        num::NonZeroU64,
+        sync::atomic::{AtomicUsize, Ordering},
+        time::Duration,
+    };
+    use uuid::Uuid;
+
+    static HANDLED: AtomicUsize = AtomicUsize::new(0);
+
+    /// Handles events not divisible by three per parity and records its events when reacting.
+    #[derive(Debug, Clone, Default)]
+    struct Parity(Arc<Mutex<Vec<i32>>>);
+
+    impl EventSourced for Parity {
+        type Id = Uuid;
+        type Cmd = i32;
+        type Evt = i32;
+        type State = u64;
+        type Error = Infallible;
+
+        const TYPE_NAME: &'static str = "parity";
+
+        fn handle_cmd(
+            _id: &Self::Id,
+            _state: &Self::State,
+            cmd: Self::Cmd,
+        ) -> Result<Self::Evt, Self::Error> {
+            HANDLED.fetch_add(1, Ordering::SeqCst);
+            Ok(cmd)
+        }
+
+        fn handle_evt(state: Self::State, _evt: Self::Evt) -> Self::State {
+            state + 1
+        }
+    }
+
+    impl ProcessManager for Parity {
+        type ReactError = Infallible;
+
+        fn correlation_id(cmd: &Self::Cmd) -> Option<Self::Id> {
+            (cmd % 3 != 0).then(|| Uuid::from_u128((cmd % 2) as u128))
+        }
+
+        async fn react(&self, evt: Self::Evt) -> Result<(), Self::ReactError> {
+            self.0.lock().await.push(evt);
+            Ok(())
+        }
+    }
+
+    static ONCE_HANDLED: AtomicUsize = AtomicUsize::new(0);
+
+    /// Handles each command by the instance identified by it.
+    #[derive(Debug, Clone)]
+    struct Once;
+
+    impl EventSourced for Once {
+        type Id = Uuid;
+        type Cmd = i32;
+        type Evt = i32;
+        type State = ();
+        type Error = Infallible;
+
+        const TYPE_NAME: &'static str = "once";
+
+        const IDEMPOTENCY_WINDOW: u64 = 10;
+
+        fn handle_cmd(
+            _id: &Self::Id,
+            _state: &Self::State,
+            cmd: Self::Cmd,
+        ) -> Result<Self::Evt, Self::Error> {
+            ONCE_HANDLED.fetch_add(1, Ordering::SeqCst);
+            Ok(cmd)
+        }
+
+        fn handle_evt(state: Self::State, _evt: Self::Evt) -> Self::State {
+            state
+        }
+    }
+
+    impl ProcessManager for Once {
+        type ReactError = Infallible;
+
+        fn correlation_id(cmd: &Self::Cmd) -> Option<Self::Id> {
+            Some(Uuid::from_u128(*cmd as u128))
+        }
+
+        async fn react(&self, _evt: Self::Evt) -> Result<(), Self::ReactError> {
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_inputs() -> Result<(), BoxError> {
+        let inputs = Inputs {
+            evt_log: TestEvtLog,
+            snapshot_store: NoopSnapshotStore::new(),
+            binarize: SerdeJsonBinarize,
+            instances: Arc::new(Mutex::new(Instances::<Once>::new(
+                NonZeroUsize::new(2).unwrap(),
+            ))),
+            _target: PhantomData::<MemoryTarget<()>>,
+        };
+        let target = MemoryTarget::<()>::new();
+        let mut tx = target.begin("test-inputs", false).await?;
+        let evt = |n: i32| Sequenced {
+            source: Arc::from("dummy"),
+            seq_no: NonZeroU64::new(n as u64).unwrap(),
+            evt: n,
+        };
+
+        // Duplicates of events are not handled again.
+        inputs.handle_evt(evt(1), &mut tx).await?;
+        inputs.handle_evt(evt(1), &mut tx).await?;
+        assert_eq!(ONCE_HANDLED.load(Ordering::SeqCst), 1);
+
+        // Beyond the maximum number of instances, the least recently used one is dropped.
+        inputs.handle_evt(evt(2), &mut tx).await?;
+        inputs.handle_evt(evt(1), &mut tx).await?;
+        inputs.handle_evt(evt(3), &mut tx).await?;
+        assert_eq!(ONCE_HANDLED.load(Ordering::SeqCst), 3);
+        let instances = inputs.instances.lock().await;
+        let ids = instances
+            .used
+            .values()
+            .map(|id| id.as_u128())
+            .collect::<Vec<_>>();
+        assert_eq!(ids, vec![1, 3]);
+        assert_eq!(instances.instances.len(), 2);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_process_manager() -> Result<(), BoxError> {
+        let parity = Parity::default();
+
+        let process_manager = ProcessManagerRef::spawn(
+            "test-process-manager".to_string(),
+            vec![Source::new::<Dummy, _, _, _>(
+                TestEvtLog,
+                SerdeJsonBinarize,
+                |evt| evt,
+            )],
+            parity.clone(),
+            TestEvtLog,
+            NoopSnapshotStore::new(),
+            SerdeJsonBinarize,
+            NonZeroUsize::MIN,
+            ErrorStrategy::Stop,
+            MemoryTarget::<()>::new(),
+        )
+        .await?;
+
+        process_manager.run().await?;
+
+        let max = NonZeroU64::new(100).unwrap();
+        let timeout = Duration::from_secs(5);
+        let inputs = process_manager
+            .inputs()
+            .await_position(Dummy::TYPE_NAME, max, timeout)
+            .await?;
+        assert!(inputs.error().is_none());
+        assert_eq!(HANDLED.load(Ordering::SeqCst), 67);
+
+        // The test event log returns the same events for each entity type.
+        process_manager
+            .reactions()
+            .await_position(Parity::TYPE_NAME, max, timeout)
+            .await?;
+        assert_eq!(*parity.0.lock().await, (1..=100).collect::<Vec<_>>());
+
+        process_manager.stop().await?;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,1748 @@
+use crate::{
+    leader_election::NoLease,
+    partitioning::{positions, slot_key, worker_slots, SlotSeqNos},
+    source::{merge, Source},
+    LeaderElection, Lease, Partitioning, PARTITION_SLOTS,
+};
+use error_ext::{BoxError, StdErrorExt};
+use eventsourced::{binarize::Binarize, EventSourced, EvtLog};
+use futures::StreamExt;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, HashSet},
+    error::Error as StdError,
+    fmt::Debug,
+    future::Future,
+    num::{NonZeroU64, NonZeroUsize},
+    pin::pin,
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+use thiserror::Error;
+use tokio::{
+    sync::{mpsc, oneshot, watch, Mutex},
+    task::{self, JoinHandle, JoinSet},
+    time::{sleep, timeout, timeout_at, Instant},
+};
+use tracing::{debug, error, info, warn};
+
+/// The heads of the event logs of the sources of a running projection are polled in this interval,
+/// in order to determine its lag.
+pub const HEADS_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The source name under which projections created before sources were introduced have saved
+/// their sequence number.
+const LEGACY_SOURCE: &str = "";
+
+/// A projection of events of one or more [EventSourced] entity types to some [Target], e.g. a
+/// Postgres database.
+#[derive(Debug, Clone)]
+pub struct Projection {
+    name: String,
+    cmd_in: mpsc::Sender<(Cmd, oneshot::Sender<State>)>,
+    state: watch::Receiver<State>,
+}
+
+impl Projection {
+    /// Create a [Projection] of the events of the [EventSourced] entity type `E` handled by the
+    /// given [EvtHandler], converting them from bytes via the given [Binarize] implementation,
+    /// which must match the one used on the write side.
+    pub async fn new<E, L, B, H>(
+        name: String,
+        evt_log: L,
+        binarize: B,
+        evt_handler: H,
+        error_strategy: ErrorStrategy,
+        batching: Batching,
+        target: H::Target,
+    ) -> Result<Self, Error>
+    where
+        E: EventSourced,
+        E::Evt: 'static,
+        L: EvtLog + Sync,
+        B: Binarize<E::Evt, E::State>,
+        H: EvtHandler<Evt = E::Evt> + Clone + Send + Sync + 'static,
+    {
+        let source = Source::new::<E, _, _, _>(evt_log, binarize, |evt| evt);
+        Self::with_sources(
+            name,
+            vec![source],
+            evt_handler,
+            error_strategy,
+            batching,
+            Partitioning::default(),
+            target,
+        )
+        .await
+    }
+
+    /// Create a [Projection] of the events of the given [Source]s handled by the given
+    /// [EvtHandler].
+    ///
+    /// The sequence number of each source is tracked separately. The events of all sources are
+    /// handled in the order of their sequence numbers, ties broken by the order of the sources.
+    /// Hence the events of sources sharing an [EvtLog] are handled in the order of their positions
+    /// in that event log, whereas the sequence numbers of sources from different event logs are
+    /// unrelated, yet still deterministically interleaved.
+    ///
+    /// Events are handled in batches according to the given [Batching], each batch in one
+    /// transaction of the given [Target], by one or more parallel workers according to the given
+    /// [Partitioning].
+    pub async fn with_sources<H>(
+        name: String,
+        sources: Vec<Source<H::Evt>>,
+        evt_handler: H,
+        error_strategy: ErrorStrategy,
+        batching: Batching,
+        partitioning: Partitioning,
+        target: H::Target,
+    ) -> Result<Self, Error>
+    where
+        H: EvtHandler + Clone + Send + Sync + 'static,
+        H::Evt: Send + 'static,
+    {
+        Self::spawn::<H, NoLease>(
+            name,
+            sources,
+            evt_handler,
+            error_strategy,
+            batching,
+            partitioning,
+            target,
+            None,
+        )
+        .await
+    }
+
+    /// Create a [Projection] like [with_sources](Projection::with_sources), which is only run by
+    /// the replica currently elected as leader by the given [LeaderElection], i.e. which holds its
+    /// [Lease]. Running or stopping it takes part in or withdraws from the election; rebuilding it
+    /// is ignored unless it is the leader.
+    #[allow(clippy::too_many_arguments)]
+    pub async fn with_leader_election<H, L>(
+        name: String,
+        sources: Vec<Source<H::Evt>>,
+        evt_handler: H,
+        error_strategy: ErrorStrategy,
+        batching: Batching,
+        partitioning: Partitioning,
+        target: H::Target,
+        leader_election: LeaderElection<L>,
+    ) -> Result<Self, Error>
+    where
+        H: EvtHandler + Clone + Send + Sync + 'static,
+        H::Evt: Send + 'static,
+        L: Lease,
+    {
+        leader_election
+            .lease
+            .init()
+            .await
+            .map_err(|error| Error::Lease(error.into()))?;
+
+        Self::spawn(
+            name,
+            sources,
+            evt_handler,
+            error_strategy,
+            batching,
+            partitioning,
+            target,
+            Some(leader_election),
+        )
+        .await
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    async fn spawn<H, L>(
+        name: String,
+        sources: Vec<Source<H::Evt>>,
+        evt_handler: H,
+        error_strategy: ErrorStrategy,
+        batching: Batching,
+        partitioning: Partitioning,
+        target: H::Target,
+        leader_election: Option<LeaderElection<L>>,
+    ) -> Result<Self, Error>
+    where
+        H: EvtHandler + Clone + Send + Sync + 'static,
+        H::Evt: Send + 'static,
+        L: Lease,
+    {
+        let mut source_names = HashSet::new();
+        if let Some(source) = sources
+            .iter()
+            .find(|source| !source_names.insert(source.name()))
+        {
+            return Err(Error::DuplicateSource(source.name().to_string()));
+        }
+        let workers = partitioning.workers.get();
+        if workers > PARTITION_SLOTS {
+            return Err(Error::TooManyWorkers(workers));
+        }
+        if workers > 1 {
+            for source in sources.iter().filter(|source| source.is_unpartitioned()) {
+                warn!(
+                    name,
+                    source = source.name(),
+                    "source by tag has no partition key, hence all its events are handled by one \
+                     worker"
+                );
+            }
+        }
+
+        target.init().await.map_err(target_error)?;
+
+        let seq_nos = target
+            .load_seq_nos(&name, false)
+            .await
+            .map_err(target_error)?;
+        let version = match target.load_version(&name).await.map_err(target_error)? {
+            Some(version) => version,
+
+            // Projections created before versioning was introduced have version 0, new ones
+            // are set up with the current version.
+            None => {
+                let version = if seq_nos.is_empty() { H::VERSION } else { 0 };
+
+                let mut tx = target.begin(&name, false).await.map_err(target_error)?;
+                if seq_nos.is_empty() {
+                    evt_handler
+                        .setup(&mut tx)
+                        .await
+                        .map_err(|error| Error::Setup(error.into()))?;
+                }
+                target
+                    .save_version(&mut tx, &name, version)
+                    .await
+                    .map_err(target_error)?;
+                target.commit(tx).await.map_err(target_error)?;
+
+                version
+            }
+        };
+
+        // Projections created before sources were introduced have their sequence number saved
+        // under the empty source name. It is no position in the event log, hence cannot be
+        // adopted, but such projections are rebuilt when run.
+        let legacy = seq_nos.contains_key(LEGACY_SOURCE);
+        if legacy {
+            warn!(
+                name,
+                "projection has legacy sequence number and must be rebuilt"
+            );
+        }
+
+        let (seq_nos, worker_seq_nos) =
+            positions(&seq_nos, sources.iter().map(Source::name), workers);
+        let (state, state_out) = watch::channel(State {
+            seq_nos,
+            worker_seq_nos,
+            heads: BTreeMap::new(),
+            version,
+            legacy,
+            running: false,
+            rebuilding: false,
+            leader: leader_election.is_none(),
+            error: None,
+            error_at: None,
+            retries: 0,
+            evt_count: 0,
+            batch_count: 0,
+            handled_at: None,
+            behind_since: None,
+        });
+        let state = Arc::new(state);
+
+        let runner = Runner {
+            name: name.clone(),
+            state: state.clone(),
+            sources: Arc::new(sources),
+            evt_handler,
+            target,
+            error_strategy,
+            batching,
+            partitioning,
+        };
+
+        let (cmd_in, mut cmd_out) = mpsc::channel::<(Cmd, oneshot::Sender<State>)>(1);
+
+        task::spawn({
+            let name = name.clone();
+
+            async move {
+                // Shared with the election task, if any, which runs the projection while leader.
+                let projection_task = Arc::new(Mutex::new(None::<JoinHandle<()>>));
+                let mut election_task = None::<JoinHandle<()>>;
+                let heads_task = runner.spawn_heads();
+
+                while let Some((cmd, reply_in)) = cmd_out.recv().await {
+                    match cmd {
+                        Cmd::Run => {
+                            let running = state.borrow().running;
+                            if running {
+                                info!(name, "projection already running");
+                            } else {
+                                info!(name, "running projection");
+
+                                // A stopped projection task may still be waiting for events.
+                                if let Some(projection_task) = projection_task.lock().await.take() {
+                                    projection_task.abort();
+                                }
+
+                                match &leader_election {
+                                    Some(leader_election) => {
+                                        state.send_modify(|state| {
+                                            state.running = true;
+                                            state.clear_error();
+                                        });
+
+                                        election_task = Some(run_election(
+                                            runner.clone(),
+                                            leader_election.clone(),
+                                            projection_task.clone(),
+                                        ));
+                                    }
+
+                                    None => {
+                                        let mut rebuild = false;
+                                        state.send_modify(|state| {
+                                            state.running = true;
+                                            state.clear_error();
+                                            state.rebuilding =
+                                                state.version != H::VERSION || state.legacy;
+                                            rebuild = state.rebuilding;
+                                        });
+                                        if rebuild {
+                                            info!(name, "projection outdated, rebuilding");
+                                        }
+
+                                        *projection_task.lock().await =
+                                            Some(runner.spawn(rebuild, None));
+                                    }
+                                }
+                            }
+
+                            if reply_in.send(state.borrow().clone()).is_err() {
+                                error!(name, "cannot send state");
+                            }
+                        }
+
+                        Cmd::Rebuild => {
+                            // Prevent the election task from replacing the projection task.
+                            let mut projection_task = projection_task.lock().await;
+
+                            let (running, rebuilding, leader) = {
+                                let state = state.borrow();
+                                (state.running, state.rebuilding, state.leader)
+                            };
+                            if rebuilding {
+                                info!(name, "projection already rebuilding");
+                            } else if !leader {
+                                info!(name, "projection not leader, not rebuilding");
+                            } else {
+                                info!(name, "rebuilding projection");
+
+                                // A running projection keeps serving until the rebuild is swapped
+                                // in, a stopped one may still be waiting for events.
+                                let live_task = projection_task.take().and_then(|task| {
+                                    if running {
+                                        Some(task)
+                                    } else {
+                                        task.abort();
+                                        None
+                                    }
+                                });
+
+                                state.send_modify(|state| {
+                                    state.running = true;
+                                    state.rebuilding = true;
+                                    state.clear_error();
+                                });
+
+                                *projection_task = Some(runner.spawn(true, live_task));
+                            }
+
+                            if reply_in.send(state.borrow().clone()).is_err() {
+                                error!(name, "cannot send state");
+                            }
+                        }
+
+                        Cmd::Stop => {
+                            let running = state.borrow().running;
+                            if running {
+                                info!(name, "stopping projection");
+                                state.send_modify(|state| {
+                                    state.running = false;
+                                    state.rebuilding = false;
+                                });
+
+                                // Withdraw from the election, letting another replica take over.
+                                if let Some(leader_election) = &leader_election {
+                                    if let Some(election_task) = election_task.take() {
+                                        election_task.abort();
+                                    }
+                                    if let Some(projection_task) =
+                                        projection_task.lock().await.take()
+                                    {
+                                        projection_task.abort();
+                                        let _ = projection_task.await;
+                                    }
+                                    state.send_modify(|state| state.leader = false);
+
+                                    let LeaderElection { lease, holder, .. } = leader_election;
+                                    if let Err(error) = lease.release(&name, holder).await {
+                                        error!(
+                                            error = error.as_chain(),
+                                            name, "cannot release projection lease"
+                                        );
+                                    }
+                                }
+                            } else {
+                                info!(name, "projection already stopped");
+                            }
+
+                            if reply_in.send(state.borrow().clone()).is_err() {
+                                error!(name, "cannot send state");
+                            }
+                        }
+
+                        Cmd::ReplayDeadLetters => {
+                            info!(name, "replaying dead-lettered events of projection");
+                            match runner.replay_dead_letters().await {
+                                Ok(count) => {
+                                    info!(
+                                        name,
+                                        count, "replayed dead-lettered events of projection"
+                                    )
+                                }
+                                Err(error) => error!(
+                                    error = error.as_chain(),
+                                    name, "cannot replay dead-lettered events of projection"
+                                ),
+                            }
+
+                            if reply_in.send(state.borrow().clone()).is_err() {
+                                error!(name, "cannot send state");
+                            }
+                        }
+
+                        Cmd::GetState => {
+                            if reply_in.send(state.borrow().clone()).is_err() {
+                                error!(name, "cannot send state");
+                            }
+                        }
+                    }
+                }
+
+                // All Projection handles have been dropped.
+                heads_task.abort();
+            }
+        });
+
+        Ok(Projection {
+            name,
+            cmd_in,
+            state: state_out,
+        })
+    }
+
+    pub async fn run(&self) -> Result<State, CmdError> {
+        self.dispatch_cmd(Cmd::Run).await
+    }
+
+    /// Rebuild this projection from the beginning into a shadow copy of the tables of the
+    /// [EvtHandler] and swap it in once caught up, i.e. once the events up to the last ones of the
+    /// [Source]s at rebuild start have been handled, atomically if the [Target] supports that;
+    /// afterwards the projection keeps running. If running, the projection keeps
+    /// serving from the current tables until the swap.
+    ///
+    /// A rebuild is also triggered when running this projection with an [EvtHandler] whose
+    /// [VERSION](LocalEvtHandler::VERSION) differs from the persisted one, or which has a legacy
+    /// sequence number from before projections had [Source]s; then the current tables keep
+    /// serving, yet are no longer updated until the swap.
+    pub async fn rebuild(&self) -> Result<State, CmdError> {
+        self.dispatch_cmd(Cmd::Rebuild).await
+    }
+
+    pub async fn stop(&self) -> Result<State, CmdError> {
+        self.dispatch_cmd(Cmd::Stop).await
+    }
+
+    /// Handle the events dead-lettered according to [ErrorStrategy::DeadLetter] again, e.g. after
+    /// a fix has been deployed, deleting the [DeadLetter]s of those handled successfully; see
+    /// [Target::load_dead_letters] to list them.
+    ///
+    /// Dead-lettered events are handled in their original order, yet after the events following
+    /// them, hence the [EvtHandler] must tolerate that.
+    pub async fn replay_dead_letters(&self) -> Result<State, CmdError> {
+        self.dispatch_cmd(Cmd::ReplayDeadLetters).await
+    }
+
+    pub async fn get_state(&self) -> Result<State, CmdError> {
+        self.dispatch_cmd(Cmd::GetState).await
+    }
+
+    /// Subscribe to changes of the [State] of this projection, e.g. to alert on stalled
+    /// projections.
+    pub fn watch(&self) -> watch::Receiver<State> {
+        self.state.clone()
+    }
+
+    /// Wait until this projection has handled and committed the event at the given position of
+    /// the [Source] with the given name, e.g. the
+    /// [position](eventsourced::Persisted::position) returned from
+    /// [EntityRef::handle_cmd](eventsourced::EntityRef::handle_cmd), for read-your-writes
+    /// consistency; fails if that takes longer than the given duration.
+    ///
+    /// The positions of events are their sequence numbers in the events by type, which are
+    /// tracked for the sources. Sources by tag are tracked by the sequence numbers of the events
+    /// by tag, which are the positions, too, unless documented otherwise by the [EvtLog].
+    pub async fn await_position(
+        &self,
+        source: &str,
+        position: NonZeroU64,
+        within: Duration,
+    ) -> Result<State, AwaitPositionError> {
+        let mut state = self.state.clone();
+        let reached = state.wait_for(|state| state.seq_no(source) >= Some(position));
+        let reached = timeout(within, reached)
+            .await
+            .map(|state| state.map(|state| state.clone()));
+        match reached {
+            Ok(Ok(state)) => Ok(state),
+            Ok(Err(_)) => Err(AwaitPositionError::Terminated(self.name.clone())),
+            Err(_) => Err(AwaitPositionError::Timeout(
+                self.name.clone(),
+                source.to_string(),
+                position,
+            )),
+        }
+    }
+
+    async fn dispatch_cmd(&self, cmd: Cmd) -> Result<State, CmdError> {
+        let (reply_in, reply_out) = oneshot::channel();
+        self.cmd_in
+            .send((cmd, reply_in))
+            .await
+            .map_err(|_| CmdError::SendCmd(cmd, self.name.clone()))?;
+        let state = reply_out
+            .await
+            .map_err(|_| CmdError::ReceiveResponse(cmd, self.name.clone()))?;
+        Ok(state)
+    }
+}
+
+#[trait_variant::make(EvtHandler: Send)]
+pub trait LocalEvtHandler {
+    /// Event type, e.g. the event type of an [EventSourced] entity type or an enum over the event
+    /// types of several ones.
+    type Evt;
+
+    type Error: StdError + Send + Sync + 'static;
+
+    /// The [Target] this event handler writes to.
+    type Target: Target;
+
+    /// Version of this event handler; changing it triggers a rebuild of the projection.
+    const VERSION: u32 = 0;
+
+    /// Names of the tables, or whatever the [Target] uses instead, this event handler writes to,
+    /// which are rebuilt in a shadow copy and swapped in when rebuilding the projection.
+    const TABLES: &'static [&'static str] = &[];
+
+    /// Create the tables this event handler writes to, if needed: when a projection is created for
+    /// the first time and in the shadow copy when it is rebuilt; see the respective [Target] for
+    /// how the shadow copy is addressed. Tables from [TABLES](LocalEvtHandler::TABLES) not created
+    /// here are created like the current ones, if the [Target] supports that.
+    fn setup(
+        &self,
+        _tx: &mut <Self::Target as Target>::Tx,
+    ) -> impl Future<Output = Result<(), Self::Error>>
+    where
+        Self: Sync,
+    {
+        async { Ok(()) }
+    }
+
+    async fn handle_evt(
+        &self,
+        evt: Self::Evt,
+        tx: &mut <Self::Target as Target>::Tx,
+    ) -> Result<(), Self::Error>;
+
+    /// Handle a batch of events in one transaction, e.g. using a multi-row `INSERT` or `COPY`.
+    /// Defaults to invoking [handle_evt](LocalEvtHandler::handle_evt) for each event.
+    fn handle_batch(
+        &self,
+        evts: Vec<Self::Evt>,
+        tx: &mut <Self::Target as Target>::Tx,
+    ) -> impl Future<Output = Result<(), Self::Error>>
+    where
+        Self: Sync,
+        Self::Evt: Send,
+    {
+        async move {
+            for evt in evts {
+                self.handle_evt(evt, tx).await?;
+            }
+            Ok(())
+        }
+    }
+}
+
+/// A target of a [Projection], e.g. a database: provides the transactions passed to the
+/// [EvtHandler] and stores sequence numbers and versions of projections, ideally within these
+/// transactions.
+///
+/// To rebuild a projection, a target provides a shadow copy of the tables an [EvtHandler] writes
+/// to, which can be swapped in once caught up.
+pub trait Target: Clone + Send + Sync + 'static {
+    /// Transaction passed to the [EvtHandler].
+    type Tx: Send;
+
+    type Error: StdError + Send + Sync + 'static;
+
+    /// Initialize this target, e.g. create the tables for sequence numbers and versions.
+    fn init(&self) -> impl Future<Output = Result<(), Self::Error>> + Send;
+
+    /// Load the sequence numbers by [Source] name of the projection with the given name or of its
+    /// shadow copy.
+    fn load_seq_nos(
+        &self,
+        name: &str,
+        shadow: bool,
+    ) -> impl Future<Output = Result<BTreeMap<String, NonZeroU64>, Self::Error>> + Send;
+
+    /// Load the version of the projection with the given name.
+    fn load_version(
+        &self,
+        name: &str,
+    ) -> impl Future<Output = Result<Option<u32>, Self::Error>> + Send;
+
+    /// Begin a transaction for the projection with the given name or for its shadow copy.
+    fn begin(
+        &self,
+        name: &str,
+        shadow: bool,
+    ) -> impl Future<Output = Result<Self::Tx, Self::Error>> + Send;
+
+    /// Save the sequence number of the given [Source] for the projection with the given name or
+    /// for its shadow copy.
+    fn save_seq_no(
+        &self,
+        tx: &mut Self::Tx,
+        name: &str,
+        shadow: bool,
+        source: &str,
+        seq_no: NonZeroU64,
+    ) -> impl Future<Output = Result<(), Self::Error>> + Send;
+
+    /// Save the version of the projection with the given name.
+    fn save_version(
+        &self,
+        tx: &mut Self::Tx,
+        name: &str,
+        version: u32,
+    ) -> impl Future<Output = Result<(), Self::Error>> + Send;
+
+    /// Commit the given transaction.
+    fn commit(&self, tx: Self::Tx) -> impl Future<Output = Result<(), Self::Error>> + Send;
+
+    /// (Re)create an empty shadow copy of the given tables and sequence numbers of the projection
+    /// with the given name, i.e. drop an existing one.
+    fn create_shadow(
+        &self,
+        name: &str,
+        tables: &[&str],
+    ) -> impl Future<Output = Result<(), Self::Error>> + Send;
+
+    /// Create those of the given tables in the shadow copy which have not been created by
+    /// [EvtHandler::setup], e.g. like the current ones.
+    fn create_shadow_tables(
+        &self,
+        name: &str,
+        tables: &[&str],
+    ) -> impl Future<Output = Result<(), Self::Error>> + Send;
+
+    /// Save the given [DeadLetter] for the projection with the given name, replacing an existing
+    /// one for the same event. Dead letters are not part of the shadow copy.
+    fn save_dead_letter(
+        &self,
+        tx: &mut Self::Tx,
+        name: &str,
+        dead_letter: &DeadLetter,
+    ) -> impl Future<Output = Result<(), Self::Error>> + Send;
+
+    /// Delete the [DeadLetter] for the event of the given [Source] with the given sequence number
+    /// of the projection with the given name.
+    fn delete_dead_letter(
+        &self,
+        tx: &mut Self::Tx,
+        name: &str,
+        source: &str,
+        seq_no: NonZeroU64,
+    ) -> impl Future<Output = Result<(), Self::Error>> + Send;
+
+    /// Load the [DeadLetter]s of the projection with the given name, ordered by [Source] name and
+    /// sequence number.
+    fn load_dead_letters(
+        &self,
+        name: &str,
+    ) -> impl Future<Output = Result<Vec<DeadLetter>, Self::Error>> + Send;
+
+    /// Replace the given tables and the sequence numbers of the projection with the given name by
+    /// their shadow copy, ideally atomically, and save the given version.
+    fn swap_shadow(
+        &self,
+        name: &str,
+        tables: &[&str],
+        version: u32,
+    ) -> impl Future<Output = Result<(), Self::Error>> + Send;
+}
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("cannot create Projection, b/c of a target error")]
+    Target(#[source] BoxError),
+
+    #[error("cannot create Projection, b/c source name {0} is not unique")]
+    DuplicateSource(String),
+
+    #[error("cannot create Projection, b/c cannot set up tables")]
+    Setup(#[source] BoxError),
+
+    #[error("cannot create Projection, b/c of a lease error")]
+    Lease(#[source] BoxError),
+
+    #[error("cannot create Projection, b/c {0} workers exceed {PARTITION_SLOTS} partition slots")]
+    TooManyWorkers(usize),
+}
+
+#[derive(Debug, Error, Serialize, Deserialize)]
+pub enum CmdError {
+    /// The command cannot be sent from this [Projection] to its projection.
+    #[error("cannot send command {0:?} to projection {1}")]
+    SendCmd(Cmd, String),
+
+    /// A response for the command cannot be received from this [Projection]'s projection.
+    #[error("cannot receive reply for command {0:?} from projection {1}")]
+    ReceiveResponse(Cmd, String),
+}
+
+/// Error from [Projection::await_position].
+#[derive(Debug, Error, Serialize, Deserialize)]
+pub enum AwaitPositionError {
+    /// The projection has not reached the position in time.
+    #[error("projection {0} has not reached position {2} of source {1} in time")]
+    Timeout(String, String, NonZeroU64),
+
+    /// The projection has terminated.
+    #[error("projection {0} has terminated")]
+    Terminated(String),
+}
+
+/// What to do when handling events fails.
+#[derive(Debug, Clone, Copy)]
+pub enum ErrorStrategy {
+    /// Retry after the given delay, forever.
+    Retry(Duration),
+
+    /// Stop the projection.
+    Stop,
+
+    /// Retry failed batches of events according to the given [Backoff]; once exhausted, handle
+    /// their events one at a time and skip those which fail. Events which cannot be decoded are
+    /// skipped right away. Other errors, e.g. of the [Target], are retried forever.
+    Skip(Backoff),
+
+    /// Like [Skip](ErrorStrategy::Skip), yet skipped events are recorded as [DeadLetter]s, which
+    /// can be replayed via [Projection::replay_dead_letters].
+    DeadLetter(Backoff),
+}
+
+/// Exponential backoff with jitter: retry `n` is delayed by a random duration between half and all
+/// of `min_delay * 2^(n-1)`, yet at most `max_delay`.
+#[derive(Debug, Clone, Copy)]
+pub struct Backoff {
+    pub max_retries: u32,
+    pub min_delay: Duration,
+    pub max_delay: Duration,
+}
+
+impl Backoff {
+    pub(crate) fn delay(&self, retry: u32) -> Duration {
+        let factor = 2_u32.saturating_pow(retry.saturating_sub(1));
+        let delay = self.min_delay.saturating_mul(factor).min(self.max_delay);
+        delay.mul_f64(0.5 + fastrand::f64() / 2.0)
+    }
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Self {
+            max_retries: 5,
+            min_delay: Duration::from_millis(100),
+            max_delay: Duration::from_secs(30),
+        }
+    }
+}
+
+/// An event which could not be handled or decoded, recorded according to
+/// [ErrorStrategy::DeadLetter].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DeadLetter {
+    pub source: String,
+    pub seq_no: NonZeroU64,
+    pub error: String,
+}
+
+/// Batching of events: up to `max_size` events, yet no more than those available within
+/// `max_delay` after the first one, are handled in one transaction.
+///
+/// The default is a `max_size` of one, i.e. no batching.
+#[derive(Debug, Clone, Copy)]
+pub struct Batching {
+    pub max_size: NonZeroUsize,
+    pub max_delay: Duration,
+}
+
+impl Default for Batching {
+    fn default() -> Self {
+        Self {
+            max_size: NonZeroUsize::MIN,
+            max_delay: Duration::ZERO,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct State {
+    seq_nos: BTreeMap<String, NonZeroU64>,
+    worker_seq_nos: Vec<BTreeMap<String, NonZeroU64>>,
+    heads: BTreeMap<String, NonZeroU64>,
+    version: u32,
+    #[serde(skip)]
+    legacy: bool,
+    running: bool,
+    rebuilding: bool,
+    leader: bool,
+    error: Option<String>,
+    error_at: Option<SystemTime>,
+    retries: u32,
+    evt_count: u64,
+    batch_count: u64,
+    handled_at: Option<SystemTime>,
+    behind_since: Option<SystemTime>,
+}
+
+impl State {
+    /// The sequence number of the last handled event of the [Source] with the given name.
+    pub fn seq_no(&self, source: &str) -> Option<NonZeroU64> {
+        self.seq_nos.get(source).copied()
+    }
+
+    /// The sequence numbers of the last handled events by [Source] name.
+    pub fn seq_nos(&self) -> &BTreeMap<String, NonZeroU64> {
+        &self.seq_nos
+    }
+
+    /// The sequence numbers of the last handled events by [Source] name for each worker of a
+    /// projection with [Partitioning]; empty without.
+    pub fn worker_seq_nos(&self) -> &[BTreeMap<String, NonZeroU64>] {
+        &self.worker_seq_nos
+    }
+
+    /// The sequence number of the last event of the [Source] with the given name, i.e. the head of
+    /// its event log, as last polled every [HEADS_INTERVAL] while running.
+    pub fn head(&self, source: &str) -> Option<NonZeroU64> {
+        self.heads.get(source).copied()
+    }
+
+    /// The heads of the event logs by [Source] name.
+    pub fn heads(&self) -> &BTreeMap<String, NonZeroU64> {
+        &self.heads
+    }
+
+    /// The difference between the head and the sequence number of the last handled event of the
+    /// [Source] with the given name, i.e. the number of events to be handled if the sequence
+    /// numbers are dense, else an upper bound.
+    pub fn lag(&self, source: &str) -> u64 {
+        let head = self.head(source).map(NonZeroU64::get).unwrap_or_default();
+        let seq_no = self.seq_no(source).map(NonZeroU64::get).unwrap_or_default();
+        head.saturating_sub(seq_no)
+    }
+
+    /// For how long this projection has been lagging behind the heads of its [Source]s without
+    /// catching up; zero if caught up.
+    pub fn time_lag(&self) -> Duration {
+        self.behind_since
+            .and_then(|behind_since| behind_since.elapsed().ok())
+            .unwrap_or_default()
+    }
+
+    /// Whether this projection is running, is not retrying after an error and has not been lagging
+    /// behind for longer than the given duration, e.g. for health checks.
+    pub fn healthy(&self, max_time_lag: Duration) -> bool {
+        self.running && self.retries == 0 && self.time_lag() <= max_time_lag
+    }
+
+    /// The persisted [VERSION](LocalEvtHandler::VERSION) of the event handler.
+    pub fn version(&self) -> u32 {
+        self.version
+    }
+
+    pub fn running(&self) -> bool {
+        self.running
+    }
+
+    /// Whether this projection is being rebuilt.
+    pub fn rebuilding(&self) -> bool {
+        self.rebuilding
+    }
+
+    /// Whether this replica is the leader elected to run this projection, i.e. holds its [Lease];
+    /// always `true` for projections without [LeaderElection].
+    pub fn leader(&self) -> bool {
+        self.leader
+    }
+
+    /// The last error, if any, since this [Projection] has been run.
+    pub fn error(&self) -> Option<&str> {
+        self.error.as_deref()
+    }
+
+    /// When the last error occurred.
+    pub fn error_at(&self) -> Option<SystemTime> {
+        self.error_at
+    }
+
+    /// The number of consecutive errors, i.e. retries, since events have last been handled.
+    pub fn retries(&self) -> u32 {
+        self.retries
+    }
+
+    /// The number of events, including skipped ones, handled since this [Projection] was created.
+    pub fn evt_count(&self) -> u64 {
+        self.evt_count
+    }
+
+    /// The number of batches handled since this [Projection] was created.
+    pub fn batch_count(&self) -> u64 {
+        self.batch_count
+    }
+
+    /// When events have last been handled.
+    pub fn handled_at(&self) -> Option<SystemTime> {
+        self.handled_at
+    }
+
+    fn clear_error(&mut self) {
+        self.error = None;
+        self.error_at = None;
+        self.retries = 0;
+    }
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub enum Cmd {
+    Run,
+    Rebuild,
+    Stop,
+    ReplayDeadLetters,
+    GetState,
+}
+
+#[derive(Debug, Error)]
+enum IntenalRunError<H, T> {
+    #[error(transparent)]
+    Evts(BoxError),
+
+    #[error(transparent)]
+    Handler(H),
+
+    #[error("cannot handle batch of {1} events")]
+    Batch(#[source] H, usize),
+
+    #[error(transparent)]
+    Target(T),
+}
+
+fn target_error<E>(error: E) -> Error
+where
+    E: StdError + Send + Sync + 'static,
+{
+    Error::Target(error.into())
+}
+
+/// Everything needed to spawn the projection task.
+struct Runner<H>
+where
+    H: EvtHandler,
+{
+    name: String,
+    state: Arc<watch::Sender<State>>,
+    sources: Arc<Vec<Source<H::Evt>>>,
+    evt_handler: H,
+    target: H::Target,
+    error_strategy: ErrorStrategy,
+    batching: Batching,
+    partitioning: Partitioning,
+}
+
+impl<H> Clone for Runner<H>
+where
+    H: EvtHandler + Clone,
+{
+    fn clone(&self) -> Self {
+        Self {
+            name: self.name.clone(),
+            state: self.state.clone(),
+            sources: self.sources.clone(),
+            evt_handler: self.evt_handler.clone(),
+            target: self.target.clone(),
+            error_strategy: self.error_strategy,
+            batching: self.batching,
+            partitioning: self.partitioning,
+        }
+    }
+}
+
+impl<H> Runner<H>
+where
+    H: EvtHandler + Clone + Sync + 'static,
+    H::Evt: Send + 'static,
+{
+    /// Spawn the projection task, rebuilding first if requested, in which case the given live
+    /// task, if any, is aborted right before swapping in the rebuild.
+    fn spawn(&self, mut rebuild: bool, mut live_task: Option<JoinHandle<()>>) -> JoinHandle<()> {
+        let runner = self.clone();
+
+        task::spawn({
+            async move {
+                let name = &runner.name;
+                let mut retries = Retries::default();
+
+                loop {
+                    let result = if rebuild {
+                        runner
+                            .rebuild_projection(&mut retries, &mut live_task)
+                            .await
+                    } else {
+                        runner
+                            .run_projection(false, &[], &mut retries)
+                            .await
+                            .map(|_| false)
+                    };
+
+                    match result {
+                        Ok(true) => {
+                            info!(name, "projection rebuilt");
+                            rebuild = false;
+                        }
+
+                        Ok(false) => {
+                            info!(name, "projection stopped");
+                            break;
+                        }
+
+                        Err(error) => {
+                            if !runner.handle_error(error, &mut retries).await {
+                                break;
+                            }
+                        }
+                    }
+                }
+            }
+        })
+    }
+
+    /// Handle the given error of the projection task or of a worker according to the
+    /// [ErrorStrategy]. Returns whether to retry, i.e. `false` if stopped.
+    async fn handle_error(
+        &self,
+        error: IntenalRunError<H::Error, <H::Target as Target>::Error>,
+        retries: &mut Retries,
+    ) -> bool {
+        let Runner {
+            name,
+            state,
+            error_strategy,
+            ..
+        } = self;
+
+        error!(error = error.as_chain(), name, "projection error");
+        state.send_modify(|state| {
+            state.error = Some(error.as_chain());
+            state.error_at = Some(SystemTime::now());
+            state.retries = state.retries.saturating_add(1);
+        });
+
+        match error_strategy {
+            ErrorStrategy::Retry(delay) => {
+                info!(name, ?delay, "projection retrying after error");
+                sleep(*delay).await;
+            }
+
+            ErrorStrategy::Stop => {
+                info!(name, "projection stopped after error");
+                state.send_modify(|state| {
+                    state.running = false;
+                    state.rebuilding = false;
+                });
+                return false;
+            }
+
+            ErrorStrategy::Skip(backoff) | ErrorStrategy::DeadLetter(backoff) => {
+                retries.count = retries.count.saturating_add(1);
+
+                // Find the failing events of a batch by handling its events one at a time.
+                match error {
+                    IntenalRunError::Batch(_, evt_count) if retries.count > backoff.max_retries => {
+                        info!(name, evt_count, "projection handling events one at a time");
+                        retries.count = 0;
+                        retries.isolate = evt_count;
+                    }
+
+                    _ => {
+                        let delay = backoff.delay(retries.count);
+                        info!(
+                            name,
+                            ?delay,
+                            retry = retries.count,
+                            "projection retrying after error"
+                        );
+                        sleep(delay).await
+                    }
+                }
+            }
+        }
+
+        true
+    }
+
+    /// Spawn a task polling the heads of the sources every [HEADS_INTERVAL] while running and
+    /// leader, updating the lag of the projection.
+    fn spawn_heads(&self) -> JoinHandle<()> {
+        let Runner {
+            name,
+            state,
+            sources,
+            ..
+        } = self.clone();
+
+        task::spawn(async move {
+            loop {
+                let active = {
+                    let state = state.borrow();
+                    state.running && state.leader
+                };
+
+                if active {
+                    let mut heads = BTreeMap::new();
+                    for source in sources.iter() {
+                        match source.head().await {
+                            Ok(head) => {
+                                heads.insert(source.name().to_string(), head);
+                            }
+
+                            Err(error) => {
+                                let source = source.name();
+                                warn!(%error, name, source, "cannot get head of projection source")
+                            }
+                        }
+                    }
+
+                    state.send_modify(|state| {
+                        for (source, head) in heads {
+                            match head {
+                                Some(head) => state.heads.insert(source, head),
+                                None => state.heads.remove(&source),
+                            };
+                        }
+
+                        let behind = state
+                            .heads
+                            .iter()
+                            .any(|(source, head)| state.seq_nos.get(source) < Some(head));
+                        if !behind {
+                            state.behind_since = None;
+                        } else if state.behind_since.is_none() {
+                            state.behind_since = Some(SystemTime::now());
+                        }
+                    });
+                }
+
+                sleep(HEADS_INTERVAL).await;
+            }
+        })
+    }
+
+    /// Rebuild the projection from the beginning into a shadow copy and swap it in once caught
+    /// up, aborting the given live task, if any, right before. Returns whether the rebuild has
+    /// been swapped in, i.e. `false` if the projection has been stopped.
+    async fn rebuild_projection(
+        &self,
+        retries: &mut Retries,
+        live_task: &mut Option<JoinHandle<()>>,
+    ) -> Result<bool, IntenalRunError<H::Error, <H::Target as Target>::Error>> {
+        let Runner {
+            name,
+            state,
+            evt_handler: handler,
+            target,
+            ..
+        } = self;
+
+        // Create the shadow copy from scratch.
+        target
+            .create_shadow(name, H::TABLES)
+            .await
+            .map_err(IntenalRunError::Target)?;
+        let mut tx = target
+            .begin(name, true)
+            .await
+            .map_err(IntenalRunError::Target)?;
+        handler
+            .setup(&mut tx)
+            .await
+            .map_err(IntenalRunError::Handler)?;
+        target.commit(tx).await.map_err(IntenalRunError::Target)?;
+        target
+            .create_shadow_tables(name, H::TABLES)
+            .await
+            .map_err(IntenalRunError::Target)?;
+        debug!(name, "projection created shadow copy");
+
+        // The shadow copy is caught up once it has reached the heads at rebuild start.
+        let mut heads = Vec::with_capacity(self.sources.len());
+        for source in self.sources.iter() {
+            let head = source.head().await.map_err(IntenalRunError::Evts)?;
+            heads.push(head);
+        }
+        self.run_projection(true, &heads, retries).await?;
+        if !state.borrow().running {
+            return Ok(false);
+        }
+
+        // Swap the shadow copy in.
+        if let Some(live_task) = live_task.take() {
+            live_task.abort();
+            let _ = live_task.await;
+        }
+        target
+            .swap_shadow(name, H::TABLES, H::VERSION)
+            .await
+            .map_err(IntenalRunError::Target)?;
+
+        let seq_nos = target
+            .load_seq_nos(name, false)
+            .await
+            .map_err(IntenalRunError::Target)?;
+        let (seq_nos, worker_seq_nos) = self.positions(&seq_nos);
+        state.send_modify(|state| {
+            state.seq_nos = seq_nos;
+            state.worker_seq_nos = worker_seq_nos;
+            state.version = H::VERSION;
+            state.legacy = false;
+            state.rebuilding = false;
+        });
+
+        Ok(true)
+    }
+
+    /// Handle events until stopped. When handling events for the shadow copy, i.e. when
+    /// rebuilding, only until caught up, i.e. until the given heads of the sources, which are
+    /// ignored otherwise, have been reached.
+    ///
+    /// With [Partitioning], the workers are run in parallel, each handling its errors itself.
+    async fn run_projection(
+        &self,
+        shadow: bool,
+        heads: &[Option<NonZeroU64>],
+        retries: &mut Retries,
+    ) -> Result<(), IntenalRunError<H::Error, <H::Target as Target>::Error>> {
+        let workers = self.partitioning.workers.get();
+        if workers == 1 {
+            return self.run_partition(shadow, heads, 0, retries).await;
+        }
+
+        // Dropping the workers, e.g. when the projection task is aborted, aborts them.
+        let mut tasks = JoinSet::new();
+        for worker in 0..workers {
+            let runner = self.clone();
+            let heads = heads.to_vec();
+            tasks.spawn(async move {
+                let mut retries = Retries::default();
+                while let Err(error) = runner
+                    .run_partition(shadow, &heads, worker, &mut retries)
+                    .await
+                {
+                    if !runner.handle_error(error, &mut retries).await {
+                        break;
+                    }
+                }
+            });
+        }
+        while tasks.join_next().await.is_some() {}
+
+        Ok(())
+    }
+
+    /// The sequence numbers up to which all events have been handled, overall and per worker, from
+    /// the given saved ones.
+    fn positions(
+        &self,
+        seq_nos: &BTreeMap<String, NonZeroU64>,
+    ) -> (
+        BTreeMap<String, NonZeroU64>,
+        Vec<BTreeMap<String, NonZeroU64>>,
+    ) {
+        let sources = self.sources.iter().map(Source::name);
+        positions(seq_nos, sources, self.partitioning.workers.get())
+    }
+
+    /// Handle the events of the slots of the given worker, passing over the others, like
+    /// [run_projection](Runner::run_projection).
+    async fn run_partition(
+        &self,
+        shadow: bool,
+        heads: &[Option<NonZeroU64>],
+        worker: usize,
+        retries: &mut Retries,
+    ) -> Result<(), IntenalRunError<H::Error, <H::Target as Target>::Error>> {
+        let Runner {
+            name,
+            state,
+            sources,
+            evt_handler: handler,
+            target,
+            error_strategy,
+            batching,
+            partitioning,
+        } = self;
+        let workers = partitioning.workers.get();
+        let slots = worker_slots(worker, workers);
+        // Even a single worker needs the real slots of the events to check their progress, which
+        // may differ per slot after changing the number of workers.
+        let own_slots = slots.clone().collect::<Vec<_>>();
+
+        let seq_nos = target
+            .load_seq_nos(name, shadow)
+            .await
+            .map_err(IntenalRunError::Target)?;
+        let mut slot_seq_nos = sources
+            .iter()
+            .map(|source| SlotSeqNos::new(&seq_nos, source.name()))
+            .collect::<Vec<_>>();
+        let evts = sources
+            .iter()
+            .zip(&slot_seq_nos)
+            .map(|(source, slot_seq_nos)| {
+                let seq_no = slot_seq_nos
+                    .min(slots.clone())
+                    .map(|n| n.saturating_add(1))
+                    .unwrap_or(NonZeroU64::MIN);
+                (source.evts(seq_no, Some(&own_slots)), source.head_fn())
+            })
+            .collect();
+        let mut evts = pin!(merge(evts));
+
+        // The sequence numbers up to which the events of the sources have been seen, whether of
+        // the slots of this worker or not, to determine whether the shadow copy is caught up.
+        let mut seen = slot_seq_nos
+            .iter()
+            .map(|slot_seq_nos| slot_seq_nos.min(slots.clone()))
+            .collect::<Vec<_>>();
+        let caught_up = |seen: &[Option<NonZeroU64>]| {
+            shadow
+                && heads
+                    .iter()
+                    .zip(seen)
+                    .all(|(head, seen)| head.is_none_or(|head| *seen >= Some(head)))
+        };
+
+        loop {
+            if caught_up(&seen) {
+                break;
+            }
+
+            let Some(evt) = evts.next().await else {
+                break;
+            };
+
+            if !state.borrow().running {
+                break;
+            };
+
+            // Collect a batch, starting with the first event; one event at a time while looking
+            // for failing events.
+            let max_size = if retries.isolate > 0 {
+                1
+            } else {
+                batching.max_size.get()
+            };
+            let mut batch = vec![evt.map_err(IntenalRunError::Evts)?];
+            let deadline = Instant::now() + batching.max_delay;
+            while batch.len() < max_size {
+                match timeout_at(deadline, evts.next()).await {
+                    Ok(Some(evt)) => batch.push(evt.map_err(IntenalRunError::Evts)?),
+                    Ok(None) | Err(_) => break,
+                }
+            }
+
+            // Handle the batch in one transaction.
+            let skip_failed = matches!(
+                error_strategy,
+                ErrorStrategy::Skip(_) | ErrorStrategy::DeadLetter(_)
+            );
+            let evt_count = batch.len();
+            let mut batch_seq_nos = BTreeMap::new();
+            let mut own_evt_count = 0;
+            let mut handled_evts = Vec::with_capacity(evt_count);
+            let mut failed_evts = vec![];
+            for (n, seq_no, evt) in batch {
+                let source = sources[n].name();
+                batch_seq_nos.insert(source, (n, seq_no));
+                seen[n] = seen[n].max(Some(seq_no));
+
+                // Pass over events of slots of other workers, the ones already filtered out by the
+                // event log included, or handled before changing the number of workers.
+                let Some((slot, evt)) = evt else {
+                    continue;
+                };
+                if slot % workers != worker || slot_seq_nos[n].get(slot) >= Some(seq_no) {
+                    continue;
+                }
+                own_evt_count += 1;
+
+                match evt {
+                    Ok(Some(evt)) => handled_evts.push(evt),
+
+                    Ok(None) => debug!(name, source, seq_no, "projection skips filtered event"),
+
+                    Err(error) if skip_failed => failed_evts.push(DeadLetter {
+                        source: source.to_string(),
+                        seq_no,
+                        error,
+                    }),
+
+                    Err(error) => return Err(IntenalRunError::Evts(error.into())),
+                }
+            }
+
+            let mut tx = target
+                .begin(name, shadow)
+                .await
+                .map_err(IntenalRunError::Target)?;
+            if !handled_evts.is_empty() {
+                if let Err(error) = handler.handle_batch(handled_evts, &mut tx).await {
+                    if !(skip_failed && retries.isolate > 0) {
+                        return Err(IntenalRunError::Batch(error, evt_count));
+                    }
+
+                    // Skip the single event of this batch, discarding its changes.
+                    let (source, (_, seq_no)) =
+                        batch_seq_nos.first_key_value().expect("one event in batch");
+                    failed_evts.push(DeadLetter {
+                        source: source.to_string(),
+                        seq_no: *seq_no,
+                        error: error.as_chain(),
+                    });
+                    drop(tx);
+                    tx = target
+                        .begin(name, shadow)
+                        .await
+                        .map_err(IntenalRunError::Target)?;
+                }
+            }
+            for dead_letter in &failed_evts {
+                let DeadLetter {
+                    source,
+                    seq_no,
+                    error,
+                } = dead_letter;
+                if matches!(error_strategy, ErrorStrategy::DeadLetter(_)) {
+                    warn!(name, source, seq_no, error, "projection dead-letters event");
+                    target
+                        .save_dead_letter(&mut tx, name, dead_letter)
+                        .await
+                        .map_err(IntenalRunError::Target)?;
+                } else {
+                    warn!(name, source, seq_no, error, "projection skips failed event");
+                }
+            }
+            // Without partitioning the sequence number is saved for all slots.
+            for (source, (n, seq_no)) in &batch_seq_nos {
+                if workers == 1 {
+                    target
+                        .save_seq_no(&mut tx, name, shadow, source, *seq_no)
+                        .await
+                        .map_err(IntenalRunError::Target)?;
+                } else {
+                    for slot in slots.clone() {
+                        if slot_seq_nos[*n].get(slot) < Some(*seq_no) {
+                            let key = slot_key(source, slot);
+                            target
+                                .save_seq_no(&mut tx, name, shadow, &key, *seq_no)
+                                .await
+                                .map_err(IntenalRunError::Target)?;
+                        }
+                    }
+                }
+            }
+            target.commit(tx).await.map_err(IntenalRunError::Target)?;
+            debug!(
+                name,
+                shadow,
+                worker,
+                ?batch_seq_nos,
+                evt_count,
+                "projection handled batch"
+            );
+            retries.count = 0;
+            retries.isolate = retries.isolate.saturating_sub(evt_count);
+
+            for (n, seq_no) in batch_seq_nos.values() {
+                for slot in slots.clone() {
+                    slot_seq_nos[*n].advance(slot, *seq_no);
+                }
+            }
+
+            state.send_modify(|state| {
+                if !shadow {
+                    for (source, (n, _)) in batch_seq_nos {
+                        let Some(seq_no) = slot_seq_nos[n].min(slots.clone()) else {
+                            continue;
+                        };
+                        if workers == 1 {
+                            state.seq_nos.insert(source.to_string(), seq_no);
+                        } else {
+                            state.worker_seq_nos[worker].insert(source.to_string(), seq_no);
+                            let seq_no = state
+                                .worker_seq_nos
+                                .iter()
+                                .map(|seq_nos| seq_nos.get(source).copied())
+                                .min()
+                                .flatten();
+                            if let Some(seq_no) = seq_no {
+                                state.seq_nos.insert(source.to_string(), seq_no);
+                            }
+                        }
+                    }
+                }
+                state.evt_count += own_evt_count;
+                state.batch_count += 1;
+                state.retries = 0;
+                state.handled_at = Some(SystemTime::now());
+            });
+        }
+
+        Ok(())
+    }
+
+    /// Handle the dead-lettered events again, deleting the [DeadLetter]s of those handled
+    /// successfully and updating the others. Returns the number of events handled successfully.
+    async fn replay_dead_letters(
+        &self,
+    ) -> Result<usize, IntenalRunError<H::Error, <H::Target as Target>::Error>> {
+        let Runner {
+            name,
+            sources,
+            evt_handler: handler,
+            target,
+            ..
+        } = self;
+
+        let dead_letters = target
+            .load_dead_letters(name)
+            .await
+            .map_err(IntenalRunError::Target)?;
+
+        let mut count = 0;
+        for mut dead_letter in dead_letters {
+            let DeadLetter { source, seq_no, .. } = &dead_letter;
+            let Some(evts) = sources
+                .iter()
+                .find(|s| s.name() == source)
+                .map(|source| source.evts(*seq_no, None))
+            else {
+                warn!(name, source, "unknown source of dead-lettered event");
+                continue;
+            };
+            let evt = match pin!(evts).next().await {
+                Some(Ok((n, Some((_, evt))))) if n == *seq_no => evt,
+                Some(Err(error)) => return Err(IntenalRunError::Evts(error)),
+                _ => {
+                    warn!(name, source, seq_no, "dead-lettered event not found");
+                    continue;
+                }
+            };
+
+            let mut tx = target
+                .begin(name, false)
+                .await
+                .map_err(IntenalRunError::Target)?;
+            let result = match evt {
+                Ok(Some(evt)) => handler
+                    .handle_evt(evt, &mut tx)
+                    .await
+                    .map_err(|error| error.as_chain()),
+                Ok(None) => Ok(()),
+                Err(error) => Err(error),
+            };
+
+            match result {
+                Ok(()) => {
+                    target
+                        .delete_dead_letter(&mut tx, name, source, *seq_no)
+                        .await
+                        .map_err(IntenalRunError::Target)?;
+                    count += 1;
+                }
+
+                // Keep the dead letter with the current error, discarding any changes.
+                Err(error) => {
+                    warn!(
+                        name,
+                        source, seq_no, error, "cannot replay dead-lettered event"
+                    );
+                    dead_letter.error = error;
+                    drop(tx);
+                    tx = target
+                        .begin(name, false)
+                        .await
+                        .map_err(IntenalRunError::Target)?;
+                    target
+                        .save_dead_letter(&mut tx, name, &dead_letter)
+                        .await
+                        .map_err(IntenalRunError::Target)?;
+                }
+            }
+            target.commit(tx).await.map_err(IntenalRunError::Target)?;
+        }
+
+        Ok(count)
+    }
+}
+
+/// Consecutive failures of the projection task and the number of events to be handled one at a
+/// time, in order to skip the failing ones.
+#[derive(Debug, Default)]
+struct Retries {
+    count: u32,
+    isolate: usize,
+}
+
+/// Take part in the given [LeaderElection] until aborted: while holding the lease, run the
+/// projection, once it is lost or has expired, abort the projection task.
+fn run_election<H, L>(
+    runner: Runner<H>,
+    leader_election: LeaderElection<L>,
+    projection_task: Arc<Mutex<Option<JoinHandle<()>>>>,
+) -> JoinHandle<()>
+where
+    H: EvtHandler + Clone + Send + Sync + 'static,
+    H::Evt: Send + 'static,
+    L: Lease,
+{
+    let LeaderElection {
+        lease,
+        holder,
+        ttl,
+        heartbeat,
+    } = leader_election;
+    let name = runner.name.clone();
+    let state = runner.state.clone();
+
+    task::spawn(async move {
+        let mut leased_until = None::<Instant>;
+
+        loop {
+            let now = Instant::now();
+            let acquired = lease.acquire(&name, &holder, ttl).await;
+            if let Err(error) = &acquired {
+                error!(
+                    error = error.as_chain(),
+                    name, "cannot acquire projection lease"
+                );
+            }
+
+            let mut projection_task = projection_task.lock().await;
+            match acquired {
+                Ok(true) => {
+                    leased_until = Some(now + ttl);
+
+                    if projection_task.is_none() {
+                        info!(name, holder, "projection elected leader");
+
+                        // Another replica may have handled events or rebuilt in the meantime.
+                        match load_seq_nos_and_version(&runner).await {
+                            Ok((seq_nos, version)) => {
+                                let legacy = seq_nos.contains_key(LEGACY_SOURCE);
+                                let rebuild = version != H::VERSION || legacy;
+                                let (seq_nos, worker_seq_nos) = runner.positions(&seq_nos);
+                                state.send_modify(|state| {
+                                    state.seq_nos = seq_nos;
+                                    state.worker_seq_nos = worker_seq_nos;
+                                    state.version = version;
+                                    state.legacy = legacy;
+                                    state.leader = true;
+                                    state.rebuilding = rebuild;
+                                });
+                                if rebuild {
+                                    info!(name, "projection outdated, rebuilding");
+                                }
+
+                                *projection_task = Some(runner.spawn(rebuild, None));
+                            }
+
+                            Err(error) => {
+                                error!(
+                                    error = error.as_chain(),
+                                    name, "cannot load projection sequence numbers and version"
+                                );
+                            }
+                        }
+                    }
+                }
+
+                // Lost the lease or it has expired, because it could not be renewed.
+                Ok(false) | Err(_) => {
+                    let expired = matches!(acquired, Ok(false))
+                        || leased_until.is_some_and(|leased_until| leased_until <= now);
+                    if expired {
+                        if let Some(task) = projection_task.take() {
+                            info!(name, holder, "projection no longer leader");
+                            task.abort();
+                            let _ = task.await;
+                            state.send_modify(|state| {
+                                state.leader = false;
+                                state.rebuilding = false;
+                            });
+                        }
+                        leased_until = None;
+                    }
+                }
+            }
+            drop(projection_task);
+
+            sleep(heartbeat).await;
+        }
+    })
+}
+
+async fn load_seq_nos_and_version<H>(
+    runner: &Runner<H>,
+) -> Result<(BTreeMap<String, NonZeroU64>, u32), <H::Target as Target>::Error>
+where
+    H: EvtHandler,
+{
+    let seq_nos = runner.target.load_seq_nos(&runner.name, false).await?;
+    let version = runner
+        .target
+        .load_version(&runner.name)
+        .await?
+        .unwrap_or_default();
+    Ok((seq_nos, version))
+}
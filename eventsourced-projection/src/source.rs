@@ -0,0 +1,704 @@
+//! Sources of events for projections.
+
+use async_stream::stream;
+use error_ext::{BoxError, StdErrorExt};
+use eventsourced::{binarize::Binarize, slot, EventSourced, EvtLog, SLOTS};
+use futures::{
+    future::{self, BoxFuture},
+    stream::{BoxStream, Fuse, FusedStream},
+    FutureExt, Stream, StreamExt, TryStreamExt,
+};
+use std::{
+    convert::Infallible,
+    fmt::{self, Debug, Formatter},
+    marker::PhantomData,
+    num::NonZeroU64,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+};
+use tracing::warn;
+
+type Evts<T> = BoxStream<'static, Result<(NonZeroU64, T), BoxError>>;
+
+type Head = BoxFuture<'static, Result<Option<NonZeroU64>, BoxError>>;
+
+type HeadFn = Arc<dyn Fn() -> Head + Send + Sync>;
+
+/// Events along with their slot, if determined by the [EvtLog], or just sequence numbers if events
+/// are filtered out by the [EvtLog], see [EvtLog::evts_by_type_in_slots].
+type SlottedEvts<T> = Evts<Option<(Option<usize>, Decoded<T>)>>;
+
+type EvtsFn<T> = Arc<dyn Fn(NonZeroU64, Option<Vec<usize>>) -> SlottedEvts<T> + Send + Sync>;
+
+type Filter<T> = Arc<dyn Fn(&T) -> bool + Send + Sync>;
+
+type Slot<T> = Arc<dyn Fn(&T) -> usize + Send + Sync>;
+
+/// An event which has been decoded or the error chain why it could not be decoded.
+type Decoded<T> = Result<T, String>;
+
+/// A source of events for a projection, i.e. the events of one [EventSourced] entity type from
+/// some [EvtLog], converted into the event type of the projection's event handler.
+///
+/// Events not matching an optional filter are skipped, i.e. not passed to the event handler, yet
+/// still tracked as consumed.
+///
+/// Events which cannot be decoded do not fail the stream of events, but are passed along with their
+/// sequence number, such that they can be skipped or dead-lettered according to the
+/// [ErrorStrategy](crate::ErrorStrategy).
+///
+/// For [Partitioning](crate::Partitioning), events are distributed to slots by an optional
+/// partition key. Without one, events by type are distributed by the ID of their entity, filtered
+/// by the [EvtLog] via [EvtLog::evts_by_type_in_slots], such that each worker only reads its share.
+/// Events by tag without partition key, events by type from event logs not supporting slots, as
+/// well as undecodable events with a partition key are all in the same slot.
+pub struct Source<T> {
+    name: String,
+    evts: EvtsFn<T>,
+    head: HeadFn,
+    by_tag: bool,
+    filter: Option<Filter<T>>,
+    slot: Option<Slot<T>>,
+}
+
+impl<T> Source<T>
+where
+    T: Send + 'static,
+{
+    /// Create a [Source] for the events of the [EventSourced] entity type `E` from the given
+    /// [EvtLog], converted from bytes via the given [Binarize] implementation and into the event
+    /// type of the projection's event handler via the given function.
+    ///
+    /// The name of the source, which is used to track its sequence number, is `E::TYPE_NAME`.
+    pub fn new<E, L, B, F>(evt_log: L, binarize: B, into_evt: F) -> Self
+    where
+        E: EventSourced,
+        E::Evt: 'static,
+        L: EvtLog + Sync,
+        B: Binarize<E::Evt, E::State>,
+        F: Fn(E::Evt) -> T + Send + Sync + 'static,
+    {
+        Self::from_evt_log::<E, L, B, F>(
+            E::TYPE_NAME.to_string(),
+            evt_log,
+            None,
+            binarize,
+            into_evt,
+        )
+    }
+
+    /// Create a [Source] for the events of the [EventSourced] entity type `E` with the given tag,
+    /// as given by [EventSourced::tags], from the given [EvtLog], converted like for
+    /// [Source::new].
+    ///
+    /// The name of the source, which is used to track its sequence number, is `E::TYPE_NAME`
+    /// followed by a dot and the tag. As the sequence numbers of events by tag may differ from the
+    /// ones by type, a tagged source must not be renamed to the name of an untagged one or vice
+    /// versa.
+    pub fn new_by_tag<E, L, B, F>(
+        evt_log: L,
+        binarize: B,
+        tag: impl Into<String>,
+        into_evt: F,
+    ) -> Self
+    where
+        E: EventSourced,
+        E::Evt: 'static,
+        L: EvtLog + Sync,
+        B: Binarize<E::Evt, E::State>,
+        F: Fn(E::Evt) -> T + Send + Sync + 'static,
+    {
+        let tag = Arc::<str>::from(tag.into());
+        let name = format!("{}.{tag}", E::TYPE_NAME);
+        Self::from_evt_log::<E, L, B, F>(name, evt_log, Some(tag), binarize, into_evt)
+    }
+
+    fn from_evt_log<E, L, B, F>(
+        name: String,
+        evt_log: L,
+        tag: Option<Arc<str>>,
+        binarize: B,
+        into_evt: F,
+    ) -> Self
+    where
+        E: EventSourced,
+        E::Evt: 'static,
+        L: EvtLog + Sync,
+        B: Binarize<E::Evt, E::State>,
+        F: Fn(E::Evt) -> T + Send + Sync + 'static,
+    {
+        let into_evt = Arc::new(into_evt);
+        let by_tag = tag.is_some();
+
+        let head = {
+            let evt_log = evt_log.clone();
+            let tag = tag.clone();
+            move || {
+                let evt_log = evt_log.clone();
+                let tag = tag.clone();
+                async move {
+                    match tag {
+                        Some(tag) => evt_log.last_seq_no_by_tag::<E>(&tag).await,
+                        None => evt_log.last_seq_no_by_type::<E>().await,
+                    }
+                    .map_err(|error| error.into())
+                }
+                .boxed()
+            }
+        };
+
+        let evts = move |seq_no, slots: Option<Vec<usize>>| {
+            let evt_log = evt_log.clone();
+            let tag = tag.clone();
+            let into_evt = into_evt.clone();
+
+            let evts = stream! {
+                let from_bytes = move |bytes| {
+                    let evt = binarize
+                        .evt_from_bytes(bytes)
+                        .map_err(|error| error.as_chain());
+                    Ok::<_, Infallible>(evt)
+                };
+                let evts = match (&tag, &slots) {
+                    (Some(tag), _) => evt_log
+                        .evts_by_tag::<Undecoded<E>, _, _>(tag, seq_no, from_bytes)
+                        .await
+                        .map(|evts| {
+                            evts.map_ok(|(seq_no, evt)| (seq_no, Some((None, evt))))
+                                .boxed()
+                        }),
+
+                    (None, Some(slots)) => match evt_log
+                        .evts_by_type_in_slots::<Undecoded<E>, _, _>(slots, seq_no, from_bytes)
+                        .await
+                    {
+                        Ok(Some(evts)) => Ok(evts
+                            .map_ok(|(seq_no, evt)| {
+                                (seq_no, evt.map(|(slot, evt)| (Some(slot), evt)))
+                            })
+                            .boxed()),
+
+                        Ok(None) => {
+                            if slots.len() < SLOTS {
+                                warn!(
+                                    type_name = E::TYPE_NAME,
+                                    "event log does not support slots, hence all events of source \
+                                     without partition key are handled by one worker"
+                                );
+                            }
+                            evt_log
+                                .evts_by_type::<Undecoded<E>, _, _>(seq_no, from_bytes)
+                                .await
+                                .map(|evts| {
+                                    evts.map_ok(|(seq_no, evt)| (seq_no, Some((None, evt))))
+                                        .boxed()
+                                })
+                        }
+
+                        Err(error) => Err(error),
+                    },
+
+                    (None, None) => evt_log
+                        .evts_by_type::<Undecoded<E>, _, _>(seq_no, from_bytes)
+                        .await
+                        .map(|evts| {
+                            evts.map_ok(|(seq_no, evt)| (seq_no, Some((None, evt))))
+                                .boxed()
+                        }),
+                };
+
+                match evts {
+                    Ok(evts) => {
+                        for await evt in evts {
+                            yield evt
+                                .map(|(seq_no, evt)| {
+                                    let evt = evt.map(|(slot, evt)| {
+                                        (slot, evt.map(|evt| into_evt(evt)))
+                                    });
+                                    (seq_no, evt)
+                                })
+                                .map_err(|error| error.into());
+                        }
+                    }
+
+                    Err(error) => yield Err(error.into()),
+                }
+            };
+
+            evts.boxed()
+        };
+
+        Self {
+            name,
+            evts: Arc::new(evts),
+            head: Arc::new(head),
+            by_tag,
+            filter: None,
+            slot: None,
+        }
+    }
+
+    /// Use the given name instead of `E::TYPE_NAME`, e.g. to consume the same entity type from
+    /// different event logs.
+    pub fn with_name(mut self, name: impl Into<String>) -> Self {
+        self.name = name.into();
+        self
+    }
+
+    /// Only pass events matching the given predicate to the event handler, e.g. only certain
+    /// variants of the event type.
+    pub fn with_filter<F>(mut self, filter: F) -> Self
+    where
+        F: Fn(&T) -> bool + Send + Sync + 'static,
+    {
+        self.filter = Some(Arc::new(filter));
+        self
+    }
+
+    /// Use the given partition key for [Partitioning](crate::Partitioning), typically the ID of the
+    /// entity which has emitted the event, e.g. `|evt| evt.id.into_bytes()` for an ID of type
+    /// `Uuid`.
+    pub fn with_partition_key<F, K>(mut self, key: F) -> Self
+    where
+        F: Fn(&T) -> K + Send + Sync + 'static,
+        K: AsRef<[u8]>,
+    {
+        self.slot = Some(Arc::new(move |evt| slot(key(evt).as_ref())));
+        self
+    }
+
+    /// The name of this source.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Whether the events of this source cannot be distributed to slots, i.e. it is a source by tag
+    /// without partition key.
+    pub(crate) fn is_unpartitioned(&self) -> bool {
+        self.by_tag && self.slot.is_none()
+    }
+
+    /// Get the events starting at the given sequence number along with their slot; events not
+    /// matching the filter are `None`. If partitioned by entity ID, i.e. for a source by type
+    /// without partition key, the [EvtLog] may filter out the events of slots other than the given
+    /// ones, only passing their sequence numbers, if at all.
+    pub(crate) fn evts(
+        &self,
+        seq_no: NonZeroU64,
+        slots: Option<&[usize]>,
+    ) -> Evts<Option<(usize, Decoded<Option<T>>)>> {
+        let slots = slots
+            .filter(|_| !self.by_tag && self.slot.is_none())
+            .map(|slots| slots.to_vec());
+        let filter = self.filter.clone();
+        let key_slot = self.slot.clone();
+
+        (self.evts)(seq_no, slots)
+            .map_ok(move |(seq_no, evt)| {
+                let evt = evt.map(|(slot, evt)| {
+                    let slot = slot
+                        .or_else(|| {
+                            let evt = evt.as_ref().ok()?;
+                            key_slot.as_ref().map(|slot| slot(evt))
+                        })
+                        .unwrap_or_default();
+                    let evt = evt.map(|evt| {
+                        filter
+                            .as_ref()
+                            .is_none_or(|filter| filter(&evt))
+                            .then_some(evt)
+                    });
+                    (slot, evt)
+                });
+                (seq_no, evt)
+            })
+            .boxed()
+    }
+
+    /// Get the sequence number of the last event, i.e. the head of the event log for this source.
+    pub(crate) fn head(&self) -> Head {
+        (self.head)()
+    }
+
+    /// Get the function to get the head of the event log for this source, see [Source::head].
+    pub(crate) fn head_fn(&self) -> HeadFn {
+        self.head.clone()
+    }
+}
+
+impl<T> Source<T>
+where
+    T: Send + 'static,
+{
+    /// Convert this source into one of its events along with its name and their sequence numbers,
+    /// e.g. to derive idempotency keys from them.
+    pub(crate) fn sequenced(self) -> Source<Sequenced<T>> {
+        let name = Arc::<str>::from(self.name.as_str());
+        let evts = self.evts;
+        let evts = move |seq_no, slots| {
+            let name = name.clone();
+            evts(seq_no, slots)
+                .map_ok(move |(seq_no, evt)| {
+                    let evt = evt.map(|(slot, evt)| {
+                        let evt = evt.map(|evt| Sequenced {
+                            source: name.clone(),
+                            seq_no,
+                            evt,
+                        });
+                        (slot, evt)
+                    });
+                    (seq_no, evt)
+                })
+                .boxed()
+        };
+        let filter = self.filter.map(|filter| {
+            Arc::new(move |evt: &Sequenced<T>| filter(&evt.evt)) as Filter<Sequenced<T>>
+        });
+        let slot = self
+            .slot
+            .map(|slot| Arc::new(move |evt: &Sequenced<T>| slot(&evt.evt)) as Slot<Sequenced<T>>);
+
+        Source {
+            name: self.name,
+            evts: Arc::new(evts),
+            head: self.head,
+            by_tag: self.by_tag,
+            filter,
+            slot,
+        }
+    }
+}
+
+/// An event of a [Source] along with the name of the source and its sequence number.
+#[derive(Debug)]
+pub(crate) struct Sequenced<T> {
+    pub source: Arc<str>,
+    pub seq_no: NonZeroU64,
+    pub evt: T,
+}
+
+impl<T> Clone for Source<T> {
+    fn clone(&self) -> Self {
+        Self {
+            name: self.name.clone(),
+            evts: self.evts.clone(),
+            head: self.head.clone(),
+            by_tag: self.by_tag,
+            filter: self.filter.clone(),
+            slot: self.slot.clone(),
+        }
+    }
+}
+
+impl<T> Debug for Source<T> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Source")
+            .field("name", &self.name)
+            .field("filtered", &self.filter.is_some())
+            .field("partitioned", &self.slot.is_some())
+            .finish()
+    }
+}
+
+/// The [EventSourced] entity type `E` with events which might not be decodable, used to get the
+/// events of `E` from an [EvtLog] without failing the stream of events for undecodable ones.
+struct Undecoded<E>(PhantomData<E>);
+
+impl<E> EventSourced for Undecoded<E>
+where
+    E: EventSourced,
+{
+    type Id = E::Id;
+    type Cmd = E::Cmd;
+    type Evt = Decoded<E::Evt>;
+    type State = E::State;
+    type Error = E::Error;
+
+    const TYPE_NAME: &'static str = E::TYPE_NAME;
+
+    fn handle_cmd(
+        id: &Self::Id,
+        state: &Self::State,
+        cmd: Self::Cmd,
+    ) -> Result<Self::Evt, Self::Error> {
+        E::handle_cmd(id, state, cmd).map(Ok)
+    }
+
+    fn handle_evt(state: Self::State, evt: Self::Evt) -> Self::State {
+        match evt {
+            Ok(evt) => E::handle_evt(state, evt),
+            Err(_) => state,
+        }
+    }
+}
+
+/// Merge the given streams of events into one stream of events along with the index of the
+/// respective stream, ordered by sequence number, ties broken by the index of the stream. Hence the
+/// events of sources sharing an event log are merged by their position in that event log. Errors
+/// are emitted as soon as they are available.
+///
+/// An event is only emitted once each other stream has either a next event with a higher sequence
+/// number, has ended, or cannot have an event with a lower sequence number, i.e. it has already
+/// emitted all events up to its head which, if needed, is obtained via the given function.
+pub(crate) fn merge<T>(
+    evts: Vec<(Evts<T>, HeadFn)>,
+) -> impl Stream<Item = Result<(usize, NonZeroU64, T), BoxError>> + Send
+where
+    T: Send + 'static,
+{
+    Merge {
+        sources: evts
+            .into_iter()
+            .map(|(evts, head)| MergeSource {
+                evts: evts.fuse(),
+                head,
+                next: None,
+                last: None,
+                known_head: None,
+            })
+            .collect(),
+        heads: None,
+        watermark: None,
+    }
+}
+
+struct Merge<T> {
+    sources: Vec<MergeSource<T>>,
+    heads: Option<BoxFuture<'static, Result<Vec<Option<NonZeroU64>>, BoxError>>>,
+    watermark: Option<NonZeroU64>,
+}
+
+struct MergeSource<T> {
+    evts: Fuse<Evts<T>>,
+    head: HeadFn,
+    next: Option<Result<(NonZeroU64, T), BoxError>>,
+    last: Option<NonZeroU64>,
+    known_head: Option<NonZeroU64>,
+}
+
+impl<T> MergeSource<T> {
+    /// The sequence number up to which no event of this pending stream is to be expected: a stream
+    /// lagging behind its known head has its next events still to come; otherwise any future event
+    /// is appended after the watermark, i.e. the head of the event log when last obtained.
+    fn bound(&self, watermark: Option<NonZeroU64>) -> Option<NonZeroU64> {
+        if self.known_head > self.last {
+            self.last
+        } else {
+            watermark
+        }
+    }
+}
+
+// No field is ever pinned, the event streams and futures are boxed.
+impl<T> Unpin for Merge<T> {}
+
+impl<T> Stream for Merge<T> {
+    type Item = Result<(usize, NonZeroU64, T), BoxError>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+
+        loop {
+            // Make sure each stream has its next event, if available.
+            for source in this.sources.iter_mut() {
+                if source.next.is_none() && !source.evts.is_terminated() {
+                    if let Poll::Ready(Some(evt)) = source.evts.poll_next_unpin(cx) {
+                        source.next = Some(evt);
+                    }
+                }
+            }
+
+            // Errors first.
+            if let Some(source) = this
+                .sources
+                .iter_mut()
+                .find(|source| matches!(source.next, Some(Err(_))))
+            {
+                let error = source.next.take().and_then(Result::err);
+                return Poll::Ready(error.map(Err));
+            }
+
+            // The lowest sequence number, ties broken by index.
+            let next = this
+                .sources
+                .iter()
+                .enumerate()
+                .filter_map(|(n, source)| match &source.next {
+                    Some(Ok((seq_no, _))) => Some((*seq_no, n)),
+                    _ => None,
+                })
+                .min();
+            let Some((seq_no, n)) = next else {
+                return if this
+                    .sources
+                    .iter()
+                    .all(|source| source.evts.is_terminated())
+                {
+                    Poll::Ready(None)
+                } else {
+                    Poll::Pending
+                };
+            };
+
+            let pending = this
+                .sources
+                .iter()
+                .filter(|source| source.next.is_none() && !source.evts.is_terminated());
+            let bound = pending.map(|source| source.bound(this.watermark)).min();
+            if bound.is_none_or(|bound| bound >= Some(seq_no)) {
+                let source = &mut this.sources[n];
+                source.last = Some(seq_no);
+                let evt = source
+                    .next
+                    .take()
+                    .map(|evt| evt.map(|(seq_no, evt)| (n, seq_no, evt)));
+                return Poll::Ready(evt);
+            }
+
+            // Some pending stream is lagging behind its known head, hence its next event is
+            // awaited, or the heads are obtained to find out whether pending streams are caught up.
+            let lagging = this.sources.iter().any(|source| {
+                source.next.is_none()
+                    && !source.evts.is_terminated()
+                    && source.known_head > source.last
+                    && source.last < Some(seq_no)
+            });
+            if lagging && this.heads.is_none() {
+                return Poll::Pending;
+            }
+
+            let heads = this.heads.get_or_insert_with(|| {
+                future::try_join_all(this.sources.iter().map(|source| (source.head)())).boxed()
+            });
+            match heads.poll_unpin(cx) {
+                Poll::Ready(Ok(heads)) => {
+                    this.heads = None;
+                    // Events already received have been appended before the heads were obtained.
+                    let received = this
+                        .sources
+                        .iter()
+                        .filter_map(|source| source.next.as_ref()?.as_ref().ok())
+                        .map(|(seq_no, _)| *seq_no);
+                    this.watermark = heads.iter().copied().flatten().chain(received).max();
+                    for (source, head) in this.sources.iter_mut().zip(heads) {
+                        source.known_head = head;
+                    }
+                }
+
+                Poll::Ready(Err(error)) => {
+                    this.heads = None;
+                    return Poll::Ready(Some(Err(error)));
+                }
+
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::{Dummy, TestEvtLog};
+    use eventsourced::binarize::serde_json::SerdeJsonBinarize;
+    use futures::stream;
+    use std::time::Duration;
+    use tokio::time::{sleep, timeout};
+
+    fn evts(seq_nos: Vec<u64>) -> (Evts<u64>, HeadFn) {
+        let head = seq_nos.last().copied();
+        let evts = stream::iter(seq_nos)
+            .map(|n| Ok((NonZeroU64::new(n).unwrap(), n)))
+            .boxed();
+        (evts, head_fn(head))
+    }
+
+    fn head_fn(head: Option<u64>) -> HeadFn {
+        Arc::new(move || future::ok(head.and_then(NonZeroU64::new)).boxed())
+    }
+
+    #[tokio::test]
+    async fn test_merge() {
+        let evts = merge(vec![
+            evts(vec![1, 4, 5, 9]),
+            evts(vec![2, 3, 5]),
+            evts(vec![]),
+            evts(vec![6]),
+        ]);
+        let evts = evts
+            .map_ok(|(n, _, evt)| (n, evt))
+            .try_collect::<Vec<_>>()
+            .await
+            .unwrap();
+        assert_eq!(
+            evts,
+            vec![
+                (0, 1),
+                (1, 2),
+                (1, 3),
+                (0, 4),
+                (0, 5),
+                (1, 5),
+                (3, 6),
+                (0, 9)
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_merge_tailing() {
+        // A lagging stream is awaited, an idle one is passed over once caught up with its head.
+        let lagging = stream::once(async {
+            sleep(Duration::from_millis(100)).await;
+            Ok((NonZeroU64::new(3).unwrap(), 3))
+        })
+        .chain(stream::pending())
+        .boxed();
+        let tailing = stream::iter([2, 5])
+            .map(|n| Ok((NonZeroU64::new(n).unwrap(), n)))
+            .chain(stream::pending())
+            .boxed();
+        let idle = stream::pending().boxed();
+        let evts = merge(vec![
+            (lagging, head_fn(Some(3))),
+            (tailing, head_fn(Some(5))),
+            (idle, head_fn(None)),
+        ]);
+        let evts = timeout(
+            Duration::from_secs(1),
+            evts.map_ok(|(n, _, evt)| (n, evt))
+                .take(3)
+                .try_collect::<Vec<_>>(),
+        )
+        .await
+        .unwrap()
+        .unwrap();
+        assert_eq!(evts, vec![(1, 2), (0, 3), (1, 5)]);
+    }
+
+    #[tokio::test]
+    async fn test_merge_error() {
+        let failing = stream::iter(vec![Err::<(NonZeroU64, u64), BoxError>("error".into())]);
+        let evts = merge(vec![evts(vec![1, 2]), (failing.boxed(), head_fn(None))]);
+        let evts = evts.collect::<Vec<_>>().await;
+        assert!(evts[0].is_err());
+        assert_eq!(evts.len(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_new_by_tag() {
+        let source =
+            Source::new_by_tag::<Dummy, _, _, _>(TestEvtLog, SerdeJsonBinarize, "even", |evt| evt);
+        assert_eq!(source.name(), "simple.even");
+        assert_eq!(source.head().await.unwrap(), NonZeroU64::new(100));
+
+        let evts = source
+            .evts(NonZeroU64::new(95).unwrap(), None)
+            .map_ok(|(n, evt)| (n.get(), evt.unwrap().1.unwrap().unwrap()))
+            .try_collect::<Vec<_>>()
+            .await
+            .unwrap();
+        assert_eq!(evts, vec![(96, 96), (98, 98), (100, 100)]);
+    }
+}
@@ -0,0 +1,461 @@
+//! SQLite as [Target] of projections: event handlers get a [SqliteTx] wrapping a sqlx SQLite
+//! transaction; sequence numbers, versions and dead letters of projections are stored in the
+//! `projection`, `projection_version` and `projection_dead_letter` tables.
+//!
+//! When rebuilding a projection, its shadow copy consists of tables named like the current ones
+//! with a `_rebuild` suffix, hence event handlers must use [SqliteTx::table] for table names.
+//! Tables not created by [EvtHandler::setup](crate::LocalEvtHandler::setup) are created like the
+//! current ones, yet without indexes. The shadow tables are swapped in atomically.
+
+use crate::{DeadLetter, Target};
+use sqlx::{Pool, Row, Sqlite, SqliteConnection, Transaction};
+use std::{
+    collections::BTreeMap,
+    num::{NonZeroU64, TryFromIntError},
+    ops::{Deref, DerefMut},
+};
+use thiserror::Error;
+
+impl Target for Pool<Sqlite> {
+    type Tx = SqliteTx;
+
+    type Error = Error;
+
+    async fn init(&self) -> Result<(), Self::Error> {
+        sqlx::query(include_str!("create_projection.sql"))
+            .execute(self)
+            .await?;
+        sqlx::query(include_str!("create_projection_version.sql"))
+            .execute(self)
+            .await?;
+        sqlx::query(include_str!("create_projection_dead_letter.sql"))
+            .execute(self)
+            .await?;
+        Ok(())
+    }
+
+    async fn load_seq_nos(
+        &self,
+        name: &str,
+        shadow: bool,
+    ) -> Result<BTreeMap<String, NonZeroU64>, Self::Error> {
+        sqlx::query("SELECT source, seq_no FROM projection WHERE name=$1")
+            .bind(with_suffix(name, shadow))
+            .fetch_all(self)
+            .await?
+            .into_iter()
+            .map(|row| {
+                let source = row.try_get::<String, _>(0)?;
+                let seq_no = (row.try_get::<i64, _>(1)? as u64).try_into()?;
+                Ok((source, seq_no))
+            })
+            .collect()
+    }
+
+    async fn load_version(&self, name: &str) -> Result<Option<u32>, Self::Error> {
+        sqlx::query("SELECT version FROM projection_version WHERE name=$1")
+            .bind(name)
+            .fetch_optional(self)
+            .await?
+            .map(|row| Ok((row.try_get::<i64, _>(0)?).try_into()?))
+            .transpose()
+    }
+
+    async fn begin(&self, _name: &str, shadow: bool) -> Result<Self::Tx, Self::Error> {
+        let tx = self.begin().await?;
+        Ok(SqliteTx { tx, shadow })
+    }
+
+    async fn save_seq_no(
+        &self,
+        tx: &mut Self::Tx,
+        name: &str,
+        shadow: bool,
+        source: &str,
+        seq_no: NonZeroU64,
+    ) -> Result<(), Self::Error> {
+        let query = r#"INSERT INTO projection (name, source, seq_no)
+                       VALUES ($1, $2, $3)
+                       ON CONFLICT (name, source) DO UPDATE SET seq_no = $3"#;
+        sqlx::query(query)
+            .bind(with_suffix(name, shadow))
+            .bind(source)
+            .bind(seq_no.get() as i64)
+            .execute(&mut **tx)
+            .await?;
+        Ok(())
+    }
+
+    async fn save_version(
+        &self,
+        tx: &mut Self::Tx,
+        name: &str,
+        version: u32,
+    ) -> Result<(), Self::Error> {
+        let query = r#"INSERT INTO projection_version (name, version)
+                       VALUES ($1, $2)
+                       ON CONFLICT (name) DO UPDATE SET version = $2"#;
+        sqlx::query(query)
+            .bind(name)
+            .bind(version as i64)
+            .execute(&mut **tx)
+            .await?;
+        Ok(())
+    }
+
+    async fn save_dead_letter(
+        &self,
+        tx: &mut Self::Tx,
+        name: &str,
+        dead_letter: &DeadLetter,
+    ) -> Result<(), Self::Error> {
+        let query = r#"INSERT INTO projection_dead_letter (name, source, seq_no, error)
+                       VALUES ($1, $2, $3, $4)
+                       ON CONFLICT (name, source, seq_no) DO UPDATE SET error = $4"#;
+        sqlx::query(query)
+            .bind(name)
+            .bind(&dead_letter.source)
+            .bind(dead_letter.seq_no.get() as i64)
+            .bind(&dead_letter.error)
+            .execute(&mut **tx)
+            .await?;
+        Ok(())
+    }
+
+    async fn delete_dead_letter(
+        &self,
+        tx: &mut Self::Tx,
+        name: &str,
+        source: &str,
+        seq_no: NonZeroU64,
+    ) -> Result<(), Self::Error> {
+        sqlx::query("DELETE FROM projection_dead_letter WHERE name=$1 AND source=$2 AND seq_no=$3")
+            .bind(name)
+            .bind(source)
+            .bind(seq_no.get() as i64)
+            .execute(&mut **tx)
+            .await?;
+        Ok(())
+    }
+
+    async fn load_dead_letters(&self, name: &str) -> Result<Vec<DeadLetter>, Self::Error> {
+        let query = r#"SELECT source, seq_no, error FROM projection_dead_letter
+                       WHERE name=$1
+                       ORDER BY source, seq_no"#;
+        sqlx::query(query)
+            .bind(name)
+            .fetch_all(self)
+            .await?
+            .into_iter()
+            .map(|row| {
+                let source = row.try_get::<String, _>(0)?;
+                let seq_no = (row.try_get::<i64, _>(1)? as u64).try_into()?;
+                let error = row.try_get::<String, _>(2)?;
+                Ok(DeadLetter {
+                    source,
+                    seq_no,
+                    error,
+                })
+            })
+            .collect()
+    }
+
+    async fn commit(&self, tx: Self::Tx) -> Result<(), Self::Error> {
+        tx.tx.commit().await?;
+        Ok(())
+    }
+
+    async fn create_shadow(&self, name: &str, tables: &[&str]) -> Result<(), Self::Error> {
+        let mut tx = self.begin().await?;
+        for table in tables {
+            let shadow_table = quote_ident(&with_suffix(table, true));
+            sqlx::query(&format!("DROP TABLE IF EXISTS {shadow_table}"))
+                .execute(&mut *tx)
+                .await?;
+        }
+        sqlx::query("DELETE FROM projection WHERE name=$1")
+            .bind(with_suffix(name, true))
+            .execute(&mut *tx)
+            .await?;
+        tx.commit().await?;
+
+        Ok(())
+    }
+
+    async fn create_shadow_tables(&self, _name: &str, tables: &[&str]) -> Result<(), Self::Error> {
+        let mut tx = self.begin().await?;
+        for table in tables {
+            let shadow_table = with_suffix(table, true);
+            let sql = |table: String| {
+                sqlx::query("SELECT sql FROM sqlite_master WHERE type='table' AND name=$1")
+                    .bind(table)
+            };
+
+            let shadow_exists = sql(shadow_table.clone())
+                .fetch_optional(&mut *tx)
+                .await?
+                .is_some();
+            if shadow_exists {
+                continue;
+            }
+
+            // Reuse the column definitions and constraints of the current table.
+            let current_sql = sql(table.to_string())
+                .fetch_optional(&mut *tx)
+                .await?
+                .map(|row| row.try_get::<String, _>(0))
+                .transpose()?;
+            if let Some(definitions) = current_sql
+                .as_deref()
+                .and_then(|sql| sql.find('(').map(|n| &sql[n..]))
+            {
+                let shadow_table = quote_ident(&shadow_table);
+                sqlx::query(&format!("CREATE TABLE {shadow_table} {definitions}"))
+                    .execute(&mut *tx)
+                    .await?;
+            }
+        }
+        tx.commit().await?;
+
+        Ok(())
+    }
+
+    async fn swap_shadow(
+        &self,
+        name: &str,
+        tables: &[&str],
+        version: u32,
+    ) -> Result<(), Self::Error> {
+        let mut tx = self.begin().await?;
+        for table in tables {
+            let shadow_table = quote_ident(&with_suffix(table, true));
+            let table = quote_ident(table);
+            sqlx::query(&format!("DROP TABLE IF EXISTS {table}"))
+                .execute(&mut *tx)
+                .await?;
+            sqlx::query(&format!("ALTER TABLE {shadow_table} RENAME TO {table}"))
+                .execute(&mut *tx)
+                .await?;
+        }
+        sqlx::query("DELETE FROM projection WHERE name=$1")
+            .bind(name)
+            .execute(&mut *tx)
+            .await?;
+        sqlx::query("UPDATE projection SET name=$1 WHERE name=$2")
+            .bind(name)
+            .bind(with_suffix(name, true))
+            .execute(&mut *tx)
+            .await?;
+        let mut tx = SqliteTx { tx, shadow: false };
+        Target::save_version(self, &mut tx, name, version).await?;
+        tx.tx.commit().await?;
+
+        Ok(())
+    }
+}
+
+/// Transaction of the SQLite [Target], dereferencing to a [SqliteConnection].
+#[derive(Debug)]
+pub struct SqliteTx {
+    tx: Transaction<'static, Sqlite>,
+    shadow: bool,
+}
+
+impl SqliteTx {
+    /// The quoted name of the table with the given name to be used in queries; when rebuilding a
+    /// projection, the one of the shadow copy.
+    pub fn table(&self, name: &str) -> String {
+        quote_ident(&with_suffix(name, self.shadow))
+    }
+}
+
+impl Deref for SqliteTx {
+    type Target = SqliteConnection;
+
+    fn deref(&self) -> &Self::Target {
+        &self.tx
+    }
+}
+
+impl DerefMut for SqliteTx {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.tx
+    }
+}
+
+/// Errors from the SQLite [Target].
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("SQLite error")]
+    Sqlx(#[from] sqlx::Error),
+
+    #[error("cannot convert loaded seq_no or version")]
+    TryFromInt(#[from] TryFromIntError),
+}
+
+/// Name of the projection or table with the given name or of its shadow copy.
+fn with_suffix(name: &str, shadow: bool) -> String {
+    if shadow {
+        format!("{name}_rebuild")
+    } else {
+        name.to_string()
+    }
+}
+
+fn quote_ident(ident: &str) -> String {
+    format!("\"{}\"", ident.replace('"', "\"\""))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        tests::{Dummy, TestEvtLog},
+        Batching, ErrorStrategy, EvtHandler, Projection,
+    };
+    use error_ext::BoxError;
+    use eventsourced::{binarize::serde_json::SerdeJsonBinarize, EventSourced};
+    use sqlx::sqlite::SqlitePoolOptions;
+    use std::time::Duration;
+    use tokio::time::sleep;
+
+    #[derive(Clone)]
+    struct TestHandler;
+
+    impl EvtHandler for TestHandler {
+        type Evt = i32;
+
+        type Error = sqlx::Error;
+
+        type Target = Pool<Sqlite>;
+
+        const TABLES: &'static [&'static str] = &["test"];
+
+        async fn handle_evt(&self, evt: Self::Evt, tx: &mut SqliteTx) -> Result<(), Self::Error> {
+            let query = format!("INSERT INTO {} (n) VALUES ($1)", tx.table("test"));
+            sqlx::query(&query).bind(evt).execute(&mut **tx).await?;
+            Ok(())
+        }
+    }
+
+    #[derive(Clone)]
+    struct TestHandlerV1;
+
+    impl EvtHandler for TestHandlerV1 {
+        type Evt = i32;
+
+        type Error = sqlx::Error;
+
+        type Target = Pool<Sqlite>;
+
+        const VERSION: u32 = 1;
+
+        const TABLES: &'static [&'static str] = &["test"];
+
+        async fn setup(&self, tx: &mut SqliteTx) -> Result<(), Self::Error> {
+            let query = format!(
+                "CREATE TABLE {} (n INTEGER PRIMARY KEY, m INTEGER)",
+                tx.table("test")
+            );
+            sqlx::query(&query).execute(&mut **tx).await?;
+            Ok(())
+        }
+
+        async fn handle_evt(&self, evt: Self::Evt, tx: &mut SqliteTx) -> Result<(), Self::Error> {
+            let query = format!("INSERT INTO {} (n, m) VALUES ($1, $2)", tx.table("test"));
+            sqlx::query(&query)
+                .bind(evt)
+                .bind(2 * evt)
+                .execute(&mut **tx)
+                .await?;
+            Ok(())
+        }
+    }
+
+    async fn pool() -> Result<Pool<Sqlite>, sqlx::Error> {
+        // Each connection has its own in-memory database, hence exactly one must be kept.
+        SqlitePoolOptions::new()
+            .max_connections(1)
+            .min_connections(1)
+            .idle_timeout(None)
+            .max_lifetime(None)
+            .connect("sqlite::memory:")
+            .await
+    }
+
+    async fn sum(pool: &Pool<Sqlite>, column: &str) -> Result<i64, sqlx::Error> {
+        sqlx::query(&format!("SELECT SUM({column}) FROM test"))
+            .fetch_one(pool)
+            .await?
+            .try_get::<i64, _>(0)
+    }
+
+    #[tokio::test]
+    async fn test_rebuild() -> Result<(), BoxError> {
+        let pool = pool().await?;
+
+        sqlx::query("CREATE TABLE test (n INTEGER PRIMARY KEY)")
+            .execute(&pool)
+            .await?;
+
+        let projection = Projection::new::<Dummy, _, _, _>(
+            "test-projection".to_string(),
+            TestEvtLog,
+            SerdeJsonBinarize,
+            TestHandler,
+            ErrorStrategy::Stop,
+            Batching::default(),
+            pool.clone(),
+        )
+        .await?;
+
+        projection.run().await?;
+
+        let mut state = projection.get_state().await?;
+        let max = Some(NonZeroU64::new(100).unwrap());
+        while state.seq_no(Dummy::TYPE_NAME) < max {
+            sleep(Duration::from_millis(100)).await;
+            state = projection.get_state().await?;
+        }
+        assert_eq!(sum(&pool, "n").await?, 5_050);
+
+        // Rebuild while running.
+        projection.rebuild().await?;
+        let mut state = projection.get_state().await?;
+        while state.rebuilding() {
+            sleep(Duration::from_millis(100)).await;
+            state = projection.get_state().await?;
+        }
+        assert_eq!(state.seq_no(Dummy::TYPE_NAME), max);
+        assert_eq!(sum(&pool, "n").await?, 5_050);
+
+        projection.stop().await?;
+
+        // A version bump triggers a rebuild with a changed table.
+        let projection = Projection::new::<Dummy, _, _, _>(
+            "test-projection".to_string(),
+            TestEvtLog,
+            SerdeJsonBinarize,
+            TestHandlerV1,
+            ErrorStrategy::Stop,
+            Batching::default(),
+            pool.clone(),
+        )
+        .await?;
+
+        let state = projection.run().await?;
+        assert!(state.rebuilding());
+        let mut state = projection.get_state().await?;
+        while state.rebuilding() {
+            sleep(Duration::from_millis(100)).await;
+            state = projection.get_state().await?;
+        }
+        assert_eq!(state.version(), 1);
+        assert_eq!(state.seq_no(Dummy::TYPE_NAME), max);
+        assert_eq!(sum(&pool, "m").await?, 10_100);
+
+        projection.stop().await?;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,56 @@
+//! Conversion to [Bytes] for any type that implements [Serialize] and from any type that implements
+//! [Deserialize] based upon [ciborium](https://github.com/enarx/ciborium), i.e. as
+//! [CBOR](https://cbor.io/).
+
+use crate::binarize::Binarize;
+use bytes::Bytes;
+use ciborium::{de, from_reader, into_writer, ser};
+use serde::{Deserialize, Serialize};
+use std::io;
+
+#[derive(Debug, Clone, Copy)]
+pub struct CiboriumBinarize;
+
+impl<E, S> Binarize<E, S> for CiboriumBinarize
+where
+    for<'de> E: Serialize + Deserialize<'de>,
+    for<'de> S: Serialize + Deserialize<'de>,
+{
+    type EvtToBytesError = ser::Error<io::Error>;
+    type EvtFromBytesError = de::Error<io::Error>;
+
+    type StateToBytesError = ser::Error<io::Error>;
+    type StateFromBytesError = de::Error<io::Error>;
+
+    fn evt_to_bytes(&self, evt: &E) -> Result<Bytes, Self::EvtToBytesError> {
+        to_bytes(evt)
+    }
+
+    fn state_to_bytes(&self, state: &S) -> Result<Bytes, Self::StateToBytesError> {
+        to_bytes(state)
+    }
+
+    fn evt_from_bytes(&self, bytes: Bytes) -> Result<E, Self::EvtFromBytesError> {
+        from_bytes(bytes)
+    }
+
+    fn state_from_bytes(&self, bytes: Bytes) -> Result<S, Self::StateFromBytesError> {
+        from_bytes(bytes)
+    }
+}
+
+pub fn to_bytes<T>(value: &T) -> Result<Bytes, ser::Error<io::Error>>
+where
+    T: Serialize,
+{
+    let mut bytes = vec![];
+    into_writer(value, &mut bytes)?;
+    Ok(bytes.into())
+}
+
+pub fn from_bytes<T>(bytes: Bytes) -> Result<T, de::Error<io::Error>>
+where
+    for<'de> T: Deserialize<'de>,
+{
+    from_reader(bytes.as_ref())
+}
@@ -0,0 +1,258 @@
+//! A [Binarize] wrapper which compresses the [Bytes] of any other [Binarize] implementation above
+//! a configurable size threshold, based upon [zstd](https://github.com/gyscos/zstd-rs) or
+//! [lz4](https://github.com/PSeitz/lz4_flex).
+//!
+//! Each payload is prefixed with a header byte denoting how it has been compressed, hence
+//! compressed and uncompressed payloads can coexist in the same event log or snapshot store.
+//! Notice that payloads written without this wrapper do not have such a header byte and therefore
+//! cannot be read.
+
+use crate::binarize::Binarize;
+use bytes::{BufMut, Bytes, BytesMut};
+use error_ext::BoxError;
+use std::error::Error as StdError;
+use thiserror::Error;
+
+const UNCOMPRESSED: u8 = 0;
+
+#[cfg(feature = "zstd")]
+const ZSTD: u8 = 1;
+
+#[cfg(feature = "lz4_flex")]
+const LZ4: u8 = 2;
+
+/// Compression algorithm used by [CompressedBinarize].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    /// zstd with the given compression level; `0` means the zstd default level.
+    #[cfg_attr(docsrs, doc(cfg(feature = "zstd")))]
+    #[cfg(feature = "zstd")]
+    Zstd(i32),
+
+    /// lz4 (block format).
+    #[cfg_attr(docsrs, doc(cfg(feature = "lz4_flex")))]
+    #[cfg(feature = "lz4_flex")]
+    Lz4,
+}
+
+/// A [Binarize] implementation wrapping another one and compressing its [Bytes] if their length
+/// is at least the given threshold.
+#[derive(Debug, Clone, Copy)]
+pub struct CompressedBinarize<B> {
+    binarize: B,
+    compression: Compression,
+    threshold: usize,
+}
+
+impl<B> CompressedBinarize<B> {
+    /// Create a [CompressedBinarize] wrapping the given [Binarize] implementation, using the given
+    /// [Compression] for payloads with a length of at least the given threshold.
+    pub fn new(binarize: B, compression: Compression, threshold: usize) -> Self {
+        Self {
+            binarize,
+            compression,
+            threshold,
+        }
+    }
+
+    fn compress<E>(&self, bytes: Bytes) -> Result<Bytes, Error<E>>
+    where
+        E: StdError + Send + Sync + 'static,
+    {
+        if bytes.len() < self.threshold {
+            return Ok(with_header(UNCOMPRESSED, &bytes));
+        }
+
+        let (header, compressed) = match self.compression {
+            #[cfg(feature = "zstd")]
+            Compression::Zstd(level) => {
+                let compressed = zstd::bulk::compress(&bytes, level)
+                    .map_err(|error| Error::Compress(error.into()))?;
+                (ZSTD, compressed)
+            }
+
+            #[cfg(feature = "lz4_flex")]
+            Compression::Lz4 => (LZ4, lz4_flex::compress_prepend_size(&bytes)),
+        };
+
+        // Compressing does not pay off for incompressible payloads.
+        if compressed.len() < bytes.len() {
+            Ok(with_header(header, &compressed))
+        } else {
+            Ok(with_header(UNCOMPRESSED, &bytes))
+        }
+    }
+
+    fn decompress<E>(&self, bytes: Bytes) -> Result<Bytes, Error<E>>
+    where
+        E: StdError + Send + Sync + 'static,
+    {
+        let header = *bytes.first().ok_or(Error::MissingHeader)?;
+        let payload = bytes.slice(1..);
+
+        match header {
+            UNCOMPRESSED => Ok(payload),
+
+            #[cfg(feature = "zstd")]
+            ZSTD => zstd::stream::decode_all(payload.as_ref())
+                .map(Bytes::from)
+                .map_err(|error| Error::Decompress(error.into())),
+
+            #[cfg(feature = "lz4_flex")]
+            LZ4 => lz4_flex::decompress_size_prepended(&payload)
+                .map(Bytes::from)
+                .map_err(|error| Error::Decompress(error.into())),
+
+            header => Err(Error::UnknownHeader(header)),
+        }
+    }
+}
+
+impl<E, S, B> Binarize<E, S> for CompressedBinarize<B>
+where
+    B: Binarize<E, S>,
+{
+    type EvtToBytesError = Error<B::EvtToBytesError>;
+    type EvtFromBytesError = Error<B::EvtFromBytesError>;
+
+    type StateToBytesError = Error<B::StateToBytesError>;
+    type StateFromBytesError = Error<B::StateFromBytesError>;
+
+    fn evt_to_bytes(&self, evt: &E) -> Result<Bytes, Self::EvtToBytesError> {
+        let bytes = self.binarize.evt_to_bytes(evt).map_err(Error::Binarize)?;
+        self.compress(bytes)
+    }
+
+    fn state_to_bytes(&self, state: &S) -> Result<Bytes, Self::StateToBytesError> {
+        let bytes = self
+            .binarize
+            .state_to_bytes(state)
+            .map_err(Error::Binarize)?;
+        self.compress(bytes)
+    }
+
+    fn evt_from_bytes(&self, bytes: Bytes) -> Result<E, Self::EvtFromBytesError> {
+        let bytes = self.decompress(bytes)?;
+        self.binarize.evt_from_bytes(bytes).map_err(Error::Binarize)
+    }
+
+    fn state_from_bytes(&self, bytes: Bytes) -> Result<S, Self::StateFromBytesError> {
+        let bytes = self.decompress(bytes)?;
+        self.binarize
+            .state_from_bytes(bytes)
+            .map_err(Error::Binarize)
+    }
+}
+
+/// Errors from [CompressedBinarize].
+#[derive(Debug, Error)]
+pub enum Error<E>
+where
+    E: StdError + Send + Sync + 'static,
+{
+    /// The wrapped [Binarize] implementation failed.
+    #[error("cannot convert to or from bytes")]
+    Binarize(#[source] E),
+
+    /// Bytes cannot be compressed.
+    #[error("cannot compress bytes")]
+    Compress(#[source] BoxError),
+
+    /// Bytes cannot be decompressed.
+    #[error("cannot decompress bytes")]
+    Decompress(#[source] BoxError),
+
+    /// The header byte is missing, i.e. the bytes are empty.
+    #[error("missing header byte")]
+    MissingHeader,
+
+    /// The header byte is unknown or the respective compression feature is not enabled.
+    #[error("unknown header byte {0}")]
+    UnknownHeader(u8),
+}
+
+fn with_header(header: u8, payload: &[u8]) -> Bytes {
+    let mut bytes = BytesMut::with_capacity(payload.len() + 1);
+    bytes.put_u8(header);
+    bytes.put_slice(payload);
+    bytes.freeze()
+}
+
+#[cfg(all(test, feature = "serde_json"))]
+mod tests {
+    use super::*;
+    use crate::binarize::serde_json::SerdeJsonBinarize;
+
+    fn compressions() -> Vec<Compression> {
+        vec![
+            #[cfg(feature = "zstd")]
+            Compression::Zstd(0),
+            #[cfg(feature = "lz4_flex")]
+            Compression::Lz4,
+        ]
+    }
+
+    #[test]
+    fn test_below_threshold() {
+        for compression in compressions() {
+            let binarize = CompressedBinarize::new(SerdeJsonBinarize, compression, 1_024);
+
+            let evt = "small".to_string();
+            let bytes = Binarize::<String, ()>::evt_to_bytes(&binarize, &evt).unwrap();
+            assert_eq!(bytes.first(), Some(&UNCOMPRESSED));
+
+            let evt_2 = Binarize::<String, ()>::evt_from_bytes(&binarize, bytes).unwrap();
+            assert_eq!(evt_2, evt);
+        }
+    }
+
+    #[test]
+    fn test_above_threshold() {
+        for compression in compressions() {
+            let binarize = CompressedBinarize::new(SerdeJsonBinarize, compression, 1_024);
+
+            let state = vec![42u64; 1_024];
+            let bytes = Binarize::<(), Vec<u64>>::state_to_bytes(&binarize, &state).unwrap();
+            assert_ne!(bytes.first(), Some(&UNCOMPRESSED));
+            assert!(bytes.len() < serde_json::to_vec(&state).unwrap().len());
+
+            let state_2 = Binarize::<(), Vec<u64>>::state_from_bytes(&binarize, bytes).unwrap();
+            assert_eq!(state_2, state);
+        }
+    }
+
+    #[test]
+    fn test_coexisting_payloads() {
+        for compression in compressions() {
+            let compressing = CompressedBinarize::new(SerdeJsonBinarize, compression, 0);
+            let not_compressing =
+                CompressedBinarize::new(SerdeJsonBinarize, compression, usize::MAX);
+
+            let evt = vec!["evt".to_string(); 100];
+            let compressed = Binarize::<Vec<String>, ()>::evt_to_bytes(&compressing, &evt).unwrap();
+            let uncompressed =
+                Binarize::<Vec<String>, ()>::evt_to_bytes(&not_compressing, &evt).unwrap();
+            assert_ne!(compressed, uncompressed);
+
+            for bytes in [compressed, uncompressed] {
+                let evt_2 =
+                    Binarize::<Vec<String>, ()>::evt_from_bytes(&not_compressing, bytes).unwrap();
+                assert_eq!(evt_2, evt);
+            }
+        }
+    }
+
+    #[test]
+    fn test_invalid_header() {
+        for compression in compressions() {
+            let binarize = CompressedBinarize::new(SerdeJsonBinarize, compression, 0);
+
+            let result = Binarize::<String, ()>::evt_from_bytes(&binarize, Bytes::new());
+            assert!(matches!(result, Err(Error::MissingHeader)));
+
+            let result =
+                Binarize::<String, ()>::evt_from_bytes(&binarize, Bytes::from_static(&[42]));
+            assert!(matches!(result, Err(Error::UnknownHeader(42))));
+        }
+    }
+}
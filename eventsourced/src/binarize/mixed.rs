@@ -0,0 +1,108 @@
+//! A [Binarize] implementation using one [Binarize] implementation for events and another one for
+//! snapshot state.
+
+use crate::binarize::Binarize;
+use bytes::Bytes;
+use std::{
+    fmt::{self, Debug, Formatter},
+    marker::PhantomData,
+};
+
+/// A [Binarize] implementation using one [Binarize] implementation for events and another one for
+/// snapshot state, e.g. Protocol Buffers for events and a compressed serde format for large
+/// snapshot state.
+///
+/// Only the event conversions of the former and only the state conversions of the latter are
+/// used, hence the former only needs to implement [Binarize] for the event type along with any
+/// state type `ES` and the latter only for the state type along with any event type `SE`. For
+/// implementations generic over both types, these default to `()`, see [MixedBinarize::new]; for
+/// ones implementing [Binarize] for specific types only, e.g. `Binarize<Evt, State>` of some
+/// entity, see [MixedBinarize::with_types].
+pub struct MixedBinarize<EB, SB, ES = (), SE = ()> {
+    evt_binarize: EB,
+    state_binarize: SB,
+    _types: PhantomData<fn() -> (ES, SE)>,
+}
+
+impl<EB, SB> MixedBinarize<EB, SB> {
+    /// Create a [MixedBinarize] using the given [Binarize] implementations for events and for
+    /// snapshot state respectively, which are generic over the respective other type, like the
+    /// ones provided by this crate.
+    pub fn new(evt_binarize: EB, state_binarize: SB) -> Self {
+        Self::with_types(evt_binarize, state_binarize)
+    }
+}
+
+impl<EB, SB, ES, SE> MixedBinarize<EB, SB, ES, SE> {
+    /// Create a [MixedBinarize] using the given [Binarize] implementations for events and for
+    /// snapshot state respectively, which implement [Binarize] for the event type along with the
+    /// state type `ES` and for the state type along with the event type `SE` respectively, e.g.
+    /// entity specific ones. `ES` and `SE` are inferred if unambiguous.
+    pub fn with_types(evt_binarize: EB, state_binarize: SB) -> Self {
+        Self {
+            evt_binarize,
+            state_binarize,
+            _types: PhantomData,
+        }
+    }
+}
+
+impl<EB, SB, ES, SE> Clone for MixedBinarize<EB, SB, ES, SE>
+where
+    EB: Clone,
+    SB: Clone,
+{
+    fn clone(&self) -> Self {
+        Self::with_types(self.evt_binarize.clone(), self.state_binarize.clone())
+    }
+}
+
+impl<EB, SB, ES, SE> Copy for MixedBinarize<EB, SB, ES, SE>
+where
+    EB: Copy,
+    SB: Copy,
+{
+}
+
+impl<EB, SB, ES, SE> Debug for MixedBinarize<EB, SB, ES, SE>
+where
+    EB: Debug,
+    SB: Debug,
+{
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.debug_struct("MixedBinarize")
+            .field("evt_binarize", &self.evt_binarize)
+            .field("state_binarize", &self.state_binarize)
+            .finish()
+    }
+}
+
+impl<E, S, EB, SB, ES, SE> Binarize<E, S> for MixedBinarize<EB, SB, ES, SE>
+where
+    EB: Binarize<E, ES>,
+    SB: Binarize<SE, S>,
+    ES: 'static,
+    SE: 'static,
+{
+    type EvtToBytesError = EB::EvtToBytesError;
+    type EvtFromBytesError = EB::EvtFromBytesError;
+
+    type StateToBytesError = SB::StateToBytesError;
+    type StateFromBytesError = SB::StateFromBytesError;
+
+    fn evt_to_bytes(&self, evt: &E) -> Result<Bytes, Self::EvtToBytesError> {
+        self.evt_binarize.evt_to_bytes(evt)
+    }
+
+    fn state_to_bytes(&self, state: &S) -> Result<Bytes, Self::StateToBytesError> {
+        self.state_binarize.state_to_bytes(state)
+    }
+
+    fn evt_from_bytes(&self, bytes: Bytes) -> Result<E, Self::EvtFromBytesError> {
+        self.evt_binarize.evt_from_bytes(bytes)
+    }
+
+    fn state_from_bytes(&self, bytes: Bytes) -> Result<S, Self::StateFromBytesError> {
+        self.state_binarize.state_from_bytes(bytes)
+    }
+}
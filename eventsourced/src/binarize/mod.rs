@@ -3,10 +3,30 @@
 use bytes::Bytes;
 use std::error::Error as StdError;
 
+mod mixed;
+
+pub use mixed::*;
+
+#[cfg_attr(docsrs, doc(cfg(feature = "ciborium")))]
+#[cfg(feature = "ciborium")]
+pub mod ciborium;
+
+#[cfg_attr(docsrs, doc(cfg(any(feature = "zstd", feature = "lz4_flex"))))]
+#[cfg(any(feature = "zstd", feature = "lz4_flex"))]
+pub mod compressed;
+
+#[cfg_attr(docsrs, doc(cfg(feature = "postcard")))]
+#[cfg(feature = "postcard")]
+pub mod postcard;
+
 #[cfg_attr(docsrs, doc(cfg(feature = "prost")))]
 #[cfg(feature = "prost")]
 pub mod prost;
 
+#[cfg_attr(docsrs, doc(cfg(feature = "rmp-serde")))]
+#[cfg(feature = "rmp-serde")]
+pub mod rmp_serde;
+
 #[cfg_attr(docsrs, doc(cfg(feature = "serde_json")))]
 #[cfg(feature = "serde_json")]
 pub mod serde_json;
@@ -31,3 +51,164 @@ pub trait Binarize<E, S>: Copy + Send + Sync + 'static {
     /// Convert bytes to state.
     fn state_from_bytes(&self, bytes: Bytes) -> Result<S, Self::StateFromBytesError>;
 }
+
+#[cfg(all(test, feature = "serde_json"))]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+    use serde::{Deserialize, Serialize};
+    use std::{collections::BTreeMap, fmt::Debug};
+
+    #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+    enum Evt {
+        Created { name: String, tags: Vec<String> },
+        Increased(u64),
+        Decreased(i64),
+        Deleted,
+    }
+
+    #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+    struct State {
+        value: u64,
+        limit: Option<u32>,
+        names: BTreeMap<String, bool>,
+        bytes: Vec<u8>,
+    }
+
+    fn evt() -> impl Strategy<Value = Evt> {
+        prop_oneof![
+            (any::<String>(), any::<Vec<String>>())
+                .prop_map(|(name, tags)| Evt::Created { name, tags }),
+            any::<u64>().prop_map(Evt::Increased),
+            any::<i64>().prop_map(Evt::Decreased),
+            Just(Evt::Deleted),
+        ]
+    }
+
+    fn state() -> impl Strategy<Value = State> {
+        (
+            any::<u64>(),
+            any::<Option<u32>>(),
+            any::<BTreeMap<String, bool>>(),
+            any::<Vec<u8>>(),
+        )
+            .prop_map(|(value, limit, names, bytes)| State {
+                value,
+                limit,
+                names,
+                bytes,
+            })
+    }
+
+    fn round_trip<E, S, B>(binarize: B, evt: E, state: S) -> Result<(), TestCaseError>
+    where
+        E: Debug + PartialEq,
+        S: Debug + PartialEq,
+        B: Binarize<E, S>,
+    {
+        let bytes = binarize.evt_to_bytes(&evt).map_err(fail)?;
+        prop_assert_eq!(binarize.evt_from_bytes(bytes).map_err(fail)?, evt);
+
+        let bytes = binarize.state_to_bytes(&state).map_err(fail)?;
+        prop_assert_eq!(binarize.state_from_bytes(bytes).map_err(fail)?, state);
+
+        Ok(())
+    }
+
+    fn fail(error: impl StdError) -> TestCaseError {
+        TestCaseError::fail(error.to_string())
+    }
+
+    /// An entity specific [Binarize] implementation, which is not generic over the event and state
+    /// types.
+    #[derive(Debug, Clone, Copy)]
+    struct EntityBinarize;
+
+    impl Binarize<Evt, State> for EntityBinarize {
+        type EvtToBytesError = ::serde_json::Error;
+        type EvtFromBytesError = ::serde_json::Error;
+
+        type StateToBytesError = ::serde_json::Error;
+        type StateFromBytesError = ::serde_json::Error;
+
+        fn evt_to_bytes(&self, evt: &Evt) -> Result<Bytes, Self::EvtToBytesError> {
+            ::serde_json::to_vec(evt).map(Bytes::from)
+        }
+
+        fn state_to_bytes(&self, state: &State) -> Result<Bytes, Self::StateToBytesError> {
+            ::serde_json::to_vec(state).map(Bytes::from)
+        }
+
+        fn evt_from_bytes(&self, bytes: Bytes) -> Result<Evt, Self::EvtFromBytesError> {
+            ::serde_json::from_slice(&bytes)
+        }
+
+        fn state_from_bytes(&self, bytes: Bytes) -> Result<State, Self::StateFromBytesError> {
+            ::serde_json::from_slice(&bytes)
+        }
+    }
+
+    proptest! {
+        #[cfg(feature = "ciborium")]
+        #[test]
+        fn test_ciborium(evt in evt(), state in state()) {
+            round_trip(ciborium::CiboriumBinarize, evt, state)?;
+        }
+
+        #[cfg(feature = "postcard")]
+        #[test]
+        fn test_postcard(evt in evt(), state in state()) {
+            round_trip(postcard::PostcardBinarize, evt, state)?;
+        }
+
+        #[cfg(feature = "prost")]
+        #[test]
+        fn test_prost(evt in any::<String>(), state in any::<Vec<u8>>()) {
+            round_trip(prost::ProstBinarize, evt, state)?;
+        }
+
+        #[cfg(feature = "rmp-serde")]
+        #[test]
+        fn test_rmp_serde(evt in evt(), state in state()) {
+            round_trip(rmp_serde::RmpSerdeBinarize, evt, state)?;
+        }
+
+        #[cfg(feature = "serde_json")]
+        #[test]
+        fn test_serde_json(evt in evt(), state in state()) {
+            round_trip(serde_json::SerdeJsonBinarize, evt, state)?;
+        }
+
+        #[cfg(any(feature = "zstd", feature = "lz4_flex"))]
+        #[test]
+        fn test_compressed(evt in evt(), state in state(), threshold in 0..256usize) {
+            #[cfg(feature = "zstd")]
+            let compression = compressed::Compression::Zstd(0);
+            #[cfg(not(feature = "zstd"))]
+            let compression = compressed::Compression::Lz4;
+
+            let binarize = compressed::CompressedBinarize::new(
+                serde_json::SerdeJsonBinarize,
+                compression,
+                threshold,
+            );
+            round_trip(binarize, evt, state)?;
+        }
+
+        #[cfg(all(feature = "prost", feature = "postcard"))]
+        #[test]
+        fn test_mixed(evt in any::<String>(), state in state()) {
+            let binarize = MixedBinarize::new(prost::ProstBinarize, postcard::PostcardBinarize);
+            round_trip(binarize, evt, state)?;
+        }
+
+        #[test]
+        fn test_mixed_with_types(evt in evt(), state in state()) {
+            let binarize = MixedBinarize::<_, _, _, ()>::with_types(
+                EntityBinarize,
+                serde_json::SerdeJsonBinarize,
+            );
+            round_trip(binarize, evt, state)?;
+        }
+    }
+}
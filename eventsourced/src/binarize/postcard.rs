@@ -0,0 +1,55 @@
+//! Conversion to [Bytes] for any type that implements [Serialize] and from any type that implements
+//! [Deserialize] based upon [postcard](https://github.com/jamesmunns/postcard).
+//!
+//! Postcard is a compact, non self-describing format, i.e. it is not possible to add fields to
+//! already persisted events or snapshot state.
+
+use crate::binarize::Binarize;
+use bytes::Bytes;
+use postcard::{from_bytes as postcard_from_bytes, to_allocvec, Error};
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Copy)]
+pub struct PostcardBinarize;
+
+impl<E, S> Binarize<E, S> for PostcardBinarize
+where
+    for<'de> E: Serialize + Deserialize<'de>,
+    for<'de> S: Serialize + Deserialize<'de>,
+{
+    type EvtToBytesError = Error;
+    type EvtFromBytesError = Error;
+
+    type StateToBytesError = Error;
+    type StateFromBytesError = Error;
+
+    fn evt_to_bytes(&self, evt: &E) -> Result<Bytes, Self::EvtToBytesError> {
+        to_bytes(evt)
+    }
+
+    fn state_to_bytes(&self, state: &S) -> Result<Bytes, Self::StateToBytesError> {
+        to_bytes(state)
+    }
+
+    fn evt_from_bytes(&self, bytes: Bytes) -> Result<E, Self::EvtFromBytesError> {
+        from_bytes(bytes)
+    }
+
+    fn state_from_bytes(&self, bytes: Bytes) -> Result<S, Self::StateFromBytesError> {
+        from_bytes(bytes)
+    }
+}
+
+pub fn to_bytes<T>(value: &T) -> Result<Bytes, Error>
+where
+    T: Serialize,
+{
+    to_allocvec(value).map(Bytes::from)
+}
+
+pub fn from_bytes<T>(bytes: Bytes) -> Result<T, Error>
+where
+    for<'de> T: Deserialize<'de>,
+{
+    postcard_from_bytes::<T>(&bytes)
+}
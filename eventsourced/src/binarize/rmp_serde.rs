@@ -0,0 +1,55 @@
+//! Conversion to [Bytes] for any type that implements [Serialize] and from any type that implements
+//! [Deserialize] based upon [rmp-serde](https://github.com/3Hren/msgpack-rust), i.e. as
+//! [MessagePack](https://msgpack.org/).
+//!
+//! Structs are serialized as maps, i.e. with field names, which allows for adding optional fields.
+
+use crate::binarize::Binarize;
+use bytes::Bytes;
+use rmp_serde::{decode, encode, from_slice, to_vec_named};
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Copy)]
+pub struct RmpSerdeBinarize;
+
+impl<E, S> Binarize<E, S> for RmpSerdeBinarize
+where
+    for<'de> E: Serialize + Deserialize<'de>,
+    for<'de> S: Serialize + Deserialize<'de>,
+{
+    type EvtToBytesError = encode::Error;
+    type EvtFromBytesError = decode::Error;
+
+    type StateToBytesError = encode::Error;
+    type StateFromBytesError = decode::Error;
+
+    fn evt_to_bytes(&self, evt: &E) -> Result<Bytes, Self::EvtToBytesError> {
+        to_bytes(evt)
+    }
+
+    fn state_to_bytes(&self, state: &S) -> Result<Bytes, Self::StateToBytesError> {
+        to_bytes(state)
+    }
+
+    fn evt_from_bytes(&self, bytes: Bytes) -> Result<E, Self::EvtFromBytesError> {
+        from_bytes(bytes)
+    }
+
+    fn state_from_bytes(&self, bytes: Bytes) -> Result<S, Self::StateFromBytesError> {
+        from_bytes(bytes)
+    }
+}
+
+pub fn to_bytes<T>(value: &T) -> Result<Bytes, encode::Error>
+where
+    T: Serialize,
+{
+    to_vec_named(value).map(Bytes::from)
+}
+
+pub fn from_bytes<T>(bytes: Bytes) -> Result<T, decode::Error>
+where
+    for<'de> T: Deserialize<'de>,
+{
+    from_slice::<T>(&bytes)
+}
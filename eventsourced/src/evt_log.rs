@@ -2,10 +2,30 @@
 
 use crate::EventSourced;
 use bytes::Bytes;
-use futures::Stream;
-use std::{error::Error as StdError, fmt::Debug, future::Future, num::NonZeroU64};
+use futures::{future::ready, stream, Stream, StreamExt, TryStreamExt};
+use std::{
+    collections::VecDeque, error::Error as StdError, fmt::Debug, future::Future, num::NonZeroU64,
+    ops::RangeInclusive,
+};
+
+/// The number of slots the entities of a type are distributed to by a stable hash of their ID, see
+/// [EvtLog::evts_by_type_in_slots]; a power of two.
+pub const SLOTS: usize = 64;
+
+/// The slot for the given key, e.g. an encoded entity ID, using the 64-bit FNV-1a hash, which,
+/// other than the hashers of the standard library, is stable across processes and Rust versions.
+pub fn slot(key: &[u8]) -> usize {
+    let hash = key.iter().fold(0xcbf29ce484222325_u64, |hash, byte| {
+        (hash ^ *byte as u64).wrapping_mul(0x100000001b3)
+    });
+    (hash % SLOTS as u64) as usize
+}
 
 /// Persistence for events.
+///
+/// Only the methods for persisting and getting the events by ID and by type must be implemented;
+/// the ones for tags, idempotency keys and current events have default implementations based on
+/// those, which implementations should override if they can do better.
 pub trait EvtLog: Clone + Send + 'static {
     type Id: Debug;
 
@@ -15,16 +35,18 @@ pub trait EvtLog: Clone + Send + 'static {
     /// implementation.
     const MAX_SEQ_NO: NonZeroU64 = NonZeroU64::MAX;
 
-    /// Persist the given event and optional tag for the given entity ID and return the
-    /// sequence number for the persisted event. The given last sequence number is used for
-    /// optimistic locking, i.e. it must match the current last sequence number of the event log.
+    /// Persist the given event for the given entity ID, storing its tags as given by
+    /// [EventSourced::tags] and the given optional idempotency key alongside, and return the
+    /// sequence number and the position of the persisted event. The given last sequence number is
+    /// used for optimistic locking, i.e. it must match the current last one of the event log.
     fn persist<E, ToBytes, ToBytesError>(
         &mut self,
         evt: &E::Evt,
         id: &Self::Id,
         last_seq_no: Option<NonZeroU64>,
+        idempotency_key: Option<&str>,
         to_bytes: &ToBytes,
-    ) -> impl Future<Output = Result<NonZeroU64, Self::Error>> + Send
+    ) -> impl Future<Output = Result<Persisted, Self::Error>> + Send
     where
         E: EventSourced,
         ToBytes: Fn(&E::Evt) -> Result<Bytes, ToBytesError> + Sync,
@@ -38,7 +60,51 @@ pub trait EvtLog: Clone + Send + 'static {
     where
         E: EventSourced;
 
-    /// Get the events for the given entity ID starting at the given sequence number.
+    /// Get the last sequence number for the given entity type, i.e. the position of its last event.
+    fn last_seq_no_by_type<E>(
+        &self,
+    ) -> impl Future<Output = Result<Option<NonZeroU64>, Self::Error>> + Send
+    where
+        E: EventSourced;
+
+    /// Get the last sequence number for the given entity type and the given tag. The default
+    /// implementation returns the last sequence number by type, which is an upper bound.
+    fn last_seq_no_by_tag<E>(
+        &self,
+        _tag: &str,
+    ) -> impl Future<Output = Result<Option<NonZeroU64>, Self::Error>> + Send
+    where
+        E: EventSourced,
+    {
+        self.last_seq_no_by_type::<E>()
+    }
+
+    /// Get the idempotency keys stored alongside the events for the given entity ID starting at
+    /// the given sequence number up to the current last one, skipping events without one. The
+    /// default implementation does not store idempotency keys, hence returns none, such that
+    /// commands are only deduplicated as long as an entity is running.
+    #[allow(clippy::type_complexity)]
+    fn idempotency_keys<E>(
+        &self,
+        _id: &Self::Id,
+        _seq_no: NonZeroU64,
+    ) -> impl Future<
+        Output = Result<
+            impl Stream<Item = Result<(Persisted, String), Self::Error>> + Send,
+            Self::Error,
+        >,
+    > + Send
+    where
+        E: EventSourced,
+    {
+        async { Ok(stream::empty()) }
+    }
+
+    /// Get the events for the given entity ID starting at the given sequence number. Like for
+    /// [evts_by_type](EvtLog::evts_by_type), the returned stream does not end, but tails newly
+    /// persisted events; see [current_evts_by_id](EvtLog::current_evts_by_id) for a stream which
+    /// ends with the last event at query time.
+    #[allow(clippy::type_complexity)]
     fn evts_by_id<E, FromBytes, FromBytesError>(
         &self,
         id: &Self::Id,
@@ -55,7 +121,10 @@ pub trait EvtLog: Clone + Send + 'static {
         FromBytes: Fn(Bytes) -> Result<E::Evt, FromBytesError> + Copy + Send + Sync + 'static,
         FromBytesError: StdError + Send + Sync + 'static;
 
-    /// Get the events for the given entity type starting at the given sequence number.
+    /// Get the events for the given entity type starting at the given sequence number. The
+    /// sequence numbers of the events by type are their positions, see [Persisted::position], and
+    /// the returned stream does not end, but tails newly persisted events.
+    #[allow(clippy::type_complexity)]
     fn evts_by_type<E, FromBytes, FromBytesError>(
         &self,
         seq_no: NonZeroU64,
@@ -70,4 +139,323 @@ pub trait EvtLog: Clone + Send + 'static {
         E: EventSourced,
         FromBytes: Fn(Bytes) -> Result<E::Evt, FromBytesError> + Copy + Send + Sync + 'static,
         FromBytesError: StdError + Send + Sync + 'static;
+
+    /// Get the events for the given entity type starting at the given sequence number like
+    /// [evts_by_type](EvtLog::evts_by_type), yet only the ones of the entities in the given slots
+    /// out of [SLOTS], along with their slot, which is determined by a stable hash of the entity ID
+    /// specific to the implementation, e.g. to partition the events to parallel consumers. Returns
+    /// `None` if not supported, which is what the default implementation does.
+    ///
+    /// Implementations should filter the events on the server, such that each consumer only reads
+    /// its share. To track progress nevertheless, sequence numbers may come without event, stating
+    /// that there are no further events of the given slots up to them, and whenever caught up, the
+    /// sequence number of the last event of the type must have come, with or without event.
+    #[allow(clippy::type_complexity)]
+    fn evts_by_type_in_slots<E, FromBytes, FromBytesError>(
+        &self,
+        _slots: &[usize],
+        _seq_no: NonZeroU64,
+        _from_bytes: FromBytes,
+    ) -> impl Future<
+        Output = Result<
+            Option<
+                impl Stream<Item = Result<(NonZeroU64, Option<(usize, E::Evt)>), Self::Error>> + Send,
+            >,
+            Self::Error,
+        >,
+    > + Send
+    where
+        E: EventSourced,
+        FromBytes: Fn(Bytes) -> Result<E::Evt, FromBytesError> + Copy + Send + Sync + 'static,
+        FromBytesError: StdError + Send + Sync + 'static,
+    {
+        async { Ok(None::<stream::Empty<_>>) }
+    }
+
+    /// Get the events for the given entity type with the given tag starting at the given sequence
+    /// number. Like for [EvtLog::evts_by_type], the sequence numbers are not the ones of the
+    /// respective entities and the returned stream does not end, but tails newly persisted events.
+    /// The default implementation filters the events by type by their tags.
+    #[allow(clippy::type_complexity)]
+    fn evts_by_tag<E, FromBytes, FromBytesError>(
+        &self,
+        tag: &str,
+        seq_no: NonZeroU64,
+        from_bytes: FromBytes,
+    ) -> impl Future<
+        Output = Result<
+            impl Stream<Item = Result<(NonZeroU64, E::Evt), Self::Error>> + Send,
+            Self::Error,
+        >,
+    > + Send
+    where
+        E: EventSourced,
+        FromBytes: Fn(Bytes) -> Result<E::Evt, FromBytesError> + Copy + Send + Sync + 'static,
+        FromBytesError: StdError + Send + Sync + 'static,
+    {
+        let evts = self.evts_by_type::<E, _, _>(seq_no, from_bytes);
+        let tag = tag.to_string();
+        async move {
+            let evts = evts
+                .await?
+                .try_filter(move |(_, evt)| ready(E::tags(evt).contains(&tag)));
+            Ok(evts)
+        }
+    }
+
+    /// Get the current events for the given entity ID according to the given query, i.e. the
+    /// returned stream ends with the last event at query time or the upper bound of the query,
+    /// whichever comes first. The default implementation takes the events by ID up to the last
+    /// sequence number at query time, buffering the last ones up to the limit in reverse order.
+    #[allow(clippy::type_complexity)]
+    fn current_evts_by_id<E, FromBytes, FromBytesError>(
+        &self,
+        id: &Self::Id,
+        query: EvtsQuery,
+        from_bytes: FromBytes,
+    ) -> impl Future<
+        Output = Result<
+            impl Stream<Item = Result<(NonZeroU64, E::Evt), Self::Error>> + Send,
+            Self::Error,
+        >,
+    > + Send
+    where
+        E: EventSourced,
+        FromBytes: Fn(Bytes) -> Result<E::Evt, FromBytesError> + Copy + Send + Sync + 'static,
+        FromBytesError: StdError + Send + Sync + 'static,
+    {
+        // Not holding `self` or `id` across awaits, as these need not be `Sync`.
+        let last_seq_no = self.last_seq_no::<E>(id);
+        let evts = self.evts_by_id::<E, _, _>(id, query.from, from_bytes);
+        async move { current_evts(last_seq_no.await?, evts, query).await }
+    }
+
+    /// Get the current events for the given entity type according to the given query, with the
+    /// same sequence numbers as [EvtLog::evts_by_type], i.e. the returned stream ends with the last
+    /// event at query time or the upper bound of the query, whichever comes first. The default
+    /// implementation takes the events by type up to the last sequence number at query time,
+    /// buffering the last ones up to the limit in reverse order.
+    #[allow(clippy::type_complexity)]
+    fn current_evts_by_type<E, FromBytes, FromBytesError>(
+        &self,
+        query: EvtsQuery,
+        from_bytes: FromBytes,
+    ) -> impl Future<
+        Output = Result<
+            impl Stream<Item = Result<(NonZeroU64, E::Evt), Self::Error>> + Send,
+            Self::Error,
+        >,
+    > + Send
+    where
+        E: EventSourced,
+        FromBytes: Fn(Bytes) -> Result<E::Evt, FromBytesError> + Copy + Send + Sync + 'static,
+        FromBytesError: StdError + Send + Sync + 'static,
+    {
+        let last_seq_no = self.last_seq_no_by_type::<E>();
+        let evts = self.evts_by_type::<E, _, _>(query.from, from_bytes);
+        async move { current_evts(last_seq_no.await?, evts, query).await }
+    }
+}
+
+/// The sequence number of a persisted event for its entity and its position in the [EvtLog], which
+/// is the sequence number of the event in the events by type, hence increasing with the order the
+/// events become visible there, e.g. to wait for a projection to reach it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Persisted {
+    pub seq_no: NonZeroU64,
+    pub position: NonZeroU64,
+}
+
+/// A query for the finite [EvtLog::current_evts_by_id] and [EvtLog::current_evts_by_type], by
+/// default for all events up to the last one at query time in ascending order.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EvtsQuery {
+    /// Lower bound (inclusive) of the sequence numbers.
+    pub from: NonZeroU64,
+
+    /// Optional upper bound (inclusive) of the sequence numbers.
+    pub to: Option<NonZeroU64>,
+
+    /// Optional maximum number of events, applied after ordering, i.e. in reverse order the ones
+    /// with the highest sequence numbers are returned.
+    pub limit: Option<u64>,
+
+    /// Whether to return the events in descending order of their sequence numbers.
+    pub reverse: bool,
+}
+
+impl EvtsQuery {
+    /// A query for the events with sequence numbers in the given inclusive range.
+    pub fn range(range: RangeInclusive<NonZeroU64>) -> Self {
+        Self {
+            from: *range.start(),
+            to: Some(*range.end()),
+            ..Default::default()
+        }
+    }
+
+    /// A query for the events with sequence numbers starting at the given one.
+    pub fn from(seq_no: NonZeroU64) -> Self {
+        Self {
+            from: seq_no,
+            ..Default::default()
+        }
+    }
+
+    /// Return at most the given number of events.
+    pub fn with_limit(mut self, limit: u64) -> Self {
+        self.limit = Some(limit);
+        self
+    }
+
+    /// Return the events in descending order of their sequence numbers.
+    pub fn reversed(mut self) -> Self {
+        self.reverse = true;
+        self
+    }
+
+    /// Whether the given sequence number is within the bounds of this query.
+    pub fn contains(&self, seq_no: NonZeroU64) -> bool {
+        self.from <= seq_no && self.to.is_none_or(|to| seq_no <= to)
+    }
+
+    /// The upper bound of this query given the last sequence number at query time, if any events
+    /// can match at all.
+    pub fn end(&self, last_seq_no: Option<NonZeroU64>) -> Option<NonZeroU64> {
+        let end = match (self.to, last_seq_no) {
+            (Some(to), Some(last_seq_no)) => to.min(last_seq_no),
+            (_, last_seq_no) => last_seq_no?,
+        };
+        (self.from <= end).then_some(end)
+    }
+}
+
+/// The events of the given not ending stream according to the given query, up to the given last
+/// sequence number at query time, which the stream must reach.
+async fn current_evts<T, E>(
+    last_seq_no: Option<NonZeroU64>,
+    evts: impl Future<Output = Result<impl Stream<Item = Result<(NonZeroU64, T), E>> + Send, E>>,
+    query: EvtsQuery,
+) -> Result<impl Stream<Item = Result<(NonZeroU64, T), E>> + Send, E>
+where
+    T: Send,
+    E: Send,
+{
+    let Some(end) = query.end(last_seq_no) else {
+        return Ok(stream::empty().left_stream());
+    };
+
+    // End right after the event at the end, as polling the not ending stream again might never
+    // complete.
+    let evts = stream::unfold(Some(Box::pin(evts.await?)), move |evts| async move {
+        let mut evts = evts?;
+        let evt = evts.next().await?;
+        let done = evt.as_ref().is_ok_and(|(seq_no, _)| *seq_no >= end);
+        Some((evt, (!done).then_some(evts)))
+    })
+    .try_filter(move |(seq_no, _)| ready(query.contains(*seq_no)));
+    let limit = query.limit.map_or(usize::MAX, |limit| {
+        usize::try_from(limit).unwrap_or(usize::MAX)
+    });
+
+    let evts = if query.reverse {
+        // Only keep the last events up to the limit.
+        let evts = evts
+            .try_fold(VecDeque::new(), move |mut evts, evt| {
+                if limit > 0 {
+                    if evts.len() == limit {
+                        evts.pop_front();
+                    }
+                    evts.push_back(evt);
+                }
+                ready(Ok(evts))
+            })
+            .await?;
+        stream::iter(evts.into_iter().rev().map(Ok)).left_stream()
+    } else {
+        evts.take(limit).right_stream()
+    };
+
+    Ok(evts.right_stream())
+}
+
+impl Default for EvtsQuery {
+    fn default() -> Self {
+        Self {
+            from: NonZeroU64::MIN,
+            to: None,
+            limit: None,
+            reverse: false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{convert::Infallible, time::Duration};
+    use tokio::time::timeout;
+
+    #[test]
+    fn test_slot() {
+        // FNV-1a must be stable, else the assignment of entities to slots would change.
+        assert_eq!(slot(b""), (0xcbf29ce484222325_u64 % 64) as usize);
+        assert_eq!(slot(b"a"), (0xaf63dc4c8601ec8c_u64 % 64) as usize);
+    }
+
+    #[tokio::test]
+    async fn test_current_evts() {
+        let n = |n| NonZeroU64::new(n).unwrap();
+        // Like a real event log, the events do not end, but are pending after the last one.
+        let evts = || async {
+            let evts = stream::iter(1..=5)
+                .map(|n| Ok::<_, Infallible>((NonZeroU64::new(n).unwrap(), n)))
+                .chain(stream::pending());
+            Ok(evts)
+        };
+        let current = |last_seq_no, query| async move {
+            let evts = current_evts(last_seq_no, evts(), query)
+                .await
+                .unwrap()
+                .try_collect::<Vec<_>>();
+            timeout(Duration::from_secs(1), evts)
+                .await
+                .expect("current events end")
+                .unwrap()
+        };
+
+        assert!(current(None, EvtsQuery::default()).await.is_empty());
+
+        let evts = current(Some(n(5)), EvtsQuery::from(n(2)).with_limit(2)).await;
+        assert_eq!(evts, vec![(n(2), 2), (n(3), 3)]);
+
+        let evts = current(Some(n(5)), EvtsQuery::from(n(2)).with_limit(2).reversed()).await;
+        assert_eq!(evts, vec![(n(5), 5), (n(4), 4)]);
+
+        let evts = current(Some(n(5)), EvtsQuery::range(n(2)..=n(3)).reversed()).await;
+        assert_eq!(evts, vec![(n(3), 3), (n(2), 2)]);
+
+        let evts = current(Some(n(5)), EvtsQuery::from(n(4))).await;
+        assert_eq!(evts, vec![(n(4), 4), (n(5), 5)]);
+    }
+
+    #[test]
+    fn test_evts_query() {
+        let n = |n| NonZeroU64::new(n).unwrap();
+
+        let query = EvtsQuery::range(n(2)..=n(5)).with_limit(2).reversed();
+        assert!(query.reverse);
+        assert_eq!(query.limit, Some(2));
+        assert!(!query.contains(n(1)));
+        assert!(query.contains(n(5)));
+        assert!(!query.contains(n(6)));
+        assert_eq!(query.end(None), None);
+        assert_eq!(query.end(Some(n(1))), None);
+        assert_eq!(query.end(Some(n(3))), Some(n(3)));
+        assert_eq!(query.end(Some(n(7))), Some(n(5)));
+
+        let query = EvtsQuery::from(n(3));
+        assert!(query.contains(n(42)));
+        assert_eq!(query.end(Some(n(42))), Some(n(42)));
+    }
 }
@@ -16,7 +16,12 @@
 //! identifiable by an ID, for some event log and  some snapshot store. Conversion of events and
 //! snapshot state to and from bytes happens via the given [Binarize] implementation; for
 //! [prost](https://github.com/tokio-rs/prost) and
-//! [serde_json](https://github.com/serde-rs/json) these are already provided.
+//! [serde_json](https://github.com/serde-rs/json) these are already provided, as well as for
+//! [CBOR](https://cbor.io/), [MessagePack](https://msgpack.org/) and
+//! [postcard](https://github.com/jamesmunns/postcard). A
+//! [MixedBinarize](binarize::MixedBinarize) uses one of these for events and another one for
+//! snapshot state. Any [Binarize] implementation can be wrapped in a
+//! [CompressedBinarize](binarize::compressed::CompressedBinarize) to compress large payloads.
 //!
 //! Calling [spawn](EventSourcedExt::spawn) results in a cloneable [EntityRef] which can be used to
 //! pass commands to the spawned entity by invoking [handle_cmd](EntityRef::handle_cmd). Commands
@@ -24,8 +29,16 @@
 //! error. Valid commands produce an event which gets persisted to the [EvtLog] and then applied to
 //! the event handler of the respective entity. Snapshots can be taken to speed up future spawning.
 //!
-//! Events can be queried from the event log by ID or by entity type. These queries can be used to
-//! build read side projections. There is early support for projections in the
+//! Commands can carry an idempotency key by invoking
+//! [handle_cmd_idempotent](EntityRef::handle_cmd_idempotent), e.g. to safely retry them. The key is
+//! stored alongside the resulting event and the entity keeps the keys of its most recent events,
+//! see [IDEMPOTENCY_WINDOW](EventSourced::IDEMPOTENCY_WINDOW), returning the sequence number and
+//! the position of the original event for duplicates instead of persisting again.
+//!
+//! Events can be queried from the event log by ID or by entity type, either tailing newly persisted
+//! events or, via the `current_` variants and an [EvtsQuery], only the current ones in some range
+//! with an optional limit and in optionally reverse order. These queries can be used to build read
+//! side projections. There is early support for projections in the
 //! `eventsourced-projection` crate.
 
 pub mod binarize;
@@ -41,6 +54,7 @@ use error_ext::{BoxError, StdErrorExt};
 use futures::{future::ok, TryStreamExt};
 use serde::{Deserialize, Serialize};
 use std::{
+    collections::VecDeque,
     error::Error as StdError,
     fmt::Debug,
     num::{NonZeroU64, NonZeroUsize},
@@ -71,6 +85,10 @@ pub trait EventSourced {
 
     const TYPE_NAME: &'static str;
 
+    /// Number of most recent events of an entity whose idempotency keys are kept, restored when
+    /// spawning, to detect duplicate commands. Defaults to zero, i.e. no detection.
+    const IDEMPOTENCY_WINDOW: u64 = 0;
+
     /// Command handler, returning the to be persisted event or an error.
     fn handle_cmd(
         id: &Self::Id,
@@ -80,6 +98,12 @@ pub trait EventSourced {
 
     /// Event handler.
     fn handle_evt(state: Self::State, evt: Self::Evt) -> Self::State;
+
+    /// Tags for the given event, persisted alongside it, such that events of cross-cutting
+    /// categories can be queried via [EvtLog::evts_by_tag]. Defaults to no tags.
+    fn tags(_evt: &Self::Evt) -> Vec<String> {
+        vec![]
+    }
 }
 
 /// Extension methods for types implementing [EventSourced].
@@ -133,6 +157,22 @@ pub trait EventSourcedExt: Sized {
             return Err(SpawnError::InvalidLastSeqNo(last_seq_no, snapshot_seq_no));
         };
 
+        // Restore idempotency keys of the most recent events.
+        let mut idempotency_keys = IdempotencyKeys::new(Self::IDEMPOTENCY_WINDOW);
+        if let Some(from_seq_no) = last_seq_no.and_then(|n| idempotency_keys.first_seq_no(n)) {
+            idempotency_keys = evt_log
+                .idempotency_keys::<Self>(&id, from_seq_no)
+                .await
+                .map_err(|error| SpawnError::IdempotencyKeys(error.into()))?
+                .map_err(|error| SpawnError::IdempotencyKeys(error.into()))
+                .try_fold(idempotency_keys, |mut keys, (persisted, key)| {
+                    keys.insert(persisted, key);
+                    ok(keys)
+                })
+                .await?;
+            debug!(?id, ?idempotency_keys, "restored idempotency keys");
+        }
+
         // Replay latest events.
         let mut state = state.unwrap_or_default();
         if snapshot_seq_no < last_seq_no {
@@ -143,15 +183,16 @@ pub trait EventSourcedExt: Sized {
             debug!(?id, from_seq_no, to_seq_no, "replaying evts");
 
             let evts = evt_log
-                .evts_by_id::<Self, _, _>(&id, from_seq_no, move |bytes| {
-                    binarize.evt_from_bytes(bytes)
-                })
+                .current_evts_by_id::<Self, _, _>(
+                    &id,
+                    EvtsQuery::range(from_seq_no..=to_seq_no),
+                    move |bytes| binarize.evt_from_bytes(bytes),
+                )
                 .await
                 .map_err(|error| SpawnError::EvtsById(error.into()))?;
 
             state = evts
                 .map_err(|error| SpawnError::NextEvt(error.into()))
-                .try_take_while(|(seq_no, _)| ok(*seq_no <= to_seq_no))
                 .try_fold(state, |state, (_, evt)| ok(Self::handle_evt(state, evt)))
                 .await?;
 
@@ -161,28 +202,55 @@ pub trait EventSourcedExt: Sized {
         // Spawn handler loop.
         let (cmd_in, mut cmd_out) = mpsc::channel::<(
             Self::Cmd,
-            oneshot::Sender<Result<(), Self::Error>>,
+            Option<String>,
+            oneshot::Sender<Result<Persisted, Self::Error>>,
         )>(cmd_buffer.get());
         task::spawn({
             let mut evt_count = 0u64;
 
             async move {
-                while let Some((cmd, result_sender)) = cmd_out.recv().await {
-                    debug!(?id, ?cmd, "handling command");
+                while let Some((cmd, idempotency_key, result_sender)) = cmd_out.recv().await {
+                    debug!(?id, ?cmd, ?idempotency_key, "handling command");
+
+                    // Return the outcome of the original command for duplicates.
+                    if let Some(persisted) = idempotency_key
+                        .as_deref()
+                        .and_then(|key| idempotency_keys.get(key, last_seq_no))
+                    {
+                        debug!(
+                            ?id,
+                            ?cmd,
+                            ?idempotency_key,
+                            ?persisted,
+                            "ignoring duplicate command"
+                        );
+                        if result_sender.send(Ok(persisted)).is_err() {
+                            error!(?id, "cannot send command handler OK");
+                        };
+                        continue;
+                    }
 
                     match Self::handle_cmd(&id, &state, cmd) {
                         Ok(evt) => {
                             debug!(?id, ?evt, "persisting event");
 
                             match evt_log
-                                .persist::<Self, _, _>(&evt, &id, last_seq_no, &|evt| {
-                                    binarize.evt_to_bytes(evt)
-                                })
+                                .persist::<Self, _, _>(
+                                    &evt,
+                                    &id,
+                                    last_seq_no,
+                                    idempotency_key.as_deref(),
+                                    &|evt| binarize.evt_to_bytes(evt),
+                                )
                                 .await
                             {
-                                Ok(seq_no) => {
+                                Ok(persisted) => {
+                                    let seq_no = persisted.seq_no;
                                     debug!(?id, ?evt, seq_no, "persited event");
 
+                                    if let Some(key) = idempotency_key {
+                                        idempotency_keys.insert(persisted, key);
+                                    }
                                     last_seq_no = Some(seq_no);
                                     state = Self::handle_evt(state, evt);
 
@@ -207,7 +275,7 @@ pub trait EventSourcedExt: Sized {
                                         };
                                     }
 
-                                    if result_sender.send(Ok(())).is_err() {
+                                    if result_sender.send(Ok(persisted)).is_err() {
                                         error!(?id, "cannot send command handler OK");
                                     };
                                 }
@@ -258,6 +326,10 @@ pub enum SpawnError {
     /// The next event cannot be obtained from the event log.
     #[error("cannot get next event from event log")]
     NextEvt(#[source] BoxError),
+
+    /// Idempotency keys cannot be obtained from the event log.
+    #[error("cannot get idempotency keys from event log")]
+    IdempotencyKeys(#[source] BoxError),
 }
 
 impl<E> EventSourcedExt for E where E: EventSourced {}
@@ -269,19 +341,50 @@ pub struct EntityRef<E>
 where
     E: EventSourced,
 {
-    cmd_in: mpsc::Sender<(E::Cmd, oneshot::Sender<Result<(), E::Error>>)>,
+    cmd_in: mpsc::Sender<(
+        E::Cmd,
+        Option<String>,
+        oneshot::Sender<Result<Persisted, E::Error>>,
+    )>,
 }
 
 impl<E> EntityRef<E>
 where
     E: EventSourced,
 {
-    /// Invoke the command handler of the entity.
+    /// Invoke the command handler of the entity, returning the sequence number and the position of
+    /// the persisted event unless the command has been rejected. The position can be used to wait
+    /// for a projection to reach the event, e.g. for read-your-writes consistency.
     #[instrument(skip(self))]
-    pub async fn handle_cmd(&self, cmd: E::Cmd) -> Result<Result<(), E::Error>, HandleCmdError> {
+    pub async fn handle_cmd(
+        &self,
+        cmd: E::Cmd,
+    ) -> Result<Result<Persisted, E::Error>, HandleCmdError> {
+        self.dispatch_cmd(cmd, None).await
+    }
+
+    /// Invoke the command handler of the entity like [handle_cmd](EntityRef::handle_cmd), unless
+    /// the event of a command with the given idempotency key is among the most recent events of
+    /// the entity, in which case its sequence number and position are returned without persisting
+    /// again.
+    /// Rejected commands are not recorded, hence their duplicates are handled again.
+    #[instrument(skip(self))]
+    pub async fn handle_cmd_idempotent(
+        &self,
+        cmd: E::Cmd,
+        idempotency_key: impl Into<String> + Debug,
+    ) -> Result<Result<Persisted, E::Error>, HandleCmdError> {
+        self.dispatch_cmd(cmd, Some(idempotency_key.into())).await
+    }
+
+    async fn dispatch_cmd(
+        &self,
+        cmd: E::Cmd,
+        idempotency_key: Option<String>,
+    ) -> Result<Result<Persisted, E::Error>, HandleCmdError> {
         let (result_in, result_out) = oneshot::channel();
         self.cmd_in
-            .send((cmd, result_in))
+            .send((cmd, idempotency_key, result_in))
             .await
             .map_err(|_| HandleCmdError("cannot send command".to_string()))?;
         result_out
@@ -290,6 +393,56 @@ where
     }
 }
 
+/// The idempotency keys of the events of an entity within the window of the given size, i.e. with
+/// sequence numbers greater than the last one minus the size.
+#[derive(Debug)]
+struct IdempotencyKeys {
+    window: u64,
+    keys: VecDeque<(Persisted, String)>,
+}
+
+impl IdempotencyKeys {
+    fn new(window: u64) -> Self {
+        Self {
+            window,
+            keys: VecDeque::new(),
+        }
+    }
+
+    /// The first sequence number within the window for the given last one, if any.
+    fn first_seq_no(&self, last_seq_no: NonZeroU64) -> Option<NonZeroU64> {
+        (self.window > 0).then(|| {
+            NonZeroU64::new(last_seq_no.get().saturating_sub(self.window - 1))
+                .unwrap_or(NonZeroU64::MIN)
+        })
+    }
+
+    /// Insert the given key for the given persisted event, whose sequence number must be greater
+    /// than the ones inserted before, dropping the keys outside of the window.
+    fn insert(&mut self, persisted: Persisted, key: String) {
+        let Some(first_seq_no) = self.first_seq_no(persisted.seq_no) else {
+            return;
+        };
+        self.keys.push_back((persisted, key));
+        while self
+            .keys
+            .front()
+            .is_some_and(|(persisted, _)| persisted.seq_no < first_seq_no)
+        {
+            self.keys.pop_front();
+        }
+    }
+
+    /// The persisted event for the given key if within the window for the given last sequence
+    /// number.
+    fn get(&self, key: &str, last_seq_no: Option<NonZeroU64>) -> Option<Persisted> {
+        let first_seq_no = last_seq_no.and_then(|n| self.first_seq_no(n))?;
+        self.keys.iter().find_map(|(persisted, k)| {
+            (persisted.seq_no >= first_seq_no && k == key).then_some(*persisted)
+        })
+    }
+}
+
 /// A command cannot be sent from an [EntityRef] to its entity or the result cannot be received
 /// from its entity.
 #[derive(Debug, Error, Serialize, Deserialize)]
@@ -301,7 +454,7 @@ mod tests {
     use super::*;
     use bytes::Bytes;
     use futures::{stream, Stream, StreamExt};
-    use std::{convert::Infallible, iter};
+    use std::convert::Infallible;
     use tracing_test::traced_test;
     use uuid::Uuid;
 
@@ -331,9 +484,45 @@ mod tests {
         }
     }
 
+    #[derive(Debug)]
+    struct Idempotent;
+
+    impl EventSourced for Idempotent {
+        type Id = Uuid;
+        type Cmd = ();
+        type Evt = ();
+        type State = u64;
+        type Error = Infallible;
+
+        const TYPE_NAME: &'static str = "idempotent";
+
+        const IDEMPOTENCY_WINDOW: u64 = 10;
+
+        fn handle_cmd(
+            _id: &Self::Id,
+            _state: &Self::State,
+            _cmd: Self::Cmd,
+        ) -> Result<Self::Evt, Self::Error> {
+            Ok(())
+        }
+
+        fn handle_evt(mut state: Self::State, _evt: Self::Evt) -> Self::State {
+            state += 1;
+            state
+        }
+    }
+
     #[derive(Debug, Clone)]
     struct TestEvtLog;
 
+    /// The events of the [TestEvtLog] are positioned after 100 events of other entities.
+    fn persisted(seq_no: NonZeroU64) -> Persisted {
+        Persisted {
+            seq_no,
+            position: seq_no.saturating_add(100),
+        }
+    }
+
     impl EvtLog for TestEvtLog {
         type Id = Uuid;
         type Error = TestEvtLogError;
@@ -343,15 +532,16 @@ mod tests {
             _evt: &E::Evt,
             _id: &Self::Id,
             last_seq_no: Option<NonZeroU64>,
+            _idempotency_key: Option<&str>,
             _to_bytes: &ToBytes,
-        ) -> Result<NonZeroU64, Self::Error>
+        ) -> Result<Persisted, Self::Error>
         where
             E: EventSourced,
             ToBytes: Fn(&E::Evt) -> Result<Bytes, ToBytesError> + Sync,
             ToBytesError: StdError + Send + Sync + 'static,
         {
             let seq_no = last_seq_no.unwrap_or(NonZeroU64::MIN);
-            Ok(seq_no)
+            Ok(persisted(seq_no))
         }
 
         async fn last_seq_no<E>(
@@ -364,6 +554,31 @@ mod tests {
             Ok(Some(42.try_into().unwrap()))
         }
 
+        async fn last_seq_no_by_type<E>(&self) -> Result<Option<NonZeroU64>, Self::Error>
+        where
+            E: EventSourced,
+        {
+            Ok(None)
+        }
+
+        async fn idempotency_keys<E>(
+            &self,
+            _id: &Self::Id,
+            seq_no: NonZeroU64,
+        ) -> Result<impl Stream<Item = Result<(Persisted, String), Self::Error>> + Send, Self::Error>
+        where
+            E: EventSourced,
+        {
+            let keys = [(
+                persisted(NonZeroU64::new(42).unwrap()),
+                "restored".to_string(),
+            )]
+            .into_iter()
+            .filter(move |(persisted, _)| persisted.seq_no >= seq_no)
+            .map(Ok);
+            Ok(stream::iter(keys))
+        }
+
         async fn evts_by_id<E, FromBytes, FromBytesError>(
             &self,
             _id: &Self::Id,
@@ -375,11 +590,13 @@ mod tests {
             FromBytes: Fn(Bytes) -> Result<E::Evt, FromBytesError> + Copy + Send + Sync,
             FromBytesError: StdError + Send + Sync + 'static,
         {
-            let successors = iter::successors(Some(seq_no), |n| n.checked_add(1));
-            let evts = stream::iter(successors).map(move |n| {
-                let evt = evt_from_bytes(serde_json::to_vec(&()).unwrap().into()).unwrap();
-                Ok((n, evt))
-            });
+            // Up to the last sequence number and then pending, like a real event log.
+            let evts = stream::iter(seq_no.get()..=42)
+                .map(move |n| {
+                    let evt = evt_from_bytes(serde_json::to_vec(&()).unwrap().into()).unwrap();
+                    Ok((NonZeroU64::new(n).unwrap(), evt))
+                })
+                .chain(stream::pending());
 
             Ok(evts)
         }
@@ -462,10 +679,57 @@ mod tests {
         )
         .await?;
 
-        entity.handle_cmd(()).await??;
+        let persisted = entity.handle_cmd(()).await??;
+        assert_eq!(persisted.seq_no, NonZeroU64::new(42).unwrap());
+        assert_eq!(persisted.position, NonZeroU64::new(142).unwrap());
 
         assert!(logs_contain("state=42"));
 
         Ok(())
     }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_handle_cmd_idempotent() -> Result<(), BoxError> {
+        let entity = Idempotent::spawn(
+            Uuid::from_u128(1),
+            None,
+            NonZeroUsize::MIN,
+            TestEvtLog,
+            TestSnapshotStore,
+            binarize::serde_json::SerdeJsonBinarize,
+        )
+        .await?;
+
+        // The key of the last event has been restored.
+        let persisted = entity.handle_cmd_idempotent((), "restored").await??;
+        assert_eq!(persisted.seq_no, NonZeroU64::new(42).unwrap());
+        assert_eq!(persisted.position, NonZeroU64::new(142).unwrap());
+        assert!(logs_contain("ignoring duplicate command"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_idempotency_keys() {
+        let seq_no = |n| NonZeroU64::new(n).unwrap();
+
+        let mut keys = IdempotencyKeys::new(3);
+        assert_eq!(keys.first_seq_no(seq_no(2)), Some(seq_no(1)));
+        assert_eq!(keys.first_seq_no(seq_no(5)), Some(seq_no(3)));
+
+        keys.insert(persisted(seq_no(1)), "a".to_string());
+        keys.insert(persisted(seq_no(3)), "b".to_string());
+        assert_eq!(keys.get("a", Some(seq_no(3))), Some(persisted(seq_no(1))));
+        assert_eq!(keys.get("a", Some(seq_no(4))), None);
+        assert_eq!(keys.get("b", Some(seq_no(4))), Some(persisted(seq_no(3))));
+
+        keys.insert(persisted(seq_no(4)), "c".to_string());
+        assert_eq!(keys.keys.len(), 2);
+
+        let mut keys = IdempotencyKeys::new(0);
+        assert_eq!(keys.first_seq_no(seq_no(1)), None);
+        keys.insert(persisted(seq_no(1)), "a".to_string());
+        assert_eq!(keys.get("a", Some(seq_no(1))), None);
+    }
 }
@@ -1,6 +1,8 @@
 use anyhow::{Context, Result};
 use configured::Configured;
-use eventsourced_nats::{NatsEvtLog, NatsEvtLogConfig, NatsSnapshotStore, NatsSnapshotStoreConfig};
+use eventsourced_nats::{
+    ClientConfig, NatsEvtLog, NatsEvtLogConfig, NatsSnapshotStore, NatsSnapshotStoreConfig,
+};
 use serde::Deserialize;
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt, EnvFilter};
 
@@ -15,11 +17,16 @@ async fn main() -> Result<()> {
     let config = Config::load().context("load configuration")?;
     println!("Starting with configuration: {config:?}");
 
-    let evt_log = NatsEvtLog::new(config.evt_log)
+    // Share one connection between the event log and the snapshot store.
+    let client = eventsourced_nats::connect(&config.nats)
+        .await
+        .context("connect to NATS")?;
+
+    let evt_log = NatsEvtLog::with_client(client.clone(), config.evt_log)
         .await
         .context("create event log")?;
 
-    let snapshot_store = NatsSnapshotStore::new(config.snapshot_store)
+    let snapshot_store = NatsSnapshotStore::with_client(client, config.snapshot_store)
         .await
         .context("create snapshot store")?;
 
@@ -30,6 +37,7 @@ async fn main() -> Result<()> {
 #[serde(rename_all = "kebab-case")]
 struct Config {
     counter: counter::Config,
+    nats: ClientConfig,
     evt_log: NatsEvtLogConfig,
     snapshot_store: NatsSnapshotStoreConfig,
 }